@@ -1,10 +1,14 @@
 use std::f64;
 
-use vecmath::Vector3;
+use vecmath::{Matrix3, Vector3};
+use vecmath::row_mat3_transform;
 use vecmath::{vec3_add, vec3_cross, vec3_sub, vec3_dot, vec3_scale, vec3_neg};
 
 use ray::RayTraceRay;
 
+// Minimum half extent along each axis, points and flat boxes (planes, disks, ...) are inflated by it
+const MIN_HALF_EXTENT: f64 = 1.0e-7;
+
 #[allow(dead_code)]
 pub struct AABB {
 	start: Vector3<f64>,
@@ -14,10 +18,12 @@ pub struct AABB {
 #[allow(dead_code)]
 impl AABB {
 	pub fn new(x1: Vector3<f64>, x2: Vector3<f64>) -> Self {
-		Self {
+		let mut aabb = Self {
 			start: [x1[0].min(x2[0]), x1[1].min(x2[1]), x1[2].min(x2[2])],
 			end: [x1[0].max(x2[0]), x1[1].max(x2[1]), x1[2].max(x2[2])],
-		}
+		};
+		aabb.inflate_degenerate();
+		aabb
 	}
 
 	pub fn expand(&mut self, x: Vector3<f64>) {
@@ -27,10 +33,50 @@ impl AABB {
 		self.end[0] = self.end[0].max(x[0]);
 		self.end[1] = self.end[1].max(x[1]);
 		self.end[2] = self.end[2].max(x[2]);
+		self.inflate_degenerate();
+	}
+
+	// Zero extents make the start and end of the slabs coincide, which the hit tests can't handle reliably
+	fn inflate_degenerate(&mut self) {
+		for axis in 0..3 {
+			if self.end[axis] - self.start[axis] < 2.0 * MIN_HALF_EXTENT {
+				let center = (self.start[axis] + self.end[axis]) * 0.5;
+				self.start[axis] = center - MIN_HALF_EXTENT;
+				self.end[axis] = center + MIN_HALF_EXTENT;
+			}
+		}
+	}
+
+	pub fn expand_aabb(&mut self, other: &Self) {
+		self.expand(other.start);
+		self.expand(other.end);
+	}
+
+	// Moves all faces outwards by the amount
+	pub fn grown(&self, amount: f64) -> Self {
+		let offset = [amount, amount, amount];
+		Self::new(vec3_sub(self.start, offset), vec3_add(self.end, offset))
+	}
+
+	pub fn get_corners(&self) -> [Vector3<f64>; 8] {
+		get_corners(self.start, self.end)
+	}
+
+	// Tight bounds of the box after rotating (row_mat3_transform) and then translating it, built from the
+	// transformed corners. Meant for local bounds (see RayTraceObject::local_bounds).
+	pub fn transform(&self, rotation: Matrix3<f64>, translation: Vector3<f64>) -> Self {
+		let corners = self.get_corners();
+		let first = vec3_add(row_mat3_transform(rotation, corners[0]), translation);
+		let mut aabb = Self::new(first, first);
+		for corner in corners[1..].iter() {
+			aabb.expand(vec3_add(row_mat3_transform(rotation, *corner), translation));
+		}
+		aabb
 	}
 
-	pub fn is_intersecting(&self, other: &Self) -> bool {
-		for i in 0..2 {
+	// Boxes sharing only a face, edge or corner intersect as well
+	pub fn intersects(&self, other: &Self) -> bool {
+		for i in 0..3 {
 			if self.end[i] < other.start[i] || self.start[i] > other.end[i] {
 				return false;
 			}
@@ -39,6 +85,11 @@ impl AABB {
 		true
 	}
 
+	// Points on the faces are inside
+	pub fn contains(&self, point: Vector3<f64>) -> bool {
+		(0..3).all(|i| point[i] >= self.start[i] && point[i] <= self.end[i])
+	}
+
 	pub fn is_hit(&self, ray: &RayTraceRay) -> bool {
 		let [x, y, z] = *ray.get_position();
 		let (x_start, x_end, y_start, y_end, z_start, z_end) = project_points_onto_ray(ray, (self.start, self.end));
@@ -171,6 +222,36 @@ impl AABB {
 		return Some(ray_min);
 	}
 
+	// Distances along the ray where it enters and leaves the box (slab test)
+	pub fn get_interval(&self, ray: &RayTraceRay) -> Option<(f64, f64)> {
+		let origin = ray.get_position();
+		let direction = ray.get_direction();
+
+		let mut t_min = 0.0_f64;
+		let mut t_max = f64::INFINITY;
+
+		for axis in 0..3 {
+			if direction[axis].abs() < 1.0e-10 {
+				if origin[axis] < self.start[axis] || origin[axis] > self.end[axis] {
+					return None;
+				}
+
+				continue;
+			}
+
+			let t1 = (self.start[axis] - origin[axis]) / direction[axis];
+			let t2 = (self.end[axis] - origin[axis]) / direction[axis];
+			t_min = t_min.max(t1.min(t2));
+			t_max = t_max.min(t1.max(t2));
+		}
+
+		if t_min > t_max {
+			None
+		} else {
+			Some((t_min, t_max))
+		}
+	}
+
 	pub fn get_start(&self) -> &Vector3<f64> {
 		&self.start
 	}
@@ -272,8 +353,8 @@ fn project_points_onto_ray(ray: &RayTraceRay, points: (Vector3<f64>, Vector3<f64
 	return (res[0], res[1], res[2], res[3], res[4], res[5]);
 }
 
-fn get_aabb_interval_on_axis(axis: Vector3<f64>, start: Vector3<f64>, end: Vector3<f64>) -> (f64, f64) {
-	let points = [
+fn get_corners(start: Vector3<f64>, end: Vector3<f64>) -> [Vector3<f64>; 8] {
+	[
 		[start[0], start[1], start[2]],
 		[start[0], start[1], end[2]],
 		[start[0], end[1], start[2]],
@@ -282,9 +363,11 @@ fn get_aabb_interval_on_axis(axis: Vector3<f64>, start: Vector3<f64>, end: Vecto
 		[end[0], start[1], end[2]],
 		[end[0], end[1], start[2]],
 		[end[0], end[1], end[2]]
-	];
+	]
+}
 
-	get_point_interval_on_axis(axis, &points)
+fn get_aabb_interval_on_axis(axis: Vector3<f64>, start: Vector3<f64>, end: Vector3<f64>) -> (f64, f64) {
+	get_point_interval_on_axis(axis, &get_corners(start, end))
 }
 
 fn get_point_interval_on_axis(axis: Vector3<f64>, points: &[Vector3<f64>]) -> (f64, f64) {
@@ -305,4 +388,112 @@ fn get_point_interval_on_axis(axis: Vector3<f64>, points: &[Vector3<f64>]) -> (f
 			if o.0.is_nan() || o.0 > v { v } else { o.0 },
 			if o.1.is_nan() || o.1 < v { v } else { o.1 }
 		)})*/
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+	use ray::RayTraceRay;
+
+	use super::AABB;
+	use super::MIN_HALF_EXTENT;
+
+	const EPSILON: f64 = 1e-9;
+
+	fn unit_box_at(x: f64, y: f64, z: f64) -> AABB {
+		AABB::new([x, y, z], [x + 1.0, y + 1.0, z + 1.0])
+	}
+
+	#[test]
+	fn touching_boxes_intersect() {
+		let aabb = unit_box_at(0.0, 0.0, 0.0);
+		assert!(aabb.intersects(&unit_box_at(1.0, 0.0, 0.0)));
+		assert!(aabb.intersects(&unit_box_at(1.0, 1.0, 1.0)));
+		assert!(unit_box_at(0.0, -1.0, 0.0).intersects(&aabb));
+	}
+
+	#[test]
+	fn overlapping_boxes_intersect() {
+		let aabb = unit_box_at(0.0, 0.0, 0.0);
+		assert!(aabb.intersects(&unit_box_at(0.5, 0.5, 0.5)));
+		assert!(unit_box_at(-0.5, 0.25, -0.75).intersects(&aabb));
+	}
+
+	#[test]
+	fn disjoint_boxes_do_not_intersect() {
+		let aabb = unit_box_at(0.0, 0.0, 0.0);
+		assert!(!aabb.intersects(&unit_box_at(1.5, 0.0, 0.0)));
+		assert!(!aabb.intersects(&unit_box_at(0.0, -2.0, 0.0)));
+		// Overlapping in x and y only
+		assert!(!aabb.intersects(&unit_box_at(0.5, 0.5, 1.5)));
+	}
+
+	#[test]
+	fn nested_boxes_intersect() {
+		let outer = AABB::new([-2.0, -2.0, -2.0], [2.0, 2.0, 2.0]);
+		let inner = AABB::new([-0.5, -0.5, -0.5], [0.5, 0.5, 0.5]);
+		assert!(outer.intersects(&inner));
+		assert!(inner.intersects(&outer));
+	}
+
+	#[test]
+	fn contains_points_on_the_faces() {
+		let aabb = unit_box_at(0.0, 0.0, 0.0);
+		assert!(aabb.contains([0.5, 0.5, 0.5]));
+		assert!(aabb.contains([1.0, 0.0, 0.5]));
+		assert!(!aabb.contains([1.1, 0.5, 0.5]));
+		assert!(!aabb.contains([0.5, 0.5, -0.1]));
+	}
+
+	#[test]
+	fn point_box_is_inflated() {
+		let mut aabb = AABB::new([1.0, 2.0, 3.0], [1.0, 2.0, 3.0]);
+		aabb.expand([1.0, 2.0, 3.0]);
+		for axis in 0..3 {
+			assert!((aabb.get_end()[axis] - aabb.get_start()[axis] - 2.0 * MIN_HALF_EXTENT).abs() < EPSILON);
+		}
+		assert!(aabb.contains([1.0, 2.0, 3.0]));
+	}
+
+	#[test]
+	fn flat_box_is_only_inflated_along_its_normal() {
+		let aabb = AABB::new([-1.0, 0.0, -2.0], [1.0, 0.0, 2.0]);
+		assert_eq!(aabb.get_start()[0], -1.0);
+		assert_eq!(aabb.get_end()[2], 2.0);
+		assert!(aabb.get_start()[1] < 0.0 && aabb.get_end()[1] > 0.0);
+	}
+
+	#[test]
+	fn flat_box_is_hit() {
+		let aabb = AABB::new([-1.0, 0.0, -1.0], [1.0, 0.0, 1.0]);
+
+		// Along the normal and at an angle
+		let ray = RayTraceRay::new([0.2, 5.0, 0.3], [0.0, -1.0, 0.0]);
+		assert!(aabb.is_hit(&ray));
+		assert!((aabb.get_first_hit(&ray).unwrap() - 5.0).abs() < 1e-6);
+		let (t_min, t_max) = aabb.get_interval(&ray).unwrap();
+		assert!(t_min < t_max);
+
+		let ray = RayTraceRay::new([-3.0, 4.0, 0.0], [3.0, -4.0, 0.0]);
+		assert!(aabb.is_hit(&ray));
+		let (t_min, t_max) = aabb.get_interval(&ray).unwrap();
+		assert!((t_min - 5.0).abs() < 1e-6 && (t_max - 5.0).abs() < 1e-6);
+
+		let ray = RayTraceRay::new([3.0, 5.0, 0.0], [0.0, -1.0, 0.0]);
+		assert!(!aabb.is_hit(&ray));
+		assert!(aabb.get_first_hit(&ray).is_none());
+		assert!(aabb.get_interval(&ray).is_none());
+	}
+
+	#[test]
+	fn point_box_is_hit() {
+		let aabb = AABB::new([0.0, 0.0, 0.0], [0.0, 0.0, 0.0]);
+		assert!(aabb.is_hit(&RayTraceRay::new([0.0, 0.0, -5.0], [0.0, 0.0, 1.0])));
+		assert!(!aabb.is_hit(&RayTraceRay::new([0.0, 0.1, -5.0], [0.0, 0.0, 1.0])));
+	}
+
+	#[test]
+	fn flat_boxes_intersect_only_in_the_same_plane() {
+		let aabb = AABB::new([0.0, 0.0, 0.0], [1.0, 1.0, 0.0]);
+		assert!(aabb.intersects(&AABB::new([0.5, 0.5, 0.0], [2.0, 2.0, 0.0])));
+		assert!(!aabb.intersects(&AABB::new([0.5, 0.5, 1.0], [2.0, 2.0, 1.0])));
+	}
+}
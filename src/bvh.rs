@@ -0,0 +1,213 @@
+use std::cmp::Ordering;
+use std::f64;
+
+use vecmath::Vector3;
+use vecmath::{vec3_add, vec3_scale, vec3_sub};
+
+use aabb::AABB;
+#[cfg(feature = "ray_packets")]
+use packet::{RayTracePacket, PACKET_SIZE};
+use ray::RayTraceRay;
+
+// Bounding volume hierarchy over the bounds of the scene objects, split at the median center along the longest
+// axis. Only the bounded objects are in it, unbounded ones (planes, ...) have to be tested separately.
+pub struct RayTraceBvh {
+	nodes: Vec<Node>,
+	// Object ids, the leaves hold ranges of them
+	ids: Vec<usize>
+}
+
+struct Node {
+	aabb: AABB,
+	content: NodeContent
+}
+
+enum NodeContent {
+	// Indices of the child nodes
+	Inner(usize, usize),
+	// Range in the ids
+	Leaf(usize, usize)
+}
+
+const LEAF_SIZE: usize = 4;
+
+#[allow(dead_code)]
+impl RayTraceBvh {
+	pub fn new(bounds: &[(usize, AABB)]) -> Self {
+		let mut items: Vec<(usize, &AABB, Vector3<f64>)> = bounds.iter()
+			.map(|&(id, ref aabb)| (id, aabb, vec3_add(*aabb.get_start(),
+				vec3_scale(vec3_sub(*aabb.get_end(), *aabb.get_start()), 0.5))))
+			.collect();
+
+		let mut bvh = Self {
+			nodes: Vec::with_capacity(2 * bounds.len() / LEAF_SIZE + 1),
+			ids: Vec::with_capacity(bounds.len())
+		};
+
+		if !items.is_empty() {
+			bvh.build(&mut items);
+		}
+
+		bvh
+	}
+
+	pub fn get_node_count(&self) -> usize {
+		self.nodes.len()
+	}
+
+	// Visits the objects whose bounds the ray enters before the maximum distance, the nearer nodes first.
+	// The visit gets the object id and the current maximum distance and returns the new one (the distance of
+	// a nearer hit, ...), negative infinity stops the walk.
+	pub fn walk<F>(&self, ray: &RayTraceRay, max_distance: f64, mut visit: F) where F: FnMut(usize, f64) -> f64 {
+		if self.nodes.is_empty() {
+			return;
+		}
+
+		let mut max_distance = max_distance;
+		let mut stack = match self.nodes[0].aabb.get_interval(ray) {
+			Some((start, _)) => vec![(0, start)],
+			None => { return; }
+		};
+
+		while let Some((index, start)) = stack.pop() {
+			if start > max_distance {
+				continue;
+			}
+
+			match self.nodes[index].content {
+				NodeContent::Leaf(first, count) => {
+					for &id in self.ids[first..(first + count)].iter() {
+						max_distance = visit(id, max_distance);
+						if max_distance == f64::NEG_INFINITY {
+							return;
+						}
+					}
+				},
+				NodeContent::Inner(left, right) => {
+					let left_hit = self.nodes[left].aabb.get_interval(ray).map(|(start, _)| (left, start));
+					let right_hit = self.nodes[right].aabb.get_interval(ray).map(|(start, _)| (right, start));
+
+					// The nearer child is pushed last to be visited first
+					match (left_hit, right_hit) {
+						(Some(left_hit), Some(right_hit)) => if left_hit.1 <= right_hit.1 {
+							stack.push(right_hit);
+							stack.push(left_hit);
+						} else {
+							stack.push(left_hit);
+							stack.push(right_hit);
+						},
+						(Some(hit), None) | (None, Some(hit)) => stack.push(hit),
+						(None, None) => { }
+					}
+				}
+			}
+		}
+	}
+
+	// Same as walk for all rays of the packet at once. A node is visited while one of the lanes enters it before
+	// the maximum distance of the lane, the visit gets the object id, the lanes entering the leaf and the maximum
+	// distances to update.
+	#[cfg(feature = "ray_packets")]
+	pub fn walk_packet<F>(&self, packet: &RayTracePacket, max_distances: &mut [f64; PACKET_SIZE], mut visit: F)
+			where F: FnMut(usize, [bool; PACKET_SIZE], &mut [f64; PACKET_SIZE]) {
+		if self.nodes.is_empty() {
+			return;
+		}
+
+		let mut stack = vec![(0, packet.get_entries(&self.nodes[0].aabb))];
+		while let Some((index, entries)) = stack.pop() {
+			let mut lanes = [false; PACKET_SIZE];
+			for lane in 0..PACKET_SIZE {
+				lanes[lane] = entries[lane].map_or(false, |start| start <= max_distances[lane]);
+			}
+			if !lanes.iter().any(|&lane| lane) {
+				continue;
+			}
+
+			match self.nodes[index].content {
+				NodeContent::Leaf(first, count) => {
+					for &id in self.ids[first..(first + count)].iter() {
+						visit(id, lanes, max_distances);
+					}
+				},
+				NodeContent::Inner(left, right) => {
+					let left_hit = (left, packet.get_entries(&self.nodes[left].aabb));
+					let right_hit = (right, packet.get_entries(&self.nodes[right].aabb));
+
+					// The child entered first by any lane is pushed last to be visited first
+					if get_first_entry(&left_hit.1) <= get_first_entry(&right_hit.1) {
+						stack.push(right_hit);
+						stack.push(left_hit);
+					} else {
+						stack.push(left_hit);
+						stack.push(right_hit);
+					}
+				}
+			}
+		}
+	}
+
+	// Visits the objects in the leaves whose bounds overlap the box, the bounds of the objects themselves still
+	// have to be checked
+	pub fn walk_aabb<F>(&self, aabb: &AABB, mut visit: F) where F: FnMut(usize) {
+		if self.nodes.is_empty() {
+			return;
+		}
+
+		let mut stack = vec![0];
+		while let Some(index) = stack.pop() {
+			if !self.nodes[index].aabb.intersects(aabb) {
+				continue;
+			}
+
+			match self.nodes[index].content {
+				NodeContent::Leaf(first, count) => {
+					for &id in self.ids[first..(first + count)].iter() {
+						visit(id);
+					}
+				},
+				NodeContent::Inner(left, right) => {
+					stack.push(right);
+					stack.push(left);
+				}
+			}
+		}
+	}
+
+	fn build(&mut self, items: &mut [(usize, &AABB, Vector3<f64>)]) -> usize {
+		let mut aabb = items[0].1.clone();
+		let mut centers = AABB::new(items[0].2, items[0].2);
+		for &(_, item_aabb, center) in items.iter() {
+			aabb.expand_aabb(item_aabb);
+			centers.expand(center);
+		}
+
+		let index = self.nodes.len();
+		self.nodes.push(Node {
+			aabb: aabb,
+			content: NodeContent::Leaf(self.ids.len(), items.len())
+		});
+
+		if items.len() <= LEAF_SIZE {
+			self.ids.extend(items.iter().map(|&(id, _, _)| id));
+			return index;
+		}
+
+		let extent = vec3_sub(*centers.get_end(), *centers.get_start());
+		let axis = if extent[0] >= extent[1] && extent[0] >= extent[2] { 0 } else if extent[1] >= extent[2] { 1 } else { 2 };
+		items.sort_by(|a, b| a.2[axis].partial_cmp(&b.2[axis]).unwrap_or(Ordering::Equal));
+
+		let middle = items.len() / 2;
+		let (left_items, right_items) = items.split_at_mut(middle);
+		let left = self.build(left_items);
+		let right = self.build(right_items);
+		self.nodes[index].content = NodeContent::Inner(left, right);
+
+		index
+	}
+}
+
+#[cfg(feature = "ray_packets")]
+fn get_first_entry(entries: &[Option<f64>; PACKET_SIZE]) -> f64 {
+	entries.iter().filter_map(|&entry| entry).fold(f64::INFINITY, f64::min)
+}
@@ -0,0 +1,226 @@
+use std::collections::HashMap;
+use std::collections::VecDeque;
+use std::collections::hash_map::DefaultHasher;
+use std::hash::Hasher;
+use std::io::Error;
+
+use color::RayTraceColor;
+use sink::RayTraceSink;
+
+// Key of a frame of one view (camera) of a source, the version of the source stands for its whole state
+pub fn make_frame_key(version: usize, view: usize, frame: usize) -> u64 {
+	let mut hasher = DefaultHasher::new();
+	hasher.write_u64(version as u64);
+	hasher.write_u64(view as u64);
+	hasher.write_u64(frame as u64);
+	hasher.finish()
+}
+
+// The final colors (display transform applied), object ids and depths of a frame, row by row
+pub struct RayTraceFrame {
+	width: usize,
+	height: usize,
+	colors: Vec<RayTraceColor>,
+	// Linear luminance before the exposure and the display transform
+	luminances: Vec<f32>,
+	object_ids: Vec<Option<usize>>,
+	depths: Vec<f32>
+}
+
+#[allow(dead_code)]
+impl RayTraceFrame {
+	pub fn new(width: usize, height: usize, colors: Vec<RayTraceColor>, luminances: Vec<f32>,
+			object_ids: Vec<Option<usize>>, depths: Vec<f32>) -> Self {
+		Self {
+			width: width,
+			height: height,
+			colors: colors,
+			luminances: luminances,
+			object_ids: object_ids,
+			depths: depths
+		}
+	}
+
+	pub fn get_width(&self) -> usize {
+		self.width
+	}
+
+	pub fn get_height(&self) -> usize {
+		self.height
+	}
+
+	pub fn get_colors(&self) -> &Vec<RayTraceColor> {
+		&self.colors
+	}
+
+	pub fn get_luminances(&self) -> &Vec<f32> {
+		&self.luminances
+	}
+
+	pub fn get_object_ids(&self) -> &Vec<Option<usize>> {
+		&self.object_ids
+	}
+
+	// See RayTraceParams::map_depth
+	pub fn get_depths(&self) -> &Vec<f32> {
+		&self.depths
+	}
+
+	pub fn write(&self, sink: &mut Box<RayTraceSink>, frame: usize) -> Result<(), Error> {
+		self.write_with_passes(sink, frame, &[])
+	}
+
+	// Only the colors of the passes are written, they share the object ids and depths of the frame
+	pub fn write_with_passes(&self, sink: &mut Box<RayTraceSink>, frame: usize, passes: &[(String, RayTraceFrame)])
+			-> Result<(), Error> {
+		try!(sink.start_frame(frame));
+
+		for y in 0..self.height {
+			for x in 0..self.width {
+				let index = x + y * self.width;
+				try!(sink.set_sample(x, y, &self.colors[index]));
+				try!(sink.set_luminance(x, y, self.luminances[index]));
+				try!(sink.set_object_id(x, y, self.object_ids[index]));
+				try!(sink.set_depth(x, y, self.depths[index]));
+			}
+		}
+
+		for &(ref name, ref pass) in passes.iter() {
+			for y in 0..pass.height {
+				for x in 0..pass.width {
+					try!(sink.set_pass_sample(name, x, y, &pass.colors[x + y * pass.width]));
+				}
+			}
+		}
+
+		sink.finish_frame(frame)
+	}
+}
+
+// Keeps the last rendered frames by the version of their source, so rendering an unchanged source again (another
+// sink, a preview, ...) skips the rendering. The oldest frame is dropped when full.
+pub struct RayTraceFrameCache {
+	capacity: usize,
+	frames: HashMap<u64, RayTraceFrame>,
+	order: VecDeque<u64>,
+	hits: usize,
+	misses: usize
+}
+
+#[allow(dead_code)]
+impl RayTraceFrameCache {
+	pub fn new(capacity: usize) -> Self {
+		Self {
+			capacity: capacity.max(1),
+			frames: HashMap::new(),
+			order: VecDeque::new(),
+			hits: 0,
+			misses: 0
+		}
+	}
+
+	pub fn get_capacity(&self) -> usize {
+		self.capacity
+	}
+
+	pub fn len(&self) -> usize {
+		self.frames.len()
+	}
+
+	pub fn get_hits(&self) -> usize {
+		self.hits
+	}
+
+	pub fn get_misses(&self) -> usize {
+		self.misses
+	}
+
+	pub fn lookup(&mut self, key: u64) -> Option<&RayTraceFrame> {
+		if self.frames.contains_key(&key) {
+			self.hits += 1;
+		} else {
+			self.misses += 1;
+		}
+
+		self.frames.get(&key)
+	}
+
+	pub fn insert(&mut self, key: u64, frame: RayTraceFrame) {
+		if self.frames.insert(key, frame).is_none() {
+			self.order.push_back(key);
+		}
+
+		while self.order.len() > self.capacity {
+			if let Some(oldest) = self.order.pop_front() {
+				self.frames.remove(&oldest);
+			}
+		}
+	}
+
+	pub fn clear(&mut self) {
+		self.frames.clear();
+		self.order.clear();
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use color::RayTraceColor;
+	use render::RayTracer;
+	use scenes;
+	use sink::BufferSink;
+	use sink::RayTraceSink;
+	use source::RayTraceSource;
+
+	use super::RayTraceFrame;
+	use super::RayTraceFrameCache;
+
+	fn make_frame() -> RayTraceFrame {
+		RayTraceFrame::new(1, 1, vec![RayTraceColor::white()], vec![1.0], vec![None], vec![0.0])
+	}
+
+	fn render_with_cache(tracer: &mut RayTracer, source: &mut RayTraceSource) -> Box<[u8]> {
+		let buffer = BufferSink::new();
+		let mut sink: Box<RayTraceSink> = Box::new(buffer.clone());
+		tracer.render(source, &mut sink).unwrap();
+		buffer.get_frame(0).unwrap()
+	}
+
+	#[test]
+	fn oldest_frame_is_dropped_when_full() {
+		let mut cache = RayTraceFrameCache::new(2);
+		cache.insert(1, make_frame());
+		cache.insert(2, make_frame());
+		cache.insert(3, make_frame());
+
+		assert!(cache.lookup(1).is_none());
+		assert!(cache.lookup(3).is_some());
+		assert_eq!((cache.get_hits(), cache.get_misses(), cache.len()), (1, 1, 2));
+	}
+
+	#[test]
+	fn unchanged_source_comes_from_the_cache() {
+		let mut tracer = RayTracer::new();
+		tracer.set_frame_cache(RayTraceFrameCache::new(4));
+
+		let mut source = scenes::single_sphere(64, 48);
+		let rendered = render_with_cache(&mut tracer, &mut source);
+		// Only reading the source keeps its version
+		assert_eq!(source.get_camera_count(), 1);
+		let cached = render_with_cache(&mut tracer, &mut source);
+		{
+			let cache = tracer.get_frame_cache().as_ref().unwrap();
+			assert_eq!((cache.get_hits(), cache.get_misses(), cache.len()), (1, 1, 1));
+		}
+		assert!(rendered == cached, "the cached frame differs from the rendered one");
+
+		// Any access through the source gives a new version
+		source.get().params.set_background_color(RayTraceColor::red());
+		render_with_cache(&mut tracer, &mut source);
+
+		// Other sources never share the frames, even for the same scene
+		render_with_cache(&mut tracer, &mut scenes::single_sphere(64, 48));
+		let cache = tracer.get_frame_cache().as_ref().unwrap();
+		assert_eq!((cache.get_hits(), cache.get_misses(), cache.len()), (1, 3, 3));
+	}
+}
@@ -6,10 +6,20 @@ pub use self::orthographic::RayTracerCameraOrthographic;
 
 use vecmath::Vector3;
 
+use math_util::RayTraceCoordinateSystem;
 use ray::RayTraceRay;
 
 pub trait RayTraceCamera: Send + Sync {
+	// Set by the renderer from the scene before each init
+	#[allow(unused_variables)]
+	fn set_coordinate_system(&mut self, system: RayTraceCoordinateSystem) { }
+
 	fn init(&mut self, frame: usize);
 	fn make_ray(&self, x: f64, y: f64) -> RayTraceRay;
 	fn get_direction(&self) -> Vector3<f64>;
+
+	// Image size the camera generates rays for, None if it doesn't depend on one
+	fn get_screen_size(&self) -> Option<(usize, usize)> {
+		None
+	}
 }
\ No newline at end of file
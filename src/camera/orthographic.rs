@@ -18,6 +18,7 @@ pub struct RayTracerCameraOrthographic {
 	screen_height: f64,
 	anim_pos: Option<Box<RayTraceAnimation<Vector3<f64>>>>,
 	anim_rot: Option<Box<RayTraceAnimation<Vector3<f64>>>>,
+	coordinate_system: RayTraceCoordinateSystem,
 	data: Option<WorkingData>
 }
 
@@ -29,7 +30,7 @@ struct WorkingData {
 #[allow(dead_code)]
 impl<'a> RayTracerCameraOrthographic {
 	pub fn new(screen: &RayTraceOutputParams, scale: f64) -> Self {
-		Self::new_with(screen, (screen.get_width() as f64) / (screen.get_height() as f64) * scale, scale)
+		Self::new_with(screen, screen.get_display_aspect_ratio() * scale, scale)
 	}
 
 	pub fn new_with(screen: &RayTraceOutputParams, width: f64, height: f64) -> Self {
@@ -42,6 +43,7 @@ impl<'a> RayTracerCameraOrthographic {
 			screen_height: screen.get_height() as f64,
 			anim_rot: None,
 			anim_pos: None,
+			coordinate_system: RayTraceCoordinateSystem::RightHandedYUp,
 			data: None
 		}
 	}
@@ -75,6 +77,10 @@ impl<'a> RayTracerCameraOrthographic {
 
 #[allow(unused_variables)]
 impl RayTraceCamera for RayTracerCameraOrthographic {
+	fn set_coordinate_system(&mut self, system: RayTraceCoordinateSystem) {
+		self.coordinate_system = system;
+	}
+
 	fn init(&mut self, frame: usize) {
 		if let Some(ref anim_pos) = self.anim_pos {
 			self.position = anim_pos.next_frame(frame);
@@ -88,7 +94,7 @@ impl RayTraceCamera for RayTracerCameraOrthographic {
 		let plane_vec2 = [0.0, -self.height / self.screen_height, 0.0];
 		let normal_vec = [0.0, 0.0, -1.0];
 
-		let rot = rotate_xyz(self.rotation);
+		let rot = rotate_xyz_in(self.rotation, self.coordinate_system);
 
 		self.data = Some(WorkingData {
 			plane_vec: [row_mat3_transform(rot, plane_vec1), row_mat3_transform(rot, plane_vec2)],
@@ -115,4 +121,8 @@ impl RayTraceCamera for RayTracerCameraOrthographic {
 			[0.0, 0.0, 0.0]
 		}
 	}
+
+	fn get_screen_size(&self) -> Option<(usize, usize)> {
+		Some((self.screen_width as usize, self.screen_height as usize))
+	}
 }
\ No newline at end of file
@@ -5,6 +5,7 @@ use anim::RayTraceAnimation;
 use camera::RayTraceCamera;
 use params::RayTraceOutputParams;
 use ray::RayTraceRay;
+use scene::RayTraceScene;
 
 #[allow(dead_code)]
 pub struct RayTracerCameraPerspective {
@@ -17,6 +18,8 @@ pub struct RayTracerCameraPerspective {
 	screen_height: f64,
 	anim_pos: Option<Box<RayTraceAnimation<Vector3<f64>>>>,
 	anim_rot: Option<Box<RayTraceAnimation<Vector3<f64>>>>,
+	anim_fov: Option<Box<RayTraceAnimation<f64>>>,
+	coordinate_system: RayTraceCoordinateSystem,
 	data: Option<WorkingData>
 }
 
@@ -29,7 +32,13 @@ struct WorkingData {
 #[allow(dead_code)]
 impl RayTracerCameraPerspective {
 	pub fn new(screen: &RayTraceOutputParams, scale: f64, distance: f64) -> Self {
-		Self::new_with(screen, (screen.get_width() as f64) / (screen.get_height() as f64) * scale, scale, distance)
+		Self::new_with(screen, screen.get_display_aspect_ratio() * scale, scale, distance)
+	}
+
+	// Vertical field of view in degree
+	pub fn new_with_fov(screen: &RayTraceOutputParams, fov: f64) -> Self {
+		let height = 2.0 * (fov * DEG_TO_RAD * 0.5).tan();
+		Self::new_with(screen, screen.get_display_aspect_ratio() * height, height, 1.0)
 	}
 
 	pub fn new_with(screen: &RayTraceOutputParams, width: f64, height: f64, distance: f64) -> Self {
@@ -43,6 +52,8 @@ impl RayTracerCameraPerspective {
 			screen_height: screen.get_height() as f64,
 			anim_rot: None,
 			anim_pos: None,
+			anim_fov: None,
+			coordinate_system: RayTraceCoordinateSystem::RightHandedYUp,
 			data: None
 		}
 	}
@@ -57,6 +68,41 @@ impl RayTracerCameraPerspective {
 		self.data = None;
 	}
 
+	// Vertical field of view in degree, the aspect ratio and the distance of the view plane are kept
+	pub fn set_fov(&mut self, fov: f64) {
+		let height = 2.0 * self.distance * (fov * DEG_TO_RAD * 0.5).tan();
+		self.width *= height / self.height;
+		self.height = height;
+		self.data = None;
+	}
+
+	pub fn get_fov(&self) -> f64 {
+		2.0 * (self.height * 0.5 / self.distance).atan() / DEG_TO_RAD
+	}
+
+	// Moves the camera back along its current view direction until the scene bounds fit into the view.
+	// The scene has to be initialized, returns false if there is nothing to frame.
+	pub fn frame_scene(&mut self, scene: &RayTraceScene, margin: f64) -> bool {
+		let bounds = match scene.get_bounds() {
+			Some(bounds) => bounds,
+			None => { return false; }
+		};
+
+		let size = vec3_sub(*bounds.get_end(), *bounds.get_start());
+		let center = vec3_add(*bounds.get_start(), vec3_scale(size, 0.5));
+		let radius = (vec3_len(size) * 0.5 * (1.0 + margin)).max(1e-10);
+
+		let half_fov_x = (self.width * 0.5 / self.distance).atan();
+		let half_fov_y = (self.height * 0.5 / self.distance).atan();
+		let half_fov = half_fov_x.min(half_fov_y);
+
+		let direction = row_mat3_transform(rotate_xyz_in(self.rotation, self.coordinate_system), [0.0, 0.0, -1.0]);
+		let camera_distance = radius / half_fov.sin();
+
+		self.set_position(vec3_sub(center, vec3_scale(direction, camera_distance)));
+		true
+	}
+
 	pub fn set_anim_pos_opt(&mut self, anim: Option<Box<RayTraceAnimation<Vector3<f64>>>>) {
 		self.anim_pos = anim;
 	}
@@ -72,10 +118,23 @@ impl RayTracerCameraPerspective {
 	pub fn set_anim_rot(&mut self, anim: Box<RayTraceAnimation<Vector3<f64>>>) {
 		self.anim_rot = Some(anim);
 	}
+
+	// Vertical field of view in degree per frame (zoom), see set_fov
+	pub fn set_anim_fov_opt(&mut self, anim: Option<Box<RayTraceAnimation<f64>>>) {
+		self.anim_fov = anim;
+	}
+
+	pub fn set_anim_fov(&mut self, anim: Box<RayTraceAnimation<f64>>) {
+		self.anim_fov = Some(anim);
+	}
 }
 
 #[allow(unused_variables)]
 impl RayTraceCamera for RayTracerCameraPerspective {
+	fn set_coordinate_system(&mut self, system: RayTraceCoordinateSystem) {
+		self.coordinate_system = system;
+	}
+
 	fn init(&mut self, frame: usize) {
 		if let Some(ref anim_pos) = self.anim_pos {
 			self.position = anim_pos.next_frame(frame);
@@ -83,13 +142,16 @@ impl RayTraceCamera for RayTracerCameraPerspective {
 		if let Some(ref anim_rot) = self.anim_rot {
 			self.rotation = anim_rot.next_frame(frame);
 		}
+		if let Some(fov) = self.anim_fov.as_ref().map(|anim_fov| anim_fov.next_frame(frame)) {
+			self.set_fov(fov);
+		}
 
 		// Start with a view into neg z-axis
 		let plane_vec1 = [self.width / self.screen_width, 0.0, 0.0];
 		let plane_vec2 = [0.0, -self.height / self.screen_height, 0.0];
 		let normal_vec = [0.0, 0.0, -1.0];
 
-		let rot = rotate_xyz(self.rotation);
+		let rot = rotate_xyz_in(self.rotation, self.coordinate_system);
 
 		let plane_normal = row_mat3_transform(rot, normal_vec);
 		self.data = Some(WorkingData {
@@ -118,4 +180,8 @@ impl RayTraceCamera for RayTracerCameraPerspective {
 			[0.0, 0.0, 0.0]
 		}
 	}
+
+	fn get_screen_size(&self) -> Option<(usize, usize)> {
+		Some((self.screen_width as usize, self.screen_height as usize))
+	}
 }
\ No newline at end of file
@@ -1,5 +1,52 @@
 use std::ops::{Add, AddAssign, Sub, SubAssign, Mul, MulAssign, Div, DivAssign};
 
+#[cfg(feature = "half")]
+use half::f16;
+
+use post::RayTraceCubeLut;
+
+// Linear rgb color spaces, identified by their primaries (sRGB shares the Rec.709 primaries)
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum RayTraceColorSpace {
+	Rec709,
+	Rec2020
+}
+
+impl RayTraceColorSpace {
+	fn get_to_xyz(&self) -> [[f32; 3]; 3] {
+		match *self {
+			RayTraceColorSpace::Rec709 => [
+				[0.4124564, 0.3575761, 0.1804375],
+				[0.2126729, 0.7151522, 0.0721750],
+				[0.0193339, 0.1191920, 0.9503041]
+			],
+			RayTraceColorSpace::Rec2020 => [
+				[0.6369580, 0.1446169, 0.1688810],
+				[0.2627002, 0.6779981, 0.0593017],
+				[0.0000000, 0.0280727, 1.0609851]
+			]
+		}
+	}
+
+	fn get_from_xyz(&self) -> [[f32; 3]; 3] {
+		match *self {
+			RayTraceColorSpace::Rec709 => [
+				[3.2404542, -1.5371385, -0.4985314],
+				[-0.9692660, 1.8760108, 0.0415560],
+				[0.0556434, -0.2040259, 1.0572252]
+			],
+			RayTraceColorSpace::Rec2020 => [
+				[1.7166512, -0.3556708, -0.2533663],
+				[-0.6666844, 1.6164812, 0.0157685],
+				[0.0176399, -0.0427706, 0.9421031]
+			]
+		}
+	}
+}
+
+// Channels at or below it are treated as black by is_black, contributions that dark are skipped when shading
+pub const BLACK_THRESHOLD: f32 = 1e-6;
+
 #[derive(Debug, Clone)]
 pub struct RayTraceColor {
 	r: f32,
@@ -19,6 +66,7 @@ impl RayTraceColor {
 		}
 	}
 
+	// All colors are stored and shaded with linear values, new_with takes them as they are
 	pub fn new_with(r: f32, g: f32, b: f32, a: f32) -> Self {
 		RayTraceColor {
 			r: r,
@@ -28,6 +76,15 @@ impl RayTraceColor {
 		}
 	}
 
+	pub fn from_linear(r: f32, g: f32, b: f32, a: f32) -> Self {
+		Self::new_with(r, g, b, a)
+	}
+
+	// Encoded sRGB values as picked in image editors or given as hex codes, alpha is always linear
+	pub fn from_srgb(r: f32, g: f32, b: f32, a: f32) -> Self {
+		Self::new_with(decode_srgb(r), decode_srgb(g), decode_srgb(b), a)
+	}
+
 	pub fn transparent() -> Self {
 		RayTraceColor {
 			r: 1.0,
@@ -194,6 +251,45 @@ impl RayTraceColor {
 		self.a
 	}
 
+	// Channels in the order r, g, b, a
+	pub fn channel(&self, index: usize) -> f32 {
+		match index {
+			0 => self.r,
+			1 => self.g,
+			2 => self.b,
+			3 => self.a,
+			_ => panic!("Color channel {} is out of range!", index)
+		}
+	}
+
+	pub fn channel_mut(&mut self, index: usize) -> &mut f32 {
+		match index {
+			0 => &mut self.r,
+			1 => &mut self.g,
+			2 => &mut self.b,
+			3 => &mut self.a,
+			_ => panic!("Color channel {} is out of range!", index)
+		}
+	}
+
+	pub fn as_array(&self) -> [f32; 4] {
+		[self.r, self.g, self.b, self.a]
+	}
+
+	pub fn from_array(channels: [f32; 4]) -> Self {
+		Self::new_with(channels[0], channels[1], channels[2], channels[3])
+	}
+
+	// Only looks at the rgb channels, see BLACK_THRESHOLD for the usual epsilon
+	pub fn is_black(&self, epsilon: f32) -> bool {
+		self.r.abs() <= epsilon && self.g.abs() <= epsilon && self.b.abs() <= epsilon
+	}
+
+	// Relative luminance with the Rec.709 weights
+	pub fn get_luminance(&self) -> f32 {
+		0.2126 * self.r + 0.7152 * self.g + 0.0722 * self.b
+	}
+
 	pub fn add(&mut self, r: f32, g: f32, b: f32, a: f32) {
 		self.r += r;
 		self.g += g;
@@ -223,14 +319,190 @@ impl RayTraceColor {
 		}
 	}
 
-	pub fn clamp(&mut self) {
-		self.r = clamp_value(self.r);
-		self.g = clamp_value(self.g);
-		self.b = clamp_value(self.b);
-		self.a = clamp_value(self.a);
+	// All four channels are averaged, so alpha ends up as the coverage of the samples
+	pub fn average_of<'a, I>(colors: I) -> Self where I: IntoIterator<Item = &'a RayTraceColor> {
+		let mut sum = Self::new_with(0.0, 0.0, 0.0, 0.0);
+		let mut count = 0_usize;
+
+		for color in colors {
+			sum += color;
+			count += 1;
+		}
+
+		if count == 0 {
+			sum
+		} else {
+			sum / (count as f32)
+		}
 	}
 
-	pub fn get_clamped(&self) -> Self {
+	// Alpha is weighted like the color channels and the result is not normalized
+	pub fn weighted_sum<'a, I>(colors: I) -> Self where I: IntoIterator<Item = (&'a RayTraceColor, f32)> {
+		let mut sum = Self::new_with(0.0, 0.0, 0.0, 0.0);
+
+		for (color, weight) in colors {
+			sum.r += color.r * weight;
+			sum.g += color.g * weight;
+			sum.b += color.b * weight;
+			sum.a += color.a * weight;
+		}
+
+		sum
+	}
+
+	// Scales the rgb channels by the coverage, so averaging such colors weights each sample by its alpha and
+	// the edges against a transparent background don't blend in the background color. Fully transparent
+	// samples keep a tiny weight, so pixels without any coverage still get a color.
+	pub fn to_coverage_weighted(&self) -> Self {
+		let weight = get_coverage_weight(self.a);
+		Self {
+			r: self.r * weight,
+			g: self.g * weight,
+			b: self.b * weight,
+			a: self.a
+		}
+	}
+
+	// Inverse of to_coverage_weighted, also for averages of such colors
+	pub fn from_coverage_weighted(&self) -> Self {
+		let weight = get_coverage_weight(self.a);
+		Self {
+			r: self.r / weight,
+			g: self.g / weight,
+			b: self.b / weight,
+			a: self.a
+		}
+	}
+
+	// The rgb channels multiplied by alpha (premultiplied alpha)
+	pub fn premultiplied(&self) -> Self {
+		Self {
+			r: self.r * self.a,
+			g: self.g * self.a,
+			b: self.b * self.a,
+			a: self.a
+		}
+	}
+
+	// Reinhard tone mapping of the rgb channels, c * (1 + c / white²) / (1 + c). The white luminance maps to 1,
+	// without it (infinite white) this is the basic c / (1 + c). Alpha is kept as is.
+	pub fn reinhard(&self, white: Option<f32>) -> Self {
+		let inv_white_sq = white.map_or(0.0, |white| 1.0 / (white * white));
+		let map = |c: f32| {
+			let c = c.max(0.0);
+			c * (1.0 + c * inv_white_sq) / (1.0 + c)
+		};
+
+		Self {
+			r: map(self.r),
+			g: map(self.g),
+			b: map(self.b),
+			a: self.a
+		}
+	}
+
+	// Blends the rgb channels towards the luminance, 0 is grayscale, 1 keeps the color and above 1 boosts it.
+	// Alpha is kept as is.
+	pub fn adjust_saturation(&self, amount: f32) -> Self {
+		let luminance = self.get_luminance();
+
+		Self {
+			r: luminance + (self.r - luminance) * amount,
+			g: luminance + (self.g - luminance) * amount,
+			b: luminance + (self.b - luminance) * amount,
+			a: self.a
+		}
+	}
+
+	// Graded rgb channels from the lookup table, alpha is kept as is
+	pub fn apply_lut(&self, lut: &RayTraceCubeLut) -> Self {
+		let rgb = lut.lookup([self.r, self.g, self.b]);
+
+		Self {
+			r: rgb[0],
+			g: rgb[1],
+			b: rgb[2],
+			a: self.a
+		}
+	}
+
+	// Power curve with exponent 1 / gamma on the rgb channels, alpha is kept as is
+	pub fn gamma(&self, gamma: f32) -> Self {
+		let exponent = 1.0 / gamma;
+		Self {
+			r: self.r.max(0.0).powf(exponent),
+			g: self.g.max(0.0).powf(exponent),
+			b: self.b.max(0.0).powf(exponent),
+			a: self.a
+		}
+	}
+
+	// Linear, opaque color from CIE XYZ values
+	pub fn from_xyz(x: f32, y: f32, z: f32, color_space: RayTraceColorSpace) -> Self {
+		let [r, g, b] = mul_mat3(color_space.get_from_xyz(), [x, y, z]);
+		Self::new_with(r, g, b, 1.0)
+	}
+
+	// sRGB transfer curve on the rgb channels (linear to encoded), alpha is kept as is
+	pub fn to_srgb(&self) -> Self {
+		Self {
+			r: encode_srgb(self.r),
+			g: encode_srgb(self.g),
+			b: encode_srgb(self.b),
+			a: self.a
+		}
+	}
+
+	// Inverse of to_srgb
+	pub fn to_linear(&self) -> Self {
+		Self {
+			r: decode_srgb(self.r),
+			g: decode_srgb(self.g),
+			b: decode_srgb(self.b),
+			a: self.a
+		}
+	}
+
+	// Expects linear values, alpha is kept as is
+	pub fn convert(&self, from: RayTraceColorSpace, to: RayTraceColorSpace) -> Self {
+		if from == to {
+			return self.clone();
+		}
+
+		let xyz = mul_mat3(from.get_to_xyz(), [self.r, self.g, self.b]);
+		let [r, g, b] = mul_mat3(to.get_from_xyz(), xyz);
+
+		Self {
+			r: r,
+			g: g,
+			b: b,
+			a: self.a
+		}
+	}
+
+	// Packs the channels as little endian half floats (r, g, b, a).
+	// Half floats keep about 3 significant digits, values above 65504 are clamped to it
+	// and values below 6.1e-5 lose precision until they flush to zero below 6e-8.
+	#[cfg(feature = "half")]
+	pub fn to_f16_bytes(&self) -> [u8; 8] {
+		let mut bytes = [0_u8; 8];
+		for (i, value) in [self.r, self.g, self.b, self.a].iter().enumerate() {
+			let bits = f16::from_f32(value.max(-HALF_MAX).min(HALF_MAX)).to_bits();
+			bytes[i * 2] = (bits & 0xFF) as u8;
+			bytes[i * 2 + 1] = (bits >> 8) as u8;
+		}
+
+		bytes
+	}
+
+	#[cfg(feature = "half")]
+	pub fn from_f16_bytes(bytes: &[u8; 8]) -> Self {
+		let channel = |i: usize| f16::from_bits(bytes[i * 2] as u16 | (bytes[i * 2 + 1] as u16) << 8).to_f32();
+		Self::new_with(channel(0), channel(1), channel(2), channel(3))
+	}
+
+	// Clamps all channels to [0, 1], NaN channels become 0
+	pub fn clamp01(&self) -> Self {
 		Self {
 			r: clamp_value(self.r),
 			g: clamp_value(self.g),
@@ -238,12 +510,91 @@ impl RayTraceColor {
 			a: clamp_value(self.a)
 		}
 	}
+
+	pub fn clamp(&mut self) {
+		*self = self.clamp01();
+	}
+
+	pub fn get_clamped(&self) -> Self {
+		self.clamp01()
+	}
+}
+
+// Largest finite half float
+#[cfg(feature = "half")]
+const HALF_MAX: f32 = 65504.0;
+
+// Weight of fully transparent samples, a power of two keeps opaque colors exact
+const MIN_COVERAGE_WEIGHT: f32 = 1.0 / 16384.0;
+
+// Linear in alpha, so the weight of an average follows from the averaged alpha
+fn get_coverage_weight(alpha: f32) -> f32 {
+	MIN_COVERAGE_WEIGHT + alpha.max(0.0) * (1.0 - MIN_COVERAGE_WEIGHT)
+}
+
+fn encode_srgb(value: f32) -> f32 {
+	if value <= 0.0031308 { value * 12.92 } else { 1.055 * value.powf(1.0 / 2.4) - 0.055 }
+}
+
+fn decode_srgb(value: f32) -> f32 {
+	if value <= 0.04045 { value / 12.92 } else { ((value + 0.055) / 1.055).powf(2.4) }
+}
+
+fn mul_mat3(mat: [[f32; 3]; 3], vec: [f32; 3]) -> [f32; 3] {
+	[
+		mat[0][0] * vec[0] + mat[0][1] * vec[1] + mat[0][2] * vec[2],
+		mat[1][0] * vec[0] + mat[1][1] * vec[1] + mat[1][2] * vec[2],
+		mat[2][0] * vec[0] + mat[2][1] * vec[1] + mat[2][2] * vec[2]
+	]
 }
 
 fn clamp_value(value: f32) -> f32 {
-	if value <= 0.0 { return 0.0; }
 	if value >= 1.0 { return 1.0; }
-	value
+	if value > 0.0 { return value; }
+	0.0
+}
+
+// Iterates the channels in the order r, g, b, a
+pub struct RayTraceColorChannels {
+	channels: [f32; 4],
+	index: usize
+}
+
+impl Iterator for RayTraceColorChannels {
+	type Item = f32;
+
+	fn next(&mut self) -> Option<f32> {
+		if self.index < 4 {
+			self.index += 1;
+			Some(self.channels[self.index - 1])
+		} else {
+			None
+		}
+	}
+
+	fn size_hint(&self) -> (usize, Option<usize>) {
+		(4 - self.index, Some(4 - self.index))
+	}
+}
+
+impl ExactSizeIterator for RayTraceColorChannels {}
+
+impl IntoIterator for RayTraceColor {
+	type Item = f32;
+	type IntoIter = RayTraceColorChannels;
+
+	fn into_iter(self) -> RayTraceColorChannels {
+		RayTraceColorChannels { channels: self.as_array(), index: 0 }
+	}
+}
+
+impl<'a> IntoIterator for &'a RayTraceColor {
+	type Item = f32;
+	type IntoIter = RayTraceColorChannels;
+
+	fn into_iter(self) -> RayTraceColorChannels {
+		RayTraceColorChannels { channels: self.as_array(), index: 0 }
+	}
 }
 
 impl Add for RayTraceColor {
@@ -456,6 +807,7 @@ impl DivAssign<f32> for RayTraceColor {
 	}
 }
 
+// Linear blend of all four channels, the alpha is blended like the color
 pub fn mix_color(color_a: &RayTraceColor, color_b: &RayTraceColor, factor: f32) -> RayTraceColor {
 	if factor >= 1.0 {
 		color_b.clone()
@@ -470,3 +822,182 @@ pub fn mix_color(color_a: &RayTraceColor, color_b: &RayTraceColor, factor: f32)
 		}
 	}
 }
+
+// Blends only the color and keeps the alpha of color_a, for light arriving at a surface (reflections, ...)
+// which doesn't change how much of the surface covers the pixel
+pub fn mix_color_keep_alpha(color_a: &RayTraceColor, color_b: &RayTraceColor, factor: f32) -> RayTraceColor {
+	let mut color = mix_color(color_a, color_b, factor);
+	color.a = color_a.a;
+	color
+}
+
+// Blend modes for compositing a layer on top of a base color, the factor fades the layer in like mix_color.
+// The channels are expected in [0, 1], the alpha of the base is kept.
+pub fn mix_screen(base: &RayTraceColor, layer: &RayTraceColor, factor: f32) -> RayTraceColor {
+	blend_channels(base, layer, factor, |b, l| 1.0 - (1.0 - b) * (1.0 - l))
+}
+
+pub fn mix_multiply(base: &RayTraceColor, layer: &RayTraceColor, factor: f32) -> RayTraceColor {
+	blend_channels(base, layer, factor, |b, l| b * l)
+}
+
+// Multiply for dark base values and screen for bright ones, raises the contrast
+pub fn mix_overlay(base: &RayTraceColor, layer: &RayTraceColor, factor: f32) -> RayTraceColor {
+	blend_channels(base, layer, factor, |b, l| {
+		if b < 0.5 {
+			2.0 * b * l
+		} else {
+			1.0 - 2.0 * (1.0 - b) * (1.0 - l)
+		}
+	})
+}
+
+fn blend_channels<F>(base: &RayTraceColor, layer: &RayTraceColor, factor: f32, blend: F) -> RayTraceColor
+		where F: Fn(f32, f32) -> f32 {
+	let blended = RayTraceColor {
+		r: blend(base.r, layer.r),
+		g: blend(base.g, layer.g),
+		b: blend(base.b, layer.b),
+		a: base.a
+	};
+
+	mix_color(base, &blended, factor)
+}
+
+#[cfg(test)]
+mod tests {
+	use camera::RayTracerCameraOrthographic;
+	use material::RayTraceSimpleMaterial;
+	use object::RayTraceObjectCube;
+	use params::RayTraceOutputParams;
+	use params::RayTraceParams;
+	use params::RayTraceRotatedGridSampling;
+	use render::RayTracer;
+	use scene::RayTraceScene;
+	use sink::BufferSink;
+	use sink::RayTraceSink;
+	use source::RayTraceSource;
+
+	use super::RayTraceColor;
+	use super::mix_color_keep_alpha;
+	use super::{mix_multiply, mix_overlay, mix_screen};
+
+	const EPSILON: f32 = 1e-6;
+
+	fn assert_color(color: RayTraceColor, r: f32, g: f32, b: f32, a: f32) {
+		assert!((color.get_r() - r).abs() < EPSILON && (color.get_g() - g).abs() < EPSILON
+			&& (color.get_b() - b).abs() < EPSILON && (color.get_a() - a).abs() < EPSILON,
+			"{:?} != ({}, {}, {}, {})", color, r, g, b, a);
+	}
+
+	#[test]
+	fn average_of_includes_alpha() {
+		// A covered and an uncovered sample average to half coverage
+		let colors = [RayTraceColor::new_with(1.0, 0.5, 0.0, 1.0), RayTraceColor::new_with(0.0, 0.5, 1.0, 0.0)];
+		assert_color(RayTraceColor::average_of(colors.iter()), 0.5, 0.5, 0.5, 0.5);
+	}
+
+	#[test]
+	fn average_of_nothing_is_transparent_black() {
+		let colors: Vec<RayTraceColor> = Vec::new();
+		assert_color(RayTraceColor::average_of(colors.iter()), 0.0, 0.0, 0.0, 0.0);
+	}
+
+	#[test]
+	fn weighted_sum_weights_alpha() {
+		let colors = [RayTraceColor::new_with(1.0, 0.0, 0.0, 1.0), RayTraceColor::new_with(0.0, 1.0, 0.0, 0.5)];
+		let sum = RayTraceColor::weighted_sum(colors.iter().zip([0.25_f32, 0.5].iter().cloned()));
+		assert_color(sum, 0.25, 0.5, 0.0, 0.5);
+	}
+
+	#[test]
+	fn weighted_sum_is_not_normalized() {
+		let colors = [RayTraceColor::white(), RayTraceColor::white()];
+		let sum = RayTraceColor::weighted_sum(colors.iter().map(|color| (color, 2.0_f32)));
+		assert_color(sum, 4.0, 4.0, 4.0, 4.0);
+	}
+
+	#[test]
+	fn reflection_keeps_the_surface_alpha() {
+		// A half covered surface reflecting the opaque sky stays half covered
+		let surface = RayTraceColor::new_with(1.0, 0.0, 0.0, 0.5);
+		let reflection = RayTraceColor::new_with(0.0, 0.0, 1.0, 1.0);
+		assert_color(mix_color_keep_alpha(&surface, &reflection, 0.25), 0.75, 0.0, 0.25, 0.5);
+	}
+
+	#[test]
+	fn full_reflection_keeps_the_surface_alpha() {
+		let surface = RayTraceColor::new_with(1.0, 1.0, 1.0, 0.2);
+		assert_color(mix_color_keep_alpha(&surface, &RayTraceColor::transparent(), 1.0), 1.0, 1.0, 1.0, 0.2);
+		assert_color(mix_color_keep_alpha(&surface, &RayTraceColor::black(), 1.0), 0.0, 0.0, 0.0, 0.2);
+		assert_color(mix_color_keep_alpha(&surface, &RayTraceColor::black(), 0.0), 1.0, 1.0, 1.0, 0.2);
+	}
+
+	#[test]
+	fn screen_blend() {
+		let base = RayTraceColor::new_with(0.5, 0.0, 1.0, 0.7);
+		let layer = RayTraceColor::new_with(0.5, 0.4, 0.2, 1.0);
+		// 1 - (1 - b) * (1 - l)
+		assert_color(mix_screen(&base, &layer, 1.0), 0.75, 0.4, 1.0, 0.7);
+	}
+
+	#[test]
+	fn multiply_blend() {
+		let base = RayTraceColor::new_with(0.5, 1.0, 0.2, 0.7);
+		let layer = RayTraceColor::new_with(0.5, 0.4, 0.0, 1.0);
+		assert_color(mix_multiply(&base, &layer, 1.0), 0.25, 0.4, 0.0, 0.7);
+	}
+
+	#[test]
+	fn overlay_blend() {
+		// Multiplies below a base of 0.5 and screens above it
+		let base = RayTraceColor::new_with(0.25, 0.75, 0.5, 0.7);
+		let layer = RayTraceColor::new_with(0.5, 0.5, 0.5, 1.0);
+		assert_color(mix_overlay(&base, &layer, 1.0), 0.25, 0.75, 0.5, 0.7);
+
+		let layer = RayTraceColor::new_with(0.8, 0.2, 1.0, 1.0);
+		assert_color(mix_overlay(&base, &layer, 1.0), 0.4, 0.6, 1.0, 0.7);
+	}
+
+	#[test]
+	fn blend_factor_fades_the_layer_in() {
+		let base = RayTraceColor::new_with(0.4, 0.4, 0.4, 1.0);
+		let layer = RayTraceColor::new_with(0.5, 0.5, 0.5, 1.0);
+		assert_color(mix_multiply(&base, &layer, 0.0), 0.4, 0.4, 0.4, 1.0);
+		assert_color(mix_multiply(&base, &layer, 0.5), 0.3, 0.3, 0.3, 1.0);
+	}
+
+	#[test]
+	fn edge_pixel_is_half_covered() {
+		// One world unit per pixel, the right face of the cube runs through the center of column 4. The rotated grid
+		// is symmetric, so two of its four samples hit the cube.
+		let out_params = RayTraceOutputParams::new(8, 8, 1);
+
+		let mut scene = RayTraceScene::new();
+		scene.add_object(Box::new(RayTraceObjectCube::new([-4.75, 0.0, 0.0], [10.5, 20.0, 2.0],
+			Box::new(RayTraceSimpleMaterial::new(RayTraceColor::red())))));
+
+		let mut camera = RayTracerCameraOrthographic::new_with(&out_params, 8.0, 8.0);
+		camera.set_position([0.0, 0.0, 5.0]);
+
+		let mut params = RayTraceParams::new();
+		params.set_sampling(Some(Box::new(RayTraceRotatedGridSampling::new_with(2))));
+		params.set_transparent_background(true);
+
+		let buffer = BufferSink::new();
+		let mut sink: Box<RayTraceSink> = Box::new(buffer.clone());
+		let mut source = RayTraceSource::new(scene, Box::new(camera), out_params, params);
+		RayTracer::new().render(&mut source, &mut sink).unwrap();
+		let frame = buffer.get_frame(0).unwrap();
+		let pixel = |x: usize| &frame[(x + 4 * 8) * 4..(x + 4 * 8) * 4 + 4];
+
+		assert_eq!(pixel(3)[3], 255);
+		assert!((pixel(4)[3] as i32 - 128).abs() <= 2, "edge alpha {}", pixel(4)[3]);
+		assert_eq!(pixel(5)[3], 0);
+		// The edge keeps the color of the cube instead of blending in the transparent background
+		for channel in 0..3 {
+			assert!((pixel(4)[channel] as i32 - pixel(3)[channel] as i32).abs() <= 2,
+				"edge {:?}, inside {:?}", pixel(4), pixel(3));
+		}
+	}
+}
@@ -6,10 +6,23 @@ pub struct RayTraceMaterialHit {
 	diffuse_light: f32,
 	specular_light: f32,
 	surface_roughness: f32,
-	reflectance: f32
+	reflectance: f32,
+	transparency: f32,
+	refraction_index: f32,
+	bump: Option<[f64; 2]>,
+	anisotropy: f32,
+	anisotropy_rotation: f32,
+	cut_out: bool,
+	fresnel: bool,
+	max_depth: Option<usize>
 }
 
 impl<'a> RayTraceMaterialHit {
+	// Black, non-lit surface, to be replaced once the real surface is known
+	pub fn new() -> Self {
+		Self::new_with(RayTraceColor::new(), 0.0, 0.0, 0.0, 0.0)
+	}
+
 	pub fn new_with(color: RayTraceColor, reflectance: f32, diffuse_light: f32, specular_light: f32,
 			surface_roughness: f32) -> Self {
 		Self {
@@ -17,7 +30,43 @@ impl<'a> RayTraceMaterialHit {
 			diffuse_light: diffuse_light,
 			specular_light: specular_light,
 			surface_roughness: surface_roughness,
-			reflectance: reflectance
+			reflectance: reflectance,
+			transparency: 0.0,
+			refraction_index: 1.0,
+			bump: None,
+			anisotropy: 0.0,
+			anisotropy_rotation: 0.0,
+			cut_out: false,
+			fresnel: false,
+			max_depth: None
+		}
+	}
+
+	pub fn mix(hit_a: &Self, hit_b: &Self, factor: f32) -> Self {
+		let factor = factor.max(0.0).min(1.0);
+		let mix_value = |a: f32, b: f32| (1.0 - factor) * a + factor * b;
+
+		Self {
+			color: hit_a.color.mix(&hit_b.color, factor),
+			diffuse_light: mix_value(hit_a.diffuse_light, hit_b.diffuse_light),
+			specular_light: mix_value(hit_a.specular_light, hit_b.specular_light),
+			surface_roughness: mix_value(hit_a.surface_roughness, hit_b.surface_roughness),
+			reflectance: mix_value(hit_a.reflectance, hit_b.reflectance),
+			transparency: mix_value(hit_a.transparency, hit_b.transparency),
+			refraction_index: mix_value(hit_a.refraction_index, hit_b.refraction_index),
+			bump: match (hit_a.bump, hit_b.bump) {
+				(None, None) => None,
+				(bump_a, bump_b) => {
+					let (a, b) = (bump_a.unwrap_or([0.0, 0.0]), bump_b.unwrap_or([0.0, 0.0]));
+					let factor = factor as f64;
+					Some([(1.0 - factor) * a[0] + factor * b[0], (1.0 - factor) * a[1] + factor * b[1]])
+				}
+			},
+			anisotropy: mix_value(hit_a.anisotropy, hit_b.anisotropy),
+			anisotropy_rotation: mix_value(hit_a.anisotropy_rotation, hit_b.anisotropy_rotation),
+			cut_out: if factor < 0.5 { hit_a.cut_out } else { hit_b.cut_out },
+			fresnel: if factor < 0.5 { hit_a.fresnel } else { hit_b.fresnel },
+			max_depth: if factor < 0.5 { hit_a.max_depth } else { hit_b.max_depth }
 		}
 	}
 
@@ -60,4 +109,66 @@ impl<'a> RayTraceMaterialHit {
 	pub fn set_reflectance(&mut self, reflectance: f32) {
 		self.reflectance = reflectance;
 	}
+
+	pub fn get_transparency(&self) -> f32 {
+		self.transparency
+	}
+
+	pub fn set_transparency(&mut self, transparency: f32) {
+		self.transparency = transparency;
+	}
+
+	pub fn get_refraction_index(&self) -> f32 {
+		self.refraction_index
+	}
+
+	pub fn set_refraction_index(&mut self, refraction_index: f32) {
+		self.refraction_index = refraction_index;
+	}
+
+	// Height gradient in material coordinates (x, y)
+	pub fn get_bump(&self) -> Option<[f64; 2]> {
+		self.bump
+	}
+
+	pub fn set_bump(&mut self, bump: Option<[f64; 2]>) {
+		self.bump = bump;
+	}
+
+	// Strength in [0, 1] stretches the highlight along the material x direction rotated by the angle (degrees)
+	pub fn get_anisotropy(&self) -> (f32, f32) {
+		(self.anisotropy, self.anisotropy_rotation)
+	}
+
+	pub fn set_anisotropy(&mut self, strength: f32, rotation: f32) {
+		self.anisotropy = strength;
+		self.anisotropy_rotation = rotation;
+	}
+
+	// Cut out surfaces are skipped by the renderer as if there was no hit
+	pub fn is_cut_out(&self) -> bool {
+		self.cut_out
+	}
+
+	pub fn set_cut_out(&mut self, cut_out: bool) {
+		self.cut_out = cut_out;
+	}
+
+	// The reflectance is the one at normal incidence and rises towards grazing angles (Schlick's approximation)
+	pub fn is_fresnel(&self) -> bool {
+		self.fresnel
+	}
+
+	pub fn set_fresnel(&mut self, fresnel: bool) {
+		self.fresnel = fresnel;
+	}
+
+	// Depth limit for the rays spawned off the surface, None uses the one of the params
+	pub fn get_max_depth(&self) -> Option<usize> {
+		self.max_depth
+	}
+
+	pub fn set_max_depth(&mut self, max_depth: Option<usize>) {
+		self.max_depth = max_depth;
+	}
 }
@@ -1,15 +1,25 @@
-use vecmath::Vector3;
+use vecmath::{Matrix3, Vector3};
+use vecmath::{vec3_add, vec3_dot, vec3_scale, vec3_sub, vec3_normalized};
+use vecmath::row_mat3_transform;
 
 use hit::RayTraceMaterialHit;
 
 #[allow(dead_code)]
+#[derive(Clone)]
 pub struct RayTraceRayHit {
 	distance: f64,
 	position: Vector3<f64>,
 	surface_normal: Vector3<f64>,
-	surface: RayTraceMaterialHit
+	geometric_normal: Vector3<f64>,
+	tangents: Option<[Vector3<f64>; 2]>,
+	surface: RayTraceMaterialHit,
+	barycentric: (f64, f64, f64),
+	object_id: Option<usize>
 }
 
+// Below it the tangents are (nearly) parallel and the bump is ignored
+const GRADIENT_THRESHOLD: f64 = 1e-20;
+
 #[allow(dead_code)]
 impl RayTraceRayHit {
 	pub fn new(distance: f64, position: Vector3<f64>, normal: Vector3<f64>, surface: RayTraceMaterialHit) -> Self {
@@ -17,7 +27,28 @@ impl RayTraceRayHit {
 			distance: distance,
 			position: position,
 			surface_normal: normal,
-			surface: surface
+			geometric_normal: normal,
+			tangents: None,
+			surface: surface,
+			barycentric: (1.0, 0.0, 0.0),
+			object_id: None
+		}
+	}
+
+	// The tangents are the derivatives of the position along the material x and y coordinates in world space
+	// (unit vectors if the material coordinates are world units), they are required to apply the bump of the
+	// material to the surface normal.
+	pub fn new_with_tangents(distance: f64, position: Vector3<f64>, normal: Vector3<f64>, tangents: [Vector3<f64>; 2],
+			surface: RayTraceMaterialHit) -> Self {
+		Self {
+			distance: distance,
+			position: position,
+			surface_normal: apply_bump(normal, &tangents, &surface),
+			geometric_normal: normal,
+			tangents: Some(tangents),
+			surface: surface,
+			barycentric: (1.0, 0.0, 0.0),
+			object_id: None
 		}
 	}
 
@@ -25,6 +56,10 @@ impl RayTraceRayHit {
 		self.distance
 	}
 
+	pub fn set_distance(&mut self, distance: f64) {
+		self.distance = distance;
+	}
+
 	pub fn get_position(&self) -> &Vector3<f64> {
 		&self.position
 	}
@@ -33,7 +68,80 @@ impl RayTraceRayHit {
 		&self.surface_normal
 	}
 
+	// Normal of the surface itself, without the bump of the material
+	pub fn get_geometric_normal(&self) -> &Vector3<f64> {
+		&self.geometric_normal
+	}
+
+	// For surfaces shaded with a normal that differs from the real one (interpolated normals of meshes, ...)
+	pub fn set_geometric_normal(&mut self, normal: Vector3<f64>) {
+		self.geometric_normal = normal;
+	}
+
+	pub fn get_tangents(&self) -> Option<&[Vector3<f64>; 2]> {
+		self.tangents.as_ref()
+	}
+
 	pub fn get_surface_material(&self) -> &RayTraceMaterialHit {
 		&self.surface
 	}
-}
\ No newline at end of file
+
+	// Replaces the shading of the hit, the bump of the material is applied with the tangents of the hit
+	pub fn set_surface(&mut self, normal: Vector3<f64>, surface: RayTraceMaterialHit) {
+		self.surface_normal = match self.tangents {
+			Some(ref tangents) => apply_bump(normal, tangents, &surface),
+			None => normal
+		};
+		self.surface = surface;
+	}
+
+	// Weights of the triangle vertices at the hit, (1, 0, 0) for non-triangle hits
+	pub fn get_barycentric(&self) -> (f64, f64, f64) {
+		self.barycentric
+	}
+
+	pub fn set_barycentric(&mut self, barycentric: (f64, f64, f64)) {
+		self.barycentric = barycentric;
+	}
+
+	// Moves the hit along with its object (rotation, then translation), the distance is kept
+	pub fn transform(&mut self, rotation: Matrix3<f64>, translation: Vector3<f64>) {
+		self.position = vec3_add(row_mat3_transform(rotation, self.position), translation);
+		self.surface_normal = row_mat3_transform(rotation, self.surface_normal);
+		self.geometric_normal = row_mat3_transform(rotation, self.geometric_normal);
+		self.tangents = self.tangents
+			.map(|tangents| [row_mat3_transform(rotation, tangents[0]), row_mat3_transform(rotation, tangents[1])]);
+	}
+
+	// Index of the hit object in the scene
+	pub fn get_object_id(&self) -> Option<usize> {
+		self.object_id
+	}
+
+	pub fn set_object_id(&mut self, object_id: Option<usize>) {
+		self.object_id = object_id;
+	}
+}
+
+// Tilts the normal along the gradients of the material coordinates by the bump of the material
+fn apply_bump(normal: Vector3<f64>, tangents: &[Vector3<f64>; 2], surface: &RayTraceMaterialHit) -> Vector3<f64> {
+	match surface.get_bump() {
+		Some(bump) => {
+			// World space gradients of the material coordinates, the tangents themselves if they are orthonormal
+			let (t_xx, t_xy, t_yy) = (vec3_dot(tangents[0], tangents[0]), vec3_dot(tangents[0], tangents[1]),
+				vec3_dot(tangents[1], tangents[1]));
+			let det = t_xx * t_yy - t_xy * t_xy;
+			if det > GRADIENT_THRESHOLD {
+				let gradient_x = vec3_scale(vec3_sub(vec3_scale(tangents[0], t_yy), vec3_scale(tangents[1], t_xy)),
+					1.0 / det);
+				let gradient_y = vec3_scale(vec3_sub(vec3_scale(tangents[1], t_xx), vec3_scale(tangents[0], t_xy)),
+					1.0 / det);
+				vec3_normalized(vec3_sub(normal,
+					vec3_add(vec3_scale(gradient_x, bump[0]), vec3_scale(gradient_y, bump[1]))))
+			} else {
+				normal
+			}
+		},
+		None => normal
+	}
+}
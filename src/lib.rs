@@ -5,18 +5,25 @@
 #[macro_use]
 extern crate log;
 extern crate rand;
+#[cfg(feature = "half")]
+extern crate half;
 
 extern crate time;
 extern crate scoped_threadpool;
 
 pub extern crate vecmath;
 
-mod aabb;
+mod bvh;
 mod hit;
 mod ray;
 mod nonsync;
+#[cfg(feature = "ray_packets")]
+mod packet;
+mod scratch;
 
+pub mod aabb;
 pub mod anim;
+pub mod cache;
 pub mod camera;
 pub mod color;
 pub mod light;
@@ -24,8 +31,13 @@ pub mod material;
 pub mod math_util;
 pub mod object;
 pub mod params;
+pub mod post;
+pub mod random;
 pub mod render;
 pub mod sample;
 pub mod sink;
 pub mod scene;
-pub mod source;
\ No newline at end of file
+pub mod scenes;
+pub mod sky;
+pub mod source;
+pub mod texture;
\ No newline at end of file
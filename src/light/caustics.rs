@@ -0,0 +1,209 @@
+use std::collections::HashMap;
+
+use vecmath::Vector3;
+use vecmath::{vec3_dot, vec3_square_len, vec3_sub};
+
+use color::RayTraceColor;
+use params::RayTraceParams;
+use random::RayTraceRng;
+use random::derive_seed;
+use ray::RayTraceRay;
+use scene::RayTraceScene;
+
+use math_util::PI;
+use math_util::compute_reflected_ray;
+use math_util::compute_refracted_ray_between;
+
+struct Photon {
+	position: Vector3<f64>,
+	power: RayTraceColor
+}
+
+// Light focused by reflective / refractive objects onto diffuse surfaces.
+// Photons are shot from the lights once per frame and gathered in a radius around the shaded point.
+pub struct RayTraceCausticMap {
+	radius: f64,
+	photons: HashMap<[i64; 3], Vec<Photon>>
+}
+
+#[allow(dead_code)]
+impl RayTraceCausticMap {
+	pub fn new(scene: &RayTraceScene, params: &RayTraceParams) -> Self {
+		let mut map = Self {
+			radius: params.get_caustic_radius(),
+			photons: HashMap::new()
+		};
+
+		let photon_count = params.get_caustic_photons();
+		for (light_index, light) in scene.get_lights().iter().enumerate() {
+			let position = light.get_position();
+			let mut rng = RayTraceRng::new(derive_seed(params.get_frame_seed(), &[light_index as u64]));
+
+			for _ in 0..photon_count {
+				// Uniform direction on the sphere
+				let z = rng.gen_range(-1.0, 1.0);
+				let phi = rng.gen_range(0.0, 2.0 * PI);
+				let r = (1.0 - z * z).sqrt();
+
+				let direction = [r * phi.cos(), r * phi.sin(), z];
+				let mut power = light.get_emission(direction);
+				if power.get_a() <= 0.0 {
+					// Outside of the cone of spots
+					continue;
+				}
+
+				let ray = RayTraceRay::new(position, direction);
+				let intensity = power.get_a() * (4.0 * PI / photon_count as f64) as f32;
+				power = power * intensity;
+				power.set_a(1.0);
+
+				// Lights can be inside of transparent objects (under water, ...) like the camera
+				let media = scene.find_media_at(position, direction);
+				map.trace_photon(scene, params, ray, power, media, &mut rng);
+			}
+		}
+
+		map
+	}
+
+	// Reflective surfaces reflect the photon with the probability of their reflectance (russian roulette) and
+	// absorb it otherwise, so the power of the photons doesn't have to be split. The media are the refraction
+	// indices of the objects the photon is inside of like the renderer tracks them, innermost last.
+	fn trace_photon(&mut self, scene: &RayTraceScene, params: &RayTraceParams, ray: RayTraceRay,
+			power: RayTraceColor, media: Vec<f64>, rng: &mut RayTraceRng) {
+		let mut ray = ray;
+		let mut power = power;
+		let mut media = media;
+		let mut specular_bounces = 0;
+
+		for _ in 0..(params.get_max_depth() + 1) {
+			let hit = match scene.ray_cast_visible(&ray, params) {
+				Some(hit) => hit,
+				None => { return; }
+			};
+
+			let material = hit.get_surface_material().clone();
+			let transparency = material.get_transparency();
+			let reflectance = material.get_reflectance();
+
+			if transparency > 0.0 {
+				let refraction_index = material.get_refraction_index() as f64;
+				let outer_index = media.last().cloned().unwrap_or(1.0);
+				let entering = vec3_dot(ray.get_direction().clone(), hit.get_surface_normal().clone()) < 0.0;
+				let (from_index, to_index) = if entering {
+					(outer_index, refraction_index)
+				} else {
+					(refraction_index, if media.len() > 1 { media[media.len() - 2] } else { 1.0 })
+				};
+
+				ray = match compute_refracted_ray_between(&ray, &hit, from_index, to_index, params.get_ray_offset()) {
+					Some(refracted_ray) => {
+						if entering {
+							media.push(refraction_index);
+						} else {
+							media.pop();
+						}
+						refracted_ray
+					},
+					None => compute_reflected_ray(&ray, &hit, params.get_ray_offset())
+				};
+				power = power * material.get_color() * transparency;
+			} else if rng.next_f64() < reflectance as f64 {
+				ray = compute_reflected_ray(&ray, &hit, params.get_ray_offset());
+			} else {
+				// Only light that went through a specular bounce forms caustics, the rest is direct light
+				if specular_bounces > 0 {
+					self.store(hit.get_position().clone(), power);
+				}
+				return;
+			}
+
+			specular_bounces += 1;
+		}
+	}
+
+	fn store(&mut self, position: Vector3<f64>, power: RayTraceColor) {
+		let cell = self.get_cell(position);
+		self.photons.entry(cell).or_insert_with(Vec::new).push(Photon { position: position, power: power });
+	}
+
+	fn get_cell(&self, position: Vector3<f64>) -> [i64; 3] {
+		[
+			(position[0] / self.radius).floor() as i64,
+			(position[1] / self.radius).floor() as i64,
+			(position[2] / self.radius).floor() as i64
+		]
+	}
+
+	// Irradiance estimate from the photons around the position
+	pub fn get_irradiance(&self, position: Vector3<f64>, normal: Vector3<f64>) -> RayTraceColor {
+		let mut irradiance = RayTraceColor::new_with(0.0, 0.0, 0.0, 0.0);
+		let radius_sq = self.radius * self.radius;
+		let cell = self.get_cell(position);
+
+		for x in -1..2 {
+			for y in -1..2 {
+				for z in -1..2 {
+					if let Some(photons) = self.photons.get(&[cell[0] + x, cell[1] + y, cell[2] + z]) {
+						for photon in photons.iter() {
+							let offset = vec3_sub(photon.position, position);
+
+							// Skip photons on surfaces facing elsewhere (other side of thin walls, ...)
+							if vec3_square_len(offset) <= radius_sq && vec3_dot(offset, normal).abs() < self.radius * 0.5 {
+								irradiance += &photon.power;
+							}
+						}
+					}
+				}
+			}
+		}
+
+		irradiance / (PI * radius_sq) as f32
+	}
+
+	pub fn get_photon_count(&self) -> usize {
+		self.photons.values().map(|photons| photons.len()).sum()
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use color::RayTraceColor;
+	use light::lights::RayTraceDirectedSpotLight;
+	use material::RayTraceSimpleMaterial;
+	use math_util::PI;
+	use object::RayTraceObjectPlane;
+	use object::RayTraceObjectSphere;
+	use params::RayTraceParams;
+	use scene::RayTraceScene;
+
+	use super::RayTraceCausticMap;
+
+	#[test]
+	fn spot_photons_land_inside_the_cone() {
+		// Narrow spot straight down onto a glass sphere above the floor
+		let mut spot = RayTraceDirectedSpotLight::new([0.0, 5.0, 0.0], RayTraceColor::white());
+		spot.set_rotation([0.0, 0.0, PI / 2.0]);
+		spot.set_size(20.0);
+
+		let mut scene = RayTraceScene::new();
+		scene.add_light::<RayTraceDirectedSpotLight>(Box::new(spot));
+		scene.add_object(Box::new(RayTraceObjectSphere::new([0.0, 2.0, 0.0], 1.0,
+			Box::new(RayTraceSimpleMaterial::dielectric(1.5, RayTraceColor::white())))));
+		scene.add_object(Box::new(RayTraceObjectPlane::new([0.0, 0.0, 0.0], [0.0, 0.0, 0.0],
+			Box::new(RayTraceSimpleMaterial::new(RayTraceColor::white())))));
+		scene.init(0).unwrap();
+
+		let mut params = RayTraceParams::new();
+		params.set_caustic_photons(20000);
+		let map = RayTraceCausticMap::new(&scene, &params);
+		assert!(map.get_photon_count() > 0);
+
+		// The floor under the cone is within 5 * tan(10 degrees) of the axis, the sphere focuses the light further
+		for photon in map.photons.values().flat_map(|photons| photons.iter()) {
+			let distance = (photon.position[0] * photon.position[0] + photon.position[2] * photon.position[2]).sqrt();
+			assert!(photon.position[1].abs() < 1.0e-6);
+			assert!(distance < 5.0 * (10.0 * PI / 180.0).tan(), "Photon at {:?} outside of the cone", photon.position);
+		}
+	}
+}
@@ -1,8 +1,14 @@
+use std::collections::HashSet;
+
 use vecmath::Vector3;
+use vecmath::{vec3_add, vec3_len, vec3_neg, vec3_sub, vec3_normalized};
 
+use aabb::AABB;
 use camera::RayTraceCamera;
 use color::RayTraceColor;
+use color::mix_color;
 use hit::RayTraceRayHit;
+use math_util::RayTraceCoordinateSystem;
 use params::RayTraceParams;
 use ray::RayTraceRay;
 use scene::RayTraceScene;
@@ -10,10 +16,237 @@ use scene::RayTraceScene;
 pub trait RayTraceShading {
 	fn apply(&self, ray: &RayTraceRay, ray_hit: &RayTraceRayHit, camera: &Box<RayTraceCamera>, scene: &RayTraceScene,
 		params: &RayTraceParams) -> (RayTraceColor, RayTraceColor);
+
+	// Same as apply, but also splits the color and the overlay into the light passes.
+	// Shadings that don't tell the lights apart put everything into the ambient pass.
+	fn apply_with_passes(&self, ray: &RayTraceRay, ray_hit: &RayTraceRayHit, camera: &Box<RayTraceCamera>,
+			scene: &RayTraceScene, params: &RayTraceParams)
+			-> ((RayTraceColor, RayTraceColor), (RayTraceLightPasses, RayTraceLightPasses)) {
+		let (color, overlay) = self.apply(ray, ray_hit, camera, scene, params);
+		let light_count = scene.get_lights().len();
+		let passes = (RayTraceLightPasses::new_ambient(&color, light_count),
+			RayTraceLightPasses::new_ambient(&overlay, light_count));
+		((color, overlay), passes)
+	}
 }
 
 pub trait RayTraceLight: Sync + Send {
+	// Set by the scene before each init, lights without a rotation ignore it
+	#[allow(unused_variables)]
+	fn set_coordinate_system(&mut self, system: RayTraceCoordinateSystem) { }
+
 	fn init(&mut self, frame: usize);
 	fn get_position(&self) -> Vector3<f64>;
 	fn get_light(&self, ray: &RayTraceRay) -> RayTraceColor;
+
+	// Color leaving the light in the direction (photons, ...), get_light is asked for a ray pointing back at it
+	fn get_emission(&self, direction: Vector3<f64>) -> RayTraceColor {
+		let point = vec3_add(self.get_position(), direction);
+		self.get_light(&RayTraceRay::new(point, vec3_neg(direction)))
+	}
+
+	// Direction towards the light, distance to it (infinite for directional lights) and the arriving color
+	fn sample_toward(&self, point: Vector3<f64>) -> (Vector3<f64>, f64, RayTraceColor) {
+		let offset = vec3_sub(self.get_position(), point);
+		let direction = vec3_normalized(offset);
+		let color = self.get_light(&RayTraceRay::new(point, direction));
+		(direction, vec3_len(offset), color)
+	}
+
+	// Called after the objects of the scene were initialized with their bounds (None for unbounded objects),
+	// lights can use it to find the objects that can possibly cast shadows for them
+	#[allow(unused_variables)]
+	fn init_shadow_casters(&mut self, object_bounds: &[Option<&AABB>]) { }
+
+	// Ids of the objects that have to be tested by shadow rays, None tests all objects
+	fn get_shadow_casters(&self) -> Option<&[usize]> {
+		None
+	}
+
+	// Light linking, whether the light contributes to the shading of the object
+	#[allow(unused_variables)]
+	fn affects(&self, object_id: Option<usize>) -> bool {
+		true
+	}
+
+	// Moves the linked object ids when the scene of the light is merged into another one
+	#[allow(unused_variables)]
+	fn offset_object_ids(&mut self, offset: usize) { }
+
+	// See RayTraceObject::get_type_name
+	fn get_type_name(&self) -> &str {
+		"light"
+	}
+}
+
+// Split of a color into the light passes, which let a compositor rebalance the lights.
+// The ambient pass also holds everything not caused by a light directly (background, sky, caustics, ...),
+// the other passes one light of the scene each. The passes sum up to the color, only their rgb is used.
+#[derive(Debug, Clone)]
+pub struct RayTraceLightPasses {
+	// Ambient pass first, then the lights in scene order
+	colors: Vec<RayTraceColor>
+}
+
+#[allow(dead_code)]
+impl RayTraceLightPasses {
+	pub fn new(light_count: usize) -> Self {
+		Self {
+			colors: vec![RayTraceColor::new_with(0.0, 0.0, 0.0, 0.0); light_count + 1]
+		}
+	}
+
+	// All of the color in the ambient pass
+	pub fn new_ambient(color: &RayTraceColor, light_count: usize) -> Self {
+		let mut passes = Self::new(light_count);
+		passes.colors[0] = color.clone();
+		passes
+	}
+
+	// Number of passes, including the ambient pass
+	pub fn get_count(&self) -> usize {
+		self.colors.len()
+	}
+
+	pub fn get_colors(&self) -> &Vec<RayTraceColor> {
+		&self.colors
+	}
+
+	pub fn get_colors_mut(&mut self) -> &mut Vec<RayTraceColor> {
+		&mut self.colors
+	}
+
+	pub fn add_ambient(&mut self, color: &RayTraceColor) {
+		self.colors[0] += color;
+	}
+
+	pub fn add_light(&mut self, light_index: usize, color: &RayTraceColor) {
+		self.colors[light_index + 1] += color;
+	}
+
+	pub fn scale(&self, factor: f32) -> Self {
+		Self {
+			colors: self.colors.iter().map(|color| color * factor).collect()
+		}
+	}
+
+	pub fn tint(&self, tint: &RayTraceColor) -> Self {
+		Self {
+			colors: self.colors.iter().map(|color| color * tint).collect()
+		}
+	}
+
+	// Same weights as mix_color, so the passes follow the mixed color
+	pub fn mix(&self, passes: &RayTraceLightPasses, factor: f32) -> Self {
+		Self {
+			colors: self.colors.iter().zip(passes.colors.iter())
+				.map(|(color_a, color_b)| mix_color(color_a, color_b, factor))
+				.collect()
+		}
+	}
+
+	// Name the pass is handed to the sink with
+	pub fn get_name(pass: usize) -> String {
+		match pass {
+			0 => "ambient".to_string(),
+			pass => format!("light_{}", pass - 1)
+		}
+	}
+}
+
+// Objects (by scene object id) a light is restricted to or kept away from
+#[derive(Debug, Clone)]
+pub enum RayTraceLightLinking {
+	All,
+	Include(HashSet<usize>),
+	Exclude(HashSet<usize>)
+}
+
+impl RayTraceLightLinking {
+	pub fn affects(&self, object_id: Option<usize>) -> bool {
+		match (self, object_id) {
+			(&RayTraceLightLinking::All, _) => true,
+			(&RayTraceLightLinking::Include(ref ids), Some(id)) => ids.contains(&id),
+			(&RayTraceLightLinking::Exclude(ref ids), Some(id)) => !ids.contains(&id),
+			(_, None) => true
+		}
+	}
+
+	pub fn offset_object_ids(&mut self, offset: usize) {
+		match *self {
+			RayTraceLightLinking::All => {},
+			RayTraceLightLinking::Include(ref mut ids) | RayTraceLightLinking::Exclude(ref mut ids) => {
+				*ids = ids.iter().map(|id| id + offset).collect();
+			}
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use camera::RayTracerCameraPerspective;
+	use color::RayTraceColor;
+	use light::RayTracePhongShading;
+	use light::lights::RayTraceSpotLight;
+	use material::RayTraceSimpleMaterial;
+	use object::RayTraceObjectPlane;
+	use object::RayTraceObjectSphere;
+	use params::RayTraceOutputParams;
+	use params::RayTraceParams;
+	use params::RayTraceRandomSampling;
+	use render::RayTracer;
+	use scene::RayTraceScene;
+	use sink::BufferSink;
+	use sink::RayTraceSink;
+	use source::RayTraceSource;
+
+	#[test]
+	fn light_passes_sum_up_to_the_frame() {
+		let (width, height) = (64, 48);
+		let out_params = RayTraceOutputParams::new(width, height, 1);
+
+		// Dim lights, so no pass is clipped on its own
+		let mut scene = RayTraceScene::new();
+		scene.add_object(Box::new(RayTraceObjectSphere::new([-1.2, 0.0, 0.0], 1.0,
+			Box::new(RayTraceSimpleMaterial::new_with(RayTraceColor::new_with(0.6, 0.6, 0.6, 1.0), 0.5)))));
+		scene.add_object(Box::new(RayTraceObjectSphere::new([1.2, 0.0, 0.0], 1.0,
+			Box::new(RayTraceSimpleMaterial::dielectric(1.5, RayTraceColor::white())))));
+		scene.add_object(Box::new(RayTraceObjectPlane::new([0.0, -1.0, 0.0], [0.0, 0.0, 0.0],
+			Box::new(RayTraceSimpleMaterial::new(RayTraceColor::new_with(0.5, 0.5, 0.5, 1.0))))));
+		scene.add_light::<RayTraceSpotLight>(Box::new(RayTraceSpotLight::new([-3.0, 5.0, 5.0],
+			RayTraceColor::new_with(0.3, 0.2, 0.2, 1.0))));
+		scene.add_light::<RayTraceSpotLight>(Box::new(RayTraceSpotLight::new([3.0, 5.0, 5.0],
+			RayTraceColor::new_with(0.2, 0.2, 0.3, 1.0))));
+
+		let mut camera = RayTracerCameraPerspective::new_with_fov(&out_params, 50.0);
+		camera.set_position([0.0, 0.5, 6.0]);
+
+		let mut params = RayTraceParams::new();
+		params.set_shading(Some(Box::new(RayTracePhongShading::new())));
+		params.set_ambient_light(RayTraceColor::new_with(1.0, 1.0, 1.0, 0.1));
+		params.set_sampling(Some(Box::new(RayTraceRandomSampling::new_with(0.5, 4))));
+		params.set_light_passes_enabled(true);
+		params.set_background_color(RayTraceColor::new_with(0.2, 0.2, 0.2, 1.0));
+
+		let buffer = BufferSink::new();
+		let mut sink: Box<RayTraceSink> = Box::new(buffer.clone());
+		let mut source = RayTraceSource::new(scene, Box::new(camera), out_params, params);
+		RayTracer::new().render(&mut source, &mut sink).unwrap();
+		assert_eq!(buffer.get_pass_names(0), vec!["ambient", "light_0", "light_1"]);
+
+		let frame = buffer.get_frame(0).unwrap();
+		let passes: Vec<Box<[u8]>> = buffer.get_pass_names(0).iter()
+			.map(|name| buffer.get_pass(0, name).unwrap())
+			.collect();
+
+		// Each pass is rounded to 8 bit on its own
+		for index in 0..(width * height) {
+			for channel in 0..3 {
+				let offset = index * 4 + channel;
+				let sum = passes.iter().map(|pass| pass[offset] as i32).sum::<i32>().min(255);
+				assert!((sum - frame[offset] as i32).abs() <= 3, "pixel {} channel {}: passes sum up to {}, frame {}",
+					index, channel, sum, frame[offset]);
+			}
+		}
+	}
 }
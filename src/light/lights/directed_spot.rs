@@ -1,13 +1,17 @@
 use vecmath::Vector3;
-use vecmath::vec3_dot;
+use vecmath::{vec3_add, vec3_dot, vec3_len, vec3_neg, vec3_scale, vec3_sub};
 use vecmath::row_mat3_transform;
 
+use aabb::AABB;
 use anim::RayTraceAnimation;
 use color::RayTraceColor;
 use light::RayTraceLight;
+use light::RayTraceLightLinking;
 use ray::RayTraceRay;
+use texture::RayTraceTexture;
 
-use math_util::rotate_xyz;
+use math_util::rotate_xyz_in;
+use math_util::RayTraceCoordinateSystem;
 use math_util::PI;
 use math_util::DEG_TO_RAD;
 
@@ -15,27 +19,39 @@ pub struct RayTraceDirectedSpotLight {
 	position: Vector3<f64>,
 	size: f64,
 	rotation: Vector3<f64>,
+	coordinate_system: RayTraceCoordinateSystem,
 	anim_pos: Option<Box<RayTraceAnimation<Vector3<f64>>>>,
 	anim_size: Option<Box<RayTraceAnimation<f64>>>,
 	anim_rotation: Option<Box<RayTraceAnimation<Vector3<f64>>>>,
 	color: RayTraceColor,
+	linking: RayTraceLightLinking,
+	projection: Option<Box<RayTraceTexture>>,
 	data: Option<WorkingData>
 }
 
 struct WorkingData {
-	direction: Vector3<f64>
+	direction: Vector3<f64>,
+	// Right and up of the projected texture, perpendicular to the direction
+	axes: [Vector3<f64>; 2],
+	shadow_casters: Vec<usize>
 }
 
+// Wider cones are projected as if they had this half angle, a plane can't cover 90 degrees
+const MAX_PROJECTION_ANGLE: f64 = 85.0 * DEG_TO_RAD;
+
 impl RayTraceDirectedSpotLight {
 	pub fn new(position: Vector3<f64>, color: RayTraceColor) -> Self {
 		Self {
 			position: position,
 			size: 0.0,
 			rotation: [0.0, 0.0, 0.0],
+			coordinate_system: RayTraceCoordinateSystem::RightHandedYUp,
 			anim_pos: None,
 			anim_size: None,
 			anim_rotation: None,
 			color: color,
+			linking: RayTraceLightLinking::All,
+			projection: None,
 			data: None
 		}
 	}
@@ -87,9 +103,61 @@ impl RayTraceDirectedSpotLight {
 	pub fn set_rotation(&mut self, rotation: Vector3<f64>) {
 		self.rotation = rotation;
 	}
+
+	pub fn set_linking(&mut self, linking: RayTraceLightLinking) {
+		self.linking = linking;
+	}
+
+	pub fn get_linking(&self) -> &RayTraceLightLinking {
+		&self.linking
+	}
+
+	// Texture (gobo) spanning the cone, its color filters the light. The texture is seen from behind the light
+	// with x to the right and y up, parts outside of it stay dark.
+	pub fn set_projection_texture(&mut self, texture: Box<RayTraceTexture>) {
+		self.projection = Some(texture);
+	}
+
+	pub fn set_projection_texture_opt(&mut self, texture: Option<Box<RayTraceTexture>>) {
+		self.projection = texture;
+	}
+
+	pub fn clear_projection_texture(&mut self) {
+		self.projection = None;
+	}
+}
+
+impl RayTraceDirectedSpotLight {
+	// Angle between the direction and the edge of the lit cone (half of the size)
+	fn get_cone_half_angle(&self) -> f64 {
+		(PI / 2.0 + self.size * DEG_TO_RAD).max(0.0).min(PI)
+	}
+
+	// Filter of the texture for light leaving in the direction, None if it doesn't pass
+	fn compute_projection(&self, texture: &Box<RayTraceTexture>, data: &WorkingData, direction: Vector3<f64>)
+			-> Option<RayTraceColor> {
+		let forward = vec3_dot(direction, data.direction);
+		if forward <= 0.0 {
+			return None;
+		}
+
+		let half_angle = self.get_cone_half_angle().min(MAX_PROJECTION_ANGLE);
+		let extent = 2.0 * half_angle.tan() * forward;
+		let x = 0.5 + vec3_dot(direction, data.axes[0]) / extent;
+		let y = 0.5 - vec3_dot(direction, data.axes[1]) / extent;
+		if x < 0.0 || x > 1.0 || y < 0.0 || y > 1.0 {
+			return None;
+		}
+
+		Some(texture.get_color(x, y))
+	}
 }
 
 impl RayTraceLight for RayTraceDirectedSpotLight {
+	fn set_coordinate_system(&mut self, system: RayTraceCoordinateSystem) {
+		self.coordinate_system = system;
+	}
+
 	fn init(&mut self, frame: usize) {
 		if let Some(ref anim) = self.anim_pos {
 			self.position = anim.next_frame(frame);
@@ -103,11 +171,52 @@ impl RayTraceLight for RayTraceDirectedSpotLight {
 			self.rotation = anim.next_frame(frame);
 		}
 
+		let rot = rotate_xyz_in(self.rotation, self.coordinate_system);
 		self.data = Some(WorkingData {
-				direction: row_mat3_transform(rotate_xyz(self.rotation), [1.0, 0.0, 0.0])
+				direction: row_mat3_transform(rot, [1.0, 0.0, 0.0]),
+				axes: [row_mat3_transform(rot, [0.0, 0.0, 1.0]), row_mat3_transform(rot, [0.0, 1.0, 0.0])],
+				shadow_casters: Vec::new()
 			});
 	}
 
+	// Every point on a shadow ray lies inside the lit cone, so only objects reaching into the cone can cast shadows
+	fn init_shadow_casters(&mut self, object_bounds: &[Option<&AABB>]) {
+		// Beyond this angle to the direction the light doesn't arrive any more
+		let half_angle = self.get_cone_half_angle();
+
+		if let Some(ref mut data) = self.data {
+			data.shadow_casters.clear();
+			for (object_id, bounds) in object_bounds.iter().enumerate() {
+				let in_cone = match *bounds {
+					// Test the bounding sphere of the AABB against the cone
+					Some(aabb) => {
+						let extent = vec3_sub(*aabb.get_end(), *aabb.get_start());
+						let center = vec3_add(*aabb.get_start(), vec3_scale(extent, 0.5));
+						let radius = vec3_len(extent) * 0.5;
+						let offset = vec3_sub(center, self.position);
+						let distance = vec3_len(offset);
+
+						distance <= radius || {
+							let angle = (vec3_dot(offset, data.direction) / distance).max(-1.0).min(1.0).acos();
+							angle - (radius / distance).asin() <= half_angle
+						}
+					},
+					None => true
+				};
+
+				if in_cone {
+					data.shadow_casters.push(object_id);
+				}
+			}
+		} else {
+			panic!("Light source was not initialized!");
+		}
+	}
+
+	fn get_shadow_casters(&self) -> Option<&[usize]> {
+		self.data.as_ref().map(|data| data.shadow_casters.as_slice())
+	}
+
 	fn get_position(&self) -> Vector3<f64> {
 		self.position
 	}
@@ -116,12 +225,70 @@ impl RayTraceLight for RayTraceDirectedSpotLight {
 		if let Some(ref data) = self.data {
 			let angle = (-vec3_dot(ray.get_direction().clone(), data.direction)).acos().abs() - self.size * DEG_TO_RAD;
 
-			let light_strength = self.color.get_a();
+			let mut light_strength = self.color.get_a();
 			let mut result = self.color.clone();
+			if let Some(ref texture) = self.projection {
+				match self.compute_projection(texture, data, vec3_neg(ray.get_direction().clone())) {
+					Some(filter) => {
+						result = RayTraceColor::new_with(result.get_r() * filter.get_r(),
+							result.get_g() * filter.get_g(), result.get_b() * filter.get_b(), result.get_a());
+						light_strength *= filter.get_a();
+					},
+					None => { light_strength = 0.0; }
+				}
+			}
 			result.set_a(light_strength * angle.min(PI).max(0.0).cos().max(0.0) as f32);
 			result
 		} else {
 			panic!("Light source was not initialized!");
 		}
 	}
+
+	fn affects(&self, object_id: Option<usize>) -> bool {
+		self.linking.affects(object_id)
+	}
+
+	fn offset_object_ids(&mut self, offset: usize) {
+		self.linking.offset_object_ids(offset);
+	}
+
+	fn get_type_name(&self) -> &str {
+		"directed_spot"
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use color::RayTraceColor;
+	use light::RayTraceLight;
+	use math_util::DEG_TO_RAD;
+	use texture::RayTraceTexture;
+
+	use super::RayTraceDirectedSpotLight;
+
+	// Only lets the light through at the right edge of the texture
+	struct RightEdgeTexture;
+
+	impl RayTraceTexture for RightEdgeTexture {
+		fn get_color(&self, x: f64, _: f64) -> RayTraceColor {
+			RayTraceColor::new_with(1.0, 1.0, 1.0, if x > 0.9 { 1.0 } else { 0.0 })
+		}
+	}
+
+	#[test]
+	fn projection_spans_a_narrow_cone() {
+		// Spot along the x axis with a cone of 20 degrees, the texture's x axis points along z
+		let mut spot = RayTraceDirectedSpotLight::new([0.0, 0.0, 0.0], RayTraceColor::white());
+		spot.set_size(20.0);
+		spot.set_projection_texture(Box::new(RightEdgeTexture));
+		spot.init(0);
+
+		let emission = |degrees: f64| {
+			let angle = degrees * DEG_TO_RAD;
+			spot.get_emission([angle.cos(), 0.0, angle.sin()]).get_a()
+		};
+		assert!(emission(9.0) > 0.0);
+		assert_eq!(emission(5.0), 0.0);
+		assert_eq!(emission(11.0), 0.0);
+	}
 }
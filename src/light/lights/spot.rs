@@ -3,12 +3,14 @@ use vecmath::Vector3;
 use anim::RayTraceAnimation;
 use color::RayTraceColor;
 use light::RayTraceLight;
+use light::RayTraceLightLinking;
 use ray::RayTraceRay;
 
 pub struct RayTraceSpotLight {
 	position: Vector3<f64>,
 	anim_pos: Option<Box<RayTraceAnimation<Vector3<f64>>>>,
-	color: RayTraceColor
+	color: RayTraceColor,
+	linking: RayTraceLightLinking
 }
 
 impl RayTraceSpotLight {
@@ -16,7 +18,8 @@ impl RayTraceSpotLight {
 		Self {
 			position: position,
 			anim_pos: None,
-			color: color
+			color: color,
+			linking: RayTraceLightLinking::All
 		}
 	}
 
@@ -43,6 +46,14 @@ impl RayTraceSpotLight {
 	pub fn set_position(&mut self, position: Vector3<f64>) {
 		self.position = position;
 	}
+
+	pub fn set_linking(&mut self, linking: RayTraceLightLinking) {
+		self.linking = linking;
+	}
+
+	pub fn get_linking(&self) -> &RayTraceLightLinking {
+		&self.linking
+	}
 }
 
 #[allow(unused_variables)]
@@ -60,4 +71,16 @@ impl RayTraceLight for RayTraceSpotLight {
 	fn get_light(&self, ray: &RayTraceRay) -> RayTraceColor {
 		self.color.clone()
 	}
+
+	fn affects(&self, object_id: Option<usize>) -> bool {
+		self.linking.affects(object_id)
+	}
+
+	fn offset_object_ids(&mut self, offset: usize) {
+		self.linking.offset_object_ids(offset);
+	}
+
+	fn get_type_name(&self) -> &str {
+		"spot"
+	}
 }
@@ -1,9 +1,11 @@
+mod caustics;
 mod light;
 mod phong;
 mod debug;
 
 pub mod lights;
 
+pub use self::caustics::RayTraceCausticMap;
 pub use self::light::*;
 pub use self::phong::RayTracePhongShading;
 
@@ -1,19 +1,21 @@
-use vecmath::vec3_dot;
-use vecmath::vec3_normalized_sub;
-use vecmath::vec3_sub;
-use vecmath::vec3_len;
+use vecmath::Vector3;
+use vecmath::{vec3_add, vec3_cross, vec3_dot, vec3_neg, vec3_normalized, vec3_scale, vec3_sub};
 
+use color::BLACK_THRESHOLD;
 use color::RayTraceColor;
 use color::mix_color;
 
 use camera::RayTraceCamera;
 use ray::RayTraceRay;
 use hit::RayTraceRayHit;
+use light::RayTraceLightPasses;
 use light::RayTraceShading;
 use params::RayTraceParams;
 use scene::RayTraceScene;
 
-use math_util::compute_reflected_ray;
+use math_util::PI;
+use math_util::compute_offset_position;
+use math_util::compute_reflected_direction;
 
 pub struct RayTracePhongShading {
 	ambient: f32,
@@ -54,9 +56,28 @@ impl RayTracePhongShading {
 impl RayTraceShading for RayTracePhongShading {
 	fn apply(&self, ray: &RayTraceRay, ray_hit: &RayTraceRayHit, camera: &Box<RayTraceCamera>, scene: &RayTraceScene,
 			params: &RayTraceParams) -> (RayTraceColor, RayTraceColor) {
+		self.shade(ray, ray_hit, camera, scene, params, None)
+	}
+
+	fn apply_with_passes(&self, ray: &RayTraceRay, ray_hit: &RayTraceRayHit, camera: &Box<RayTraceCamera>,
+			scene: &RayTraceScene, params: &RayTraceParams)
+			-> ((RayTraceColor, RayTraceColor), (RayTraceLightPasses, RayTraceLightPasses)) {
+		let light_count = scene.get_lights().len();
+		let mut passes = (RayTraceLightPasses::new(light_count), RayTraceLightPasses::new(light_count));
+		let colors = self.shade(ray, ray_hit, camera, scene, params, Some(&mut passes));
+		(colors, passes)
+	}
+}
+
+impl RayTracePhongShading {
+	// Adds each light to its own pass of the color (diffuse) and the overlay (specular) if passes are given
+	fn shade(&self, ray: &RayTraceRay, ray_hit: &RayTraceRayHit, camera: &Box<RayTraceCamera>, scene: &RayTraceScene,
+			params: &RayTraceParams, mut passes: Option<&mut (RayTraceLightPasses, RayTraceLightPasses)>)
+			-> (RayTraceColor, RayTraceColor) {
 		let surface_normal = ray_hit.get_surface_normal();
-		let hit_distance = ray_hit.get_distance();
-		let light_ray_start = ray.get_position_on_ray(hit_distance - 1e-10);
+		// Shadow rays start on the side the ray came from
+		let light_ray_start = compute_offset_position(ray_hit, vec3_neg(ray.get_direction().clone()),
+			params.get_ray_offset());
 		let camera_direction = camera.get_direction();
 		let ambient_light = params.get_ambient_light();
 
@@ -65,6 +86,7 @@ impl RayTraceShading for RayTracePhongShading {
 		let diffuse_light = material.get_diffuse_light();
 		let specular_light = material.get_specular_light();
 		let surface_roughness = material.get_surface_roughness();
+		let anisotropic_frame = compute_anisotropic_frame(ray_hit);
 
 		// Ambient offset
 		let ambient_color = ambient_light * material_color;
@@ -73,47 +95,90 @@ impl RayTraceShading for RayTracePhongShading {
 		let mut specular_component = RayTraceColor::new_with(0.0, 0.0, 0.0, 0.0);
 		let mut diffuse_component = RayTraceColor::new_with(0.0, 0.0, 0.0, 0.0);
 
-		for light in scene.get_lights() {
-			let light_position = light.get_position();
-			let light_distance = vec3_len(vec3_sub(light_position.clone(), light_ray_start));
-
-			let light_ray_direction = vec3_normalized_sub(light_position.clone(), light_ray_start);
-			let light_ray = RayTraceRay::new(light_ray_start, light_ray_direction);
-			let reflected_ray = compute_reflected_ray(surface_normal.clone(), &light_ray, 0.0);
-			let mut light_ray_intersected = false;
+		for (light_index, light) in scene.get_lights().iter().enumerate() {
+			if !light.affects(ray_hit.get_object_id()) {
+				continue;
+			}
 
-			for object in scene.get_objects().iter() {
-				if let Some(aabb) = object.get_aabb() {
-					if !aabb.is_hit(&light_ray) {
-						continue;
-					}
-				}
+			let (light_ray_direction, light_distance, light_color) = light.sample_toward(light_ray_start);
 
-				if let Some(hit) = object.next_hit(&light_ray) {
-					let dist = hit.get_distance();
-					if dist > 0.0 && dist < light_distance {
-						light_ray_intersected = true;
-						break;
-					}
-				}
+			// No need to trace a shadow ray if nothing arrives anyway
+			if light_color.is_black(BLACK_THRESHOLD) || light_color.get_a() <= BLACK_THRESHOLD {
+				continue;
 			}
 
+			let light_ray = ray.derive(light_ray_start, light_ray_direction);
+			let reflected_direction = compute_reflected_direction(surface_normal.clone(), light_ray_direction);
+			let light_ray_intersected = scene.is_light_blocked(light, &light_ray, light_distance, params);
+
 			if !light_ray_intersected {
-				let light_color = light.get_light(&light_ray);
+				let mut light_diffuse = RayTraceColor::new_with(0.0, 0.0, 0.0, 0.0);
+				let mut light_specular = RayTraceColor::new_with(0.0, 0.0, 0.0, 0.0);
+
 				let diffuse = vec3_dot(surface_normal.clone(), light_ray_direction) as f32;
 				if diffuse > 0.0 {
-					diffuse_component += material_color * light_color.clone() * diffuse * light_color.get_a()
+					light_diffuse += material_color * light_color.clone() * diffuse * light_color.get_a()
 						* diffuse_light;
 				}
 
-				let specular = vec3_dot(reflected_ray.get_direction().clone(), camera_direction) as f32;
+				let specular = vec3_dot(reflected_direction, camera_direction) as f32;
+				let mut specular = if specular > 0.0 {
+					(surface_roughness + 2.0) / (2.0 * PI as f32) * specular.powf(surface_roughness)
+				} else {
+					0.0
+				};
+
+				if let Some((tangent, bitangent)) = anisotropic_frame {
+					// Ashikhmin-Shirley lobe with a lower exponent along the (rotated) tangent. It is blended in by
+					// the strength, so weak anisotropy stays close to the isotropic highlight.
+					let (strength, _) = material.get_anisotropy();
+					let exponent_u = surface_roughness * (1.0 - strength * 0.99);
+					let exponent_v = surface_roughness;
+
+					let half = vec3_normalized(vec3_sub(light_ray_direction, ray.get_direction().clone()));
+					let half_normal = vec3_dot(half, surface_normal.clone());
+					let anisotropic = if half_normal > 0.0 {
+						let half_tangent = vec3_dot(half, tangent);
+						let half_bitangent = vec3_dot(half, bitangent);
+						let exponent = (exponent_u as f64 * half_tangent * half_tangent
+							+ exponent_v as f64 * half_bitangent * half_bitangent)
+							/ (1.0 - half_normal * half_normal).max(1e-10);
+						((exponent_u + 1.0) * (exponent_v + 1.0)).sqrt() / (8.0 * PI as f32)
+							* half_normal.powf(exponent) as f32
+					} else {
+						0.0
+					};
+
+					specular = specular * (1.0 - strength) + anisotropic * strength;
+				}
+
 				if specular > 0.0 {
-					specular_component += &light_color * (surface_roughness + 2.0) / (2.0 * 3.14159265359)
-						* specular.powf(surface_roughness) * light_color.get_a() * specular_light;
+					light_specular += &light_color * specular * light_color.get_a() * specular_light;
 				}
+
+				if let Some(ref mut passes) = passes {
+					passes.0.add_light(light_index, &(&light_diffuse * self.diffuse));
+					passes.1.add_light(light_index, &(&light_specular * self.specular));
+				}
+				diffuse_component += light_diffuse;
+				specular_component += light_specular;
 			}
 		}
 
+		// Caustics are indirect light, they belong to the ambient pass
+		let caustic_component = match scene.get_caustics() {
+			Some(caustics) => {
+				let irradiance = caustics.get_irradiance(ray_hit.get_position().clone(), surface_normal.clone());
+				material_color * irradiance * diffuse_light
+			},
+			None => RayTraceColor::new_with(0.0, 0.0, 0.0, 0.0)
+		};
+
+		if let Some(ref mut passes) = passes {
+			passes.0.add_ambient(&(&ambient_component * self.ambient + &caustic_component * self.diffuse));
+		}
+		diffuse_component += caustic_component;
+
 		// Mix the colors with respect to the maximum color levels
 		let mut final_color = ambient_component * self.ambient + diffuse_component * self.diffuse;
 		let final_overlay = specular_component * self.specular;
@@ -122,4 +187,33 @@ impl RayTraceShading for RayTracePhongShading {
 
 		return (final_color, final_overlay);
 	}
-}
\ No newline at end of file
+}
+
+// Tangent frame rotated by the anisotropy angle, none for isotropic materials or hits without tangents
+fn compute_anisotropic_frame(ray_hit: &RayTraceRayHit) -> Option<(Vector3<f64>, Vector3<f64>)> {
+	let (strength, rotation) = ray_hit.get_surface_material().get_anisotropy();
+	if strength <= 0.0 {
+		return None;
+	}
+
+	let tangents = match ray_hit.get_tangents() {
+		Some(tangents) => tangents,
+		None => { return None; }
+	};
+
+	// Make the tangent orthogonal to the (possibly bumped) normal
+	let normal = ray_hit.get_surface_normal().clone();
+	let tangent = vec3_sub(tangents[0], vec3_scale(normal, vec3_dot(tangents[0], normal)));
+	if vec3_dot(tangent, tangent) < 1e-20 {
+		return None;
+	}
+
+	let tangent = vec3_normalized(tangent);
+	let bitangent = vec3_cross(normal, tangent);
+	let (sin, cos) = (rotation as f64).to_radians().sin_cos();
+
+	Some((
+		vec3_add(vec3_scale(tangent, cos), vec3_scale(bitangent, sin)),
+		vec3_add(vec3_scale(tangent, -sin), vec3_scale(bitangent, cos))
+	))
+}
@@ -0,0 +1,69 @@
+use anim::RayTraceAnimation;
+use hit::RayTraceMaterialHit;
+
+use material::RayTraceMaterial;
+
+pub struct RayTraceMixedMaterial {
+	materials: [Box<RayTraceMaterial>; 2],
+	factor: f32,
+	anim_factor: Option<Box<RayTraceAnimation<f64>>>
+}
+
+#[allow(dead_code)]
+impl RayTraceMixedMaterial {
+	pub fn new(material_a: Box<RayTraceMaterial>, material_b: Box<RayTraceMaterial>, factor: f32) -> Self {
+		Self {
+			materials: [material_a, material_b],
+			factor: factor,
+			anim_factor: None
+		}
+	}
+
+	pub fn set_factor(&mut self, factor: f32) {
+		self.factor = factor;
+	}
+
+	pub fn get_factor(&self) -> f32 {
+		self.factor
+	}
+
+	pub fn set_anim_factor_opt(&mut self, anim: Option<Box<RayTraceAnimation<f64>>>) {
+		self.anim_factor = anim;
+	}
+
+	pub fn set_anim_factor(&mut self, anim: Box<RayTraceAnimation<f64>>) {
+		self.anim_factor = Some(anim);
+	}
+}
+
+impl RayTraceMaterial for RayTraceMixedMaterial {
+	fn init(&mut self, frame: usize) {
+		if let Some(ref anim_factor) = self.anim_factor {
+			self.factor = anim_factor.next_frame(frame) as f32;
+		}
+
+		self.materials[0].init(frame);
+		self.materials[1].init(frame);
+	}
+
+	fn get_hit(&self, x: f64, y: f64) -> RayTraceMaterialHit {
+		// Skip evaluating the other material if it does not contribute
+		if self.factor <= 0.0 {
+			return self.materials[0].get_hit(x, y);
+		}
+		if self.factor >= 1.0 {
+			return self.materials[1].get_hit(x, y);
+		}
+
+		RayTraceMaterialHit::mix(&self.materials[0].get_hit(x, y), &self.materials[1].get_hit(x, y), self.factor)
+	}
+
+	fn has_cut_outs(&self) -> bool {
+		self.materials[0].has_cut_outs() || self.materials[1].has_cut_outs()
+	}
+
+	fn set_anisotropy(&mut self, strength: f32, rotation: f32) {
+		self.materials[0].set_anisotropy(strength, rotation);
+		self.materials[1].set_anisotropy(strength, rotation);
+	}
+}
@@ -1,11 +1,28 @@
+mod mixed;
 mod simple;
 mod test;
 
+pub use self::mixed::RayTraceMixedMaterial;
 pub use self::simple::RayTraceSimpleMaterial;
 pub use self::test::RayTraceCheckerboardMaterial;
 
 use hit::RayTraceMaterialHit;
 
 pub trait RayTraceMaterial: Send + Sync {
+	// Called by the owning object once per frame to update animated properties
+	#[allow(unused_variables)]
+	fn init(&mut self, frame: usize) { }
+
 	fn get_hit(&self, x: f64, y: f64) -> RayTraceMaterialHit;
+
+	// Brushed look, the highlight is stretched along the material x direction rotated by the angle (degrees).
+	// The strength is clamped to [0, 1], 0 is isotropic. Materials without highlights ignore it.
+	#[allow(unused_variables)]
+	fn set_anisotropy(&mut self, strength: f32, rotation: f32) { }
+
+	// Whether some hits may be cut out (alpha maps, ...), occlusion tests of the other materials only need
+	// the distance of the surface
+	fn has_cut_outs(&self) -> bool {
+		false
+	}
 }
\ No newline at end of file
@@ -1,40 +1,228 @@
+use anim::RayTraceAnimation;
 use color::RayTraceColor;
 
 use hit::RayTraceMaterialHit;
 
 use material::RayTraceMaterial;
 
+use math_util::compute_fresnel_f0;
+
+use texture::RayTraceDisplacementMap;
+use texture::RayTraceTexture;
+
 pub struct RayTraceSimpleMaterial {
 	color: RayTraceColor,
-	reflectance: f32
+	reflectance: f32,
+	transparency: f32,
+	refraction_index: f32,
+	displacement: Option<RayTraceDisplacementMap>,
+	alpha_map: Option<(Box<RayTraceTexture>, f32)>,
+	anisotropy: f32,
+	anisotropy_rotation: f32,
+	fresnel: bool,
+	max_depth_override: Option<usize>,
+	anim_color: Option<Box<RayTraceAnimation<RayTraceColor>>>,
+	anim_reflectance: Option<Box<RayTraceAnimation<f64>>>,
+	anim_transparency: Option<Box<RayTraceAnimation<f64>>>
 }
 
 impl RayTraceSimpleMaterial {
 	pub fn new(color: RayTraceColor) -> Self {
 		Self {
 			color: color,
-			reflectance: 0.0
+			reflectance: 0.0,
+			transparency: 0.0,
+			refraction_index: 1.0,
+			displacement: None,
+			alpha_map: None,
+			anisotropy: 0.0,
+			anisotropy_rotation: 0.0,
+			fresnel: false,
+			max_depth_override: None,
+			anim_color: None,
+			anim_reflectance: None,
+			anim_transparency: None
 		}
 	}
 
 	pub fn new_with_color(r: f32, g: f32, b: f32, a: f32) -> Self {
 		Self {
 			color: RayTraceColor::new_with(r, g, b, a),
-			reflectance: 0.0
+			reflectance: 0.0,
+			transparency: 0.0,
+			refraction_index: 1.0,
+			displacement: None,
+			alpha_map: None,
+			anisotropy: 0.0,
+			anisotropy_rotation: 0.0,
+			fresnel: false,
+			max_depth_override: None,
+			anim_color: None,
+			anim_reflectance: None,
+			anim_transparency: None
 		}
 	}
 
 	pub fn new_with(color: RayTraceColor, reflectance: f32) -> Self {
 		Self {
 			color: color,
-			reflectance: reflectance
+			reflectance: reflectance,
+			transparency: 0.0,
+			refraction_index: 1.0,
+			displacement: None,
+			alpha_map: None,
+			anisotropy: 0.0,
+			anisotropy_rotation: 0.0,
+			fresnel: false,
+			max_depth_override: None,
+			anim_color: None,
+			anim_reflectance: None,
+			anim_transparency: None
+		}
+	}
+
+	pub fn new_with_refraction(color: RayTraceColor, reflectance: f32, transparency: f32, refraction_index: f32)
+			-> Self {
+		Self {
+			color: color,
+			reflectance: reflectance,
+			transparency: transparency,
+			refraction_index: refraction_index,
+			displacement: None,
+			alpha_map: None,
+			anisotropy: 0.0,
+			anisotropy_rotation: 0.0,
+			fresnel: false,
+			max_depth_override: None,
+			anim_color: None,
+			anim_reflectance: None,
+			anim_transparency: None
 		}
 	}
+
+	// Glass, water, ... The reflectance follows from the refraction index and rises towards grazing angles,
+	// the color tints the light passing through
+	pub fn dielectric(refraction_index: f32, color: RayTraceColor) -> Self {
+		let mut material = Self::new_with_refraction(color, compute_fresnel_f0(refraction_index as f64) as f32, 1.0,
+			refraction_index);
+		material.fresnel = true;
+		material
+	}
+
+	pub fn set_displacement_map(&mut self, texture: Box<RayTraceTexture>, scale: f64) {
+		self.displacement = Some(RayTraceDisplacementMap::new(texture, scale));
+	}
+
+	pub fn set_displacement_map_opt(&mut self, displacement: Option<RayTraceDisplacementMap>) {
+		self.displacement = displacement;
+	}
+
+	pub fn get_displacement_map(&self) -> &Option<RayTraceDisplacementMap> {
+		&self.displacement
+	}
+
+	// Surfaces where the alpha channel of the texture is below the cutoff are treated as holes
+	pub fn set_alpha_map(&mut self, texture: Box<RayTraceTexture>, cutoff: f32) {
+		self.alpha_map = Some((texture, cutoff));
+	}
+
+	pub fn clear_alpha_map(&mut self) {
+		self.alpha_map = None;
+	}
+
+	pub fn set_color(&mut self, color: RayTraceColor) {
+		self.color = color;
+	}
+
+	pub fn set_reflectance(&mut self, reflectance: f32) {
+		self.reflectance = reflectance;
+	}
+
+	pub fn set_transparency(&mut self, transparency: f32) {
+		self.transparency = transparency;
+	}
+
+	// Whether the reflectance is scaled with the viewing angle, set by dielectric
+	pub fn set_fresnel(&mut self, fresnel: bool) {
+		self.fresnel = fresnel;
+	}
+
+	// Reflection and refraction depth for the rays spawned off this material instead of the one of the params,
+	// to spend more bounces on a hero object and fewer on the rest
+	pub fn set_max_depth_override(&mut self, max_depth: usize) {
+		self.max_depth_override = Some(max_depth);
+	}
+
+	pub fn set_max_depth_override_opt(&mut self, max_depth: Option<usize>) {
+		self.max_depth_override = max_depth;
+	}
+
+	pub fn get_max_depth_override(&self) -> Option<usize> {
+		self.max_depth_override
+	}
+
+	pub fn set_anim_color_opt(&mut self, anim: Option<Box<RayTraceAnimation<RayTraceColor>>>) {
+		self.anim_color = anim;
+	}
+
+	pub fn set_anim_color(&mut self, anim: Box<RayTraceAnimation<RayTraceColor>>) {
+		self.anim_color = Some(anim);
+	}
+
+	pub fn set_anim_reflectance_opt(&mut self, anim: Option<Box<RayTraceAnimation<f64>>>) {
+		self.anim_reflectance = anim;
+	}
+
+	pub fn set_anim_reflectance(&mut self, anim: Box<RayTraceAnimation<f64>>) {
+		self.anim_reflectance = Some(anim);
+	}
+
+	pub fn set_anim_transparency_opt(&mut self, anim: Option<Box<RayTraceAnimation<f64>>>) {
+		self.anim_transparency = anim;
+	}
+
+	pub fn set_anim_transparency(&mut self, anim: Box<RayTraceAnimation<f64>>) {
+		self.anim_transparency = Some(anim);
+	}
 }
 
 #[allow(unused_variables)]
 impl RayTraceMaterial for RayTraceSimpleMaterial {
+	fn init(&mut self, frame: usize) {
+		if let Some(ref anim_color) = self.anim_color {
+			self.color = anim_color.next_frame(frame);
+		}
+		if let Some(ref anim_reflectance) = self.anim_reflectance {
+			self.reflectance = anim_reflectance.next_frame(frame) as f32;
+		}
+		if let Some(ref anim_transparency) = self.anim_transparency {
+			self.transparency = anim_transparency.next_frame(frame) as f32;
+		}
+	}
+
 	fn get_hit(&self, x: f64, y: f64) -> RayTraceMaterialHit {
-		RayTraceMaterialHit::new_with(self.color.clone(), self.reflectance, 1.0 - self.reflectance, 1.0, 100.0)
+		let mut hit = RayTraceMaterialHit::new_with(self.color.clone(), self.reflectance, 1.0 - self.reflectance,
+			1.0, 100.0);
+		hit.set_transparency(self.transparency);
+		hit.set_refraction_index(self.refraction_index);
+		hit.set_anisotropy(self.anisotropy, self.anisotropy_rotation);
+		hit.set_fresnel(self.fresnel);
+		hit.set_max_depth(self.max_depth_override);
+		if let Some(ref displacement) = self.displacement {
+			hit.set_bump(Some(displacement.get_gradient(x, y)));
+		}
+		if let Some((ref texture, cutoff)) = self.alpha_map {
+			hit.set_cut_out(texture.get_color(x, y).get_a() < cutoff);
+		}
+		hit
+	}
+
+	fn set_anisotropy(&mut self, strength: f32, rotation: f32) {
+		self.anisotropy = strength.max(0.0).min(1.0);
+		self.anisotropy_rotation = rotation;
+	}
+
+	fn has_cut_outs(&self) -> bool {
+		self.alpha_map.is_some()
 	}
 }
\ No newline at end of file
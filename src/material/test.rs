@@ -7,7 +7,9 @@ use material::RayTraceMaterial;
 pub struct RayTraceCheckerboardMaterial {
 	colors: [RayTraceColor; 2],
 	scale: [f64; 2],
-	reflectance: f32
+	reflectance: f32,
+	// Strength and rotation, see RayTraceMaterial::set_anisotropy
+	anisotropy: (f32, f32)
 }
 
 impl RayTraceCheckerboardMaterial {
@@ -15,7 +17,8 @@ impl RayTraceCheckerboardMaterial {
 		Self {
 			colors: [RayTraceColor::black(), RayTraceColor::white()],
 			scale: [1.0, 1.0],
-			reflectance: 0.0
+			reflectance: 0.0,
+			anisotropy: (0.0, 0.0)
 		}
 	}
 
@@ -23,7 +26,8 @@ impl RayTraceCheckerboardMaterial {
 		Self {
 			colors: colors,
 			scale: [1.0, 1.0],
-			reflectance: 0.0
+			reflectance: 0.0,
+			anisotropy: (0.0, 0.0)
 		}
 	}
 
@@ -31,7 +35,8 @@ impl RayTraceCheckerboardMaterial {
 		Self {
 			colors: colors,
 			scale: scale,
-			reflectance: reflectance
+			reflectance: reflectance,
+			anisotropy: (0.0, 0.0)
 		}
 	}
 
@@ -39,7 +44,8 @@ impl RayTraceCheckerboardMaterial {
 		Self {
 			colors: [RayTraceColor::black(), RayTraceColor::white()],
 			scale: scale,
-			reflectance: reflectance
+			reflectance: reflectance,
+			anisotropy: (0.0, 0.0)
 		}
 	}
 
@@ -47,7 +53,8 @@ impl RayTraceCheckerboardMaterial {
 		Self {
 			colors: [RayTraceColor::black(), RayTraceColor::white()],
 			scale: [1.0, 1.0],
-			reflectance: reflectance
+			reflectance: reflectance,
+			anisotropy: (0.0, 0.0)
 		}
 	}
 }
@@ -58,7 +65,13 @@ impl RayTraceMaterial for RayTraceCheckerboardMaterial {
 		let x_scaled = (x / self.scale[0]) as i64 + (if x >= 0.0 {0} else {1});
 		let y_scaled = (y / self.scale[1]) as i64 + (if y >= 0.0 {0} else {1});
 
-		RayTraceMaterialHit::new_with(self.colors[(x_scaled + y_scaled) as usize & 0x01].clone(), self.reflectance,
-			1.0, 0.5, 100.0)
+		let mut hit = RayTraceMaterialHit::new_with(self.colors[(x_scaled + y_scaled) as usize & 0x01].clone(),
+			self.reflectance, 1.0, 0.5, 100.0);
+		hit.set_anisotropy(self.anisotropy.0, self.anisotropy.1);
+		hit
+	}
+
+	fn set_anisotropy(&mut self, strength: f32, rotation: f32) {
+		self.anisotropy = (strength.max(0.0).min(1.0), rotation);
 	}
 }
\ No newline at end of file
@@ -1,8 +1,9 @@
 use vecmath::{Matrix3, Vector3};
-use vecmath::{vec3_sub, vec3_scale, vec3_dot};
+use vecmath::{vec3_add, vec3_sub, vec3_scale, vec3_dot, vec3_neg, vec3_normalized};
 use vecmath::{mat3_id, row_mat3_mul};
 use vecmath::{vec4_sub, vec4_scale};
 
+use hit::RayTraceRayHit;
 use ray::RayTraceRay;
 
 pub const PI: f64 = 3.14159265359;
@@ -59,6 +60,36 @@ pub fn rotate_xyz(angle: Vector3<f64>) -> Matrix3<f64> {
 	return rot;
 }
 
+// Convention of the scene coordinates, the renderer works in right handed coordinates with y pointing up
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum RayTraceCoordinateSystem {
+	RightHandedYUp,
+	RightHandedZUp,
+	LeftHandedYUp,
+	LeftHandedZUp
+}
+
+impl RayTraceCoordinateSystem {
+	// Converts from the renderer coordinates into the scene coordinates
+	pub fn get_matrix(&self) -> Matrix3<f64> {
+		match *self {
+			RayTraceCoordinateSystem::RightHandedYUp => mat3_id(),
+			RayTraceCoordinateSystem::RightHandedZUp => [[1.0, 0.0, 0.0], [0.0, 0.0, -1.0], [0.0, 1.0, 0.0]],
+			RayTraceCoordinateSystem::LeftHandedYUp => [[1.0, 0.0, 0.0], [0.0, 1.0, 0.0], [0.0, 0.0, -1.0]],
+			RayTraceCoordinateSystem::LeftHandedZUp => [[1.0, 0.0, 0.0], [0.0, 0.0, 1.0], [0.0, 1.0, 0.0]]
+		}
+	}
+}
+
+// Same as rotate_xyz, but the y rotation always turns around the up axis of the coordinate system
+pub fn rotate_xyz_in(angle: Vector3<f64>, system: RayTraceCoordinateSystem) -> Matrix3<f64> {
+	if system == RayTraceCoordinateSystem::RightHandedYUp {
+		return rotate_xyz(angle);
+	}
+
+	row_mat3_mul(system.get_matrix(), rotate_xyz(angle))
+}
+
 pub fn rot_deg(angle: Vector3<f64>) -> Vector3<f64> {
 	[
 		angle[0] * DEG_TO_RAD,
@@ -137,8 +168,78 @@ pub fn compute_plane_hit(ray: &RayTraceRay, center: Vector3<f64>, vec1: Vector3<
 	return Some((mat[0][3] / mat[0][0], mat[1][3] / mat[1][1], mat[2][3] / mat[2][2]));
 }
 
-pub fn compute_reflected_ray(n: Vector3<f64>, ray: &RayTraceRay, distance: f64) -> RayTraceRay {
+pub fn compute_reflected_direction(n: Vector3<f64>, d: Vector3<f64>) -> Vector3<f64> {
+	vec3_sub(d, vec3_scale(n, 2.0 * vec3_dot(d, n)))
+}
+
+// Start of a ray leaving the hit in the direction, moved off the surface along the geometric normal
+// to the side the ray leaves to. Unlike moving back along the incoming ray this also works for grazing angles.
+pub fn compute_offset_position(hit: &RayTraceRayHit, direction: Vector3<f64>, offset: f64) -> Vector3<f64> {
+	let normal = hit.get_geometric_normal().clone();
+	let side = if vec3_dot(direction, normal) < 0.0 { -offset } else { offset };
+	vec3_add(hit.get_position().clone(), vec3_scale(normal, side))
+}
+
+pub fn compute_reflected_ray(ray: &RayTraceRay, hit: &RayTraceRayHit, offset: f64) -> RayTraceRay {
+	let r = compute_reflected_direction(hit.get_surface_normal().clone(), ray.get_direction().clone());
+	return ray.derive(compute_offset_position(hit, r, offset), r);
+}
+
+// Reflectance at normal incidence of a dielectric in air
+pub fn compute_fresnel_f0(refraction_index: f64) -> f64 {
+	let r = (refraction_index - 1.0) / (refraction_index + 1.0);
+	r * r
+}
+
+// Schlick's approximation of the reflectance for the cosine between the ray and the surface normal
+pub fn compute_fresnel_schlick(f0: f64, cos_i: f64) -> f64 {
+	f0 + (1.0 - f0) * (1.0 - cos_i.abs().min(1.0)).powi(5)
+}
+
+pub fn compute_refracted_ray(ray: &RayTraceRay, hit: &RayTraceRayHit, refraction_index: f64, offset: f64)
+		-> Option<RayTraceRay> {
+	// Assume air on the other side of the surface
+	if vec3_dot(ray.get_direction().clone(), hit.get_surface_normal().clone()) > 0.0 {
+		compute_refracted_ray_between(ray, hit, refraction_index, 1.0, offset)
+	} else {
+		compute_refracted_ray_between(ray, hit, 1.0, refraction_index, offset)
+	}
+}
+
+pub fn compute_refracted_ray_between(ray: &RayTraceRay, hit: &RayTraceRayHit, from_index: f64, to_index: f64,
+		offset: f64) -> Option<RayTraceRay> {
+	let n = hit.get_surface_normal().clone();
 	let d = ray.get_direction().clone();
-	let r = vec3_sub(d, vec3_scale(n, 2.0 * vec3_dot(d, n)));
-	return RayTraceRay::new(ray.get_position_on_ray(distance - 1e-10), r);
+	let mut cos_i = -vec3_dot(d, n);
+	let eta = from_index / to_index;
+
+	// Flip the normal if the ray is leaving the object
+	let normal = if cos_i < 0.0 {
+		cos_i = -cos_i;
+		vec3_neg(n)
+	} else {
+		n
+	};
+
+	let k = 1.0 - eta * eta * (1.0 - cos_i * cos_i);
+	if k < 0.0 {
+		return None; // Total internal reflection
+	}
+
+	let t = vec3_normalized(vec3_add(vec3_scale(d, eta), vec3_scale(normal, eta * cos_i - k.sqrt())));
+	return Some(ray.derive(compute_offset_position(hit, t, offset), t));
+}
+
+// Uniform point on the triangle spanned by the vectors from the position, u1 and u2 are uniform in [0, 1)
+pub fn sample_triangle(u1: f64, u2: f64, position: Vector3<f64>, vec: [Vector3<f64>; 2]) -> Vector3<f64> {
+	let s = u1.sqrt();
+	vec3_add(position, vec3_add(vec3_scale(vec[0], s * (1.0 - u2)), vec3_scale(vec[1], s * u2)))
+}
+
+// Uniform direction on the unit sphere, u1 and u2 are uniform in [0, 1)
+pub fn sample_sphere(u1: f64, u2: f64) -> Vector3<f64> {
+	let z = 1.0 - 2.0 * u1;
+	let r = (1.0 - z * z).max(0.0).sqrt();
+	let phi = TWO_PI * u2;
+	[r * phi.cos(), r * phi.sin(), z]
 }
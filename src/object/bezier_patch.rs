@@ -0,0 +1,336 @@
+use std::f64;
+
+use vecmath::Vector3;
+use vecmath::{vec3_add, vec3_sub, vec3_scale, vec3_cross, vec3_dot, vec3_len, vec3_normalized};
+
+use aabb::AABB;
+use anim::RayTraceAnimation;
+use hit::RayTraceRayHit;
+use material::RayTraceMaterial;
+use object::RayTraceObject;
+use object::RayTraceSetupError;
+use object::RayTraceHitable;
+use ray::RayTraceRay;
+
+// Hits closer than this are treated as the surface the ray started on
+const MIN_DISTANCE: f64 = 1e-6;
+// Newton iteration stops once the hit point is this close to the ray
+const THRESHOLD: f64 = 1e-9;
+const MAX_ITERATIONS: usize = 16;
+// Newton may converge slightly outside of the sub patch it started in
+const UV_TOLERANCE: f64 = 1e-6;
+
+// Bicubic Bézier patch, the control points are given row by row (v major) relative to the position.
+// The patch is split into a grid of sub patches whose control hulls bound them, for each sub patch
+// the ray passes through the (u, v, t) of the hit is found by Newton iteration.
+#[allow(dead_code)]
+pub struct RayTraceObjectBezierPatch {
+	material: Box<RayTraceMaterial>,
+	control_points: [Vector3<f64>; 16],
+	position: Vector3<f64>,
+	subdivisions: usize,
+	anim_pos: Option<Box<RayTraceAnimation<Vector3<f64>>>>,
+	name: Option<String>,
+	data: Option<WorkingData>
+}
+
+#[allow(dead_code)]
+impl RayTraceObjectBezierPatch {
+	pub fn new(control_points: [Vector3<f64>; 16], material: Box<RayTraceMaterial>) -> Self {
+		Self {
+			material: material,
+			control_points: control_points,
+			position: [0.0, 0.0, 0.0],
+			subdivisions: 8,
+			anim_pos: None,
+			name: None,
+			data: None
+		}
+	}
+
+	pub fn set_position(&mut self, position: Vector3<f64>) {
+		self.position = position;
+	}
+
+	pub fn set_control_points(&mut self, control_points: [Vector3<f64>; 16]) {
+		self.control_points = control_points;
+	}
+
+	pub fn get_control_points(&self) -> &[Vector3<f64>; 16] {
+		&self.control_points
+	}
+
+	// Number of sub patches per direction, more give better start values for the Newton iteration
+	pub fn set_subdivisions(&mut self, subdivisions: usize) {
+		self.subdivisions = subdivisions.max(1);
+	}
+
+	pub fn get_subdivisions(&self) -> usize {
+		self.subdivisions
+	}
+
+	pub fn set_anim_pos_opt(&mut self, anim: Option<Box<RayTraceAnimation<Vector3<f64>>>>) {
+		self.anim_pos = anim;
+	}
+
+	pub fn set_anim_pos(&mut self, anim: Box<RayTraceAnimation<Vector3<f64>>>) {
+		self.anim_pos = Some(anim);
+	}
+}
+
+struct SubPatch {
+	aabb: AABB,
+	u: (f64, f64),
+	v: (f64, f64)
+}
+
+struct WorkingData {
+	aabb: AABB,
+	points: [Vector3<f64>; 16],
+	sub_patches: Vec<SubPatch>
+}
+
+#[allow(unused_variables)]
+impl RayTraceObject for RayTraceObjectBezierPatch {
+	fn init(&mut self, frame: usize) -> Result<(), RayTraceSetupError> {
+		if let Some(ref anim_pos) = self.anim_pos {
+			self.position = anim_pos.next_frame(frame);
+		}
+
+		self.material.init(frame);
+
+		let mut points = [[0.0; 3]; 16];
+		for (point, control_point) in points.iter_mut().zip(self.control_points.iter()) {
+			*point = vec3_add(*control_point, self.position);
+		}
+
+		let mut aabb = AABB::new(points[0], points[0]);
+		for point in points.iter() {
+			aabb.expand(*point);
+		}
+
+		let step = 1.0 / self.subdivisions as f64;
+		let mut sub_patches = Vec::with_capacity(self.subdivisions * self.subdivisions);
+		for i in 0..self.subdivisions {
+			for j in 0..self.subdivisions {
+				let u = (i as f64 * step, (i + 1) as f64 * step);
+				let v = (j as f64 * step, (j + 1) as f64 * step);
+				let hull = sub_patch_points(&points, u, v);
+
+				let mut sub_aabb = AABB::new(hull[0], hull[0]);
+				for point in hull.iter() {
+					sub_aabb.expand(*point);
+				}
+
+				sub_patches.push(SubPatch { aabb: sub_aabb, u: u, v: v });
+			}
+		}
+
+		self.data = Some(WorkingData {
+			aabb: aabb,
+			points: points,
+			sub_patches: sub_patches
+		});
+
+		Ok(())
+	}
+
+	fn get_aabb(&self) -> Option<&AABB> {
+		if let Some(ref data) = self.data {
+			return Some(&data.aabb);
+		} else {
+			panic!("Bezier patch was not initialized!");
+		}
+	}
+
+	// The control hull encloses the patch, but the patch rarely touches it
+	fn aabb_is_tight(&self) -> bool {
+		false
+	}
+
+	fn get_name(&self) -> Option<&str> {
+		self.name.as_ref().map(|name| name.as_str())
+	}
+
+	fn set_name(&mut self, name: &str) {
+		self.name = Some(name.to_string());
+	}
+
+	fn get_type_name(&self) -> &str {
+		"bezier_patch"
+	}
+}
+
+impl RayTraceObjectBezierPatch {
+	fn compute_hits(&self, data: &WorkingData, ray: &RayTraceRay) -> Vec<(f64, f64, f64)> {
+		let mut hits: Vec<(f64, f64, f64)> = Vec::new();
+
+		for sub_patch in data.sub_patches.iter() {
+			let (t_enter, t_exit) = match sub_patch.aabb.get_interval(ray) {
+				Some(interval) => interval,
+				None => { continue; }
+			};
+
+			let start = ((sub_patch.u.0 + sub_patch.u.1) * 0.5, (sub_patch.v.0 + sub_patch.v.1) * 0.5,
+				(t_enter + t_exit) * 0.5);
+			if let Some((u, v, t)) = intersect_newton(&data.points, ray, start) {
+				let inside = u >= sub_patch.u.0 - UV_TOLERANCE && u <= sub_patch.u.1 + UV_TOLERANCE
+					&& v >= sub_patch.v.0 - UV_TOLERANCE && v <= sub_patch.v.1 + UV_TOLERANCE;
+				if !inside || t < MIN_DISTANCE {
+					continue;
+				}
+
+				// Neighbouring sub patches can converge to the same hit
+				if !hits.iter().any(|hit| (hit.2 - t).abs() < MIN_DISTANCE) {
+					hits.push((u, v, t));
+				}
+			}
+		}
+
+		hits.sort_by(|a, b| a.2.partial_cmp(&b.2).unwrap());
+		hits
+	}
+
+	fn make_hit(&self, data: &WorkingData, ray: &RayTraceRay, u: f64, v: f64, t: f64) -> RayTraceRayHit {
+		let u = u.max(0.0).min(1.0);
+		let v = v.max(0.0).min(1.0);
+		let (_, du, dv) = evaluate(&data.points, u, v);
+
+		// Collapsed edges have no derivative in one direction, look slightly into the patch instead
+		let mut normal = vec3_cross(du, dv);
+		if vec3_len(normal) < THRESHOLD {
+			let (_, du, dv) = evaluate(&data.points, 0.5 + (u - 0.5) * 0.999, 0.5 + (v - 0.5) * 0.999);
+			normal = vec3_cross(du, dv);
+		}
+
+		RayTraceRayHit::new_with_tangents(t, ray.get_position_on_ray(t), safe_normalized(normal), [du, dv],
+			self.material.get_hit(u, v))
+	}
+}
+
+impl RayTraceHitable for RayTraceObjectBezierPatch {
+	fn next_hit(&self, ray: &RayTraceRay) -> Option<RayTraceRayHit> {
+		if let Some(ref data) = self.data {
+			return self.compute_hits(data, ray).first().map(|&(u, v, t)| self.make_hit(data, ray, u, v, t));
+		} else {
+			panic!("Bezier patch was not initialized!");
+		}
+	}
+
+	fn next_hits(&self, ray: &RayTraceRay) -> Vec<RayTraceRayHit> {
+		if let Some(ref data) = self.data {
+			return self.compute_hits(data, ray).into_iter().map(|(u, v, t)| self.make_hit(data, ray, u, v, t))
+				.collect();
+		} else {
+			panic!("Bezier patch was not initialized!");
+		}
+	}
+}
+
+fn safe_normalized(vec: Vector3<f64>) -> Vector3<f64> {
+	if vec3_len(vec) > 0.0 { vec3_normalized(vec) } else { vec }
+}
+
+// Bernstein polynomials of degree 3 and their derivatives
+fn bernstein(t: f64) -> ([f64; 4], [f64; 4]) {
+	let s = 1.0 - t;
+	([s * s * s, 3.0 * t * s * s, 3.0 * t * t * s, t * t * t],
+		[-3.0 * s * s, 3.0 * s * s - 6.0 * t * s, 6.0 * t * s - 3.0 * t * t, 3.0 * t * t])
+}
+
+// Point and partial derivatives in u and v
+fn evaluate(points: &[Vector3<f64>; 16], u: f64, v: f64) -> (Vector3<f64>, Vector3<f64>, Vector3<f64>) {
+	let (bu, dbu) = bernstein(u);
+	let (bv, dbv) = bernstein(v);
+
+	let mut point = [0.0; 3];
+	let mut du = [0.0; 3];
+	let mut dv = [0.0; 3];
+	for j in 0..4 {
+		for i in 0..4 {
+			let p = points[j * 4 + i];
+			point = vec3_add(point, vec3_scale(p, bu[i] * bv[j]));
+			du = vec3_add(du, vec3_scale(p, dbu[i] * bv[j]));
+			dv = vec3_add(dv, vec3_scale(p, bu[i] * dbv[j]));
+		}
+	}
+
+	(point, du, dv)
+}
+
+// Solves S(u, v) = o + t * d, starting from the given (u, v, t)
+fn intersect_newton(points: &[Vector3<f64>; 16], ray: &RayTraceRay, start: (f64, f64, f64))
+		-> Option<(f64, f64, f64)> {
+	let origin = ray.get_position().clone();
+	let direction = ray.get_direction().clone();
+	let (mut u, mut v, mut t) = start;
+
+	for _ in 0..MAX_ITERATIONS {
+		let (point, du, dv) = evaluate(points, u, v);
+		let f = vec3_sub(point, vec3_add(origin, vec3_scale(direction, t)));
+		if vec3_dot(f, f) < THRESHOLD * THRESHOLD {
+			return Some((u, v, t));
+		}
+
+		// Jacobian columns are du, dv and -d, solved by Cramer's rule
+		let neg_dir = vec3_scale(direction, -1.0);
+		let det = vec3_dot(du, vec3_cross(dv, neg_dir));
+		if det.abs() < f64::EPSILON {
+			return None;
+		}
+
+		u -= vec3_dot(f, vec3_cross(dv, neg_dir)) / det;
+		v -= vec3_dot(du, vec3_cross(f, neg_dir)) / det;
+		t -= vec3_dot(du, vec3_cross(dv, f)) / det;
+
+		// Far outside of the patch the polynomial is meaningless for the surface
+		if u < -0.5 || u > 1.5 || v < -0.5 || v > 1.5 {
+			return None;
+		}
+	}
+
+	None
+}
+
+// Control points of the part of the patch in the given parameter ranges (de Casteljau in both directions)
+fn sub_patch_points(points: &[Vector3<f64>; 16], u: (f64, f64), v: (f64, f64)) -> [Vector3<f64>; 16] {
+	let mut rows = [[0.0; 3]; 16];
+	for j in 0..4 {
+		let curve = sub_curve([points[j * 4], points[j * 4 + 1], points[j * 4 + 2], points[j * 4 + 3]], u.0, u.1);
+		for i in 0..4 {
+			rows[j * 4 + i] = curve[i];
+		}
+	}
+
+	let mut result = [[0.0; 3]; 16];
+	for i in 0..4 {
+		let curve = sub_curve([rows[i], rows[4 + i], rows[8 + i], rows[12 + i]], v.0, v.1);
+		for j in 0..4 {
+			result[j * 4 + i] = curve[j];
+		}
+	}
+	result
+}
+
+// Control points of a cubic Bézier curve restricted to [t0, t1]
+fn sub_curve(curve: [Vector3<f64>; 4], t0: f64, t1: f64) -> [Vector3<f64>; 4] {
+	// Cut away everything after t1, then everything before t0 of the remaining curve
+	let head = split(curve, t1).0;
+	if t1 <= 0.0 {
+		return head;
+	}
+	split(head, t0 / t1).1
+}
+
+fn split(curve: [Vector3<f64>; 4], t: f64) -> ([Vector3<f64>; 4], [Vector3<f64>; 4]) {
+	let lerp = |a: Vector3<f64>, b: Vector3<f64>| vec3_add(a, vec3_scale(vec3_sub(b, a), t));
+
+	let p01 = lerp(curve[0], curve[1]);
+	let p12 = lerp(curve[1], curve[2]);
+	let p23 = lerp(curve[2], curve[3]);
+	let p012 = lerp(p01, p12);
+	let p123 = lerp(p12, p23);
+	let p0123 = lerp(p012, p123);
+
+	([curve[0], p01, p012, p0123], [p0123, p123, p23, curve[3]])
+}
@@ -0,0 +1,163 @@
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicUsize, Ordering, ATOMIC_USIZE_INIT};
+
+use vecmath::Vector3;
+
+use aabb::AABB;
+use hit::RayTraceRayHit;
+use math_util::RayTraceCoordinateSystem;
+use object::RayTraceObject;
+use object::RayTraceSetupError;
+use object::RayTraceHitable;
+use object::RayTraceVolumeMedium;
+use ray::RayTraceRay;
+
+// Ids of the cached objects in the thread local hit cache
+static NEXT_CACHE_ID: AtomicUsize = ATOMIC_USIZE_INIT;
+
+// Generation of the object, the bits of the ray (position, direction, time, wavelength) and its hit
+type RayTraceCachedHit = (usize, [u64; 8], Option<RayTraceRayHit>);
+
+thread_local! {
+	// Last hit of each cached object by the id of the object
+	static HIT_CACHE: RefCell<HashMap<usize, RayTraceCachedHit>> = RefCell::new(HashMap::new());
+}
+
+fn with_hit_cache<F, R>(func: F) -> R where F: FnOnce(&mut HashMap<usize, RayTraceCachedHit>) -> R {
+	HIT_CACHE.with(|cache| func(&mut cache.borrow_mut()))
+}
+
+// Remembers the last ray and its hit per thread and answers the same ray again without tracing it
+// (the same primary ray traced by several passes, ...). Costs a lookup for every other ray, so it only
+// pays off for expensive objects that are queried repeatedly.
+pub struct RayTraceObjectCached {
+	object: Box<RayTraceObject>,
+	id: usize,
+	// Increased by each init, so hits of earlier frames are never returned
+	generation: usize
+}
+
+#[allow(dead_code)]
+impl RayTraceObjectCached {
+	pub fn new(object: Box<RayTraceObject>) -> Self {
+		Self {
+			object: object,
+			id: NEXT_CACHE_ID.fetch_add(1, Ordering::Relaxed),
+			generation: 0
+		}
+	}
+
+	pub fn get_object(&self) -> &Box<RayTraceObject> {
+		&self.object
+	}
+
+	pub fn get_object_mut(&mut self) -> &mut Box<RayTraceObject> {
+		&mut self.object
+	}
+}
+
+impl RayTraceObject for RayTraceObjectCached {
+	fn set_coordinate_system(&mut self, system: RayTraceCoordinateSystem) {
+		self.object.set_coordinate_system(system);
+	}
+
+	fn init(&mut self, frame: usize) -> Result<(), RayTraceSetupError> {
+		self.generation += 1;
+		self.object.init(frame)
+	}
+
+	fn get_aabb(&self) -> Option<&AABB> {
+		self.object.get_aabb()
+	}
+
+	fn aabb_is_tight(&self) -> bool {
+		self.object.aabb_is_tight()
+	}
+
+	fn local_bounds(&self) -> Option<AABB> {
+		self.object.local_bounds()
+	}
+
+	fn contains(&self, point: Vector3<f64>) -> bool {
+		self.object.contains(point)
+	}
+
+	fn prepare(&mut self) {
+		self.generation += 1;
+		self.object.prepare();
+	}
+
+	fn get_name(&self) -> Option<&str> {
+		self.object.get_name()
+	}
+
+	fn set_name(&mut self, name: &str) {
+		self.object.set_name(name);
+	}
+
+	fn get_type_name(&self) -> &str {
+		self.object.get_type_name()
+	}
+
+	fn get_triangle_count(&self) -> usize {
+		self.object.get_triangle_count()
+	}
+
+	fn sample_point(&self, u1: f64, u2: f64) -> Option<(Vector3<f64>, Vector3<f64>, f64)> {
+		self.object.sample_point(u1, u2)
+	}
+
+	fn is_volume(&self) -> bool {
+		self.object.is_volume()
+	}
+
+	fn get_volume_medium(&self) -> Option<RayTraceVolumeMedium> {
+		self.object.get_volume_medium()
+	}
+
+	fn get_volume_density(&self, ray: &RayTraceRay, distance: f64) -> f64 {
+		self.object.get_volume_density(ray, distance)
+	}
+}
+
+impl RayTraceHitable for RayTraceObjectCached {
+	fn next_hit(&self, ray: &RayTraceRay) -> Option<RayTraceRayHit> {
+		let key = ray_key(ray);
+		let cached = with_hit_cache(|cache| match cache.get(&self.id) {
+			Some(&(generation, ref cached_key, ref hit)) if generation == self.generation && *cached_key == key =>
+				Some(hit.clone()),
+			_ => None
+		});
+
+		if let Some(hit) = cached {
+			return hit;
+		}
+
+		// Traced outside of the cache borrow, the object might contain cached objects itself
+		let hit = self.object.next_hit(ray);
+		with_hit_cache(|cache| { cache.insert(self.id, (self.generation, key, hit.clone())); });
+		hit
+	}
+
+	fn next_hits(&self, ray: &RayTraceRay) -> Vec<RayTraceRayHit> {
+		self.object.next_hits(ray)
+	}
+
+	fn occludes(&self, ray: &RayTraceRay, max_distance: f64) -> bool {
+		self.object.occludes(ray, max_distance)
+	}
+}
+
+// Exact bits of everything the hit depends on
+fn ray_key(ray: &RayTraceRay) -> [u64; 8] {
+	let position = ray.get_position();
+	let direction = ray.get_direction();
+
+	[
+		position[0].to_bits(), position[1].to_bits(), position[2].to_bits(),
+		direction[0].to_bits(), direction[1].to_bits(), direction[2].to_bits(),
+		ray.get_time().to_bits(),
+		ray.get_wavelength().map_or(u64::max_value(), |wavelength| wavelength.to_bits())
+	]
+}
@@ -0,0 +1,308 @@
+use std::f64;
+
+use vecmath::Vector3;
+use vecmath::{vec3_add, vec3_sub, vec3_scale, vec3_dot, vec3_cross, vec3_len, vec3_square_len, vec3_normalized};
+
+use aabb::AABB;
+use anim::RayTraceAnimation;
+use hit::RayTraceRayHit;
+use material::RayTraceMaterial;
+use object::RayTraceObject;
+use object::RayTraceSetupError;
+use object::RayTraceHitable;
+use object::occludes_by_hit;
+use ray::RayTraceRay;
+
+// Cylinder between the two end points, capped by half spheres of the same radius
+#[allow(dead_code)]
+pub struct RayTraceObjectCapsule {
+	material: Box<RayTraceMaterial>,
+	start: Vector3<f64>,
+	end: Vector3<f64>,
+	radius: f64,
+	anim_start: Option<Box<RayTraceAnimation<Vector3<f64>>>>,
+	anim_end: Option<Box<RayTraceAnimation<Vector3<f64>>>>,
+	anim_radius: Option<Box<RayTraceAnimation<f64>>>,
+	name: Option<String>,
+	data: Option<WorkingData>
+}
+
+#[allow(dead_code)]
+impl RayTraceObjectCapsule {
+	pub fn new(start: Vector3<f64>, end: Vector3<f64>, radius: f64, material: Box<RayTraceMaterial>) -> Self {
+		Self {
+			material: material,
+			start: start,
+			end: end,
+			radius: radius,
+			anim_start: None,
+			anim_end: None,
+			anim_radius: None,
+			name: None,
+			data: None
+		}
+	}
+
+	pub fn set_start(&mut self, start: Vector3<f64>) {
+		self.start = start;
+	}
+
+	pub fn set_end(&mut self, end: Vector3<f64>) {
+		self.end = end;
+	}
+
+	pub fn set_radius(&mut self, radius: f64) {
+		self.radius = radius;
+	}
+
+	pub fn set_anim_start_opt(&mut self, anim: Option<Box<RayTraceAnimation<Vector3<f64>>>>) {
+		self.anim_start = anim;
+	}
+
+	pub fn set_anim_start(&mut self, anim: Box<RayTraceAnimation<Vector3<f64>>>) {
+		self.anim_start = Some(anim);
+	}
+
+	pub fn set_anim_end_opt(&mut self, anim: Option<Box<RayTraceAnimation<Vector3<f64>>>>) {
+		self.anim_end = anim;
+	}
+
+	pub fn set_anim_end(&mut self, anim: Box<RayTraceAnimation<Vector3<f64>>>) {
+		self.anim_end = Some(anim);
+	}
+
+	pub fn set_anim_radius_opt(&mut self, anim: Option<Box<RayTraceAnimation<f64>>>) {
+		self.anim_radius = anim;
+	}
+
+	pub fn set_anim_radius(&mut self, anim: Box<RayTraceAnimation<f64>>) {
+		self.anim_radius = Some(anim);
+	}
+}
+
+struct WorkingData {
+	aabb: AABB,
+	// Unit vector from start to end and two unit vectors perpendicular to it
+	axis: [Vector3<f64>; 3],
+	length: f64
+}
+
+const THRESHOLD: f64 = 1e-10;
+
+#[allow(unused_variables)]
+impl RayTraceObject for RayTraceObjectCapsule {
+	fn init(&mut self, frame: usize) -> Result<(), RayTraceSetupError> {
+		if let Some(ref anim_start) = self.anim_start {
+			self.start = anim_start.next_frame(frame);
+		}
+		if let Some(ref anim_end) = self.anim_end {
+			self.end = anim_end.next_frame(frame);
+		}
+		if let Some(ref anim_radius) = self.anim_radius {
+			self.radius = anim_radius.next_frame(frame);
+		}
+
+		self.material.init(frame);
+
+		let offset = vec3_sub(self.end, self.start);
+		let length = vec3_len(offset);
+		// Without length the capsule is a sphere and any axis does
+		let axis = if length > THRESHOLD { vec3_scale(offset, 1.0 / length) } else { [0.0, 1.0, 0.0] };
+		let helper = if axis[0].abs() < 0.9 { [1.0, 0.0, 0.0] } else { [0.0, 1.0, 0.0] };
+		let tangent = vec3_normalized(vec3_cross(helper, axis));
+		let bitangent = vec3_cross(axis, tangent);
+
+		// The bounds of the two end spheres enclose the whole capsule
+		let radius = self.radius.abs();
+		let radius_vec = [radius, radius, radius];
+		let mut aabb = AABB::new(vec3_sub(self.start, radius_vec), vec3_add(self.start, radius_vec));
+		aabb.expand(vec3_sub(self.end, radius_vec));
+		aabb.expand(vec3_add(self.end, radius_vec));
+
+		self.data = Some(WorkingData {
+			aabb: aabb,
+			axis: [axis, tangent, bitangent],
+			length: length
+		});
+
+		Ok(())
+	}
+
+	fn get_aabb(&self) -> Option<&AABB> {
+		if let Some(ref data) = self.data {
+			return Some(&data.aabb);
+		} else {
+			panic!("Capsule was not initialized!");
+		}
+	}
+
+	// Closer to the segment between the end points than the radius
+	fn contains(&self, point: Vector3<f64>) -> bool {
+		if let Some(ref data) = self.data {
+			let offset = vec3_sub(point, self.start);
+			let along = vec3_dot(offset, data.axis[0]).max(0.0).min(data.length);
+			let nearest = vec3_add(self.start, vec3_scale(data.axis[0], along));
+			vec3_square_len(vec3_sub(point, nearest)) < self.radius * self.radius
+		} else {
+			panic!("Capsule was not initialized!");
+		}
+	}
+
+	fn get_name(&self) -> Option<&str> {
+		self.name.as_ref().map(|name| name.as_str())
+	}
+
+	fn set_name(&mut self, name: &str) {
+		self.name = Some(name.to_string());
+	}
+
+	fn get_type_name(&self) -> &str {
+		"capsule"
+	}
+}
+
+impl RayTraceObjectCapsule {
+	// The capsule is convex, so the ray enters it at the first entry into the side or one of the caps
+	// and leaves it at the last exit
+	fn compute_hit_distances(&self, data: &WorkingData, ray: &RayTraceRay) -> Option<(f64, f64)> {
+		let intervals = [
+			self.compute_side_distances(data, ray),
+			compute_sphere_distances(self.start, self.radius, ray),
+			compute_sphere_distances(self.end, self.radius, ray)
+		];
+
+		intervals.iter().fold(None, |result, interval| {
+			match (result, *interval) {
+				(Some((t1, t2)), Some((s1, s2))) => Some((t1.min(s1), t2.max(s2))),
+				(None, interval) => interval,
+				(result, None) => result
+			}
+		})
+	}
+
+	// Infinite cylinder around the axis cut off at the end points
+	fn compute_side_distances(&self, data: &WorkingData, ray: &RayTraceRay) -> Option<(f64, f64)> {
+		if data.length <= THRESHOLD {
+			return None;
+		}
+
+		let axis = data.axis[0];
+		let d = ray.get_direction().clone();
+		let o = vec3_sub(ray.get_position().clone(), self.start);
+
+		let d_axis = vec3_dot(d, axis);
+		let o_axis = vec3_dot(o, axis);
+		let d_perp = vec3_sub(d, vec3_scale(axis, d_axis));
+		let o_perp = vec3_sub(o, vec3_scale(axis, o_axis));
+
+		let a = vec3_square_len(d_perp);
+		let b = vec3_dot(d_perp, o_perp) * 2.0;
+		let c = vec3_square_len(o_perp) - self.radius * self.radius;
+
+		let (mut t1, mut t2) = if a < THRESHOLD {
+			// Parallel to the axis, either always inside the cylinder or never
+			if c > 0.0 { return None; }
+			(f64::NEG_INFINITY, f64::INFINITY)
+		} else {
+			let disc = b * b - 4.0 * a * c;
+			if disc < 0.0 {
+				return None;
+			}
+			((-b - disc.sqrt()) / 2.0 / a, (-b + disc.sqrt()) / 2.0 / a)
+		};
+
+		if d_axis.abs() < THRESHOLD {
+			if o_axis < 0.0 || o_axis > data.length {
+				return None;
+			}
+		} else {
+			let s1 = -o_axis / d_axis;
+			let s2 = (data.length - o_axis) / d_axis;
+			t1 = t1.max(s1.min(s2));
+			t2 = t2.min(s1.max(s2));
+		}
+
+		if t1 > t2 { None } else { Some((t1, t2)) }
+	}
+
+	fn make_hit(&self, data: &WorkingData, ray: &RayTraceRay, t: f64) -> RayTraceRayHit {
+		let hit_point = ray.get_position_on_ray(t);
+		let offset = vec3_sub(hit_point, self.start);
+
+		// Perpendicular to the axis on the side, radial from the nearer end point on the caps
+		let along = vec3_dot(offset, data.axis[0]);
+		let nearest = vec3_add(self.start, vec3_scale(data.axis[0], along.max(0.0).min(data.length)));
+		let surface_normal = vec3_normalized(vec3_sub(hit_point, nearest));
+
+		// Angle around the axis and distance along it, a step in the angle moves the radius around the axis
+		let angle = vec3_dot(offset, data.axis[2]).atan2(vec3_dot(offset, data.axis[1]));
+		let tangents = [vec3_scale(vec3_cross(data.axis[0], surface_normal), self.radius), data.axis[0]];
+
+		RayTraceRayHit::new_with_tangents(t, hit_point, surface_normal, tangents, self.material.get_hit(angle, along))
+	}
+}
+
+fn compute_sphere_distances(center: Vector3<f64>, radius: f64, ray: &RayTraceRay) -> Option<(f64, f64)> {
+	let l = ray.get_direction().clone();
+	let dist = vec3_sub(ray.get_position().clone(), center);
+
+	let a = vec3_square_len(l);
+	let b = vec3_dot(l, dist) * 2.0;
+	let c = vec3_square_len(dist) - radius * radius;
+
+	let disc = b * b - 4.0 * a * c;
+	if disc < 0.0 {
+		return None;
+	}
+
+	Some(((-b - disc.sqrt()) / 2.0 / a, (-b + disc.sqrt()) / 2.0 / a))
+}
+
+impl RayTraceHitable for RayTraceObjectCapsule {
+	fn next_hit(&self, ray: &RayTraceRay) -> Option<RayTraceRayHit> {
+		if let Some(ref data) = self.data {
+			if let Some((t1, t2)) = self.compute_hit_distances(data, ray) {
+				let t = if t1 < 0.0 { if t2 < 0.0 { return None; } else { t2 } } else { t1 };
+				return Some(self.make_hit(data, ray, t));
+			} else {
+				return None;
+			}
+		} else {
+			panic!("Capsule was not initialized!");
+		}
+	}
+
+	fn occludes(&self, ray: &RayTraceRay, max_distance: f64) -> bool {
+		if let Some(ref data) = self.data {
+			match self.compute_hit_distances(data, ray) {
+				Some((t1, t2)) => {
+					let in_range = |t: f64| t > 0.0 && t < max_distance;
+					// The material might still cut the surface out
+					(in_range(t1) || in_range(t2)) && occludes_by_hit(self, ray, max_distance)
+				},
+				None => false
+			}
+		} else {
+			panic!("Capsule was not initialized!");
+		}
+	}
+
+	fn next_hits(&self, ray: &RayTraceRay) -> Vec<RayTraceRayHit> {
+		if let Some(ref data) = self.data {
+			let mut hits = Vec::with_capacity(2);
+
+			if let Some((t1, t2)) = self.compute_hit_distances(data, ray) {
+				if t1 >= 0.0 {
+					hits.push(self.make_hit(data, ray, t1));
+				}
+				if t2 >= 0.0 && t2 != t1 {
+					hits.push(self.make_hit(data, ray, t2));
+				}
+			}
+
+			return hits;
+		} else {
+			panic!("Capsule was not initialized!");
+		}
+	}
+}
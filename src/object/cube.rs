@@ -1,7 +1,7 @@
 use std::f64;
 
 use vecmath::Vector3;
-use vecmath::{vec3_add, vec3_sub, vec3_scale, vec3_neg};
+use vecmath::{vec3_add, vec3_sub, vec3_scale, vec3_neg, vec3_dot, vec3_len};
 use vecmath::row_mat3_transform;
 
 use aabb::AABB;
@@ -9,11 +9,13 @@ use anim::RayTraceAnimation;
 use hit::RayTraceRayHit;
 use material::RayTraceMaterial;
 use object::RayTraceObject;
+use object::RayTraceSetupError;
 use object::RayTraceHitable;
 use ray::RayTraceRay;
 
 use math_util::compute_plane_hit;
-use math_util::rotate_xyz;
+use math_util::rotate_xyz_in;
+use math_util::RayTraceCoordinateSystem;
 
 enum CubeMaterial {
 	OnePerCube(Box<RayTraceMaterial>),
@@ -26,9 +28,12 @@ pub struct RayTraceObjectCube {
 	size: Vector3<f64>,
 	center: Vector3<f64>,
 	rotation: Vector3<f64>,
+	coordinate_system: RayTraceCoordinateSystem,
+	bevel: f64,
 	anim_pos: Option<Box<RayTraceAnimation<Vector3<f64>>>>,
 	anim_rot: Option<Box<RayTraceAnimation<Vector3<f64>>>>,
 	anim_size: Option<Box<RayTraceAnimation<Vector3<f64>>>>,
+	name: Option<String>,
 	data: Option<WorkingData>
 }
 
@@ -40,9 +45,12 @@ impl RayTraceObjectCube {
 			center: center,
 			size: size,
 			rotation: [0.0, 0.0, 0.0],
+			coordinate_system: RayTraceCoordinateSystem::RightHandedYUp,
+			bevel: 0.0,
 			anim_rot: None,
 			anim_pos: None,
 			anim_size: None,
+			name: None,
 			data: None
 		}
 	}
@@ -53,9 +61,12 @@ impl RayTraceObjectCube {
 			center: center,
 			size: size,
 			rotation: [0.0, 0.0, 0.0],
+			coordinate_system: RayTraceCoordinateSystem::RightHandedYUp,
+			bevel: 0.0,
 			anim_rot: None,
 			anim_pos: None,
 			anim_size: None,
+			name: None,
 			data: None
 		}
 	}
@@ -72,6 +83,15 @@ impl RayTraceObjectCube {
 		self.size = size;
 	}
 
+	// Radius of the rounded edges and corners, limited to half of the smallest side
+	pub fn set_bevel(&mut self, bevel: f64) {
+		self.bevel = bevel.max(0.0);
+	}
+
+	pub fn get_bevel(&self) -> f64 {
+		self.bevel
+	}
+
 	pub fn set_anim_pos_opt(&mut self, anim: Option<Box<RayTraceAnimation<Vector3<f64>>>>) {
 		self.anim_pos = anim;
 	}
@@ -108,20 +128,24 @@ impl RayTraceObjectCube {
 	}
 }
 
-fn gen_aabb(center: Vector3<f64>, size: Vector3<f64>) -> AABB {
-	let dir = vec3_scale(size, 1.42 * 0.5);
-	return AABB::new(vec3_sub(center, dir), vec3_add(center, dir));
-}
-
 struct WorkingData {
 	plane_vec: [Vector3<f64>; 3],
 	plane_center: [Vector3<f64>; 6],
-	aabb: AABB
+	aabb: AABB,
+	bevel: f64,
+	// Half size of the flat face rectangles, the edges and corners around them are rounded by the bevel
+	inner_size: Vector3<f64>
 }
 
+const THRESHOLD: f64 = 1e-10;
+
 #[allow(unused_variables)]
 impl RayTraceObject for RayTraceObjectCube {
-	fn init(&mut self, frame: usize) {
+	fn set_coordinate_system(&mut self, system: RayTraceCoordinateSystem) {
+		self.coordinate_system = system;
+	}
+
+	fn init(&mut self, frame: usize) -> Result<(), RayTraceSetupError> {
 		if let Some(ref anim_pos) = self.anim_pos {
 			self.center = anim_pos.next_frame(frame);
 		}
@@ -132,16 +156,33 @@ impl RayTraceObject for RayTraceObjectCube {
 			self.size = anim_size.next_frame(frame);
 		}
 
+		match *self.material {
+			CubeMaterial::OnePerCube(ref mut material) => { material.init(frame); },
+			CubeMaterial::OnePerSide(ref mut materials) => {
+				for material in materials.iter_mut() {
+					material.init(frame);
+				}
+			}
+		}
+
 		let plane_vec1 = [1.0, 0.0, 0.0];
 		let plane_vec2 = [0.0, 1.0, 0.0];
 		let plane_vec3 = [0.0, 0.0, 1.0];
 
-		let rot = rotate_xyz(self.rotation);
+		let rot = rotate_xyz_in(self.rotation, self.coordinate_system);
 
 		let vec1 = row_mat3_transform(rot, plane_vec1);
 		let vec2 = row_mat3_transform(rot, plane_vec2);
 		let vec3 = row_mat3_transform(rot, plane_vec3);
 
+		let bevel = self.bevel.min(0.5 * self.size[0].abs()).min(0.5 * self.size[1].abs())
+			.min(0.5 * self.size[2].abs());
+		let inner_size = [
+			0.5 * self.size[0].abs() - bevel,
+			0.5 * self.size[1].abs() - bevel,
+			0.5 * self.size[2].abs() - bevel
+		];
+
 		let vec1_scaled = vec3_scale(vec1, 0.5 * self.size[0]);
 		let vec2_scaled = vec3_scale(vec2, 0.5 * self.size[1]);
 		let vec3_scaled = vec3_scale(vec3, 0.5 * self.size[2]);
@@ -156,8 +197,13 @@ impl RayTraceObject for RayTraceObjectCube {
 				vec3_add(self.center, vec3_scaled),
 				vec3_sub(self.center, vec3_scaled),
 			],
-			aabb: gen_aabb(self.center, self.size)
+			// The rounded box is the inner box grown by the bevel in every direction
+			aabb: AABB::new(vec3_neg(inner_size), inner_size).transform(rot, self.center).grown(bevel),
+			bevel: bevel,
+			inner_size: inner_size
 		});
+
+		Ok(())
 	}
 
 	fn get_aabb(&self) -> Option<&AABB> {
@@ -167,11 +213,48 @@ impl RayTraceObject for RayTraceObjectCube {
 			panic!("Qube was not initialized!");
 		}
 	}
+
+	fn local_bounds(&self) -> Option<AABB> {
+		let half_size = [0.5 * self.size[0].abs(), 0.5 * self.size[1].abs(), 0.5 * self.size[2].abs()];
+		Some(AABB::new(vec3_neg(half_size), half_size))
+	}
+
+	// Inside the flat faces and within the bevel of the rounded edges
+	fn contains(&self, point: Vector3<f64>) -> bool {
+		if let Some(ref data) = self.data {
+			let offset = vec3_sub(point, self.center);
+			let local = [vec3_dot(offset, data.plane_vec[0]).abs(), vec3_dot(offset, data.plane_vec[1]).abs(),
+				vec3_dot(offset, data.plane_vec[2]).abs()];
+			let outside = [(local[0] - data.inner_size[0]).max(0.0), (local[1] - data.inner_size[1]).max(0.0),
+				(local[2] - data.inner_size[2]).max(0.0)];
+
+			(0..3).all(|i| local[i] < data.inner_size[i] + data.bevel) && vec3_len(outside) <= data.bevel
+		} else {
+			panic!("Qube was not initialized!");
+		}
+	}
+
+	fn get_name(&self) -> Option<&str> {
+		self.name.as_ref().map(|name| name.as_str())
+	}
+
+	fn set_name(&mut self, name: &str) {
+		self.name = Some(name.to_string());
+	}
+
+	fn get_type_name(&self) -> &str {
+		"cube"
+	}
 }
 
 impl RayTraceHitable for RayTraceObjectCube {
 	fn next_hit(&self, ray: &RayTraceRay) -> Option<RayTraceRayHit> {
 		if let Some(ref data) = self.data {
+			if data.bevel > 0.0 {
+				return compute_bevel_hits(data, self.center, ray).first()
+					.map(|&(distance, normal)| self.make_bevel_hit(data, ray, distance, normal));
+			}
+
 			let mut hit_distance = f64::MAX;
 			let mut hit_ret = None;
 
@@ -222,6 +305,157 @@ impl RayTraceHitable for RayTraceObjectCube {
 			panic!("Qube was not initialized!");
 		}
 	}
+
+	fn next_hits(&self, ray: &RayTraceRay) -> Vec<RayTraceRayHit> {
+		if let Some(ref data) = self.data {
+			if data.bevel > 0.0 {
+				return compute_bevel_hits(data, self.center, ray).into_iter()
+					.map(|(distance, normal)| self.make_bevel_hit(data, ray, distance, normal)).collect();
+			}
+
+			let mut hits = Vec::with_capacity(2);
+
+			for side in 0..6 {
+				let (axis, v1, v2) = match side >> 1 {
+					0 => (0, 1, 2),
+					1 => (1, 0, 2),
+					_ => (2, 0, 1)
+				};
+				let normal_vec = if side & 1 == 0 { data.plane_vec[axis] } else { vec3_neg(data.plane_vec[axis]) };
+
+				if let Some(hit) = get_plane_hit(ray, data.plane_center[side], &self.size,
+						normal_vec, data.plane_vec, v1, v2, self.get_material(side)) {
+					hits.push(hit);
+				}
+			}
+
+			hits.sort_by(|a, b| a.get_distance().partial_cmp(&b.get_distance()).unwrap());
+			return hits;
+		} else {
+			panic!("Qube was not initialized!");
+		}
+	}
+}
+
+impl RayTraceObjectCube {
+	// The side with the largest normal component provides the material and its coordinates
+	fn make_bevel_hit(&self, data: &WorkingData, ray: &RayTraceRay, distance: f64, local_normal: Vector3<f64>)
+			-> RayTraceRayHit {
+		let position = ray.get_position_on_ray(distance);
+		let normal = vec3_add(vec3_add(vec3_scale(data.plane_vec[0], local_normal[0]),
+			vec3_scale(data.plane_vec[1], local_normal[1])), vec3_scale(data.plane_vec[2], local_normal[2]));
+
+		let axis = if local_normal[0].abs() >= local_normal[1].abs() && local_normal[0].abs() >= local_normal[2].abs() {
+			0
+		} else if local_normal[1].abs() >= local_normal[2].abs() {
+			1
+		} else {
+			2
+		};
+		let side = axis * 2 + if local_normal[axis] < 0.0 { 1 } else { 0 };
+		let (v1, v2) = match axis {
+			0 => (1, 2),
+			1 => (0, 2),
+			_ => (0, 1)
+		};
+
+		let offset = vec3_sub(position, self.center);
+		let material_hit = self.get_material(side).get_hit(vec3_dot(offset, data.plane_vec[v1]),
+			vec3_dot(offset, data.plane_vec[v2]));
+
+		RayTraceRayHit::new_with_tangents(distance, position, normal, [data.plane_vec[v1], data.plane_vec[v2]],
+			material_hit)
+	}
+}
+
+// Distances and local normals of all hits with the rounded box sorted by distance.
+// The surface consists of the shrunk flat faces, quarter cylinders along the edges and sphere octants at the corners.
+fn compute_bevel_hits(data: &WorkingData, center: Vector3<f64>, ray: &RayTraceRay) -> Vec<(f64, Vector3<f64>)> {
+	let offset = vec3_sub(ray.get_position().clone(), center);
+	let direction = ray.get_direction().clone();
+	let o = [vec3_dot(offset, data.plane_vec[0]), vec3_dot(offset, data.plane_vec[1]),
+		vec3_dot(offset, data.plane_vec[2])];
+	let d = [vec3_dot(direction, data.plane_vec[0]), vec3_dot(direction, data.plane_vec[1]),
+		vec3_dot(direction, data.plane_vec[2])];
+	let inner = data.inner_size;
+	let radius = data.bevel;
+	let signs = [1.0, -1.0];
+
+	let mut hits = Vec::new();
+
+	for axis in 0..3 {
+		let (a, b) = match axis {
+			0 => (1, 2),
+			1 => (0, 2),
+			_ => (0, 1)
+		};
+
+		// Flat faces
+		if d[axis].abs() > THRESHOLD {
+			for &sign in signs.iter() {
+				let t = (sign * (inner[axis] + radius) - o[axis]) / d[axis];
+				if t > 0.0 && (o[a] + t * d[a]).abs() <= inner[a] && (o[b] + t * d[b]).abs() <= inner[b] {
+					let mut normal = [0.0; 3];
+					normal[axis] = sign;
+					hits.push((t, normal));
+				}
+			}
+		}
+
+		// Edges parallel to the axis
+		for &sign_a in signs.iter() {
+			for &sign_b in signs.iter() {
+				let (c_a, c_b) = (sign_a * inner[a], sign_b * inner[b]);
+
+				for t in solve_quadratic(d[a] * d[a] + d[b] * d[b],
+						2.0 * ((o[a] - c_a) * d[a] + (o[b] - c_b) * d[b]),
+						(o[a] - c_a) * (o[a] - c_a) + (o[b] - c_b) * (o[b] - c_b) - radius * radius) {
+					let p = [o[0] + t * d[0], o[1] + t * d[1], o[2] + t * d[2]];
+					if p[axis].abs() <= inner[axis] && (p[a] - c_a) * sign_a >= 0.0 && (p[b] - c_b) * sign_b >= 0.0 {
+						let mut normal = [0.0; 3];
+						normal[a] = (p[a] - c_a) / radius;
+						normal[b] = (p[b] - c_b) / radius;
+						hits.push((t, normal));
+					}
+				}
+			}
+		}
+	}
+
+	// Corners
+	for &sign_x in signs.iter() {
+		for &sign_y in signs.iter() {
+			for &sign_z in signs.iter() {
+				let c = [sign_x * inner[0], sign_y * inner[1], sign_z * inner[2]];
+				let oc = vec3_sub(o, c);
+
+				for t in solve_quadratic(vec3_dot(d, d), 2.0 * vec3_dot(oc, d), vec3_dot(oc, oc) - radius * radius) {
+					let p = vec3_add(o, vec3_scale(d, t));
+					if (p[0] - c[0]) * sign_x >= 0.0 && (p[1] - c[1]) * sign_y >= 0.0 && (p[2] - c[2]) * sign_z >= 0.0 {
+						hits.push((t, vec3_scale(vec3_sub(p, c), 1.0 / radius)));
+					}
+				}
+			}
+		}
+	}
+
+	hits.sort_by(|a, b| a.0.partial_cmp(&b.0).unwrap());
+	hits
+}
+
+// Positive roots of a * t^2 + b * t + c
+fn solve_quadratic(a: f64, b: f64, c: f64) -> Vec<f64> {
+	if a.abs() < THRESHOLD {
+		return Vec::new();
+	}
+
+	let disc = b * b - 4.0 * a * c;
+	if disc < 0.0 {
+		return Vec::new();
+	}
+
+	let sqrt = disc.sqrt();
+	[(-b - sqrt) / (2.0 * a), (-b + sqrt) / (2.0 * a)].iter().cloned().filter(|&t| t > 0.0).collect()
 }
 
 fn get_plane_hit(ray: &RayTraceRay, center: Vector3<f64>, size: &Vector3<f64>, normal_vec: Vector3<f64>,
@@ -239,8 +473,8 @@ fn get_plane_hit(ray: &RayTraceRay, center: Vector3<f64>, size: &Vector3<f64>, n
 			return None;
 		}
 
-		return Some(RayTraceRayHit::new(dist, ray.get_position_on_ray(dist),
-				normal_vec, material.get_hit(vec1, vec2)));
+		return Some(RayTraceRayHit::new_with_tangents(dist, ray.get_position_on_ray(dist),
+				normal_vec, [vec[v1], vec[v2]], material.get_hit(vec1, vec2)));
 	} else {
 		return None;
 	}
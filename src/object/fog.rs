@@ -0,0 +1,215 @@
+use vecmath::Vector3;
+use vecmath::{vec3_add, vec3_scale, vec3_sub};
+
+use aabb::AABB;
+use anim::RayTraceAnimation;
+use color::RayTraceColor;
+use hit::RayTraceRayHit;
+use object::RayTraceObject;
+use object::RayTraceSetupError;
+use object::RayTraceHitable;
+use object::RayTraceVolumeMedium;
+use random::value_noise;
+use ray::RayTraceRay;
+
+// Axis aligned box of fog around the center. The scene marches rays through it in steps, the fog absorbs part of the
+// light arriving from behind and scatters the light of the scene lights towards the ray (light shafts behind
+// occluders, ...). The fog has no surface and doesn't cast shadows itself.
+#[allow(dead_code)]
+pub struct RayTraceObjectFog {
+	center: Vector3<f64>,
+	size: Vector3<f64>,
+	color: RayTraceColor,
+	// Extinction per scene unit
+	density: f64,
+	// Part of the extinction that is scattered towards the ray instead of absorbed
+	scattering: f32,
+	// Henyey-Greenstein anisotropy, above 0 scatters forward (brighter looking towards the light), below 0 back
+	anisotropy: f64,
+	// Size of the noise features in scene units and the part of the density they modulate
+	noise: Option<(f64, f64)>,
+	step_size: f64,
+	max_steps: usize,
+	anim_pos: Option<Box<RayTraceAnimation<Vector3<f64>>>>,
+	name: Option<String>,
+	data: Option<WorkingData>
+}
+
+const NOISE_SEED: u64 = 0x464F47;
+
+#[allow(dead_code)]
+impl RayTraceObjectFog {
+	pub fn new(center: Vector3<f64>, size: Vector3<f64>, color: RayTraceColor, density: f64) -> Self {
+		Self {
+			center: center,
+			size: size,
+			color: color,
+			density: density.max(0.0),
+			scattering: 1.0,
+			anisotropy: 0.0,
+			noise: None,
+			step_size: 0.1,
+			max_steps: 64,
+			anim_pos: None,
+			name: None,
+			data: None
+		}
+	}
+
+	pub fn set_position(&mut self, position: Vector3<f64>) {
+		self.center = position;
+	}
+
+	pub fn set_size(&mut self, size: Vector3<f64>) {
+		self.size = size;
+	}
+
+	pub fn set_color(&mut self, color: RayTraceColor) {
+		self.color = color;
+	}
+
+	pub fn get_color(&self) -> &RayTraceColor {
+		&self.color
+	}
+
+	pub fn set_density(&mut self, density: f64) {
+		self.density = density.max(0.0);
+	}
+
+	pub fn get_density(&self) -> f64 {
+		self.density
+	}
+
+	pub fn set_scattering(&mut self, scattering: f32) {
+		self.scattering = scattering.max(0.0).min(1.0);
+	}
+
+	pub fn get_scattering(&self) -> f32 {
+		self.scattering
+	}
+
+	// Limited to (-1, 1), the extremes would scatter everything into a single direction
+	pub fn set_anisotropy(&mut self, anisotropy: f64) {
+		self.anisotropy = anisotropy.max(-0.99).min(0.99);
+	}
+
+	pub fn get_anisotropy(&self) -> f64 {
+		self.anisotropy
+	}
+
+	// Patchy fog, an amount of 1 lets the density vary between 0 and the full density
+	pub fn set_noise(&mut self, scale: f64, amount: f64) {
+		self.noise = Some((scale, amount.max(0.0).min(1.0)));
+	}
+
+	pub fn set_noise_opt(&mut self, noise: Option<(f64, f64)>) {
+		self.noise = noise.map(|(scale, amount)| (scale, amount.max(0.0).min(1.0)));
+	}
+
+	pub fn get_noise(&self) -> Option<(f64, f64)> {
+		self.noise
+	}
+
+	// Distance between the samples along the ray, longer paths through the fog use fewer but longer steps
+	// to stay below the maximum step count
+	pub fn set_step_size(&mut self, step_size: f64) {
+		self.step_size = step_size;
+	}
+
+	pub fn get_step_size(&self) -> f64 {
+		self.step_size
+	}
+
+	pub fn set_max_steps(&mut self, max_steps: usize) {
+		self.max_steps = max_steps.max(1);
+	}
+
+	pub fn get_max_steps(&self) -> usize {
+		self.max_steps
+	}
+
+	pub fn set_anim_pos_opt(&mut self, anim: Option<Box<RayTraceAnimation<Vector3<f64>>>>) {
+		self.anim_pos = anim;
+	}
+
+	pub fn set_anim_pos(&mut self, anim: Box<RayTraceAnimation<Vector3<f64>>>) {
+		self.anim_pos = Some(anim);
+	}
+
+	fn get_density_at(&self, position: Vector3<f64>) -> f64 {
+		match self.noise {
+			Some((scale, amount)) if scale > 0.0 => {
+				let noise = value_noise(vec3_scale(vec3_sub(position, self.center), 1.0 / scale), NOISE_SEED);
+				self.density * (1.0 - amount + amount * noise)
+			},
+			_ => self.density
+		}
+	}
+}
+
+struct WorkingData {
+	aabb: AABB
+}
+
+impl RayTraceObject for RayTraceObjectFog {
+	fn init(&mut self, frame: usize) -> Result<(), RayTraceSetupError> {
+		if let Some(ref anim_pos) = self.anim_pos {
+			self.center = anim_pos.next_frame(frame);
+		}
+
+		let half_size = vec3_scale(self.size, 0.5);
+		self.data = Some(WorkingData {
+			aabb: AABB::new(vec3_sub(self.center, half_size), vec3_add(self.center, half_size))
+		});
+
+		Ok(())
+	}
+
+	fn get_aabb(&self) -> Option<&AABB> {
+		if let Some(ref data) = self.data {
+			return Some(&data.aabb);
+		} else {
+			panic!("Fog was not initialized!");
+		}
+	}
+
+	fn get_name(&self) -> Option<&str> {
+		self.name.as_ref().map(|name| name.as_str())
+	}
+
+	fn set_name(&mut self, name: &str) {
+		self.name = Some(name.to_string());
+	}
+
+	fn get_type_name(&self) -> &str {
+		"fog"
+	}
+
+	fn is_volume(&self) -> bool {
+		true
+	}
+
+	fn get_volume_medium(&self) -> Option<RayTraceVolumeMedium> {
+		Some(RayTraceVolumeMedium::new(self.color.clone(), self.scattering, self.anisotropy, self.step_size,
+			self.max_steps))
+	}
+
+	fn get_volume_density(&self, ray: &RayTraceRay, distance: f64) -> f64 {
+		let position = ray.get_position_on_ray(distance);
+		match self.data {
+			Some(ref data) if data.aabb.contains(position) => self.get_density_at(position),
+			Some(_) => 0.0,
+			None => panic!("Fog was not initialized!")
+		}
+	}
+}
+
+impl RayTraceHitable for RayTraceObjectFog {
+	fn next_hit(&self, _: &RayTraceRay) -> Option<RayTraceRayHit> {
+		None
+	}
+
+	fn occludes(&self, _: &RayTraceRay, _: f64) -> bool {
+		false
+	}
+}
@@ -0,0 +1,168 @@
+use vecmath::Vector3;
+
+use aabb::AABB;
+use anim::RayTraceAnimation;
+use color::RayTraceColor;
+use color::mix_color;
+use hit::RayTraceMaterialHit;
+use hit::RayTraceRayHit;
+use material::RayTraceSimpleMaterial;
+use object::RayTraceObject;
+use object::RayTraceObjectPlane;
+use object::RayTraceSetupError;
+use object::RayTraceHitable;
+use ray::RayTraceRay;
+
+use math_util::RayTraceCoordinateSystem;
+
+// Plane with grid lines along its x and z axis, the plane itself does the placement and the hits.
+// Its material is replaced by the grid shading and only keeps the shadows cheap (no cut outs).
+#[allow(dead_code)]
+pub struct RayTraceObjectGridPlane {
+	plane: RayTraceObjectPlane,
+	fill_color: RayTraceColor,
+	minor_color: RayTraceColor,
+	major_color: RayTraceColor,
+	minor_spacing: f64,
+	major_spacing: f64,
+	line_width: f64,
+	fade_distance: f64
+}
+
+#[allow(dead_code)]
+impl RayTraceObjectGridPlane {
+	pub fn new(center: Vector3<f64>, rotation: Vector3<f64>) -> Self {
+		Self::new_with(center, rotation, 1.0, 10.0)
+	}
+
+	pub fn new_with(center: Vector3<f64>, rotation: Vector3<f64>, minor_spacing: f64, major_spacing: f64) -> Self {
+		let fill_color = RayTraceColor::new_with(0.2, 0.2, 0.2, 1.0);
+		Self {
+			plane: RayTraceObjectPlane::new(center, rotation, Box::new(RayTraceSimpleMaterial::new(fill_color.clone()))),
+			fill_color: fill_color,
+			minor_color: RayTraceColor::new_with(0.4, 0.4, 0.4, 1.0),
+			major_color: RayTraceColor::new_with(0.7, 0.7, 0.7, 1.0),
+			minor_spacing: minor_spacing,
+			major_spacing: major_spacing,
+			line_width: 0.02,
+			fade_distance: 100.0
+		}
+	}
+
+	pub fn set_rotation(&mut self, rotation: Vector3<f64>) {
+		self.plane.set_rotation(rotation);
+	}
+
+	pub fn set_position(&mut self, position: Vector3<f64>) {
+		self.plane.set_position(position);
+	}
+
+	pub fn set_colors(&mut self, fill_color: RayTraceColor, minor_color: RayTraceColor, major_color: RayTraceColor) {
+		self.fill_color = fill_color;
+		self.minor_color = minor_color;
+		self.major_color = major_color;
+	}
+
+	pub fn set_spacing(&mut self, minor_spacing: f64, major_spacing: f64) {
+		self.minor_spacing = minor_spacing;
+		self.major_spacing = major_spacing;
+	}
+
+	pub fn set_line_width(&mut self, line_width: f64) {
+		self.line_width = line_width;
+	}
+
+	pub fn get_line_width(&self) -> f64 {
+		self.line_width
+	}
+
+	pub fn set_fade_distance(&mut self, fade_distance: f64) {
+		self.fade_distance = fade_distance;
+	}
+
+	pub fn get_fade_distance(&self) -> f64 {
+		self.fade_distance
+	}
+
+	pub fn set_anim_pos_opt(&mut self, anim: Option<Box<RayTraceAnimation<Vector3<f64>>>>) {
+		self.plane.set_anim_pos_opt(anim);
+	}
+
+	pub fn set_anim_pos(&mut self, anim: Box<RayTraceAnimation<Vector3<f64>>>) {
+		self.plane.set_anim_pos(anim);
+	}
+
+	pub fn set_anim_rot_opt(&mut self, anim: Option<Box<RayTraceAnimation<Vector3<f64>>>>) {
+		self.plane.set_anim_rot_opt(anim);
+	}
+
+	pub fn set_anim_rot(&mut self, anim: Box<RayTraceAnimation<Vector3<f64>>>) {
+		self.plane.set_anim_rot(anim);
+	}
+
+	fn get_grid_color(&self, x: f64, y: f64, dist: f64) -> RayTraceColor {
+		// Lines get wider with the distance so they don't break up into single pixels
+		let width = self.line_width * (1.0 + dist / self.fade_distance * 10.0);
+		let fade = (1.0 - dist / self.fade_distance).max(0.0).min(1.0) as f32;
+
+		if is_on_line(x, y, self.major_spacing, width) {
+			return mix_color(&self.fill_color, &self.major_color, fade);
+		}
+
+		// Minor lines vanish earlier since they alias first
+		if is_on_line(x, y, self.minor_spacing, width) {
+			return mix_color(&self.fill_color, &self.minor_color, fade * fade);
+		}
+
+		self.fill_color.clone()
+	}
+}
+
+fn is_on_line(x: f64, y: f64, spacing: f64, width: f64) -> bool {
+	if spacing <= 0.0 {
+		return false;
+	}
+
+	let dist_x = (x - (x / spacing).round() * spacing).abs();
+	let dist_y = (y - (y / spacing).round() * spacing).abs();
+	dist_x <= width * 0.5 || dist_y <= width * 0.5
+}
+
+impl RayTraceObject for RayTraceObjectGridPlane {
+	fn set_coordinate_system(&mut self, system: RayTraceCoordinateSystem) {
+		self.plane.set_coordinate_system(system);
+	}
+
+	fn init(&mut self, frame: usize) -> Result<(), RayTraceSetupError> {
+		self.plane.init(frame)
+	}
+
+	fn get_aabb(&self) -> Option<&AABB> {
+		self.plane.get_aabb()
+	}
+
+	fn get_name(&self) -> Option<&str> {
+		self.plane.get_name()
+	}
+
+	fn set_name(&mut self, name: &str) {
+		self.plane.set_name(name);
+	}
+
+	fn get_type_name(&self) -> &str {
+		"grid_plane"
+	}
+}
+
+impl RayTraceHitable for RayTraceObjectGridPlane {
+	fn next_hit(&self, ray: &RayTraceRay) -> Option<RayTraceRayHit> {
+		self.plane.compute_hit(ray).map(|(dist, vec1, vec2)| {
+			let material = RayTraceMaterialHit::new_with(self.get_grid_color(vec1, vec2, dist), 0.0, 1.0, 0.0, 100.0);
+			RayTraceRayHit::new(dist, ray.get_position_on_ray(dist), self.plane.get_normal(), material)
+		})
+	}
+
+	fn occludes(&self, ray: &RayTraceRay, max_distance: f64) -> bool {
+		self.plane.occludes(ray, max_distance)
+	}
+}
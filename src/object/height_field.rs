@@ -0,0 +1,295 @@
+use std::f64;
+use std::io::Error as IOError;
+use std::io::ErrorKind;
+use std::path::Path;
+
+use vecmath::Vector3;
+use vecmath::{vec3_cross, vec3_dot, vec3_normalized, vec3_sub};
+
+use aabb::AABB;
+use anim::RayTraceAnimation;
+use hit::RayTraceRayHit;
+use material::RayTraceMaterial;
+use object::RayTraceObject;
+use object::RayTraceSetupError;
+use object::RayTraceHitable;
+use ray::RayTraceRay;
+use sink::image;
+
+// Axis aligned terrain, the grid spans the x-z-plane starting at the position and the heights go up the y-axis.
+#[allow(dead_code)]
+pub struct RayTraceObjectHeightField {
+	material: Box<RayTraceMaterial>,
+	position: Vector3<f64>,
+	size: Vector3<f64>,
+	grid_width: usize,
+	grid_depth: usize,
+	heights: Vec<f64>,
+	anim_pos: Option<Box<RayTraceAnimation<Vector3<f64>>>>,
+	anim_size: Option<Box<RayTraceAnimation<Vector3<f64>>>>,
+	name: Option<String>,
+	data: Option<WorkingData>
+}
+
+#[allow(dead_code)]
+impl RayTraceObjectHeightField {
+	// Loads the heights from the luminance of a grayscale image (black is 0.0, white is size[1])
+	pub fn new(file_name: &Path, position: Vector3<f64>, size: Vector3<f64>, material: Box<RayTraceMaterial>)
+			-> Result<Self, IOError> {
+		let image = match image::open(file_name) {
+			Ok(image) => image.to_luma(),
+			Err(err) => { return Err(IOError::new(ErrorKind::Other, format!("{}", err))); }
+		};
+
+		let (width, depth) = (image.width() as usize, image.height() as usize);
+		let heights = image.into_raw().iter().map(|v| *v as f64 / 255.0).collect();
+
+		Self::new_with_heights(width, depth, heights, position, size, material)
+	}
+
+	pub fn new_with_heights(grid_width: usize, grid_depth: usize, heights: Vec<f64>, position: Vector3<f64>,
+			size: Vector3<f64>, material: Box<RayTraceMaterial>) -> Result<Self, IOError> {
+		if grid_width < 2 || grid_depth < 2 {
+			return Err(IOError::new(ErrorKind::InvalidData, "Height field needs at least 2x2 samples"));
+		}
+		if heights.len() != grid_width * grid_depth {
+			return Err(IOError::new(ErrorKind::InvalidData, format!("Expected {} height samples but got {}",
+				grid_width * grid_depth, heights.len())));
+		}
+
+		Ok(Self {
+			material: material,
+			position: position,
+			size: size,
+			grid_width: grid_width,
+			grid_depth: grid_depth,
+			heights: heights,
+			anim_pos: None,
+			anim_size: None,
+			name: None,
+			data: None
+		})
+	}
+
+	pub fn set_position(&mut self, position: Vector3<f64>) {
+		self.position = position;
+	}
+
+	pub fn set_size(&mut self, size: Vector3<f64>) {
+		self.size = size;
+	}
+
+	pub fn set_anim_pos_opt(&mut self, anim: Option<Box<RayTraceAnimation<Vector3<f64>>>>) {
+		self.anim_pos = anim;
+	}
+
+	pub fn set_anim_pos(&mut self, anim: Box<RayTraceAnimation<Vector3<f64>>>) {
+		self.anim_pos = Some(anim);
+	}
+
+	pub fn set_anim_size_opt(&mut self, anim: Option<Box<RayTraceAnimation<Vector3<f64>>>>) {
+		self.anim_size = anim;
+	}
+
+	pub fn set_anim_size(&mut self, anim: Box<RayTraceAnimation<Vector3<f64>>>) {
+		self.anim_size = Some(anim);
+	}
+
+	fn get_point(&self, data: &WorkingData, x: usize, z: usize) -> Vector3<f64> {
+		[
+			self.position[0] + x as f64 * data.cell_size[0],
+			self.position[1] + self.heights[x + z * self.grid_width] * self.size[1],
+			self.position[2] + z as f64 * data.cell_size[1]
+		]
+	}
+
+	// Checks both triangles of a grid cell
+	fn get_cell_hit(&self, data: &WorkingData, ray: &RayTraceRay, x: usize, z: usize)
+			-> Option<(f64, Vector3<f64>, (f64, f64, f64))> {
+		let p00 = self.get_point(data, x, z);
+		let p10 = self.get_point(data, x + 1, z);
+		let p01 = self.get_point(data, x, z + 1);
+		let p11 = self.get_point(data, x + 1, z + 1);
+
+		let mut nearest: Option<(f64, Vector3<f64>, (f64, f64, f64))> = None;
+		for triangle in [[p00, p11, p10], [p00, p01, p11]].iter() {
+			if let Some((t, u, v)) = compute_triangle_hit(ray, triangle) {
+				if nearest.map_or(true, |(dist, _, _)| t < dist) {
+					let mut normal = vec3_normalized(vec3_cross(vec3_sub(triangle[1], triangle[0]),
+						vec3_sub(triangle[2], triangle[0])));
+					if normal[1] < 0.0 {
+						normal = [-normal[0], -normal[1], -normal[2]];
+					}
+
+					nearest = Some((t, normal, (1.0 - u - v, u, v)));
+				}
+			}
+		}
+
+		nearest
+	}
+}
+
+struct WorkingData {
+	aabb: AABB,
+	cell_size: [f64; 2]
+}
+
+const THRESHOLD: f64 = 1e-10;
+
+#[allow(unused_variables)]
+impl RayTraceObject for RayTraceObjectHeightField {
+	fn init(&mut self, frame: usize) -> Result<(), RayTraceSetupError> {
+		if let Some(ref anim_pos) = self.anim_pos {
+			self.position = anim_pos.next_frame(frame);
+		}
+		if let Some(ref anim_size) = self.anim_size {
+			self.size = anim_size.next_frame(frame);
+		}
+
+		self.material.init(frame);
+
+		let min_height = self.heights.iter().cloned().fold(f64::MAX, f64::min);
+		let max_height = self.heights.iter().cloned().fold(f64::MIN, f64::max);
+
+		let start = [self.position[0], self.position[1] + min_height * self.size[1], self.position[2]];
+		let end = [self.position[0] + self.size[0], self.position[1] + max_height * self.size[1],
+			self.position[2] + self.size[2]];
+
+		self.data = Some(WorkingData {
+			aabb: AABB::new(start, end),
+			cell_size: [
+				self.size[0] / (self.grid_width - 1) as f64,
+				self.size[2] / (self.grid_depth - 1) as f64
+			]
+		});
+
+		Ok(())
+	}
+
+	fn get_aabb(&self) -> Option<&AABB> {
+		if let Some(ref data) = self.data {
+			return Some(&data.aabb);
+		} else {
+			panic!("Height field was not initialized!");
+		}
+	}
+
+	fn get_name(&self) -> Option<&str> {
+		self.name.as_ref().map(|name| name.as_str())
+	}
+
+	fn set_name(&mut self, name: &str) {
+		self.name = Some(name.to_string());
+	}
+
+	fn get_type_name(&self) -> &str {
+		"height_field"
+	}
+
+	// Two triangles per grid cell
+	fn get_triangle_count(&self) -> usize {
+		2 * (self.grid_width - 1) * (self.grid_depth - 1)
+	}
+}
+
+impl RayTraceHitable for RayTraceObjectHeightField {
+	fn next_hit(&self, ray: &RayTraceRay) -> Option<RayTraceRayHit> {
+		if let Some(ref data) = self.data {
+			let (t_enter, t_exit) = match data.aabb.get_interval(ray) {
+				Some(interval) => interval,
+				None => { return None; }
+			};
+
+			let origin = ray.get_position();
+			let direction = ray.get_direction();
+			let max_cell = [self.grid_width as i64 - 2, self.grid_depth as i64 - 2];
+
+			// Walk the cells below the ray with a 2D-DDA in the x-z-plane
+			let entry = ray.get_position_on_ray(t_enter);
+			let mut cell = [0_i64; 2];
+			let mut step = [0_i64; 2];
+			let mut t_next = [f64::INFINITY; 2];
+			let mut t_delta = [f64::INFINITY; 2];
+
+			for (i, axis) in [0_usize, 2_usize].iter().enumerate() {
+				let axis = *axis;
+				let offset = (entry[axis] - self.position[axis]) / data.cell_size[i];
+				cell[i] = (offset.floor() as i64).max(0).min(max_cell[i]);
+
+				if direction[axis] > THRESHOLD {
+					step[i] = 1;
+					let boundary = self.position[axis] + (cell[i] + 1) as f64 * data.cell_size[i];
+					t_next[i] = (boundary - origin[axis]) / direction[axis];
+					t_delta[i] = data.cell_size[i] / direction[axis];
+				} else if direction[axis] < -THRESHOLD {
+					step[i] = -1;
+					let boundary = self.position[axis] + cell[i] as f64 * data.cell_size[i];
+					t_next[i] = (boundary - origin[axis]) / direction[axis];
+					t_delta[i] = -data.cell_size[i] / direction[axis];
+				}
+			}
+
+			loop {
+				if let Some((dist, normal, barycentric)) = self.get_cell_hit(data, ray, cell[0] as usize,
+						cell[1] as usize) {
+					let position = ray.get_position_on_ray(dist);
+					let material_x = position[0] - self.position[0];
+					let material_y = position[2] - self.position[2];
+
+					let mut hit = RayTraceRayHit::new_with_tangents(dist, position, normal,
+						[[1.0, 0.0, 0.0], [0.0, 0.0, 1.0]], self.material.get_hit(material_x, material_y));
+					hit.set_barycentric(barycentric);
+					return Some(hit);
+				}
+
+				let axis = if t_next[0] < t_next[1] { 0 } else { 1 };
+				if t_next[axis] > t_exit {
+					return None;
+				}
+
+				cell[axis] += step[axis];
+				t_next[axis] += t_delta[axis];
+
+				if step[axis] == 0 || cell[axis] < 0 || cell[axis] > max_cell[axis] {
+					return None;
+				}
+			}
+		} else {
+			panic!("Height field was not initialized!");
+		}
+	}
+}
+
+// Möller-Trumbore intersection, returns the distance and the weights of the second and third vertex
+fn compute_triangle_hit(ray: &RayTraceRay, triangle: &[Vector3<f64>; 3]) -> Option<(f64, f64, f64)> {
+	let direction = ray.get_direction().clone();
+	let edge1 = vec3_sub(triangle[1], triangle[0]);
+	let edge2 = vec3_sub(triangle[2], triangle[0]);
+
+	let p = vec3_cross(direction, edge2);
+	let det = vec3_dot(edge1, p);
+	if det.abs() < THRESHOLD {
+		return None;
+	}
+
+	let inv_det = 1.0 / det;
+	let s = vec3_sub(ray.get_position().clone(), triangle[0]);
+	let u = vec3_dot(s, p) * inv_det;
+	if u < 0.0 || u > 1.0 {
+		return None;
+	}
+
+	let q = vec3_cross(s, edge1);
+	let v = vec3_dot(direction, q) * inv_det;
+	if v < 0.0 || u + v > 1.0 {
+		return None;
+	}
+
+	let t = vec3_dot(edge2, q) * inv_det;
+	if t <= THRESHOLD {
+		return None;
+	}
+
+	Some((t, u, v))
+}
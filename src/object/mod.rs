@@ -1,22 +1,394 @@
+mod bezier_patch;
+mod cached;
+mod capsule;
 mod cube;
+mod fog;
+mod grid_plane;
+mod height_field;
+mod moving;
 mod plane;
+mod sdf;
 mod sphere;
+mod text;
 pub mod model;
 
+pub use self::bezier_patch::RayTraceObjectBezierPatch;
+pub use self::cached::RayTraceObjectCached;
+pub use self::capsule::RayTraceObjectCapsule;
 pub use self::cube::RayTraceObjectCube;
+pub use self::fog::RayTraceObjectFog;
+pub use self::grid_plane::RayTraceObjectGridPlane;
+pub use self::height_field::RayTraceObjectHeightField;
+pub use self::moving::RayTraceObjectMoving;
 pub use self::plane::RayTraceObjectPlane;
+pub use self::sdf::RayTraceObjectSdf;
 pub use self::sphere::RayTraceObjectSphere;
+pub use self::text::RayTraceObjectText;
 pub use self::model::RayTraceObjectModel;
 
+use std::error::Error;
+use std::fmt;
+use std::io::Error as IOError;
+use std::io::ErrorKind;
+
+use vecmath::Vector3;
+
 use aabb::AABB;
+use color::RayTraceColor;
 use hit::RayTraceRayHit;
+use math_util::RayTraceCoordinateSystem;
+use params::RayTraceParams;
 use ray::RayTraceRay;
 
+// Distance to move past a skipped hit before tracing again
+const SKIP_OFFSET: f64 = 1e-7;
+// Maximum number of clipped / cut out surfaces skipped in one object
+const MAX_SKIPPED_HITS: usize = 32;
+
+// An object that can't be set up for a frame (mesh without data, ...), the scene adds which object it was
+#[derive(Debug)]
+pub struct RayTraceSetupError {
+	message: String,
+	object: Option<String>
+}
+
+#[allow(dead_code)]
+impl RayTraceSetupError {
+	pub fn new(message: &str) -> Self {
+		Self {
+			message: message.to_string(),
+			object: None
+		}
+	}
+
+	pub fn get_message(&self) -> &str {
+		&self.message
+	}
+
+	// Index and name of the object in the scene
+	pub fn get_object(&self) -> Option<&str> {
+		self.object.as_ref().map(|object| object.as_str())
+	}
+
+	pub fn set_object(&mut self, object: &str) {
+		self.object = Some(object.to_string());
+	}
+}
+
+impl fmt::Display for RayTraceSetupError {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		match self.object {
+			Some(ref object) => write!(f, "Setting up object {} failed: {}", object, self.message),
+			None => write!(f, "Setting up an object failed: {}", self.message)
+		}
+	}
+}
+
+impl Error for RayTraceSetupError {
+	fn description(&self) -> &str {
+		&self.message
+	}
+}
+
+// Renders report setup errors as io errors of kind InvalidData with the setup error inside
+impl From<RayTraceSetupError> for IOError {
+	fn from(error: RayTraceSetupError) -> IOError {
+		IOError::new(ErrorKind::InvalidData, error)
+	}
+}
+
+// Medium of a volume (fog, ...), the scene marches the rays through it and samples the lights along the way
+#[derive(Clone, Debug)]
+pub struct RayTraceVolumeMedium {
+	color: RayTraceColor,
+	// Part of the extinction that is scattered towards the ray instead of absorbed
+	scattering: f32,
+	// Henyey-Greenstein anisotropy, above 0 scatters forward (brighter looking towards the light), below 0 back
+	anisotropy: f64,
+	step_size: f64,
+	max_steps: usize
+}
+
+#[allow(dead_code)]
+impl RayTraceVolumeMedium {
+	pub fn new(color: RayTraceColor, scattering: f32, anisotropy: f64, step_size: f64, max_steps: usize) -> Self {
+		Self {
+			color: color,
+			scattering: scattering,
+			anisotropy: anisotropy,
+			step_size: step_size,
+			max_steps: max_steps
+		}
+	}
+
+	pub fn get_color(&self) -> &RayTraceColor {
+		&self.color
+	}
+
+	pub fn get_scattering(&self) -> f32 {
+		self.scattering
+	}
+
+	pub fn get_anisotropy(&self) -> f64 {
+		self.anisotropy
+	}
+
+	pub fn get_step_size(&self) -> f64 {
+		self.step_size
+	}
+
+	pub fn get_max_steps(&self) -> usize {
+		self.max_steps
+	}
+}
+
 pub trait RayTraceObject: Sync + Send + RayTraceHitable {
-	fn init(&mut self, frame: usize);
+	// Sets the object up for the frame, objects that can't be rendered return an error instead of panicking
+	fn init(&mut self, frame: usize) -> Result<(), RayTraceSetupError>;
+
+	// Set by the scene before each prepare and init, objects without a rotation ignore it
+	#[allow(unused_variables)]
+	fn set_coordinate_system(&mut self, system: RayTraceCoordinateSystem) { }
+
 	fn get_aabb(&self) -> Option<&AABB>;
+
+	// Whether the AABB touches the surface on all sides, padded bounds let acceleration structures know
+	// that a hit of the AABB says less about a hit of the object
+	fn aabb_is_tight(&self) -> bool {
+		true
+	}
+
+	// Bounds in the object's own frame before its rotation and position are applied, None if it has none.
+	// Transform consumers get tight world bounds from it with AABB::transform.
+	fn local_bounds(&self) -> Option<AABB> {
+		None
+	}
+
+	// Whether the point lies inside the object (only valid after init), open surfaces don't contain anything
+	#[allow(unused_variables)]
+	fn contains(&self, point: Vector3<f64>) -> bool {
+		false
+	}
+
+	// Called once before the first frame for setup that doesn't change between frames (acceleration structures, ...)
+	fn prepare(&mut self) { }
+
+	fn get_name(&self) -> Option<&str> {
+		None
+	}
+
+	// Objects without name support ignore the name
+	#[allow(unused_variables)]
+	fn set_name(&mut self, name: &str) { }
+
+	// Kind of the object for statistics and debug output
+	fn get_type_name(&self) -> &str {
+		"object"
+	}
+
+	// Number of triangles of meshes, analytic surfaces have none
+	fn get_triangle_count(&self) -> usize {
+		0
+	}
+
+	// Uniformly distributed point on the surface for the uniform numbers u1 and u2 in [0, 1), with its normal and
+	// the probability density per area (one over the surface area). None for objects that can't be sampled
+	// (unbounded planes, ...). Only valid after init.
+	#[allow(unused_variables)]
+	fn sample_point(&self, u1: f64, u2: f64) -> Option<(Vector3<f64>, Vector3<f64>, f64)> {
+		None
+	}
+
+	// Volumes (fog, ...) have no surface, they change the light travelling along the rays through them instead
+	fn is_volume(&self) -> bool {
+		false
+	}
+
+	// Scattering properties of the medium inside a volume, only called for volumes
+	fn get_volume_medium(&self) -> Option<RayTraceVolumeMedium> {
+		None
+	}
+
+	// Extinction per scene unit at the distance along the ray, zero outside of the volume. Only called for
+	// volumes, the ray gives moving volumes the time to look the density up at.
+	#[allow(unused_variables)]
+	fn get_volume_density(&self, ray: &RayTraceRay, distance: f64) -> f64 {
+		0.0
+	}
 }
 
 pub trait RayTraceHitable {
 	fn next_hit(&self, ray: &RayTraceRay) -> Option<RayTraceRayHit>;
-}
\ No newline at end of file
+
+	// All hits in front of the ray origin sorted by distance (looking past clipped surfaces, the surfaces behind
+	// transparent ones, ...)
+	fn next_hits(&self, ray: &RayTraceRay) -> Vec<RayTraceRayHit> {
+		match self.next_hit(ray) {
+			Some(hit) => vec![hit],
+			None => Vec::new()
+		}
+	}
+
+	// Nearest hit that is not cut out by the material, clip planes are ignored
+	fn next_solid_hit(&self, ray: &RayTraceRay) -> Option<RayTraceRayHit> {
+		skip_cut_out_hits(ray, |ray| self.next_hit(ray))
+	}
+
+	// Nearest hit that is neither clipped away nor cut out by the material
+	fn next_visible_hit(&self, ray: &RayTraceRay, params: &RayTraceParams) -> Option<RayTraceRayHit> {
+		skip_cut_out_hits(ray, |ray| {
+			if params.get_clip_planes().is_empty() {
+				self.next_hit(ray)
+			} else {
+				// The nearest hit might be clipped away, so look further into the object (backsides, ...)
+				self.next_hits(ray).into_iter().find(|hit| !params.is_clipped(hit.get_position().clone()))
+			}
+		})
+	}
+
+	// Whether anything blocks the ray before the distance (shadow rays), clip planes are not checked
+	fn occludes(&self, ray: &RayTraceRay, max_distance: f64) -> bool {
+		occludes_by_hit(self, ray, max_distance)
+	}
+}
+
+// Full hit based occlusion test, for primitives to fall back to once their cheap test found a candidate
+pub fn occludes_by_hit<H: RayTraceHitable + ?Sized>(hitable: &H, ray: &RayTraceRay, max_distance: f64) -> bool {
+	match skip_cut_out_hits(ray, |ray| hitable.next_hit(ray)) {
+		Some(hit) => hit.get_distance() > 0.0 && hit.get_distance() < max_distance,
+		None => false
+	}
+}
+
+fn skip_cut_out_hits<F>(ray: &RayTraceRay, next_hit: F) -> Option<RayTraceRayHit>
+		where F: Fn(&RayTraceRay) -> Option<RayTraceRayHit> {
+	let mut ray = ray.derive(ray.get_position().clone(), ray.get_direction().clone());
+	let mut skipped_distance = 0.0;
+
+	for _ in 0..MAX_SKIPPED_HITS {
+		match next_hit(&ray) {
+			None => { return None; },
+			Some(mut hit) => {
+				if !hit.get_surface_material().is_cut_out() {
+					hit.set_distance(hit.get_distance() + skipped_distance);
+					return Some(hit);
+				}
+
+				// Continue behind the hole
+				let distance = hit.get_distance() + SKIP_OFFSET;
+				skipped_distance += distance;
+				ray = ray.derive(ray.get_position_on_ray(distance), ray.get_direction().clone());
+			}
+		}
+	}
+
+	None
+}
+
+#[cfg(test)]
+mod tests {
+	use vecmath::Vector3;
+	use vecmath::vec3_len;
+
+	use color::RayTraceColor;
+	use material::RayTraceSimpleMaterial;
+	use math_util::PI;
+	use texture::RayTraceFont;
+
+	use super::RayTraceObject;
+	use super::RayTraceObjectCube;
+	use super::RayTraceObjectSphere;
+	use super::RayTraceObjectText;
+
+	const EPSILON: f64 = 1e-9;
+	const GRID: usize = 64;
+
+	fn material() -> Box<RayTraceSimpleMaterial> {
+		Box::new(RayTraceSimpleMaterial::new(RayTraceColor::white()))
+	}
+
+	// Points of a stratified grid of uniform numbers and the mean of 1 / pdf, which estimates the surface area
+	fn sample_points(object: &RayTraceObject) -> (Vec<Vector3<f64>>, f64) {
+		let mut points = Vec::with_capacity(GRID * GRID);
+		let mut inverse_pdf = 0.0;
+
+		for j in 0..GRID {
+			for i in 0..GRID {
+				let u1 = (i as f64 + 0.5) / GRID as f64;
+				let u2 = (j as f64 + 0.5) / GRID as f64;
+				let (position, _, pdf) = object.sample_point(u1, u2).unwrap();
+				points.push(position);
+				inverse_pdf += 1.0 / pdf;
+			}
+		}
+
+		(points, inverse_pdf / (GRID * GRID) as f64)
+	}
+
+	fn fraction<F>(points: &Vec<Vector3<f64>>, predicate: F) -> f64 where F: Fn(&Vector3<f64>) -> bool {
+		points.iter().filter(|point| predicate(point)).count() as f64 / points.len() as f64
+	}
+
+	#[test]
+	fn sphere_samples_cover_the_area() {
+		let mut sphere = RayTraceObjectSphere::new([0.0, 0.0, 0.0], 2.0, material());
+		sphere.init(0).unwrap();
+
+		let (points, area) = sample_points(&sphere);
+		assert!((area - 4.0 * PI).abs() < EPSILON, "area {}", area);
+		assert!(points.iter().all(|point| (vec3_len(*point) - 1.0).abs() < EPSILON));
+
+		// Uniform on the surface: the cap above z = 0.5 has a quarter of the area, the upper half one half
+		assert!((fraction(&points, |point| point[2] > 0.5) - 0.25).abs() < 0.01);
+		assert!((fraction(&points, |point| point[1] > 0.0) - 0.5).abs() < 0.01);
+	}
+
+	#[test]
+	fn text_samples_cover_the_quad() {
+		// One glyph of 2 x 2 pixels and a pixel of padding around it, the quad is 4 pixels of 0.5 wide
+		let font = RayTraceFont::new_with_coverage(2, 2, vec![1.0; 4], 1, 1, 'A');
+		let mut text = RayTraceObjectText::new("A", &font, 1.0, material());
+		text.init(0).unwrap();
+
+		let (points, area) = sample_points(&text);
+		assert!((area - 4.0).abs() < EPSILON, "area {}", area);
+		assert!(points.iter().all(|point| point[0].abs() <= 1.0 && point[1].abs() <= 1.0
+			&& point[2].abs() < EPSILON));
+		assert!((fraction(&points, |point| point[0] > 0.5) - 0.25).abs() < 0.01);
+	}
+
+	#[test]
+	fn sphere_contains() {
+		let mut sphere = RayTraceObjectSphere::new([1.0, 0.0, 0.0], 2.0, material());
+		sphere.init(0).unwrap();
+
+		assert!(sphere.contains([1.0, 0.0, 0.0]));
+		assert!(sphere.contains([1.0, 0.9, 0.0]));
+		assert!(!sphere.contains([1.0, 1.1, 0.0]));
+		assert!(!sphere.contains([-0.5, 0.0, 0.0]));
+	}
+
+	#[test]
+	fn cube_contains() {
+		let mut cube = RayTraceObjectCube::new([0.0, 0.0, 0.0], [2.0, 4.0, 2.0], material());
+		cube.init(0).unwrap();
+
+		assert!(cube.contains([0.0, 0.0, 0.0]));
+		assert!(cube.contains([0.9, 1.9, -0.9]));
+		assert!(!cube.contains([0.0, 2.1, 0.0]));
+		assert!(!cube.contains([1.1, 0.0, 0.0]));
+	}
+
+	#[test]
+	fn bevelled_cube_contains() {
+		let mut cube = RayTraceObjectCube::new([0.0, 0.0, 0.0], [2.0, 2.0, 2.0], material());
+		cube.set_bevel(0.5);
+		cube.init(0).unwrap();
+
+		// The faces are flat, the corners are rounded off with a radius of 0.5 around (0.5, 0.5, 0.5)
+		assert!(cube.contains([0.95, 0.0, 0.0]));
+		assert!(cube.contains([0.75, 0.75, 0.75]));
+		assert!(!cube.contains([0.9, 0.9, 0.9]));
+		assert!(!cube.contains([0.0, 0.0, 1.05]));
+	}
+}
@@ -8,24 +8,29 @@ pub use self::obj_loader::obj_load;
 
 use std::f64;
 use std::mem;
-use std::collections::BinaryHeap;
 
 use vecmath::Vector3;
 use vecmath::Vector2;
-use vecmath::{vec3_add, vec3_mul, vec3_sub, vec3_normalized};
+use vecmath::{vec2_sub, vec3_add, vec3_cross, vec3_dot, vec3_len, vec3_mul, vec3_neg, vec3_scale, vec3_sub,
+	vec3_normalized};
 use vecmath::row_mat3_transform;
 
 use aabb::AABB;
 use anim::RayTraceAnimation;
 use hit::RayTraceHitHeapEntry;
+use hit::RayTraceMaterialHit;
 use hit::RayTraceRayHit;
 use material::RayTraceMaterial;
 use object::RayTraceObject;
+use object::RayTraceSetupError;
 use object::RayTraceHitable;
 use ray::RayTraceRay;
+use scratch::get_hit_heap;
 
-use math_util::rotate_xyz;
+use math_util::rotate_xyz_in;
+use math_util::RayTraceCoordinateSystem;
 use math_util::compute_plane_hit;
+use math_util::sample_triangle;
 
 pub struct RayTraceObjectModel {
 	material: Box<RayTraceMaterial>,
@@ -33,10 +38,12 @@ pub struct RayTraceObjectModel {
 	scale: Vector3<f64>,
 	position: Vector3<f64>,
 	rotation: Vector3<f64>,
+	coordinate_system: RayTraceCoordinateSystem,
 	offset: Vector3<f64>,
 	anim_pos: Option<Box<RayTraceAnimation<Vector3<f64>>>>,
 	anim_rot: Option<Box<RayTraceAnimation<Vector3<f64>>>>,
 	anim_scale: Option<Box<RayTraceAnimation<Vector3<f64>>>>,
+	name: Option<String>,
 	vertices: Vec<Vector3<f64>>,
 	vertex_normals: Vec<Vector3<f64>>,
 	texture_normals: Vec<Vector2<f64>>,
@@ -52,10 +59,16 @@ pub enum RayTraceModelNormalInterpolation {
 struct WorkingData {
 	aabb: Option<AABB>,
 	tree: Option<RayTraceOctree>,
-	vertex_normals: Vec<Vector3<f64>>
+	vertex_normals: Vec<Vector3<f64>>,
+	// Summed up areas of the faces up to and including each face, for sampling points
+	face_areas: Vec<f64>,
+	// Position, rotation, scale and offset the tree was built for
+	transform: [Vector3<f64>; 4],
+	coordinate_system: RayTraceCoordinateSystem
 }
 
 const AABB_MIN_DIST: Vector3<f64> = [0.001, 0.001, 0.001];
+const TEXTURE_THRESHOLD: f64 = 1e-12;
 
 impl RayTraceObjectModel {
 	pub fn set_rotation(&mut self, rotation: Vector3<f64>) {
@@ -106,12 +119,63 @@ impl RayTraceObjectModel {
 		&self.interpolation
 	}
 
+	fn get_transform(&self) -> [Vector3<f64>; 4] {
+		[self.position, self.rotation, self.scale, self.offset]
+	}
+
+	// The octree needs vertices to be built from and faces that only use existing vertices
+	fn check_faces(&self) -> Result<(), RayTraceSetupError> {
+		if self.vertices.is_empty() {
+			return Err(RayTraceSetupError::new("Model has no vertices"));
+		}
+
+		for (id, face) in self.faces.iter().enumerate() {
+			if face.iter().any(|corner| corner[0] == 0 || corner[0] > self.vertices.len()) {
+				return Err(RayTraceSetupError::new(&format!("Face {} uses a vertex that doesn't exist ({} vertices)",
+					id, self.vertices.len())));
+			}
+		}
+
+		Ok(())
+	}
+
+	fn update_data(&mut self) {
+		let transform = self.get_transform();
+		if let Some(ref data) = self.data {
+			if data.transform == transform && data.coordinate_system == self.coordinate_system {
+				return;
+			}
+		}
+
+		let mut working_data = None;
+		if self.data.is_some() {
+			mem::swap(&mut working_data, &mut self.data);
+		}
+
+		let mut data = if working_data.is_some() { working_data.unwrap() } else {
+			WorkingData {
+				aabb: None,
+				tree: None,
+				vertex_normals: Vec::new(),
+				face_areas: Vec::new(),
+				transform: transform,
+				coordinate_system: self.coordinate_system
+			}
+		};
+
+		self.transform_data(&mut data);
+		data.transform = transform;
+		data.coordinate_system = self.coordinate_system;
+		self.data = Some(data);
+	}
+
 	fn transform_data(&self, data: &mut WorkingData) {
 		// Reset stored data
 		data.aabb = None;
 		data.vertex_normals.clear();
+		data.face_areas.clear();
 
-		let rot_matrix = rotate_xyz(self.rotation);
+		let rot_matrix = rotate_xyz_in(self.rotation, self.coordinate_system);
 
 		let mut vertices = Vec::with_capacity(self.vertices.len());
 		for vert in self.vertices.iter() {
@@ -143,6 +207,10 @@ impl RayTraceObjectModel {
 			if tree.add([v1, v2, v3]) != id {
 				panic!("Wrong index in Octree!");
 			}
+
+			let area = 0.5 * vec3_len(vec3_cross(vec3_sub(v2, v1), vec3_sub(v3, v1)));
+			let total_area = data.face_areas.last().cloned().unwrap_or(0.0);
+			data.face_areas.push(total_area + area);
 		}
 
 		data.tree = Some(tree);
@@ -150,7 +218,11 @@ impl RayTraceObjectModel {
 }
 
 impl RayTraceObject for RayTraceObjectModel {
-	fn init(&mut self, frame: usize) {
+	fn set_coordinate_system(&mut self, system: RayTraceCoordinateSystem) {
+		self.coordinate_system = system;
+	}
+
+	fn init(&mut self, frame: usize) -> Result<(), RayTraceSetupError> {
 		if let Some(ref anim_pos) = self.anim_pos {
 			self.position = anim_pos.next_frame(frame);
 		}
@@ -161,21 +233,19 @@ impl RayTraceObject for RayTraceObjectModel {
 			self.scale = anim_scale.next_frame(frame);
 		}
 
-		let mut working_data = None;
-		if self.data.is_some() {
-			mem::swap(&mut working_data, &mut self.data);
-		}
+		self.material.init(frame);
+		try!(self.check_faces());
+		self.update_data();
 
-		let mut data = if working_data.is_some() { working_data.unwrap() } else {
-			WorkingData {
-				aabb: None,
-				tree: None,
-				vertex_normals: Vec::new()
-			}
-		};
+		Ok(())
+	}
 
-		self.transform_data(&mut data);
-		self.data = Some(data);
+	// Builds the octree once before rendering, frames only rebuild it if the model moved.
+	// Broken models are left to init, which reports them.
+	fn prepare(&mut self) {
+		if self.check_faces().is_ok() {
+			self.update_data();
+		}
 	}
 
 	fn get_aabb(&self) -> Option<&AABB> {
@@ -185,13 +255,54 @@ impl RayTraceObject for RayTraceObjectModel {
 			panic!("Model was not initialized!");
 		}
 	}
+
+	// Faces are picked by their area, u1 is reused for the point on the face
+	fn sample_point(&self, u1: f64, u2: f64) -> Option<(Vector3<f64>, Vector3<f64>, f64)> {
+		if let Some(ref data) = self.data {
+			let total_area = data.face_areas.last().cloned().unwrap_or(0.0);
+			if total_area <= 0.0 {
+				return None;
+			}
+
+			let target = u1 * total_area;
+			let id = match data.face_areas.binary_search_by(|area| area.partial_cmp(&target).unwrap()) {
+				Ok(id) => id + 1,
+				Err(id) => id
+			}.min(data.face_areas.len() - 1);
+
+			let start = if id == 0 { 0.0 } else { data.face_areas[id - 1] };
+			let face_u1 = ((target - start) / (data.face_areas[id] - start)).max(0.0).min(1.0);
+
+			let face = data.tree.as_ref().unwrap().get_face(id);
+			let position = sample_triangle(face_u1, u2, *face.get_position(), *face.get_vectors());
+			Some((position, *face.get_normal(), 1.0 / total_area))
+		} else {
+			panic!("Model was not initialized!");
+		}
+	}
+
+	fn get_name(&self) -> Option<&str> {
+		self.name.as_ref().map(|name| name.as_str())
+	}
+
+	fn set_name(&mut self, name: &str) {
+		self.name = Some(name.to_string());
+	}
+
+	fn get_type_name(&self) -> &str {
+		"model"
+	}
+
+	fn get_triangle_count(&self) -> usize {
+		self.faces.len()
+	}
 }
 
 impl RayTraceHitable for RayTraceObjectModel {
 	fn next_hit(&self, ray: &RayTraceRay) -> Option<RayTraceRayHit> {
 		if let Some(ref data) = self.data {
 			// Collect all ray hits
-			let mut ray_hits = BinaryHeap::<RayTraceHitHeapEntry<RayTraceRayHit>>::new();
+			let mut ray_hits = get_hit_heap();
 
 			for hit in data.tree.as_ref().unwrap().get_hits(ray) {
 				let face;
@@ -216,6 +327,16 @@ impl RayTraceHitable for RayTraceObjectModel {
 					}
 
 					let normals = face.get_normals(&self.faces, &data.vertex_normals, &self.texture_normals);
+					let position = ray.get_position_on_ray(dist);
+					let face_normal = *face.get_normal();
+
+					// The shading is filled in below from the barycentric coordinates of the hit
+					let mut hit = match compute_texture_tangents(vectors, &normals) {
+						Some(tangents) => RayTraceRayHit::new_with_tangents(dist, position, face_normal, tangents,
+							RayTraceMaterialHit::new()),
+						None => RayTraceRayHit::new(dist, position, face_normal, RayTraceMaterialHit::new())
+					};
+					hit.set_barycentric((1.0 - vec1 - vec2, vec1, vec2));
 
 					let surface_normal;
 					let texture_normal;
@@ -232,7 +353,8 @@ impl RayTraceHitable for RayTraceObjectModel {
 							];
 						},
 						RayTraceModelNormalInterpolation::Linear => {
-							let mut f = [1.0 - vec1 - vec2, vec1, vec2];
+							let (f_0, f_1, f_2) = hit.get_barycentric();
+							let mut f = [f_0, f_1, f_2];
 							let sum = f[0] + f[1] + f[2];
 							if sum != 0.0 {
 								f[0] /= sum;
@@ -255,10 +377,19 @@ impl RayTraceHitable for RayTraceObjectModel {
 						}*/
 					}
 
-					let material_hit = self.material.get_hit(texture_normal[0], texture_normal[1]);
+					// Faces collapsed by the transformation have no valid normal
+					if surface_normal.iter().any(|v| v.is_nan()) {
+						continue;
+					}
 
-					ray_hits.push(RayTraceHitHeapEntry::new(dist,
-							RayTraceRayHit::new(dist, ray.get_position_on_ray(dist), surface_normal, material_hit)));
+					hit.set_surface(surface_normal, self.material.get_hit(texture_normal[0], texture_normal[1]));
+					// The interpolated normal is only used for shading, secondary rays are offset along the face
+					hit.set_geometric_normal(if vec3_dot(face_normal, surface_normal) < 0.0 {
+						vec3_neg(face_normal)
+					} else {
+						face_normal
+					});
+					ray_hits.push(RayTraceHitHeapEntry::new(dist, hit));
 				}
 			}
 
@@ -272,4 +403,19 @@ impl RayTraceHitable for RayTraceObjectModel {
 			panic!("Model was not initialized!");
 		}
 	}
-}
\ No newline at end of file
+}
+
+// Derivatives of the position along the texture coordinates, None without texture coordinates on the face
+fn compute_texture_tangents(edges: &[Vector3<f64>; 2], normals: &[(Vector3<f64>, Vector2<f64>); 3])
+		-> Option<[Vector3<f64>; 2]> {
+	let (uv_1, uv_2) = (vec2_sub(normals[1].1, normals[0].1), vec2_sub(normals[2].1, normals[0].1));
+	let det = uv_1[0] * uv_2[1] - uv_2[0] * uv_1[1];
+	if det.abs() < TEXTURE_THRESHOLD {
+		return None;
+	}
+
+	Some([
+		vec3_scale(vec3_sub(vec3_scale(edges[0], uv_2[1]), vec3_scale(edges[1], uv_1[1])), 1.0 / det),
+		vec3_scale(vec3_sub(vec3_scale(edges[1], uv_1[0]), vec3_scale(edges[0], uv_2[0])), 1.0 / det)
+	])
+}
@@ -6,10 +6,12 @@ use std::io::ErrorKind;
 
 use vecmath::Vector2;
 use vecmath::Vector3;
+use vecmath::{vec3_cross, vec3_square_len, vec3_sub};
 
 use object::RayTraceObjectModel;
 use object::model::RayTraceModelNormalInterpolation;
 use material::RayTraceMaterial;
+use math_util::RayTraceCoordinateSystem;
 
 pub fn obj_load(file_name: &str, material: Box<RayTraceMaterial>) -> Result<RayTraceObjectModel, IOError> {
 	let mut vertices = Vec::<Vector3<f64>>::new();
@@ -141,6 +143,13 @@ pub fn obj_load(file_name: &str, material: Box<RayTraceMaterial>) -> Result<RayT
 			&format!("Face {} is not valid since some {} data is missing", face, t), 0);
 	}
 
+	// Zero-area faces would produce NaN normals
+	let face_count = faces.len();
+	faces.retain(|face| !is_degenerate(vertices[face[0][0] - 1], vertices[face[1][0] - 1], vertices[face[2][0] - 1]));
+	if faces.len() != face_count {
+		warn!("Dropped {} degenerate faces", face_count - faces.len());
+	}
+
 	Ok(
 		RayTraceObjectModel {
 			material: material,
@@ -148,10 +157,12 @@ pub fn obj_load(file_name: &str, material: Box<RayTraceMaterial>) -> Result<RayT
 			scale: [1.0, 1.0, 1.0],
 			position: [0.0, 0.0, 0.0],
 			rotation: [0.0, 0.0, 0.0],
+			coordinate_system: RayTraceCoordinateSystem::RightHandedYUp,
 			offset: [0.0, 0.0, 0.0],
 			anim_pos: None,
 			anim_rot: None,
 			anim_scale: None,
+			name: None,
 			vertices: vertices,
 			vertex_normals: vertex_normals,
 			texture_normals: texture_normals,
@@ -182,4 +193,86 @@ fn validate_model(faces: &Vec<[Vector3<usize>; 3]>, vert: usize, vert_norm: usiz
 	}
 
 	return None;
-}
\ No newline at end of file
+}
+
+fn is_degenerate(v1: Vector3<f64>, v2: Vector3<f64>, v3: Vector3<f64>) -> bool {
+	let cross = vec3_cross(vec3_sub(v2, v1), vec3_sub(v3, v1));
+	let len = vec3_square_len(cross);
+	len.is_nan() || len <= DEGENERATE_THRESHOLD
+}
+
+const DEGENERATE_THRESHOLD: f64 = 1e-24;
+
+#[cfg(test)]
+mod tests {
+	use std::env;
+	use std::fs::{self, File};
+	use std::io::Write;
+
+	use color::RayTraceColor;
+	use material::RayTraceSimpleMaterial;
+	use object::RayTraceObject;
+	use object::RayTraceObjectModel;
+
+	use super::is_degenerate;
+	use super::obj_load;
+
+	fn load_model(name: &str, content: &str) -> RayTraceObjectModel {
+		let path = env::temp_dir().join(format!("ray_tracer_{}.obj", name));
+		File::create(&path).unwrap().write_all(content.as_bytes()).unwrap();
+
+		let model = obj_load(path.to_str().unwrap(), Box::new(RayTraceSimpleMaterial::new(RayTraceColor::white())));
+		fs::remove_file(&path).unwrap();
+		model.unwrap()
+	}
+
+	fn load(name: &str, content: &str) -> usize {
+		load_model(name, content).get_triangle_count()
+	}
+
+	#[test]
+	fn degenerate_faces_are_dropped() {
+		// The second face has three points on a line, the third one repeats a vertex
+		let count = load("degenerate", "v 0 0 0\nv 1 0 0\nv 0 1 0\nv 2 0 0\n\
+			f 1 2 3\nf 1 2 4\nf 1 1 3\n");
+		assert_eq!(count, 1);
+	}
+
+	#[test]
+	fn valid_faces_are_kept() {
+		assert_eq!(load("valid", "v 0 0 0\nv 1 0 0\nv 0 1 0\nv 1 1 0\nf 1 2 3\nf 2 4 3\n"), 2);
+	}
+
+	#[test]
+	fn degenerate_triangles() {
+		assert!(is_degenerate([0.0, 0.0, 0.0], [1.0, 1.0, 1.0], [2.0, 2.0, 2.0]));
+		assert!(is_degenerate([1.0, 2.0, 3.0], [1.0, 2.0, 3.0], [0.0, 0.0, 1.0]));
+		assert!(is_degenerate([0.0, 0.0, 0.0], [1.0, 0.0, 0.0], [0.0, f64::NAN, 0.0]));
+		assert!(!is_degenerate([0.0, 0.0, 0.0], [1e-3, 0.0, 0.0], [0.0, 1e-3, 0.0]));
+	}
+
+	#[test]
+	fn samples_cover_the_model_area() {
+		// A unit square and a triangle of area 2 next to it
+		let mut model = load_model("sample", "v 0 0 0\nv 1 0 0\nv 0 1 0\nv 1 1 0\nv 3 0 0\nv 3 2 0\n\
+			f 1 2 3\nf 2 4 3\nf 2 5 6\n");
+		model.init(0).unwrap();
+
+		let grid = 64;
+		let (mut inverse_pdf, mut in_square) = (0.0, 0);
+		for j in 0..grid {
+			for i in 0..grid {
+				let (position, _, pdf) = model.sample_point((i as f64 + 0.5) / grid as f64,
+					(j as f64 + 0.5) / grid as f64).unwrap();
+				inverse_pdf += 1.0 / pdf;
+				if position[0] <= 1.0 && position[1] <= 1.0 {
+					in_square += 1;
+				}
+			}
+		}
+
+		let count = (grid * grid) as f64;
+		assert!((inverse_pdf / count - 3.0).abs() < 1e-9, "area {}", inverse_pdf / count);
+		assert!((in_square as f64 / count - 1.0 / 3.0).abs() < 0.02, "{} in the square", in_square);
+	}
+}
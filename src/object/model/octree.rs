@@ -71,7 +71,7 @@ impl<'a> RayTraceOctree {
 				None => { break; },
 				Some(node) => {
 					unsafe {
-						/*if !(*node).aabb.is_intersecting(&face.aabb) {
+						/*if !(*node).aabb.intersects(&face.aabb) {
 							continue;
 						}*/
 
@@ -118,6 +118,10 @@ impl<'a> RayTraceOctree {
 		return index;
 	}
 
+	pub fn get_face(&self, id: usize) -> &Face {
+		&self.faces[id]
+	}
+
 	pub fn get_hits<'c: 'a>(&'a self, ray: &'c RayTraceRay) -> Box<Iterator<Item = RayTraceOctreeItem<'a>> + 'a> {
 		let mut heap = BinaryHeap::new();
 		heap.push(RayTraceHitHeapEntry::new(0.0, &self.root as *const _));
@@ -176,7 +180,7 @@ impl<'a> RayTraceOctree {
 			for element in elements.iter() {
 				let face = if *element >= self.faces.len() { current_face } else { &self.faces[*element] };
 
-				/*if !node.aabb.is_intersecting(&face.aabb) {
+				/*if !node.aabb.intersects(&face.aabb) {
 					continue;
 				}*/
 
@@ -269,7 +273,7 @@ impl Face {
 		let face = faces[self.id];
 
 		let n = [face[0][1], face[1][1], face[2][1]];
-		let t = [face[0][2], face[0][2], face[0][2]];
+		let t = [face[0][2], face[1][2], face[2][2]];
 		let face_normal = vec3_normalized(vec3_cross(self.vec[0], self.vec[1]));
 
 		[
@@ -295,4 +299,8 @@ impl Face {
 	pub fn get_vectors(&self) -> &[Vector3<f64>; 2] {
 		&self.vec
 	}
+
+	pub fn get_normal(&self) -> &Vector3<f64> {
+		&self.normal
+	}
 }
@@ -0,0 +1,232 @@
+use vecmath::{Matrix3, Vector3};
+use vecmath::{vec3_add, vec3_len, vec3_scale, vec3_sub};
+use vecmath::{mat3_id, mat3_transposed, row_mat3_mul, row_mat3_transform};
+
+use aabb::AABB;
+use hit::RayTraceRayHit;
+use object::RayTraceObject;
+use object::RayTraceSetupError;
+use object::RayTraceHitable;
+use object::RayTraceVolumeMedium;
+use ray::RayTraceRay;
+
+use math_util::rotate_xyz_in;
+use math_util::RayTraceCoordinateSystem;
+
+// Moves the object with a constant velocity during the frame for motion blur. Rays are traced against the pose
+// at their time (relative to the frame, see RayTraceParams::get_shutter_interval), which avoids animations with
+// many small steps. The pose of the frame itself (time 0) is the one set up by the object's init.
+pub struct RayTraceObjectMoving {
+	object: Box<RayTraceObject>,
+	// Scene units per frame
+	linear_velocity: Vector3<f64>,
+	// Radians per frame around the x, y and z axis through the center of the object (like the rotations)
+	angular_velocity: Vector3<f64>,
+	coordinate_system: RayTraceCoordinateSystem,
+	data: Option<WorkingData>
+}
+
+struct WorkingData {
+	pivot: Vector3<f64>,
+	// Swept volume of the whole frame interval
+	aabb: Option<AABB>
+}
+
+#[allow(dead_code)]
+impl RayTraceObjectMoving {
+	pub fn new(object: Box<RayTraceObject>) -> Self {
+		Self {
+			object: object,
+			linear_velocity: [0.0, 0.0, 0.0],
+			angular_velocity: [0.0, 0.0, 0.0],
+			coordinate_system: RayTraceCoordinateSystem::RightHandedYUp,
+			data: None
+		}
+	}
+
+	pub fn set_velocity(&mut self, linear: Vector3<f64>, angular: Vector3<f64>) {
+		self.linear_velocity = linear;
+		self.angular_velocity = angular;
+	}
+
+	pub fn get_linear_velocity(&self) -> Vector3<f64> {
+		self.linear_velocity
+	}
+
+	pub fn get_angular_velocity(&self) -> Vector3<f64> {
+		self.angular_velocity
+	}
+
+	pub fn get_object(&self) -> &Box<RayTraceObject> {
+		&self.object
+	}
+
+	pub fn get_object_mut(&mut self) -> &mut Box<RayTraceObject> {
+		&mut self.object
+	}
+
+	fn is_moving(&self) -> bool {
+		self.linear_velocity != [0.0, 0.0, 0.0] || self.angular_velocity != [0.0, 0.0, 0.0]
+	}
+
+	// Rotation and translation from the pose of the frame to the pose at the time
+	fn get_pose(&self, time: f64) -> (Matrix3<f64>, Vector3<f64>) {
+		if let Some(ref data) = self.data {
+			// Turns around the axes of the coordinate system, back into it so there's no rotation at time 0
+			let rotation = row_mat3_mul(rotate_xyz_in(vec3_scale(self.angular_velocity, time), self.coordinate_system),
+				mat3_transposed(self.coordinate_system.get_matrix()));
+			let translation = vec3_add(vec3_sub(data.pivot, row_mat3_transform(rotation, data.pivot)),
+				vec3_scale(self.linear_velocity, time));
+			(rotation, translation)
+		} else {
+			panic!("Moving object was not initialized!");
+		}
+	}
+
+	// The ray in the pose of the frame, None if the object doesn't move at the time of the ray
+	fn transform_ray(&self, ray: &RayTraceRay) -> Option<(RayTraceRay, Matrix3<f64>, Vector3<f64>)> {
+		if ray.get_time() == 0.0 || !self.is_moving() {
+			return None;
+		}
+
+		let (rotation, translation) = self.get_pose(ray.get_time());
+		let inverse = mat3_transposed(rotation);
+		let position = row_mat3_transform(inverse, vec3_sub(ray.get_position().clone(), translation));
+		let direction = row_mat3_transform(inverse, ray.get_direction().clone());
+		Some((ray.derive(position, direction), rotation, translation))
+	}
+}
+
+impl RayTraceObject for RayTraceObjectMoving {
+	fn set_coordinate_system(&mut self, system: RayTraceCoordinateSystem) {
+		self.coordinate_system = system;
+		self.object.set_coordinate_system(system);
+	}
+
+	fn init(&mut self, frame: usize) -> Result<(), RayTraceSetupError> {
+		try!(self.object.init(frame));
+
+		let (pivot, aabb) = match self.object.get_aabb() {
+			Some(aabb) => {
+				let size = vec3_sub(*aabb.get_end(), *aabb.get_start());
+				let pivot = vec3_add(*aabb.get_start(), vec3_scale(size, 0.5));
+
+				// Any rotation stays within the sphere around the pivot through the corners
+				let start = if self.angular_velocity == [0.0, 0.0, 0.0] { aabb.clone() } else {
+					let radius = 0.5 * vec3_len(size);
+					AABB::new(vec3_sub(pivot, [radius; 3]), vec3_add(pivot, [radius; 3]))
+				};
+
+				// The shutter is open for at most one frame
+				let mut swept = start.clone();
+				swept.expand_aabb(&start.transform(mat3_id(), self.linear_velocity));
+				(pivot, Some(swept))
+			},
+			None => ([0.0, 0.0, 0.0], None)
+		};
+
+		self.data = Some(WorkingData {
+			pivot: pivot,
+			aabb: aabb
+		});
+
+		Ok(())
+	}
+
+	fn get_aabb(&self) -> Option<&AABB> {
+		if let Some(ref data) = self.data {
+			data.aabb.as_ref()
+		} else {
+			panic!("Moving object was not initialized!");
+		}
+	}
+
+	fn aabb_is_tight(&self) -> bool {
+		!self.is_moving() && self.object.aabb_is_tight()
+	}
+
+	// The motion has no frame of its own, so these are the bounds at the time of the frame.
+	// Only the AABB covers the whole motion during the shutter interval.
+	fn local_bounds(&self) -> Option<AABB> {
+		self.object.local_bounds()
+	}
+
+	// At the time of the frame
+	fn contains(&self, point: Vector3<f64>) -> bool {
+		self.object.contains(point)
+	}
+
+	fn prepare(&mut self) {
+		self.object.prepare();
+	}
+
+	fn get_name(&self) -> Option<&str> {
+		self.object.get_name()
+	}
+
+	fn set_name(&mut self, name: &str) {
+		self.object.set_name(name);
+	}
+
+	fn get_type_name(&self) -> &str {
+		self.object.get_type_name()
+	}
+
+	fn get_triangle_count(&self) -> usize {
+		self.object.get_triangle_count()
+	}
+
+	// In the pose of the frame
+	fn sample_point(&self, u1: f64, u2: f64) -> Option<(Vector3<f64>, Vector3<f64>, f64)> {
+		self.object.sample_point(u1, u2)
+	}
+
+	fn is_volume(&self) -> bool {
+		self.object.is_volume()
+	}
+
+	fn get_volume_medium(&self) -> Option<RayTraceVolumeMedium> {
+		self.object.get_volume_medium()
+	}
+
+	// The density moves with the volume during the shutter interval
+	fn get_volume_density(&self, ray: &RayTraceRay, distance: f64) -> f64 {
+		match self.transform_ray(ray) {
+			Some((moved_ray, _, _)) => self.object.get_volume_density(&moved_ray, distance),
+			None => self.object.get_volume_density(ray, distance)
+		}
+	}
+}
+
+impl RayTraceHitable for RayTraceObjectMoving {
+	fn next_hit(&self, ray: &RayTraceRay) -> Option<RayTraceRayHit> {
+		match self.transform_ray(ray) {
+			Some((moved_ray, rotation, translation)) => self.object.next_hit(&moved_ray).map(|mut hit| {
+				hit.transform(rotation, translation);
+				hit
+			}),
+			None => self.object.next_hit(ray)
+		}
+	}
+
+	fn next_hits(&self, ray: &RayTraceRay) -> Vec<RayTraceRayHit> {
+		match self.transform_ray(ray) {
+			Some((moved_ray, rotation, translation)) => {
+				let mut hits = self.object.next_hits(&moved_ray);
+				for hit in hits.iter_mut() {
+					hit.transform(rotation, translation);
+				}
+				hits
+			},
+			None => self.object.next_hits(ray)
+		}
+	}
+
+	// Rigid motion keeps the distances along the ray
+	fn occludes(&self, ray: &RayTraceRay, max_distance: f64) -> bool {
+		match self.transform_ray(ray) {
+			Some((moved_ray, _, _)) => self.object.occludes(&moved_ray, max_distance),
+			None => self.object.occludes(ray, max_distance)
+		}
+	}
+}
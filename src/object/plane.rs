@@ -1,5 +1,5 @@
 use vecmath::row_mat3_transform;
-use vecmath::vec3_dot;
+use vecmath::{vec3_dot, vec3_sub};
 use vecmath::Vector3;
 
 use aabb::AABB;
@@ -7,19 +7,24 @@ use anim::RayTraceAnimation;
 use hit::RayTraceRayHit;
 use material::RayTraceMaterial;
 use object::RayTraceObject;
+use object::RayTraceSetupError;
 use object::RayTraceHitable;
+use object::occludes_by_hit;
 use ray::RayTraceRay;
 
 use math_util::compute_plane_hit;
-use math_util::rotate_xyz;
+use math_util::rotate_xyz_in;
+use math_util::RayTraceCoordinateSystem;
 
 #[allow(dead_code)]
 pub struct RayTraceObjectPlane {
 	center: Vector3<f64>,
 	rotation: Vector3<f64>,
+	coordinate_system: RayTraceCoordinateSystem,
 	material: Box<RayTraceMaterial>,
 	anim_pos: Option<Box<RayTraceAnimation<Vector3<f64>>>>,
 	anim_rot: Option<Box<RayTraceAnimation<Vector3<f64>>>>,
+	name: Option<String>,
 	data: Option<WorkingData>
 }
 
@@ -29,9 +34,11 @@ impl RayTraceObjectPlane {
 		Self {
 			center: center,
 			rotation: rotation,
+			coordinate_system: RayTraceCoordinateSystem::RightHandedYUp,
 			material: material,
 			anim_rot: None,
 			anim_pos: None,
+			name: None,
 			data: None
 		}
 	}
@@ -59,6 +66,30 @@ impl RayTraceObjectPlane {
 	pub fn set_anim_rot(&mut self, anim: Box<RayTraceAnimation<Vector3<f64>>>) {
 		self.anim_rot = Some(anim);
 	}
+
+	// Distance of the hit in front of the ray and its coordinates along the x and z axis of the plane
+	pub fn compute_hit(&self, ray: &RayTraceRay) -> Option<(f64, f64, f64)> {
+		if let Some(ref data) = self.data {
+			if vec3_dot(data.plane_normal, ray.get_direction().clone()).abs() <= THRESHOLD {
+				return None;
+			}
+
+			match compute_plane_hit(ray, self.center, data.plane_vec[0], data.plane_vec[1]) {
+				Some((dist, vec1, vec2)) if dist > 0.0 => Some((dist, vec1, vec2)),
+				_ => None
+			}
+		} else {
+			panic!("Plane was not initialized!");
+		}
+	}
+
+	pub fn get_normal(&self) -> Vector3<f64> {
+		if let Some(ref data) = self.data {
+			data.plane_normal
+		} else {
+			panic!("Plane was not initialized!");
+		}
+	}
 }
 
 struct WorkingData {
@@ -70,7 +101,11 @@ const THRESHOLD: f64 = 1e-10;
 
 #[allow(unused_variables)]
 impl RayTraceObject for RayTraceObjectPlane {
-	fn init(&mut self, frame: usize) {
+	fn set_coordinate_system(&mut self, system: RayTraceCoordinateSystem) {
+		self.coordinate_system = system;
+	}
+
+	fn init(&mut self, frame: usize) -> Result<(), RayTraceSetupError> {
 		if let Some(ref anim_pos) = self.anim_pos {
 			self.center = anim_pos.next_frame(frame);
 		}
@@ -78,11 +113,13 @@ impl RayTraceObject for RayTraceObjectPlane {
 			self.rotation = anim_rot.next_frame(frame);
 		}
 
+		self.material.init(frame);
+
 		let plane_vec1 = [1.0, 0.0, 0.0];
 		let plane_vec2 = [0.0, 1.0, 0.0];
 		let plane_vec3 = [0.0, 0.0, 1.0];
 
-		let rot = rotate_xyz(self.rotation);
+		let rot = rotate_xyz_in(self.rotation, self.coordinate_system);
 
 		let vec1 = row_mat3_transform(rot, plane_vec1);
 		let vec2 = row_mat3_transform(rot, plane_vec2);
@@ -92,33 +129,49 @@ impl RayTraceObject for RayTraceObjectPlane {
 			plane_vec: [vec1, vec3],
 			plane_normal: vec2
 		});
+
+		Ok(())
 	}
 
 	fn get_aabb(&self) -> Option<&AABB> {
 		return None;
 	}
+
+	fn get_name(&self) -> Option<&str> {
+		self.name.as_ref().map(|name| name.as_str())
+	}
+
+	fn set_name(&mut self, name: &str) {
+		self.name = Some(name.to_string());
+	}
+
+	fn get_type_name(&self) -> &str {
+		"plane"
+	}
 }
 
 impl RayTraceHitable for RayTraceObjectPlane {
 	fn next_hit(&self, ray: &RayTraceRay) -> Option<RayTraceRayHit> {
+		self.compute_hit(ray).map(|(dist, vec1, vec2)| {
+			let data = self.data.as_ref().unwrap();
+			RayTraceRayHit::new_with_tangents(dist, ray.get_position_on_ray(dist), data.plane_normal, data.plane_vec,
+				self.material.get_hit(vec1, vec2))
+		})
+	}
+
+	fn occludes(&self, ray: &RayTraceRay, max_distance: f64) -> bool {
 		if let Some(ref data) = self.data {
-			if vec3_dot(data.plane_normal, ray.get_direction().clone()).abs() <= THRESHOLD {
-				return None;
+			let denominator = vec3_dot(data.plane_normal, ray.get_direction().clone());
+			if denominator.abs() <= THRESHOLD {
+				return false;
 			}
 
-			if let Some((dist, vec1, vec2)) = compute_plane_hit(ray, self.center, data.plane_vec[0],
-					data.plane_vec[1]) {
-				if dist <= 0.0 {
-					return None;
-				}
-
-				return Some(RayTraceRayHit::new(dist, ray.get_position_on_ray(dist), data.plane_normal,
-						self.material.get_hit(vec1, vec2)));
-			} else {
-				return None;
-			}
+			let dist = vec3_dot(data.plane_normal, vec3_sub(self.center, ray.get_position().clone())) / denominator;
+			// Only materials with holes need the full hit
+			dist > 0.0 && dist < max_distance
+				&& (!self.material.has_cut_outs() || occludes_by_hit(self, ray, max_distance))
 		} else {
 			panic!("Plane was not initialized!");
 		}
 	}
-}
\ No newline at end of file
+}
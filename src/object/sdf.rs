@@ -0,0 +1,186 @@
+use vecmath::Vector3;
+use vecmath::{vec3_add, vec3_sub, vec3_normalized};
+
+use aabb::AABB;
+use anim::RayTraceAnimation;
+use hit::RayTraceRayHit;
+use material::RayTraceMaterial;
+use object::RayTraceObject;
+use object::RayTraceSetupError;
+use object::RayTraceHitable;
+use ray::RayTraceRay;
+
+// Surface given by a signed distance function (relative to the position), intersected by sphere tracing.
+// The bounds have to enclose the whole surface since they can't be derived from the function.
+#[allow(dead_code)]
+pub struct RayTraceObjectSdf {
+	material: Box<RayTraceMaterial>,
+	func: Box<(Fn(Vector3<f64>) -> f64) + Sync + Send>,
+	bounds: AABB,
+	position: Vector3<f64>,
+	threshold: f64,
+	max_steps: usize,
+	anim_pos: Option<Box<RayTraceAnimation<Vector3<f64>>>>,
+	name: Option<String>,
+	data: Option<WorkingData>
+}
+
+#[allow(dead_code)]
+impl RayTraceObjectSdf {
+	pub fn new(func: Box<(Fn(Vector3<f64>) -> f64) + Sync + Send>, bounds: AABB, material: Box<RayTraceMaterial>)
+			-> Self {
+		Self {
+			material: material,
+			func: func,
+			bounds: bounds,
+			position: [0.0, 0.0, 0.0],
+			threshold: 1e-6,
+			max_steps: 256,
+			anim_pos: None,
+			name: None,
+			data: None
+		}
+	}
+
+	pub fn set_position(&mut self, position: Vector3<f64>) {
+		self.position = position;
+	}
+
+	pub fn set_bounds(&mut self, bounds: AABB) {
+		self.bounds = bounds;
+	}
+
+	pub fn set_threshold(&mut self, threshold: f64) {
+		self.threshold = threshold;
+	}
+
+	pub fn get_threshold(&self) -> f64 {
+		self.threshold
+	}
+
+	pub fn set_max_steps(&mut self, max_steps: usize) {
+		self.max_steps = max_steps;
+	}
+
+	pub fn get_max_steps(&self) -> usize {
+		self.max_steps
+	}
+
+	pub fn set_anim_pos_opt(&mut self, anim: Option<Box<RayTraceAnimation<Vector3<f64>>>>) {
+		self.anim_pos = anim;
+	}
+
+	pub fn set_anim_pos(&mut self, anim: Box<RayTraceAnimation<Vector3<f64>>>) {
+		self.anim_pos = Some(anim);
+	}
+
+	fn get_distance(&self, point: Vector3<f64>) -> f64 {
+		(self.func)(vec3_sub(point, self.position))
+	}
+
+	fn get_normal(&self, point: Vector3<f64>) -> Vector3<f64> {
+		let h = self.threshold.max(1e-6);
+		let d = |x: f64, y: f64, z: f64| self.get_distance(vec3_add(point, [x, y, z]));
+
+		vec3_normalized([
+			d(h, 0.0, 0.0) - d(-h, 0.0, 0.0),
+			d(0.0, h, 0.0) - d(0.0, -h, 0.0),
+			d(0.0, 0.0, h) - d(0.0, 0.0, -h)
+		])
+	}
+}
+
+const SURFACE_OFFSET: f64 = 10.0;
+
+struct WorkingData {
+	aabb: AABB
+}
+
+#[allow(unused_variables)]
+impl RayTraceObject for RayTraceObjectSdf {
+	fn init(&mut self, frame: usize) -> Result<(), RayTraceSetupError> {
+		if let Some(ref anim_pos) = self.anim_pos {
+			self.position = anim_pos.next_frame(frame);
+		}
+
+		self.material.init(frame);
+
+		self.data = Some(WorkingData {
+			aabb: AABB::new(vec3_add(*self.bounds.get_start(), self.position),
+				vec3_add(*self.bounds.get_end(), self.position))
+		});
+
+		Ok(())
+	}
+
+	fn get_aabb(&self) -> Option<&AABB> {
+		if let Some(ref data) = self.data {
+			return Some(&data.aabb);
+		} else {
+			panic!("SDF was not initialized!");
+		}
+	}
+
+	// The bounds are given by the user and can't be derived from the function
+	fn aabb_is_tight(&self) -> bool {
+		false
+	}
+
+	fn local_bounds(&self) -> Option<AABB> {
+		Some(self.bounds.clone())
+	}
+
+	fn contains(&self, point: Vector3<f64>) -> bool {
+		self.get_distance(point) < 0.0
+	}
+
+	fn get_name(&self) -> Option<&str> {
+		self.name.as_ref().map(|name| name.as_str())
+	}
+
+	fn set_name(&mut self, name: &str) {
+		self.name = Some(name.to_string());
+	}
+
+	fn get_type_name(&self) -> &str {
+		"sdf"
+	}
+}
+
+impl RayTraceHitable for RayTraceObjectSdf {
+	fn next_hit(&self, ray: &RayTraceRay) -> Option<RayTraceRayHit> {
+		if let Some(ref data) = self.data {
+			let (t_enter, t_exit) = match data.aabb.get_interval(ray) {
+				Some(interval) => interval,
+				None => { return None; }
+			};
+
+			// Step along the ray by the distance to the surface, which can't overshoot it
+			let mut t = t_enter;
+
+			// Rays starting on the surface (reflections, ...) must not hit it again right away
+			if self.get_distance(ray.get_position_on_ray(t)).abs() < self.threshold {
+				t += self.threshold * SURFACE_OFFSET;
+			}
+			for _ in 0..self.max_steps {
+				if t > t_exit {
+					return None;
+				}
+
+				let position = ray.get_position_on_ray(t);
+				let distance = self.get_distance(position).abs();
+				if distance < self.threshold {
+					let local = vec3_sub(position, self.position);
+					return Some(RayTraceRayHit::new(t, position, self.get_normal(position),
+						self.material.get_hit(local[0], local[1])));
+				}
+
+				t += distance;
+			}
+
+			return None;
+		} else {
+			panic!("SDF was not initialized!");
+		}
+	}
+}
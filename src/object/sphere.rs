@@ -2,20 +2,24 @@ use std::f64;
 
 use vecmath::Vector3;
 use vecmath::Matrix3;
-use vecmath::{vec3_add, vec3_sub, vec3_neg, vec3_dot, vec3_square_len, vec3_normalized_sub};
-use vecmath::row_mat3_transform;
+use vecmath::{vec3_add, vec3_scale, vec3_sub, vec3_neg, vec3_dot, vec3_square_len, vec3_normalized_sub};
+use vecmath::{mat3_transposed, row_mat3_mul, row_mat3_transform};
 
 use aabb::AABB;
 use anim::RayTraceAnimation;
 use hit::RayTraceRayHit;
 use material::RayTraceMaterial;
 use object::RayTraceObject;
+use object::RayTraceSetupError;
 use object::RayTraceHitable;
+use object::occludes_by_hit;
 use ray::RayTraceRay;
 
 use math_util::PI;
 use math_util::HALF_PI;
 use math_util::rotate_xyz;
+use math_util::RayTraceCoordinateSystem;
+use math_util::sample_sphere;
 
 #[allow(dead_code)]
 pub struct RayTraceObjectSphere {
@@ -23,9 +27,11 @@ pub struct RayTraceObjectSphere {
 	size: f64,
 	center: Vector3<f64>,
 	rotation: Vector3<f64>,
+	coordinate_system: RayTraceCoordinateSystem,
 	anim_pos: Option<Box<RayTraceAnimation<Vector3<f64>>>>,
 	anim_rot: Option<Box<RayTraceAnimation<Vector3<f64>>>>,
 	anim_size: Option<Box<RayTraceAnimation<f64>>>,
+	name: Option<String>,
 	data: Option<WorkingData>
 }
 
@@ -37,9 +43,11 @@ impl RayTraceObjectSphere {
 			center: center,
 			size: size,
 			rotation: [0.0, 0.0, 0.0],
+			coordinate_system: RayTraceCoordinateSystem::RightHandedYUp,
 			anim_rot: None,
 			anim_pos: None,
 			anim_size: None,
+			name: None,
 			data: None
 		}
 	}
@@ -83,12 +91,20 @@ impl RayTraceObjectSphere {
 
 struct WorkingData {
 	aabb: AABB,
-	rot_matrix: Matrix3<f64>
+	rot_matrix: Matrix3<f64>,
+	inv_rot_matrix: Matrix3<f64>
 }
 
+// Distance to the axis below which a hit counts as being on a pole, where the angles have no derivatives
+const POLE_THRESHOLD: f64 = 1e-10;
+
 #[allow(unused_variables)]
 impl RayTraceObject for RayTraceObjectSphere {
-	fn init(&mut self, frame: usize) {
+	fn set_coordinate_system(&mut self, system: RayTraceCoordinateSystem) {
+		self.coordinate_system = system;
+	}
+
+	fn init(&mut self, frame: usize) -> Result<(), RayTraceSetupError> {
 		if let Some(ref anim_pos) = self.anim_pos {
 			self.center = anim_pos.next_frame(frame);
 		}
@@ -99,11 +115,19 @@ impl RayTraceObject for RayTraceObjectSphere {
 			self.size = anim_size.next_frame(frame);
 		}
 
+		self.material.init(frame);
+
 		let size_vec = [self.size, self.size, self.size];
+		// Turns world normals into the texture frame, whose up axis is the one of the coordinate system
+		let rot_matrix = row_mat3_mul(rotate_xyz(vec3_neg(self.rotation)),
+			mat3_transposed(self.coordinate_system.get_matrix()));
 		self.data = Some(WorkingData {
 				aabb: AABB::new(vec3_sub(self.center, size_vec), vec3_add(self.center, size_vec)),
-				rot_matrix: rotate_xyz(vec3_neg(self.rotation))
+				rot_matrix: rot_matrix,
+				inv_rot_matrix: mat3_transposed(rot_matrix)
 			});
+
+		Ok(())
 	}
 
 	fn get_aabb(&self) -> Option<&AABB> {
@@ -113,42 +137,128 @@ impl RayTraceObject for RayTraceObjectSphere {
 			panic!("Sphere was not initialized!");
 		}
 	}
+
+	// The AABB uses the size as radius, which is twice the actual radius
+	fn aabb_is_tight(&self) -> bool {
+		false
+	}
+
+	fn contains(&self, point: Vector3<f64>) -> bool {
+		vec3_square_len(vec3_sub(point, self.center)) < self.size * self.size / 4.0
+	}
+
+	fn sample_point(&self, u1: f64, u2: f64) -> Option<(Vector3<f64>, Vector3<f64>, f64)> {
+		let radius = 0.5 * self.size;
+		let normal = sample_sphere(u1, u2);
+		Some((vec3_add(self.center, vec3_scale(normal, radius)), normal, 1.0 / (4.0 * PI * radius * radius)))
+	}
+
+	fn get_name(&self) -> Option<&str> {
+		self.name.as_ref().map(|name| name.as_str())
+	}
+
+	fn set_name(&mut self, name: &str) {
+		self.name = Some(name.to_string());
+	}
+
+	fn get_type_name(&self) -> &str {
+		"sphere"
+	}
 }
 
-impl RayTraceHitable for RayTraceObjectSphere {
-	fn next_hit(&self, ray: &RayTraceRay) -> Option<RayTraceRayHit> {
-		if let Some(ref data) = self.data {
-			let l = ray.get_direction().clone();
-			let o = ray.get_position().clone();
+impl RayTraceObjectSphere {
+	fn compute_hit_distances(&self, ray: &RayTraceRay) -> Option<(f64, f64)> {
+		let l = ray.get_direction().clone();
+		let o = ray.get_position().clone();
+
+		let dist = vec3_sub(o, self.center);
+
+		let a = vec3_square_len(l);
+		let b = vec3_dot(l, dist) * 2.0;
+		let c = vec3_square_len(dist) - self.size * self.size / 4.0;
 
-			let dist = vec3_sub(o, self.center);
+		let disc = b * b - 4.0 * a * c;
+		if disc < 0.0 {
+			return None;
+		}
+
+		let t1 = (-b - disc.sqrt()) / 2.0 / a;
+		let t2 = (-b + disc.sqrt()) / 2.0 / a;
+		Some((t1, t2))
+	}
+
+	fn make_hit(&self, data: &WorkingData, ray: &RayTraceRay, t: f64) -> RayTraceRayHit {
+		let hit_point = ray.get_position_on_ray(t);
+		let surface_normal = vec3_normalized_sub(hit_point, self.center);
+		let tex_normal = row_mat3_transform(data.rot_matrix, surface_normal);
+
+		let mut angle_t = if tex_normal[0] != 0.0 {(tex_normal[2] / tex_normal[0]).atan()} else { -HALF_PI };
+		angle_t -= HALF_PI;
+		if tex_normal[0] > 0.0 {
+			angle_t += PI;
+		}
 
-			let a = vec3_square_len(l);
-			let b = vec3_dot(l, dist) * 2.0;
-			let c = vec3_square_len(dist) - self.size * self.size / 4.0;
+		let angle_p = tex_normal[1].acos();
+		let surface = self.material.get_hit(angle_t, angle_p);
+
+		// Derivatives along both angles, they vanish at the poles
+		let ring_radius = (tex_normal[0] * tex_normal[0] + tex_normal[2] * tex_normal[2]).sqrt();
+		if ring_radius < POLE_THRESHOLD {
+			return RayTraceRayHit::new(t, hit_point, surface_normal, surface);
+		}
+
+		let radius = 0.5 * self.size;
+		let tangent_t = [-tex_normal[2] * radius, 0.0, tex_normal[0] * radius];
+		let tangent_p = vec3_scale([tex_normal[0] * tex_normal[1] / ring_radius, -ring_radius,
+			tex_normal[2] * tex_normal[1] / ring_radius], radius);
+
+		RayTraceRayHit::new_with_tangents(t, hit_point, surface_normal, [
+			row_mat3_transform(data.inv_rot_matrix, tangent_t),
+			row_mat3_transform(data.inv_rot_matrix, tangent_p)
+		], surface)
+	}
+}
 
-			let disc = b * b - 4.0 * a * c;
-			if disc < 0.0 {
+impl RayTraceHitable for RayTraceObjectSphere {
+	fn next_hit(&self, ray: &RayTraceRay) -> Option<RayTraceRayHit> {
+		if let Some(ref data) = self.data {
+			if let Some((t1, t2)) = self.compute_hit_distances(ray) {
+				let t = if t1 < 0.0 { if t2 < 0.0 { return None; } else { t2 } } else { t1 };
+				return Some(self.make_hit(data, ray, t));
+			} else {
 				return None;
 			}
+		} else {
+			panic!("Sphere was not initialized!");
+		}
+	}
 
-			let t1 = (-b - disc.sqrt()) / 2.0 / a;
-			let t2 = (-b + disc.sqrt()) / 2.0 / a;
-			let t = if t1 < 0.0 { if t2 < 0.0 { return None; } else { t2 } } else { t1 };
+	fn occludes(&self, ray: &RayTraceRay, max_distance: f64) -> bool {
+		match self.compute_hit_distances(ray) {
+			Some((t1, t2)) => {
+				let in_range = |t: f64| t > 0.0 && t < max_distance;
+				// Only materials with holes need the full hit
+				(in_range(t1) || in_range(t2))
+					&& (!self.material.has_cut_outs() || occludes_by_hit(self, ray, max_distance))
+			},
+			None => false
+		}
+	}
 
-			let hit_point = ray.get_position_on_ray(t);
-			let surface_normal = vec3_normalized_sub(hit_point, self.center);
-			let tex_normal = row_mat3_transform(data.rot_matrix, surface_normal);
+	fn next_hits(&self, ray: &RayTraceRay) -> Vec<RayTraceRayHit> {
+		if let Some(ref data) = self.data {
+			let mut hits = Vec::with_capacity(2);
 
-			let mut angle_t = if tex_normal[0] != 0.0 {(tex_normal[2] / tex_normal[0]).atan()} else { -HALF_PI };
-			angle_t -= HALF_PI;
-			if tex_normal[0] > 0.0 {
-				angle_t += PI;
+			if let Some((t1, t2)) = self.compute_hit_distances(ray) {
+				if t1 >= 0.0 {
+					hits.push(self.make_hit(data, ray, t1));
+				}
+				if t2 >= 0.0 && t2 != t1 {
+					hits.push(self.make_hit(data, ray, t2));
+				}
 			}
 
-			let angle_p = tex_normal[1].acos();
-
-			return Some(RayTraceRayHit::new(t, hit_point, surface_normal, self.material.get_hit(angle_t, angle_p)));
+			return hits;
 		} else {
 			panic!("Sphere was not initialized!");
 		}
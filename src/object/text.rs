@@ -0,0 +1,251 @@
+use vecmath::row_mat3_transform;
+use vecmath::{vec3_add, vec3_dot, vec3_neg, vec3_scale, vec3_sub};
+use vecmath::Vector3;
+
+use aabb::AABB;
+use anim::RayTraceAnimation;
+use color::RayTraceColor;
+use hit::RayTraceRayHit;
+use material::RayTraceMaterial;
+use object::RayTraceObject;
+use object::RayTraceSetupError;
+use object::RayTraceHitable;
+use object::occludes_by_hit;
+use ray::RayTraceRay;
+use texture::RayTraceFont;
+use texture::RayTraceImageTexture;
+use texture::RayTraceTexture;
+
+use math_util::compute_plane_hit;
+use math_util::rotate_xyz_in;
+use math_util::RayTraceCoordinateSystem;
+
+// Text drawn onto a quad centered at the position, readable when looking along the negative z axis before
+// the rotation. The glyphs are rendered into an alpha texture once, the rest of the quad is cut out.
+#[allow(dead_code)]
+pub struct RayTraceObjectText {
+	text: String,
+	center: Vector3<f64>,
+	rotation: Vector3<f64>,
+	coordinate_system: RayTraceCoordinateSystem,
+	// Height of one line
+	size: f64,
+	material: Box<RayTraceMaterial>,
+	alpha: RayTraceImageTexture,
+	// Size of the alpha texture in pixels and in lines
+	alpha_size: [usize; 2],
+	quad_size: [f64; 2],
+	anim_pos: Option<Box<RayTraceAnimation<Vector3<f64>>>>,
+	anim_rot: Option<Box<RayTraceAnimation<Vector3<f64>>>>,
+	name: Option<String>,
+	data: Option<WorkingData>
+}
+
+// Coverage below which the quad is cut out
+const ALPHA_CUTOFF: f32 = 0.5;
+// Transparent border around the text, so the filtering doesn't wrap glyphs around to the other side
+const PADDING: usize = 1;
+
+#[allow(dead_code)]
+impl RayTraceObjectText {
+	pub fn new(text: &str, font: &RayTraceFont, size: f64, material: Box<RayTraceMaterial>) -> Self {
+		let (glyph_width, glyph_height) = (font.get_glyph_width(), font.get_glyph_height().max(1));
+		let lines: Vec<Vec<char>> = text.lines().map(|line| line.chars().collect()).collect();
+		let columns = lines.iter().map(|line| line.len()).max().unwrap_or(0);
+
+		let width = columns * glyph_width + 2 * PADDING;
+		let height = lines.len() * glyph_height + 2 * PADDING;
+
+		let mut pixels = vec![RayTraceColor::transparent(); width * height];
+		for (row, line) in lines.iter().enumerate() {
+			for (column, c) in line.iter().enumerate() {
+				for y in 0..glyph_height {
+					for x in 0..glyph_width {
+						let p_x = PADDING + column * glyph_width + x;
+						let p_y = PADDING + row * glyph_height + y;
+						pixels[p_x + p_y * width] = RayTraceColor::new_with(1.0, 1.0, 1.0,
+							font.get_coverage(*c, x, y));
+					}
+				}
+			}
+		}
+
+		let pixel_size = size / glyph_height as f64;
+
+		Self {
+			text: text.to_string(),
+			center: [0.0, 0.0, 0.0],
+			rotation: [0.0, 0.0, 0.0],
+			coordinate_system: RayTraceCoordinateSystem::RightHandedYUp,
+			size: size,
+			material: material,
+			alpha: RayTraceImageTexture::new_with_pixels(width, height, pixels)
+				.expect("Glyph buffer does not match the texture size!"),
+			alpha_size: [width, height],
+			quad_size: [width as f64 * pixel_size, height as f64 * pixel_size],
+			anim_pos: None,
+			anim_rot: None,
+			name: None,
+			data: None
+		}
+	}
+
+	pub fn get_text(&self) -> &str {
+		&self.text
+	}
+
+	pub fn get_size(&self) -> f64 {
+		self.size
+	}
+
+	// Size of the whole quad in scene units
+	pub fn get_quad_size(&self) -> [f64; 2] {
+		self.quad_size
+	}
+
+	pub fn set_rotation(&mut self, rotation: Vector3<f64>) {
+		self.rotation = rotation;
+	}
+
+	pub fn set_position(&mut self, position: Vector3<f64>) {
+		self.center = position;
+	}
+
+	pub fn set_anim_pos_opt(&mut self, anim: Option<Box<RayTraceAnimation<Vector3<f64>>>>) {
+		self.anim_pos = anim;
+	}
+
+	pub fn set_anim_pos(&mut self, anim: Box<RayTraceAnimation<Vector3<f64>>>) {
+		self.anim_pos = Some(anim);
+	}
+
+	pub fn set_anim_rot_opt(&mut self, anim: Option<Box<RayTraceAnimation<Vector3<f64>>>>) {
+		self.anim_rot = anim;
+	}
+
+	pub fn set_anim_rot(&mut self, anim: Box<RayTraceAnimation<Vector3<f64>>>) {
+		self.anim_rot = Some(anim);
+	}
+}
+
+struct WorkingData {
+	// Right and up direction of the text
+	plane_vec: [Vector3<f64>; 2],
+	plane_normal: Vector3<f64>,
+	aabb: AABB
+}
+
+const THRESHOLD: f64 = 1e-10;
+
+#[allow(unused_variables)]
+impl RayTraceObject for RayTraceObjectText {
+	fn set_coordinate_system(&mut self, system: RayTraceCoordinateSystem) {
+		self.coordinate_system = system;
+	}
+
+	fn init(&mut self, frame: usize) -> Result<(), RayTraceSetupError> {
+		if let Some(ref anim_pos) = self.anim_pos {
+			self.center = anim_pos.next_frame(frame);
+		}
+		if let Some(ref anim_rot) = self.anim_rot {
+			self.rotation = anim_rot.next_frame(frame);
+		}
+
+		self.material.init(frame);
+
+		let rot = rotate_xyz_in(self.rotation, self.coordinate_system);
+		let right = row_mat3_transform(rot, [1.0, 0.0, 0.0]);
+		let up = row_mat3_transform(rot, [0.0, 1.0, 0.0]);
+		let normal = row_mat3_transform(rot, [0.0, 0.0, 1.0]);
+
+		let aabb = self.local_bounds().unwrap().transform(rot, self.center);
+
+		self.data = Some(WorkingData {
+			plane_vec: [right, up],
+			plane_normal: normal,
+			aabb: aabb
+		});
+
+		Ok(())
+	}
+
+	fn get_aabb(&self) -> Option<&AABB> {
+		if let Some(ref data) = self.data {
+			return Some(&data.aabb);
+		} else {
+			panic!("Text was not initialized!");
+		}
+	}
+
+	fn local_bounds(&self) -> Option<AABB> {
+		let half_size = [0.5 * self.quad_size[0], 0.5 * self.quad_size[1], 0.0];
+		Some(AABB::new(vec3_neg(half_size), half_size))
+	}
+
+	// The whole quad, the cut out parts around the glyphs included
+	fn sample_point(&self, u1: f64, u2: f64) -> Option<(Vector3<f64>, Vector3<f64>, f64)> {
+		if let Some(ref data) = self.data {
+			let position = vec3_add(self.center, vec3_add(
+				vec3_scale(data.plane_vec[0], (u1 - 0.5) * self.quad_size[0]),
+				vec3_scale(data.plane_vec[1], (u2 - 0.5) * self.quad_size[1])));
+			Some((position, data.plane_normal, 1.0 / (self.quad_size[0] * self.quad_size[1])))
+		} else {
+			panic!("Text was not initialized!");
+		}
+	}
+
+	fn get_name(&self) -> Option<&str> {
+		self.name.as_ref().map(|name| name.as_str())
+	}
+
+	fn set_name(&mut self, name: &str) {
+		self.name = Some(name.to_string());
+	}
+
+	fn get_type_name(&self) -> &str {
+		"text"
+	}
+}
+
+impl RayTraceHitable for RayTraceObjectText {
+	fn next_hit(&self, ray: &RayTraceRay) -> Option<RayTraceRayHit> {
+		if let Some(ref data) = self.data {
+			if vec3_dot(data.plane_normal, ray.get_direction().clone()).abs() <= THRESHOLD {
+				return None;
+			}
+
+			if let Some((dist, x, y)) = compute_plane_hit(ray, self.center, data.plane_vec[0], data.plane_vec[1]) {
+				if dist <= 0.0 || x.abs() > 0.5 * self.quad_size[0] || y.abs() > 0.5 * self.quad_size[1] {
+					return None;
+				}
+
+				let mut surface = self.material.get_hit(x, y);
+				let coverage = self.alpha.get_color(x / self.quad_size[0] + 0.5, 0.5 - y / self.quad_size[1]).get_a();
+				if coverage < ALPHA_CUTOFF {
+					surface.set_cut_out(true);
+				}
+
+				return Some(RayTraceRayHit::new_with_tangents(dist, ray.get_position_on_ray(dist), data.plane_normal,
+						data.plane_vec, surface));
+			} else {
+				return None;
+			}
+		} else {
+			panic!("Text was not initialized!");
+		}
+	}
+
+	fn occludes(&self, ray: &RayTraceRay, max_distance: f64) -> bool {
+		if let Some(ref data) = self.data {
+			let denominator = vec3_dot(data.plane_normal, ray.get_direction().clone());
+			if denominator.abs() <= THRESHOLD {
+				return false;
+			}
+
+			let dist = vec3_dot(data.plane_normal, vec3_sub(self.center, ray.get_position().clone())) / denominator;
+			dist > 0.0 && dist < max_distance && occludes_by_hit(self, ray, max_distance)
+		} else {
+			panic!("Text was not initialized!");
+		}
+	}
+}
@@ -0,0 +1,92 @@
+use std::f64;
+
+use aabb::AABB;
+use ray::RayTraceRay;
+
+pub const PACKET_SIZE: usize = 4;
+
+// Coherent rays (primary rays of neighbouring pixels) stored component-wise, so the slab test
+// runs over all lanes at once and can be vectorized by the compiler
+pub struct RayTracePacket {
+	origin: [[f64; PACKET_SIZE]; 3],
+	inv_direction: [[f64; PACKET_SIZE]; 3],
+	parallel: [[bool; PACKET_SIZE]; 3],
+	count: usize
+}
+
+#[allow(dead_code)]
+impl RayTracePacket {
+	pub fn new(rays: &[RayTraceRay]) -> Self {
+		assert!(rays.len() <= PACKET_SIZE, "Ray packet holds at most {} rays!", PACKET_SIZE);
+
+		let mut packet = Self {
+			origin: [[0.0; PACKET_SIZE]; 3],
+			inv_direction: [[0.0; PACKET_SIZE]; 3],
+			parallel: [[true; PACKET_SIZE]; 3],
+			count: rays.len()
+		};
+
+		for (lane, ray) in rays.iter().enumerate() {
+			let position = ray.get_position();
+			let direction = ray.get_direction();
+
+			for axis in 0..3 {
+				packet.origin[axis][lane] = position[axis];
+				packet.parallel[axis][lane] = direction[axis].abs() < 1.0e-10;
+				packet.inv_direction[axis][lane] = if packet.parallel[axis][lane] { 0.0 } else { 1.0 / direction[axis] };
+			}
+		}
+
+		packet
+	}
+
+	pub fn len(&self) -> usize {
+		self.count
+	}
+
+	// Slab test for all lanes, unused lanes never hit
+	pub fn is_hit(&self, aabb: &AABB) -> [bool; PACKET_SIZE] {
+		let entries = self.get_entries(aabb);
+
+		let mut hits = [false; PACKET_SIZE];
+		for lane in 0..PACKET_SIZE {
+			hits[lane] = entries[lane].is_some();
+		}
+
+		hits
+	}
+
+	// Distance at which each lane enters the box (0 if it starts inside), None for lanes missing it
+	pub fn get_entries(&self, aabb: &AABB) -> [Option<f64>; PACKET_SIZE] {
+		let start = aabb.get_start();
+		let end = aabb.get_end();
+
+		let mut t_min = [0.0_f64; PACKET_SIZE];
+		let mut t_max = [f64::INFINITY; PACKET_SIZE];
+		let mut inside = [true; PACKET_SIZE];
+
+		for axis in 0..3 {
+			for lane in 0..PACKET_SIZE {
+				let origin = self.origin[axis][lane];
+				let t1 = (start[axis] - origin) * self.inv_direction[axis][lane];
+				let t2 = (end[axis] - origin) * self.inv_direction[axis][lane];
+
+				if self.parallel[axis][lane] {
+					inside[lane] = inside[lane] && origin >= start[axis] && origin <= end[axis];
+				} else {
+					t_min[lane] = t_min[lane].max(t1.min(t2));
+					t_max[lane] = t_max[lane].min(t1.max(t2));
+				}
+			}
+		}
+
+		let mut entries = [None; PACKET_SIZE];
+		for lane in 0..self.count {
+			if inside[lane] && t_min[lane] <= t_max[lane] {
+				entries[lane] = Some(t_min[lane]);
+			}
+		}
+
+		entries
+	}
+}
@@ -1,20 +1,38 @@
-use rand::{Rng, thread_rng};
+use std::f64;
 use std::mem::swap;
 
+use vecmath::Vector3;
+use vecmath::{vec3_dot, vec3_sub, vec3_normalized};
+
 use color::RayTraceColor;
+use color::RayTraceColorSpace;
 use light::RayTraceShading;
+use post::RayTraceBloom;
+use post::RayTraceCubeLut;
+use random::RayTraceRng;
+use random::derive_seed;
 use sample::RayTraceSampleFilter;
+use sky::RayTraceSky;
+use texture::RayTraceTexture;
 
 pub trait RayTraceSampling {
 	fn apply(&self, x: f64, y: f64) -> (f64, f64);
 	fn get_ray_count(&self) -> usize;
+
+	// Used by the renderer, the seed is unique per frame, pixel and sample index (see
+	// RayTraceParams::get_sample_seed). Samplers that need neither only implement apply.
+	#[allow(unused_variables)]
+	fn apply_indexed(&self, x: f64, y: f64, index: usize, seed: u64) -> (f64, f64) {
+		self.apply(x, y)
+	}
 }
 
 #[allow(dead_code)]
 pub struct RayTraceOutputParams {
 	width: usize,
 	height: usize,
-	frames: usize
+	frames: usize,
+	pixel_aspect_ratio: f64
 }
 
 #[allow(dead_code)]
@@ -23,7 +41,8 @@ impl RayTraceOutputParams {
 		Self {
 			width: width,
 			height: height,
-			frames: frames
+			frames: frames,
+			pixel_aspect_ratio: 1.0
 		}
 	}
 
@@ -38,17 +57,88 @@ impl RayTraceOutputParams {
 	pub fn get_frames(&self) -> usize {
 		self.frames
 	}
+
+	// Width / height of a single pixel as it is displayed (anamorphic formats use values != 1.0)
+	pub fn get_pixel_aspect_ratio(&self) -> f64 {
+		self.pixel_aspect_ratio
+	}
+
+	pub fn set_pixel_aspect_ratio(&mut self, pixel_aspect_ratio: f64) {
+		self.pixel_aspect_ratio = pixel_aspect_ratio;
+	}
+
+	pub fn get_display_aspect_ratio(&self) -> f64 {
+		(self.width as f64) / (self.height as f64) * self.pixel_aspect_ratio
+	}
+}
+
+// Space in which the samples of a pixel are filtered.
+// Linear is physically correct: a pixel half covered by a white object on black shows linear 0.5 (0.735 in sRGB).
+// Srgb matches renderers that average encoded values: the same pixel shows sRGB 0.5 (linear 0.214),
+// which makes bright anti-aliased edges on dark backgrounds look thinner.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum RayTraceSampleAveraging {
+	Linear,
+	Srgb
+}
+
+// Near plane used for the logarithmic depth distribution if the given one is not positive
+const MIN_LOG_DEPTH: f64 = 1e-6;
+
+// How the distances between the near and far depth are spread over [0, 1]. Logarithmic keeps more precision
+// close to the camera, which suits scenes with a large depth range.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum RayTraceDepthDistribution {
+	Linear,
+	Logarithmic
+}
+
+// Compression of the high dynamic range colors into [0, 1] before the output gamma
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum RayTraceToneMapping {
+	None,
+	Reinhard
 }
 
 #[allow(dead_code)]
 pub struct RayTraceParams {
 	sampling: Option<Box<RayTraceSampling + Sync>>,
+	adaptive_tolerance: Option<f32>,
 	filter: Option<Box<RayTraceSampleFilter + Sync>>,
 	shading: Option<Box<RayTraceShading + Sync>>,
-	max_depth: usize,
+	max_reflection_depth: usize,
+	max_refraction_depth: usize,
+	ray_offset: f64,
 	background_color: RayTraceColor,
+	sky: Option<RayTraceSky>,
+	backplate: Option<Box<RayTraceTexture>>,
 	indirect_color: RayTraceColor,
-	ambient_light: RayTraceColor
+	background_behind_transparent: bool,
+	transparent_background: bool,
+	ambient_light: RayTraceColor,
+	clip_planes: Vec<(Vector3<f64>, Vector3<f64>)>,
+	shutter_angle: f64,
+	seed: u64,
+	frame_seed: u64,
+	caustic_photons: usize,
+	caustic_radius: f64,
+	clamp_indirect: f32,
+	working_color_space: RayTraceColorSpace,
+	output_color_space: RayTraceColorSpace,
+	exposure: f32,
+	output_gamma: Option<f32>,
+	tone_mapping: RayTraceToneMapping,
+	tone_map_white: Option<f32>,
+	saturation: f32,
+	color_lut: Option<RayTraceCubeLut>,
+	sample_averaging: RayTraceSampleAveraging,
+	premultiplied_alpha: bool,
+	depth_range: Option<(f64, f64)>,
+	depth_distribution: RayTraceDepthDistribution,
+	bloom: Option<RayTraceBloom>,
+	light_passes: bool,
+	debug_pixel: Option<(usize, usize)>,
+	preview_stride: usize
 }
 
 #[allow(dead_code)]
@@ -56,11 +146,41 @@ impl RayTraceParams {
 	pub fn new() -> RayTraceParams {
 		RayTraceParams {
 			sampling: None,
+			adaptive_tolerance: None,
 			filter: None,
-			max_depth: 3,
+			max_reflection_depth: 3,
+			max_refraction_depth: 3,
+			ray_offset: 1e-10,
 			background_color: RayTraceColor::transparent(),
+			sky: None,
+			backplate: None,
 			indirect_color: RayTraceColor::white(),
+			background_behind_transparent: false,
+			transparent_background: false,
 			ambient_light: RayTraceColor::white(),
+			clip_planes: Vec::new(),
+			shutter_angle: 180.0,
+			seed: 0,
+			frame_seed: derive_seed(0, &[0]),
+			caustic_photons: 0,
+			caustic_radius: 0.1,
+			clamp_indirect: 0.0,
+			working_color_space: RayTraceColorSpace::Rec709,
+			output_color_space: RayTraceColorSpace::Rec709,
+			exposure: 1.0,
+			output_gamma: None,
+			tone_mapping: RayTraceToneMapping::None,
+			tone_map_white: None,
+			saturation: 1.0,
+			color_lut: None,
+			sample_averaging: RayTraceSampleAveraging::Linear,
+			premultiplied_alpha: false,
+			depth_range: None,
+			depth_distribution: RayTraceDepthDistribution::Linear,
+			bloom: None,
+			light_passes: false,
+			debug_pixel: None,
+			preview_stride: 1,
 			shading: None
 		}
 	}
@@ -83,16 +203,57 @@ impl RayTraceParams {
 		filter
 	}
 
+	// Stops sampling a pixel once the 95% confidence interval of its mean luminance is within the tolerance
+	// (plus minus, checked after each batch of samples). The ray count of the sampling is the maximum.
+	pub fn set_adaptive_tolerance(&mut self, tolerance: f32) {
+		self.adaptive_tolerance = Some(tolerance);
+	}
+
+	pub fn set_adaptive_tolerance_opt(&mut self, tolerance: Option<f32>) {
+		self.adaptive_tolerance = tolerance;
+	}
+
+	pub fn get_adaptive_tolerance(&self) -> Option<f32> {
+		self.adaptive_tolerance
+	}
+
 	pub fn set_filter(&mut self, filter: Option<Box<RayTraceSampleFilter + Sync>>) {
 		self.filter = filter;
 	}
 
 	pub fn set_max_depth(&mut self, max_depth: usize) {
-		self.max_depth = max_depth;
+		self.max_reflection_depth = max_depth;
+		self.max_refraction_depth = max_depth;
 	}
 
 	pub fn get_max_depth(&self) -> usize {
-		self.max_depth
+		self.max_reflection_depth.max(self.max_refraction_depth)
+	}
+
+	pub fn set_max_reflection_depth(&mut self, max_depth: usize) {
+		self.max_reflection_depth = max_depth;
+	}
+
+	pub fn get_max_reflection_depth(&self) -> usize {
+		self.max_reflection_depth
+	}
+
+	pub fn set_max_refraction_depth(&mut self, max_depth: usize) {
+		self.max_refraction_depth = max_depth;
+	}
+
+	pub fn get_max_refraction_depth(&self) -> usize {
+		self.max_refraction_depth
+	}
+
+	// Distance by which secondary rays start off the surface (along its normal) so they don't hit it again,
+	// has to grow with the scale of the scene
+	pub fn set_ray_offset(&mut self, ray_offset: f64) {
+		self.ray_offset = ray_offset;
+	}
+
+	pub fn get_ray_offset(&self) -> f64 {
+		self.ray_offset
 	}
 
 	pub fn set_background_color(&mut self, color: RayTraceColor) {
@@ -103,6 +264,34 @@ impl RayTraceParams {
 		&self.background_color
 	}
 
+	// Replaces the background color with a daylight sky for rays that hit nothing
+	pub fn set_sky(&mut self, sun_direction: Vector3<f64>, turbidity: f64) {
+		self.sky = Some(RayTraceSky::new(sun_direction, turbidity));
+	}
+
+	pub fn set_sky_opt(&mut self, sky: Option<RayTraceSky>) {
+		self.sky = sky;
+	}
+
+	pub fn get_sky(&self) -> &Option<RayTraceSky> {
+		&self.sky
+	}
+
+	// Image behind the scene for camera rays that miss everything, looked up by the position on the screen
+	// ([0, 1] from the top left corner) instead of the direction. Replaces the background color and the sky
+	// for camera rays, frames with a backplate are never cached.
+	pub fn set_backplate(&mut self, backplate: Box<RayTraceTexture>) {
+		self.backplate = Some(backplate);
+	}
+
+	pub fn set_backplate_opt(&mut self, backplate: Option<Box<RayTraceTexture>>) {
+		self.backplate = backplate;
+	}
+
+	pub fn get_backplate(&self) -> &Option<Box<RayTraceTexture>> {
+		&self.backplate
+	}
+
 	pub fn set_indirect_color(&mut self, color: RayTraceColor) {
 		self.indirect_color = color;
 	}
@@ -111,6 +300,25 @@ impl RayTraceParams {
 		&self.indirect_color
 	}
 
+	// Whether refracted rays that hit nothing return the background instead of the indirect color
+	pub fn set_trace_background_behind_transparent(&mut self, trace_background: bool) {
+		self.background_behind_transparent = trace_background;
+	}
+
+	pub fn is_background_behind_transparent(&self) -> bool {
+		self.background_behind_transparent
+	}
+
+	// Matte for compositing, camera rays that hit nothing are fully transparent regardless of the background
+	// color, sky and backplate. Reflections and refractions of the geometry still see the environment.
+	pub fn set_transparent_background(&mut self, transparent_background: bool) {
+		self.transparent_background = transparent_background;
+	}
+
+	pub fn is_transparent_background(&self) -> bool {
+		self.transparent_background
+	}
+
 	pub fn set_ambient_light(&mut self, ambient_light: RayTraceColor) {
 		self.ambient_light = ambient_light;
 	}
@@ -119,6 +327,363 @@ impl RayTraceParams {
 		&self.ambient_light
 	}
 
+	// Everything behind the plane (opposite to the normal) is cut away
+	pub fn add_clip_plane(&mut self, point: Vector3<f64>, normal: Vector3<f64>) {
+		self.clip_planes.push((point, vec3_normalized(normal)));
+	}
+
+	pub fn clear_clip_planes(&mut self) {
+		self.clip_planes.clear();
+	}
+
+	pub fn get_clip_planes(&self) -> &Vec<(Vector3<f64>, Vector3<f64>)> {
+		&self.clip_planes
+	}
+
+	pub fn is_clipped(&self, position: Vector3<f64>) -> bool {
+		self.clip_planes.iter().any(|&(point, normal)| vec3_dot(vec3_sub(position, point), normal) < 0.0)
+	}
+
+	// Film convention, 360 degree keeps the shutter open for the whole frame interval
+	pub fn set_shutter_angle(&mut self, shutter_angle: f64) {
+		self.shutter_angle = shutter_angle.max(0.0).min(360.0);
+	}
+
+	pub fn get_shutter_angle(&self) -> f64 {
+		self.shutter_angle
+	}
+
+	// Fraction of the frame interval the shutter is open, starting at the frame itself
+	pub fn get_shutter_interval(&self) -> (f64, f64) {
+		(0.0, self.shutter_angle / 360.0)
+	}
+
+	// Stratified point in time for a temporal sample inside the shutter interval
+	pub fn get_sample_time(&self, frame: usize, index: usize, count: usize) -> f64 {
+		let (open, close) = self.get_shutter_interval();
+		let offset = (index as f64 + 0.5) / (count.max(1) as f64);
+		frame as f64 + open + (close - open) * offset
+	}
+
+	// Master seed of the render, every random decision (sample jitter, caustic photons, ...) derives from it and
+	// the frame number. Rendering the same scene with the same seed gives identical output on every sink,
+	// independent of the tile size, render order and thread scheduling.
+	pub fn set_seed(&mut self, seed: u64) {
+		self.seed = seed;
+		self.frame_seed = derive_seed(seed, &[0]);
+	}
+
+	pub fn get_seed(&self) -> u64 {
+		self.seed
+	}
+
+	// Called by the renderer before each frame
+	pub fn set_frame(&mut self, frame: usize) {
+		self.frame_seed = derive_seed(self.seed, &[frame as u64]);
+	}
+
+	pub fn get_frame_seed(&self) -> u64 {
+		self.frame_seed
+	}
+
+	pub fn get_sample_seed(&self, x: usize, y: usize, index: usize) -> u64 {
+		derive_seed(self.frame_seed, &[x as u64, y as u64, index as u64])
+	}
+
+	// Number of photons shot from each light to approximate caustics, 0 disables them
+	pub fn set_caustic_photons(&mut self, caustic_photons: usize) {
+		self.caustic_photons = caustic_photons;
+	}
+
+	pub fn get_caustic_photons(&self) -> usize {
+		self.caustic_photons
+	}
+
+	pub fn is_caustics_enabled(&self) -> bool {
+		self.caustic_photons > 0
+	}
+
+	// Radius around the shaded point in which the photons are gathered
+	pub fn set_caustic_radius(&mut self, caustic_radius: f64) {
+		self.caustic_radius = caustic_radius;
+	}
+
+	pub fn get_caustic_radius(&self) -> f64 {
+		self.caustic_radius
+	}
+
+	// Maximum luminance of a reflected or refracted ray's color, 0 disables the clamp.
+	// Trades a bit of energy for a lot less noise from bright indirect fireflies.
+	pub fn set_clamp_indirect(&mut self, clamp_indirect: f32) {
+		self.clamp_indirect = clamp_indirect.max(0.0);
+	}
+
+	pub fn get_clamp_indirect(&self) -> f32 {
+		self.clamp_indirect
+	}
+
+	pub fn is_indirect_clamped(&self) -> bool {
+		self.clamp_indirect > 0.0
+	}
+
+	// Color space of all scene colors
+	pub fn set_working_color_space(&mut self, color_space: RayTraceColorSpace) {
+		self.working_color_space = color_space;
+	}
+
+	pub fn get_working_color_space(&self) -> RayTraceColorSpace {
+		self.working_color_space
+	}
+
+	pub fn set_output_color_space(&mut self, color_space: RayTraceColorSpace) {
+		self.output_color_space = color_space;
+	}
+
+	pub fn get_output_color_space(&self) -> RayTraceColorSpace {
+		self.output_color_space
+	}
+
+	// Multiplier of the linear colors before the display transform, e.g. from BufferSink::compute_auto_exposure
+	pub fn set_exposure(&mut self, exposure: f32) {
+		self.exposure = exposure;
+	}
+
+	pub fn get_exposure(&self) -> f32 {
+		self.exposure
+	}
+
+	// Gamma applied to the output (e.g. 2.2 for legacy pipelines), None writes linear values
+	pub fn set_output_gamma(&mut self, gamma: Option<f32>) {
+		self.output_gamma = gamma;
+	}
+
+	pub fn get_output_gamma(&self) -> Option<f32> {
+		self.output_gamma
+	}
+
+	pub fn set_tone_mapping(&mut self, tone_mapping: RayTraceToneMapping) {
+		self.tone_mapping = tone_mapping;
+	}
+
+	pub fn get_tone_mapping(&self) -> RayTraceToneMapping {
+		self.tone_mapping
+	}
+
+	// Luminance that the Reinhard tone mapping maps to pure white, brighter colors are clipped.
+	// None is the basic Reinhard curve, which only approaches white for infinite luminance.
+	pub fn set_tone_map_white(&mut self, white: f32) {
+		self.tone_map_white = Some(white);
+	}
+
+	pub fn set_tone_map_white_opt(&mut self, white: Option<f32>) {
+		self.tone_map_white = white;
+	}
+
+	pub fn get_tone_map_white(&self) -> Option<f32> {
+		self.tone_map_white
+	}
+
+	// Color grading of the output, 0 renders black and white, 1 keeps the colors and above 1 boosts them
+	pub fn set_saturation(&mut self, saturation: f32) {
+		self.saturation = saturation;
+	}
+
+	pub fn get_saturation(&self) -> f32 {
+		self.saturation
+	}
+
+	// Grading lookup table applied last, after the tone mapping and the output gamma, so it sees the values as
+	// they are written
+	pub fn set_color_lut(&mut self, color_lut: RayTraceCubeLut) {
+		self.color_lut = Some(color_lut);
+	}
+
+	pub fn set_color_lut_opt(&mut self, color_lut: Option<RayTraceCubeLut>) {
+		self.color_lut = color_lut;
+	}
+
+	pub fn get_color_lut(&self) -> &Option<RayTraceCubeLut> {
+		&self.color_lut
+	}
+
+	pub fn set_sample_averaging(&mut self, sample_averaging: RayTraceSampleAveraging) {
+		self.sample_averaging = sample_averaging;
+	}
+
+	pub fn get_sample_averaging(&self) -> RayTraceSampleAveraging {
+		self.sample_averaging
+	}
+
+	// Straight alpha by default (png, ...), premultiplied output is for compositing software that expects it
+	pub fn set_premultiplied_alpha(&mut self, premultiplied_alpha: bool) {
+		self.premultiplied_alpha = premultiplied_alpha;
+	}
+
+	pub fn is_premultiplied_alpha(&self) -> bool {
+		self.premultiplied_alpha
+	}
+
+	// Applied to each sample color before it is accumulated.
+	// The colors are weighted by their coverage, so the edge pixels against a transparent background keep the
+	// color of the object and only the alpha falls off.
+	pub fn to_averaging_space(&self, color: RayTraceColor) -> RayTraceColor {
+		match self.sample_averaging {
+			RayTraceSampleAveraging::Linear => color,
+			RayTraceSampleAveraging::Srgb => color.to_srgb()
+		}.to_coverage_weighted()
+	}
+
+	// Applied to each filtered pixel before it is written to the sink
+	pub fn apply_output_transform(&self, color: RayTraceColor) -> RayTraceColor {
+		let color = self.from_averaging_space(color);
+		self.apply_display_transform(color)
+	}
+
+	// Filtered pixel back to linear working space
+	pub fn from_averaging_space(&self, color: RayTraceColor) -> RayTraceColor {
+		let color = color.from_coverage_weighted();
+		match self.sample_averaging {
+			RayTraceSampleAveraging::Linear => color,
+			RayTraceSampleAveraging::Srgb => color.to_linear()
+		}
+	}
+
+	// Output transform for linear colors (after post effects like bloom)
+	pub fn apply_display_transform(&self, color: RayTraceColor) -> RayTraceColor {
+		let mut color = color;
+
+		if self.exposure != 1.0 {
+			color = RayTraceColor::new_with(color.get_r() * self.exposure, color.get_g() * self.exposure,
+				color.get_b() * self.exposure, color.get_a());
+		}
+
+		if self.working_color_space != self.output_color_space {
+			color = color.convert(self.working_color_space, self.output_color_space);
+		}
+
+		// Graded before the tone mapping, which compresses the channels on its own
+		if self.saturation != 1.0 {
+			color = color.adjust_saturation(self.saturation);
+		}
+
+		if self.tone_mapping == RayTraceToneMapping::Reinhard {
+			color = color.reinhard(self.tone_map_white);
+		}
+
+		if let Some(gamma) = self.output_gamma {
+			color = color.gamma(gamma);
+		}
+
+		if let Some(ref color_lut) = self.color_lut {
+			color = color.apply_lut(color_lut);
+		}
+
+		if self.premultiplied_alpha {
+			color = color.premultiplied();
+		}
+
+		color
+	}
+
+	// Depths between near and far are written as [0, 1], everything outside is clamped (the background is 1).
+	// Without a range the raw distances are written, infinite for the background.
+	pub fn set_depth_range(&mut self, near: f64, far: f64) {
+		self.depth_range = Some((near, far));
+	}
+
+	pub fn set_depth_range_opt(&mut self, depth_range: Option<(f64, f64)>) {
+		self.depth_range = depth_range;
+	}
+
+	pub fn get_depth_range(&self) -> Option<(f64, f64)> {
+		self.depth_range
+	}
+
+	pub fn set_depth_distribution(&mut self, depth_distribution: RayTraceDepthDistribution) {
+		self.depth_distribution = depth_distribution;
+	}
+
+	pub fn get_depth_distribution(&self) -> RayTraceDepthDistribution {
+		self.depth_distribution
+	}
+
+	// Distance from the camera to the nearest hit of a pixel as written to the sink
+	pub fn map_depth(&self, depth: Option<f64>) -> f32 {
+		let (near, far) = match self.depth_range {
+			Some(range) => range,
+			None => { return depth.unwrap_or(f64::INFINITY) as f32; }
+		};
+		let depth = match depth {
+			Some(depth) => depth,
+			None => { return 1.0; }
+		};
+
+		let mapped = match self.depth_distribution {
+			RayTraceDepthDistribution::Linear => (depth - near) / (far - near),
+			RayTraceDepthDistribution::Logarithmic => {
+				// The log needs a positive near plane
+				let near = near.max(MIN_LOG_DEPTH);
+				(depth.max(near) / near).ln() / (far.max(near) / near).ln()
+			}
+		};
+
+		if mapped.is_nan() { 1.0 } else { mapped.max(0.0).min(1.0) as f32 }
+	}
+
+	// Bright parts above the luminance threshold glow, the radius is in pixels
+	pub fn set_bloom(&mut self, threshold: f32, radius: f64, intensity: f32) {
+		self.bloom = Some(RayTraceBloom::new(threshold, radius, intensity));
+	}
+
+	pub fn set_bloom_opt(&mut self, bloom: Option<RayTraceBloom>) {
+		self.bloom = bloom;
+	}
+
+	pub fn get_bloom(&self) -> &Option<RayTraceBloom> {
+		&self.bloom
+	}
+
+	// Splits the frame into an ambient pass and one pass per light, which are traced along with the frame and
+	// handed to the sink by name after it. Frames with passes are never taken from the frame cache.
+	pub fn set_light_passes_enabled(&mut self, light_passes: bool) {
+		self.light_passes = light_passes;
+	}
+
+	pub fn is_light_passes_enabled(&self) -> bool {
+		self.light_passes
+	}
+
+	// Only every nth row is rendered, the rows in between repeat the rendered row above them (fast previews)
+	pub fn set_preview_stride(&mut self, preview_stride: usize) {
+		self.preview_stride = preview_stride.max(1);
+	}
+
+	pub fn get_preview_stride(&self) -> usize {
+		self.preview_stride
+	}
+
+	// Row that is rendered in place of the given one
+	pub fn get_preview_row(&self, y: usize) -> usize {
+		y - y % self.preview_stride
+	}
+
+	// Logs every ray traced for the pixel with the objects tested, the hits and the resulting colors
+	pub fn set_debug_pixel(&mut self, x: usize, y: usize) {
+		self.debug_pixel = Some((x, y));
+	}
+
+	pub fn set_debug_pixel_opt(&mut self, debug_pixel: Option<(usize, usize)>) {
+		self.debug_pixel = debug_pixel;
+	}
+
+	pub fn get_debug_pixel(&self) -> Option<(usize, usize)> {
+		self.debug_pixel
+	}
+
+	pub fn is_debug_pixel(&self, x: usize, y: usize) -> bool {
+		self.debug_pixel == Some((x, y))
+	}
+
 	pub fn get_shading(&self) -> &Option<Box<RayTraceShading + Sync>> {
 		&self.shading
 	}
@@ -159,13 +724,127 @@ impl RayTraceRandomSampling {
 	}
 }
 
+#[allow(unused_variables)]
 impl RayTraceSampling for RayTraceRandomSampling {
 	fn get_ray_count(&self) -> usize {
 		self.ray_count
 	}
+	// The first sample with a fixed seed, so it is reproducible like the other samplings
 	fn apply(&self, x: f64, y: f64) -> (f64, f64) {
-		let mut rng = thread_rng();
+		self.apply_indexed(x, y, 0, 0)
+	}
+	fn apply_indexed(&self, x: f64, y: f64, index: usize, seed: u64) -> (f64, f64) {
+		let mut rng = RayTraceRng::new(seed);
 		(x + rng.gen_range(-1.0, 1.0) * self.size,
 		y + rng.gen_range(-1.0, 1.0) * self.size)
 	}
 }
+
+#[allow(dead_code)]
+pub struct RayTraceRotatedGridSampling {
+	grid_size: usize,
+	offsets: Vec<(f64, f64)>
+}
+
+#[allow(dead_code)]
+impl RayTraceRotatedGridSampling {
+	pub fn new() -> Self {
+		Self::new_with(2)
+	}
+
+	pub fn new_with(grid_size: usize) -> Self {
+		let grid_size = grid_size.max(1);
+		let n = grid_size as f64;
+
+		// Rotating by atan(1/n) puts every sample into its own row and column (n = 2 is the classic 4-rook pattern)
+		let angle = (1.0 / n).atan();
+		let scale = (n * n + 1.0).sqrt() / n;
+		let (sin, cos) = angle.sin_cos();
+
+		let mut offsets = Vec::with_capacity(grid_size * grid_size);
+		for j in 0..grid_size {
+			for i in 0..grid_size {
+				let u = (i as f64 + 0.5) / n - 0.5;
+				let v = (j as f64 + 0.5) / n - 0.5;
+
+				let x = (u * cos - v * sin) * scale;
+				let y = (u * sin + v * cos) * scale;
+
+				// Wrap the samples back into the pixel
+				offsets.push((x - x.round(), y - y.round()));
+			}
+		}
+
+		Self {
+			grid_size: grid_size,
+			offsets: offsets
+		}
+	}
+
+	pub fn get_grid_size(&self) -> usize {
+		self.grid_size
+	}
+
+	pub fn get_ray_count(&self) -> usize {
+		self.offsets.len()
+	}
+}
+
+#[allow(unused_variables)]
+impl RayTraceSampling for RayTraceRotatedGridSampling {
+	fn get_ray_count(&self) -> usize {
+		self.offsets.len()
+	}
+	// The first sample of the pattern
+	fn apply(&self, x: f64, y: f64) -> (f64, f64) {
+		self.apply_indexed(x, y, 0, 0)
+	}
+	fn apply_indexed(&self, x: f64, y: f64, index: usize, seed: u64) -> (f64, f64) {
+		let (offset_x, offset_y) = self.offsets[index % self.offsets.len()];
+		(x + 0.5 + offset_x, y + 0.5 + offset_y)
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use render::RayTracer;
+	use scenes;
+	use sink::BufferSink;
+	use sink::RayTraceSink;
+	use source::RayTraceSource;
+
+	use super::RayTraceRandomSampling;
+	use super::RayTraceSampling;
+
+	fn render(mut source: RayTraceSource) -> Box<[u8]> {
+		let buffer = BufferSink::new();
+		let mut sink: Box<RayTraceSink> = Box::new(buffer.clone());
+		RayTracer::new().render(&mut source, &mut sink).unwrap();
+		buffer.get_frame(0).unwrap()
+	}
+
+	#[test]
+	fn same_seed_renders_identically() {
+		// Jittered samples and caustic photons are both drawn from the seed
+		let make_source = || {
+			let mut source = scenes::reflection_test(64, 48);
+			{
+				let mut set = source.get();
+				let params = &mut set.params;
+				params.set_sampling(Some(Box::new(RayTraceRandomSampling::new_with(0.5, 4))));
+				params.set_caustic_photons(2000);
+				params.set_seed(467);
+			}
+			source
+		};
+
+		assert!(render(make_source()) == render(make_source()), "renders with the same seed differ");
+	}
+
+	#[test]
+	fn random_sampling_without_seed_is_reproducible() {
+		let sampling = RayTraceRandomSampling::new_with(0.5, 4);
+		assert_eq!(sampling.apply(10.0, 20.0), sampling.apply(10.0, 20.0));
+		assert_eq!(sampling.apply(10.0, 20.0), sampling.apply_indexed(10.0, 20.0, 0, 0));
+	}
+}
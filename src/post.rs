@@ -0,0 +1,225 @@
+use std::fs::File;
+use std::io::Error;
+use std::io::ErrorKind;
+use std::io::Read;
+use std::path::Path;
+
+use color::RayTraceColor;
+
+// Glow around bright parts of the image: everything above the luminance threshold is blurred with a
+// gaussian of the given radius (in pixels) and added back onto the linear frame.
+#[derive(Debug, Clone)]
+pub struct RayTraceBloom {
+	threshold: f32,
+	radius: f64,
+	intensity: f32
+}
+
+#[allow(dead_code)]
+impl RayTraceBloom {
+	pub fn new(threshold: f32, radius: f64, intensity: f32) -> Self {
+		Self {
+			threshold: threshold.max(0.0),
+			radius: radius.max(0.0),
+			intensity: intensity.max(0.0)
+		}
+	}
+
+	pub fn get_threshold(&self) -> f32 {
+		self.threshold
+	}
+
+	pub fn get_radius(&self) -> f64 {
+		self.radius
+	}
+
+	pub fn get_intensity(&self) -> f32 {
+		self.intensity
+	}
+
+	// Colors are the linear pixels of the frame row by row
+	pub fn apply(&self, colors: &mut [RayTraceColor], width: usize, height: usize) {
+		if self.intensity <= 0.0 || colors.is_empty() {
+			return;
+		}
+
+		// Only the part of the luminance above the threshold glows
+		let mut bright: Vec<RayTraceColor> = colors.iter().map(|color| {
+			let luminance = color.get_luminance();
+			if luminance <= self.threshold {
+				RayTraceColor::new_with(0.0, 0.0, 0.0, 0.0)
+			} else {
+				let scale = (luminance - self.threshold) / luminance;
+				RayTraceColor::new_with(color.get_r() * scale, color.get_g() * scale, color.get_b() * scale, 0.0)
+			}
+		}).collect();
+
+		let kernel = make_gauss_kernel(self.radius);
+		blur(&mut bright, width, height, &kernel, 1, width);
+		blur(&mut bright, height, width, &kernel, width, 1);
+
+		for (color, glow) in colors.iter_mut().zip(bright.iter()) {
+			let glow = glow * self.intensity;
+			// The glow also covers transparent background, otherwise it would vanish around the objects
+			let alpha = color.get_a().max(glow.get_luminance().min(1.0));
+			*color = RayTraceColor::new_with(color.get_r() + glow.get_r(), color.get_g() + glow.get_g(),
+				color.get_b() + glow.get_b(), alpha);
+		}
+	}
+}
+
+// 3D color lookup table for grading (film looks, matching other renders of a pipeline, ...), read from the
+// .cube format. The rgb channels are looked up with trilinear interpolation, colors outside of the domain are
+// clamped to it.
+#[derive(Debug, Clone)]
+pub struct RayTraceCubeLut {
+	size: usize,
+	domain_min: [f32; 3],
+	domain_max: [f32; 3],
+	// Red changes fastest, then green, then blue
+	table: Vec<[f32; 3]>
+}
+
+#[allow(dead_code)]
+impl RayTraceCubeLut {
+	pub fn load(file_name: &Path) -> Result<Self, Error> {
+		let mut text = String::new();
+		try!(try!(File::open(file_name)).read_to_string(&mut text));
+		Self::parse(&text)
+	}
+
+	pub fn parse(text: &str) -> Result<Self, Error> {
+		let mut size = 0;
+		let mut domain_min = [0.0; 3];
+		let mut domain_max = [1.0; 3];
+		let mut table = Vec::new();
+
+		for (index, line) in text.lines().enumerate() {
+			let invalid = |message: &str| Error::new(ErrorKind::InvalidData, format!("Line {}: {}", index + 1, message));
+			let parse_rgb = |values: &[&str]| -> Result<[f32; 3], Error> {
+				if values.len() != 3 {
+					return Err(invalid("Expected three values"));
+				}
+
+				let mut rgb = [0.0; 3];
+				for (channel, value) in values.iter().enumerate() {
+					rgb[channel] = try!(value.parse::<f32>().map_err(|_| invalid("Invalid number")));
+				}
+				Ok(rgb)
+			};
+
+			let line = line.trim();
+			if line.is_empty() || line.starts_with('#') {
+				continue;
+			}
+
+			let parts: Vec<&str> = line.split_whitespace().collect();
+			match parts[0] {
+				"TITLE" => { },
+				"LUT_3D_SIZE" => {
+					size = match parts.get(1).and_then(|value| value.parse::<usize>().ok()) {
+						Some(size) if size >= 2 => size,
+						_ => { return Err(invalid("The size has to be at least 2")); }
+					};
+				},
+				"LUT_1D_SIZE" => { return Err(invalid("1D lookup tables are not supported")); },
+				"DOMAIN_MIN" => { domain_min = try!(parse_rgb(&parts[1..])); },
+				"DOMAIN_MAX" => { domain_max = try!(parse_rgb(&parts[1..])); },
+				_ => {
+					if size == 0 {
+						return Err(invalid("Table entries before LUT_3D_SIZE"));
+					}
+					table.push(try!(parse_rgb(&parts)));
+				}
+			}
+		}
+
+		if size == 0 || table.len() != size * size * size {
+			return Err(Error::new(ErrorKind::InvalidData, format!("Expected {} table entries, found {}",
+				size * size * size, table.len())));
+		}
+
+		Ok(Self {
+			size: size,
+			domain_min: domain_min,
+			domain_max: domain_max,
+			table: table
+		})
+	}
+
+	pub fn get_size(&self) -> usize {
+		self.size
+	}
+
+	pub fn lookup(&self, rgb: [f32; 3]) -> [f32; 3] {
+		let mut cell = [0; 3];
+		let mut weights = [0.0; 3];
+		for channel in 0..3 {
+			let range = self.domain_max[channel] - self.domain_min[channel];
+			let position = if range > 0.0 {
+				((rgb[channel] - self.domain_min[channel]) / range).max(0.0).min(1.0) * (self.size - 1) as f32
+			} else {
+				0.0
+			};
+
+			cell[channel] = (position as usize).min(self.size - 2);
+			weights[channel] = position - cell[channel] as f32;
+		}
+
+		let mut result = [0.0; 3];
+		for corner in 0..8 {
+			let mut index = 0;
+			let mut weight = 1.0;
+			for (channel, stride) in [1, self.size, self.size * self.size].iter().enumerate() {
+				let offset = (corner >> channel) & 1;
+				index += (cell[channel] + offset) * stride;
+				weight *= if offset == 1 { weights[channel] } else { 1.0 - weights[channel] };
+			}
+
+			for channel in 0..3 {
+				result[channel] += weight * self.table[index][channel];
+			}
+		}
+
+		result
+	}
+}
+
+// Normalized weights for the offsets 0..=radius, sigma is a third of the radius
+fn make_gauss_kernel(radius: f64) -> Vec<f32> {
+	let size = radius.ceil() as usize;
+	let sigma = (radius / 3.0).max(1e-3);
+
+	let mut kernel: Vec<f32> = (0..(size + 1))
+		.map(|offset| (-((offset * offset) as f64) / (2.0 * sigma * sigma)).exp() as f32)
+		.collect();
+
+	let sum = kernel[0] + 2.0 * kernel[1..].iter().sum::<f32>();
+	for weight in kernel.iter_mut() {
+		*weight /= sum;
+	}
+	kernel
+}
+
+// Blurs each of the lines along one axis, pixels beyond the edge repeat the border pixel
+fn blur(colors: &mut Vec<RayTraceColor>, length: usize, lines: usize, kernel: &[f32], step: usize,
+		line_step: usize) {
+	let mut line = Vec::with_capacity(length);
+
+	for l in 0..lines {
+		let start = l * line_step;
+		line.clear();
+		line.extend((0..length).map(|i| colors[start + i * step].clone()));
+
+		for i in 0..length {
+			let mut sum = &line[i] * kernel[0];
+			for (offset, weight) in kernel.iter().enumerate().skip(1) {
+				let before = if offset > i { 0 } else { i - offset };
+				let after = (i + offset).min(length - 1);
+				sum += &line[before] * *weight;
+				sum += &line[after] * *weight;
+			}
+			colors[start + i * step] = sum;
+		}
+	}
+}
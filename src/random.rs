@@ -0,0 +1,76 @@
+use vecmath::Vector3;
+
+// Small deterministic generator (SplitMix64), so renders can be reproduced from a seed
+// independent of the rand version and of the thread a tile is rendered on
+#[derive(Debug, Clone)]
+pub struct RayTraceRng {
+	state: u64
+}
+
+#[allow(dead_code)]
+impl RayTraceRng {
+	pub fn new(seed: u64) -> Self {
+		Self {
+			state: seed
+		}
+	}
+
+	pub fn next_u64(&mut self) -> u64 {
+		self.state = self.state.wrapping_add(0x9E37_79B9_7F4A_7C15);
+		mix(self.state)
+	}
+
+	// Uniform in [0, 1)
+	pub fn next_f64(&mut self) -> f64 {
+		(self.next_u64() >> 11) as f64 / (1_u64 << 53) as f64
+	}
+
+	// Uniform in [low, high)
+	pub fn gen_range(&mut self, low: f64, high: f64) -> f64 {
+		low + (high - low) * self.next_f64()
+	}
+}
+
+// Combines a seed with further values (frame, pixel, sample index, ...) into a new seed
+pub fn derive_seed(seed: u64, values: &[u64]) -> u64 {
+	let mut result = mix(seed);
+	for value in values.iter() {
+		result = mix(result ^ mix(value.wrapping_add(0x9E37_79B9_7F4A_7C15)));
+	}
+
+	result
+}
+
+// Smooth value noise in [0, 1) with features about one unit apart, the values at the lattice points follow
+// from the seed
+pub fn value_noise(point: Vector3<f64>, seed: u64) -> f64 {
+	let cell = [point[0].floor(), point[1].floor(), point[2].floor()];
+	let mut weights = [0.0; 3];
+	for axis in 0..3 {
+		let t = point[axis] - cell[axis];
+		weights[axis] = t * t * (3.0 - 2.0 * t);
+	}
+
+	let mut result = 0.0;
+	for corner in 0..8 {
+		let mut lattice = [0; 3];
+		let mut weight = 1.0;
+		for axis in 0..3 {
+			let offset = (corner >> axis) & 1;
+			lattice[axis] = (cell[axis] as i64 + offset as i64) as u64;
+			weight *= if offset == 1 { weights[axis] } else { 1.0 - weights[axis] };
+		}
+
+		let value = (derive_seed(seed, &lattice) >> 11) as f64 / (1_u64 << 53) as f64;
+		result += weight * value;
+	}
+
+	result
+}
+
+fn mix(value: u64) -> u64 {
+	let mut z = value;
+	z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+	z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+	z ^ (z >> 31)
+}
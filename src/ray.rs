@@ -7,15 +7,23 @@ use vecmath::vec3_normalized;
 #[allow(dead_code)]
 pub struct RayTraceRay {
 	position: Vector3<f64>,
-	direction: Vector3<f64>
+	direction: Vector3<f64>,
+	// Point in time relative to the frame (motion blur) and wavelength in nm (dispersion),
+	// secondary rays inherit both from the ray they were spawned by
+	time: f64,
+	wavelength: Option<f64>
 }
 
 #[allow(dead_code)]
 impl RayTraceRay {
 	pub fn new(position: Vector3<f64>, direction: Vector3<f64>) -> RayTraceRay {
+		// The intersection code returns distances in multiples of the direction,
+		// so keep it unit length to get world-space distances
 		RayTraceRay {
 			position: position,
-			direction: direction
+			direction: vec3_normalized(direction),
+			time: 0.0,
+			wavelength: None
 		}
 	}
 
@@ -23,10 +31,36 @@ impl RayTraceRay {
 		let direction = vec3_sub(to, position);
 		Self {
 			position: position,
-			direction: vec3_normalized(direction)
+			direction: vec3_normalized(direction),
+			time: 0.0,
+			wavelength: None
 		}
 	}
 
+	// New ray that keeps the time and wavelength of this one (reflections, shadow rays, ...)
+	pub fn derive(&self, position: Vector3<f64>, direction: Vector3<f64>) -> Self {
+		let mut ray = Self::new(position, direction);
+		ray.time = self.time;
+		ray.wavelength = self.wavelength;
+		ray
+	}
+
+	pub fn set_time(&mut self, time: f64) {
+		self.time = time;
+	}
+
+	pub fn get_time(&self) -> f64 {
+		self.time
+	}
+
+	pub fn set_wavelength(&mut self, wavelength: Option<f64>) {
+		self.wavelength = wavelength;
+	}
+
+	pub fn get_wavelength(&self) -> Option<f64> {
+		self.wavelength
+	}
+
 	pub fn get_position(&self) -> &Vector3<f64> {
 		&self.position
 	}
@@ -39,3 +73,67 @@ impl RayTraceRay {
 		vec3_add(self.position, vec3_scale(self.direction, distance))
 	}
 }
+
+#[cfg(test)]
+mod tests {
+	use color::RayTraceColor;
+	use material::RayTraceSimpleMaterial;
+	use object::RayTraceHitable;
+	use object::RayTraceObject;
+	use object::RayTraceObjectPlane;
+	use object::RayTraceObjectSphere;
+
+	use super::RayTraceRay;
+
+	const EPSILON: f64 = 1e-9;
+
+	fn material() -> Box<RayTraceSimpleMaterial> {
+		Box::new(RayTraceSimpleMaterial::new(RayTraceColor::white()))
+	}
+
+	#[test]
+	fn direction_is_normalized() {
+		let ray = RayTraceRay::new([0.0, 0.0, 0.0], [3.0, 0.0, 4.0]);
+		assert!((ray.get_direction()[0] - 0.6).abs() < EPSILON);
+		assert!((ray.get_direction()[2] - 0.8).abs() < EPSILON);
+
+		let ray = RayTraceRay::new([1.0, 1.0, 1.0], [0.0, -0.001, 0.0]);
+		assert!((ray.get_direction()[1] + 1.0).abs() < EPSILON);
+	}
+
+	#[test]
+	fn sphere_hit_distance_is_world_space() {
+		// Radius 1 at the origin, the surface is 9 units in front of the ray
+		let mut sphere = RayTraceObjectSphere::new([0.0, 0.0, 0.0], 2.0, material());
+		sphere.init(0).unwrap();
+
+		for &length in [0.25, 1.0, 5.0, 100.0].iter() {
+			let ray = RayTraceRay::new([0.0, 0.0, 10.0], [0.0, 0.0, -length]);
+			let hit = sphere.next_hit(&ray).unwrap();
+			assert!((hit.get_distance() - 9.0).abs() < EPSILON, "length {}: {}", length, hit.get_distance());
+		}
+	}
+
+	#[test]
+	fn plane_hit_distance_is_world_space() {
+		// The plane is y = 0, the ray goes down diagonally and travels 5 units from y = 3 (3-4-5 triangle)
+		let mut plane = RayTraceObjectPlane::new([0.0, 0.0, 0.0], [0.0, 0.0, 0.0], material());
+		plane.init(0).unwrap();
+
+		for &length in [0.5, 1.0, 20.0].iter() {
+			let ray = RayTraceRay::new([0.0, 3.0, 0.0], [4.0 * length, -3.0 * length, 0.0]);
+			let hit = plane.next_hit(&ray).unwrap();
+			assert!((hit.get_distance() - 5.0).abs() < EPSILON, "length {}: {}", length, hit.get_distance());
+		}
+	}
+
+	#[test]
+	fn derived_ray_is_normalized() {
+		let mut ray = RayTraceRay::new([0.0, 0.0, 0.0], [0.0, 0.0, 1.0]);
+		ray.set_time(0.5);
+		let derived = ray.derive([1.0, 0.0, 0.0], [0.0, 7.0, 0.0]);
+		assert!((derived.get_direction()[1] - 1.0).abs() < EPSILON);
+		assert_eq!(derived.get_time(), 0.5);
+		assert!((derived.get_position_on_ray(2.0)[1] - 2.0).abs() < EPSILON);
+	}
+}
@@ -1,18 +1,35 @@
-use std::collections::BinaryHeap;
+use std::cell::Cell;
+use std::error::Error;
 use std::f64;
+use std::fmt;
 use std::io::Error as IOError;
+use std::io::ErrorKind;
 use std::sync::{Arc};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::channel;
+use std::sync::mpsc::Sender;
 
 use time;
 
+use vecmath::{vec3_dot, vec3_len, vec3_sub, row_mat3_transform};
+
 use scoped_threadpool::Pool;
 
+use cache::RayTraceFrame;
+use cache::RayTraceFrameCache;
+use cache::make_frame_key;
 use camera::RayTraceCamera;
+use color::BLACK_THRESHOLD;
 use color::RayTraceColor;
 use color::mix_color;
-use hit::RayTraceHitHeapEntry;
+use color::mix_color_keep_alpha;
+use hit::RayTraceMaterialHit;
 use hit::RayTraceRayHit;
+use light::RayTraceCausticMap;
+use light::RayTraceLightPasses;
+use object::RayTraceSetupError;
 //use octree::RayTraceOctree;
+use params::RayTraceOutputParams;
 use params::RayTraceParams;
 use ray::RayTraceRay;
 use sample::RayTraceSample;
@@ -22,36 +39,565 @@ use scene::RayTraceScene;
 use source::RayTraceSource;
 use source::RayTraceSourceSet;
 use math_util::compute_reflected_ray;
+use math_util::compute_refracted_ray_between;
+use math_util::compute_fresnel_schlick;
+#[cfg(feature = "ray_packets")]
+use packet::PACKET_SIZE;
+
+thread_local! {
+	// Number of rays traced by this thread
+	static RAY_COUNT: Cell<usize> = Cell::new(0);
+}
+
+fn count_ray() {
+	RAY_COUNT.with(|count| count.set(count.get() + 1));
+}
+
+fn get_ray_count() -> usize {
+	RAY_COUNT.with(|count| count.get())
+}
+
+pub struct RayTracer {
+	tile_size: usize,
+	render_order: RayTraceRenderOrder,
+	budget_fallback_color: RayTraceColor,
+	frame_cache: Option<RayTraceFrameCache>
+}
+
+// A render that failed part way, the frames before the failing one were completely written to the sink
+// (a buffer sink still holds them)
+#[derive(Debug)]
+pub struct RayTraceRenderError {
+	error: IOError,
+	completed_frames: usize
+}
+
+#[allow(dead_code)]
+impl RayTraceRenderError {
+	pub fn new(error: IOError, completed_frames: usize) -> Self {
+		Self {
+			error: error,
+			completed_frames: completed_frames
+		}
+	}
+
+	pub fn get_error(&self) -> &IOError {
+		&self.error
+	}
+
+	pub fn into_error(self) -> IOError {
+		self.error
+	}
 
-pub struct RayTracer { }
+	// The object that couldn't be set up if the render failed because of it
+	pub fn get_setup_error(&self) -> Option<&RayTraceSetupError> {
+		self.error.get_ref().and_then(|error| error.downcast_ref::<RayTraceSetupError>())
+	}
+
+	// Number of frames from the first one that made it to the sink
+	pub fn get_completed_frames(&self) -> usize {
+		self.completed_frames
+	}
+}
+
+impl fmt::Display for RayTraceRenderError {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		write!(f, "{} (after {} completed frames)", self.error, self.completed_frames)
+	}
+}
+
+impl Error for RayTraceRenderError {
+	fn description(&self) -> &str {
+		self.error.description()
+	}
+
+	fn cause(&self) -> Option<&Error> {
+		Some(&self.error)
+	}
+}
+
+// Callers that only care about the cause keep using try! with io::Error
+impl From<RayTraceRenderError> for IOError {
+	fn from(error: RayTraceRenderError) -> IOError {
+		error.error
+	}
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum RayTraceRenderOrder {
+	// Row by row from the top
+	Linear,
+	// Outwards from the image center
+	Spiral,
+	// Z-order curve for better cache locality
+	Morton
+}
+
+pub struct RayTraceRenderEstimate {
+	pixels: usize,
+	frames: usize,
+	samples_per_pixel: usize,
+	rays_per_sample: f64,
+	projected_rays: u64,
+	projected_time: time::Duration
+}
+
+#[allow(dead_code)]
+impl RayTraceRenderEstimate {
+	pub fn get_pixels(&self) -> usize {
+		self.pixels
+	}
+
+	pub fn get_frames(&self) -> usize {
+		self.frames
+	}
+
+	pub fn get_samples_per_pixel(&self) -> usize {
+		self.samples_per_pixel
+	}
+
+	// Primary ray plus the average number of reflected / refracted rays
+	pub fn get_rays_per_sample(&self) -> f64 {
+		self.rays_per_sample
+	}
+
+	pub fn get_projected_rays(&self) -> u64 {
+		self.projected_rays
+	}
+
+	pub fn get_projected_time(&self) -> time::Duration {
+		self.projected_time
+	}
+}
+
+const THREAD_COUNT: u32 = 8;
+const CALIBRATION_SAMPLES: usize = 256;
+
+#[derive(Clone, Copy)]
+pub struct RayTraceTileRect {
+	x: usize,
+	y: usize,
+	width: usize,
+	height: usize
+}
+
+#[derive(Clone, Copy)]
+struct RayState {
+	reflection_depth: usize,
+	refraction_depth: usize,
+	// Refraction indices of the media the ray is inside of, innermost last
+	media: [f64; MAX_MEDIA],
+	media_count: usize,
+	// Position on the screen in pixels the camera ray was made for
+	pixel: Option<(f64, f64)>,
+	// Depth limit of the material the ray was spawned off, None for the limits of the params
+	max_depth: Option<usize>,
+	// Log everything about this ray and the rays spawned by it (debug pixel)
+	debug: bool
+}
+
+const MAX_MEDIA: usize = 8;
+
+// Running mean and variance of the sample luminance of a pixel (Welford's algorithm) for adaptive sampling
+#[derive(Clone, Copy)]
+struct SampleStats {
+	count: usize,
+	mean: f64,
+	m2: f64
+}
+
+// Samples traced between two convergence checks, at least two batches are traced
+const ADAPTIVE_BATCH_SIZE: usize = 4;
+// Half width of the 95% confidence interval in standard errors
+const CONFIDENCE_Z: f64 = 1.96;
+
+// Color arriving along a ray and its split into the light passes, which are only traced if enabled. Everything
+// done to the color is done to the passes as well, so they always sum up to it.
+#[derive(Debug, Clone)]
+struct RayColor {
+	color: RayTraceColor,
+	passes: Option<RayTraceLightPasses>
+}
+
+#[allow(dead_code)]
+impl RayTraceTileRect {
+	pub fn new(x: usize, y: usize, width: usize, height: usize) -> Self {
+		Self {
+			x: x,
+			y: y,
+			width: width,
+			height: height
+		}
+	}
+
+	pub fn get_x(&self) -> usize {
+		self.x
+	}
+
+	pub fn get_y(&self) -> usize {
+		self.y
+	}
+
+	pub fn get_width(&self) -> usize {
+		self.width
+	}
+
+	pub fn get_height(&self) -> usize {
+		self.height
+	}
+}
+
+impl RayState {
+	fn new() -> Self {
+		Self {
+			reflection_depth: 0,
+			refraction_depth: 0,
+			media: [1.0; MAX_MEDIA],
+			media_count: 0,
+			pixel: None,
+			max_depth: None,
+			debug: false
+		}
+	}
+
+	fn new_debug(debug: bool) -> Self {
+		let mut state = Self::new();
+		state.debug = debug;
+		state
+	}
+
+	// Camera rays start inside the media around the camera (under water, in a glass box, ...)
+	fn new_primary(scene: &RayTraceScene, pixel: (f64, f64), debug: bool) -> Self {
+		let mut state = Self::new_debug(debug);
+		state.pixel = Some(pixel);
+		scene.get_camera_media().iter().fold(state, |state, &medium| state.entered(medium))
+	}
+
+	fn get_depth(&self) -> usize {
+		self.reflection_depth + self.refraction_depth
+	}
+
+	fn is_primary(&self) -> bool {
+		self.reflection_depth == 0 && self.refraction_depth == 0
+	}
+
+	// The spawned rays follow the depth limit of the surface they leave
+	fn spawned_off(&self, material: &RayTraceMaterialHit) -> Self {
+		let mut state = *self;
+		state.max_depth = material.get_max_depth();
+		state
+	}
+
+	fn get_medium(&self) -> f64 {
+		if self.media_count == 0 { 1.0 } else { self.media[self.media_count - 1] }
+	}
+
+	fn reflected(&self) -> Self {
+		let mut state = *self;
+		state.reflection_depth += 1;
+		state
+	}
+
+	fn refracted(&self) -> Self {
+		let mut state = *self;
+		state.refraction_depth += 1;
+		state
+	}
+
+	fn entered(&self, refraction_index: f64) -> Self {
+		let mut state = *self;
+		if state.media_count < MAX_MEDIA {
+			state.media[state.media_count] = refraction_index;
+			state.media_count += 1;
+		} else {
+			state.media[MAX_MEDIA - 1] = refraction_index;
+		}
+		state
+	}
+
+	fn exited(&self) -> Self {
+		let mut state = *self;
+		if state.media_count > 0 {
+			state.media_count -= 1;
+		}
+		state
+	}
+}
+
+impl SampleStats {
+	fn new() -> Self {
+		Self {
+			count: 0,
+			mean: 0.0,
+			m2: 0.0
+		}
+	}
+
+	fn add(&mut self, color: &RayTraceColor) {
+		let luminance = color.get_luminance() as f64;
+		self.count += 1;
+		let delta = luminance - self.mean;
+		self.mean += delta / self.count as f64;
+		self.m2 += delta * (luminance - self.mean);
+	}
+
+	// Whether the pixel needs no more samples, always false without adaptive sampling
+	fn is_converged(&self, params: &RayTraceParams) -> bool {
+		match params.get_adaptive_tolerance() {
+			Some(tolerance) if self.count >= 2 * ADAPTIVE_BATCH_SIZE && self.count % ADAPTIVE_BATCH_SIZE == 0 => {
+				let standard_error = (self.m2 / (self.count - 1) as f64 / self.count as f64).sqrt();
+				CONFIDENCE_Z * standard_error <= tolerance as f64
+			},
+			_ => false
+		}
+	}
+}
+
+impl RayColor {
+	fn new(color: RayTraceColor, passes: Option<RayTraceLightPasses>) -> Self {
+		Self {
+			color: color,
+			passes: passes
+		}
+	}
+
+	fn mix(&self, other: &RayColor, factor: f32) -> Self {
+		Self::new(mix_color(&self.color, &other.color, factor), mix_passes(&self.passes, &other.passes, factor))
+	}
+
+	fn mix_keep_alpha(&self, other: &RayColor, factor: f32) -> Self {
+		Self::new(mix_color_keep_alpha(&self.color, &other.color, factor),
+			mix_passes(&self.passes, &other.passes, factor))
+	}
+
+	// Scales the rgb channels, keeps the alpha
+	fn scale(&self, factor: f32) -> Self {
+		let color = RayTraceColor::new_with(self.color.get_r() * factor, self.color.get_g() * factor,
+			self.color.get_b() * factor, self.color.get_a());
+		Self::new(color, self.passes.as_ref().map(|passes| passes.scale(factor)))
+	}
+
+	// Multiplies the rgb channels, keeps the alpha
+	fn tint(&self, tint: &RayTraceColor) -> Self {
+		let color = RayTraceColor::new_with(self.color.get_r() * tint.get_r(), self.color.get_g() * tint.get_g(),
+			self.color.get_b() * tint.get_b(), self.color.get_a());
+		Self::new(color, self.passes.as_ref().map(|passes| passes.tint(tint)))
+	}
+}
+
+fn mix_passes(passes_a: &Option<RayTraceLightPasses>, passes_b: &Option<RayTraceLightPasses>, factor: f32)
+		-> Option<RayTraceLightPasses> {
+	match (passes_a, passes_b) {
+		(&Some(ref passes_a), &Some(ref passes_b)) => Some(passes_a.mix(passes_b, factor)),
+		_ => None
+	}
+}
 
 impl RayTracer {
 	pub fn new() -> Self {
-		Self { }
+		Self {
+			tile_size: 16,
+			render_order: RayTraceRenderOrder::Linear,
+			budget_fallback_color: RayTraceColor::transparent(),
+			frame_cache: None
+		}
+	}
+
+	pub fn set_tile_size(&mut self, tile_size: usize) {
+		self.tile_size = tile_size.max(1);
+	}
+
+	pub fn get_tile_size(&self) -> usize {
+		self.tile_size
+	}
+
+	pub fn set_render_order(&mut self, render_order: RayTraceRenderOrder) {
+		self.render_order = render_order;
+	}
+
+	pub fn get_render_order(&self) -> RayTraceRenderOrder {
+		self.render_order
+	}
+
+	// Color of the tiles that were skipped because the time budget ran out
+	pub fn set_budget_fallback_color(&mut self, color: RayTraceColor) {
+		self.budget_fallback_color = color;
+	}
+
+	pub fn get_budget_fallback_color(&self) -> &RayTraceColor {
+		&self.budget_fallback_color
+	}
+
+	// Frames of a source that didn't change since they were rendered are taken from the cache, see
+	// RayTraceSource::get_version
+	pub fn set_frame_cache(&mut self, frame_cache: RayTraceFrameCache) {
+		self.frame_cache = Some(frame_cache);
+	}
+
+	pub fn set_frame_cache_opt(&mut self, frame_cache: Option<RayTraceFrameCache>) {
+		self.frame_cache = frame_cache;
+	}
+
+	pub fn get_frame_cache(&self) -> &Option<RayTraceFrameCache> {
+		&self.frame_cache
+	}
+
+	pub fn get_frame_cache_mut(&mut self) -> &mut Option<RayTraceFrameCache> {
+		&mut self.frame_cache
+	}
+
+	// Traces a few rays of the first frame to project the cost of the whole render
+	pub fn estimate(&mut self, source: &mut RayTraceSource) -> Result<RayTraceRenderEstimate, RayTraceSetupError> {
+		let mut w_guard = source.get_unchanged();
+		let RayTraceSourceSet {ref mut scene, ref mut camera, ref params, ref out_params, ..} = *w_guard;
+
+		let (width, height, frames) = (out_params.get_width(), out_params.get_height(), out_params.get_frames());
+		let pixels = width * height;
+		let samples_per_pixel = match params.get_sampling() {
+			&Some(ref sampling) => sampling.get_ray_count(),
+			&None => 1
+		};
+
+		if let Err(err) = check_screen_size(camera, out_params) {
+			warn!("{}", err);
+		}
+
+		camera.set_coordinate_system(scene.get_coordinate_system());
+		camera.init(0);
+		scene.prepare();
+		try!(scene.init(0));
+
+		let calibration_samples = CALIBRATION_SAMPLES.min(pixels);
+		let ray_count_start = get_ray_count();
+		let start = time::PreciseTime::now();
+
+		for i in 0..calibration_samples {
+			// Spread the samples evenly over the image
+			let pixel = i * pixels / calibration_samples.max(1);
+			let ray = camera.make_ray((pixel % width) as f64 + 0.5, (pixel / width) as f64 + 0.5);
+			compute_primary_sample(&ray, camera, scene, params, RayState::new());
+		}
+
+		let duration = start.to(time::PreciseTime::now());
+		let traced_rays = get_ray_count() - ray_count_start;
+
+		let total_samples = (pixels * frames * samples_per_pixel) as f64;
+		let (rays_per_sample, ns_per_sample) = if calibration_samples == 0 { (1.0, 0.0) } else {
+			(traced_rays as f64 / calibration_samples as f64,
+				duration.num_nanoseconds().unwrap_or(0) as f64 / calibration_samples as f64)
+		};
+
+		Ok(RayTraceRenderEstimate {
+			pixels: pixels,
+			frames: frames,
+			samples_per_pixel: samples_per_pixel,
+			rays_per_sample: rays_per_sample,
+			projected_rays: (total_samples * rays_per_sample) as u64,
+			projected_time: time::Duration::nanoseconds((total_samples * ns_per_sample / THREAD_COUNT as f64) as i64)
+		})
+	}
+
+	pub fn render(&mut self, source: &mut RayTraceSource, sink: &mut Box<RayTraceSink>)
+			-> Result<(), RayTraceRenderError> {
+		self.render_tracked(source, &mut [sink], false, None, None)
+	}
+
+	// Additionally sends the colors of each finished tile (row by row) for a live preview.
+	// The preview colors are the plain sample average, the sink still gets the filtered frame.
+	pub fn render_with_preview(&mut self, source: &mut RayTraceSource, sink: &mut Box<RayTraceSink>,
+			preview: Sender<(RayTraceTileRect, Vec<RayTraceColor>)>) -> Result<(), RayTraceRenderError> {
+		self.render_tracked(source, &mut [sink], false, Some(preview), None)
+	}
+
+	// Stops starting new tiles once the wall-clock budget is used up, the remaining tiles of the current frame
+	// get the fallback color and the following frames are not rendered at all
+	pub fn render_with_budget(&mut self, source: &mut RayTraceSource, sink: &mut Box<RayTraceSink>,
+			budget: time::Duration) -> Result<(), RayTraceRenderError> {
+		self.render_tracked(source, &mut [sink], false, None, Some(budget))
 	}
 
-	pub fn render(&mut self, source: &mut RayTraceSource, sink: &mut Box<RayTraceSink>) -> Result<(), IOError> {
-		let mut w_guard = source.get();
-		let RayTraceSourceSet {ref mut scene, ref mut camera, ref mut params, ref out_params} = *w_guard;
+	// Renders each frame once per camera of the source (the one of the constructor first), into the sink of the
+	// same index. The scene is only initialized once per frame for all cameras.
+	pub fn render_all_cameras(&mut self, source: &mut RayTraceSource, sinks: &mut Vec<Box<RayTraceSink>>)
+			-> Result<(), RayTraceRenderError> {
+		let mut sinks: Vec<&mut Box<RayTraceSink>> = sinks.iter_mut().collect();
+		self.render_tracked(source, &mut sinks, true, None, None)
+	}
+
+	fn render_tracked(&mut self, source: &mut RayTraceSource, sinks: &mut [&mut Box<RayTraceSink>],
+			all_cameras: bool, preview: Option<Sender<(RayTraceTileRect, Vec<RayTraceColor>)>>,
+			budget: Option<time::Duration>) -> Result<(), RayTraceRenderError> {
+		let mut completed_frames = 0;
+		match self.render_tiles(source, sinks, all_cameras, preview, budget, &mut completed_frames) {
+			Ok(()) => Ok(()),
+			Err(error) => Err(RayTraceRenderError::new(error, completed_frames))
+		}
+	}
+
+	fn render_tiles(&mut self, source: &mut RayTraceSource, sinks: &mut [&mut Box<RayTraceSink>], all_cameras: bool,
+			preview: Option<Sender<(RayTraceTileRect, Vec<RayTraceColor>)>>, budget: Option<time::Duration>,
+			completed_frames: &mut usize) -> Result<(), IOError> {
+		let render_start = time::PreciseTime::now();
+		let budget_expired = AtomicBool::new(false);
+
+		let version = source.get_version();
+		let mut w_guard = source.get_unchanged();
+		let RayTraceSourceSet {ref mut scene, ref mut camera, ref mut cameras, ref mut params, ref out_params}
+			= *w_guard;
+
+		let mut views: Vec<&mut Box<RayTraceCamera>> = vec![camera];
+		if all_cameras {
+			views.extend(cameras.iter_mut());
+		}
+
+		if sinks.len() != views.len() {
+			return Err(IOError::new(ErrorKind::InvalidInput,
+				format!("Got {} sinks for {} cameras", sinks.len(), views.len())));
+		}
+		for view in views.iter() {
+			try!(check_screen_size(view, out_params));
+		}
+
 		let mut arc_acc = Arc::new(RayTraceSampleAccumulator::new(params.unwrap_filter()));
 
-		try!(sink.init(out_params.get_width(), out_params.get_height(), out_params.get_frames()));
+		for sink in sinks.iter_mut() {
+			try!(sink.init(out_params.get_width(), out_params.get_height(), out_params.get_frames()));
+		}
 		Arc::get_mut(&mut arc_acc).unwrap().init(out_params.get_width(), out_params.get_height());
 
 		let mut arc_params: Arc<&mut RayTraceParams> = Arc::new(params);
-		let mut arc_camera: Arc<&mut Box<RayTraceCamera>> = Arc::new(camera);
 		let mut arc_scene: Arc<&mut RayTraceScene> = Arc::new(scene);
 		//let mut arc_tree: Arc<RayTraceOctree<usize>>;
 
-		let mut thread_pool = Pool::new(8);
+		Arc::get_mut(&mut arc_scene).unwrap().prepare();
+
+		let mut thread_pool = Pool::new(THREAD_COUNT);
 
 		for frame in 0..out_params.get_frames() {
 			info!("Initializing frame {} ...", frame + 1);
 			let start = time::now();
 
 			{
-				Arc::get_mut(&mut arc_camera).unwrap().init(frame);
-				Arc::get_mut(&mut arc_scene).unwrap().init(frame);
+				let coordinate_system = arc_scene.get_coordinate_system();
+				for view in views.iter_mut() {
+					view.set_coordinate_system(coordinate_system);
+					view.init(frame);
+				}
+				try!(Arc::get_mut(&mut arc_scene).unwrap().init(frame));
+				Arc::get_mut(&mut arc_params).unwrap().set_frame(frame);
+			}
+
+			if frame == 0 {
+				info!("Scene: {}", arc_scene.statistics());
+			}
+
+			{
+				let caustics = if arc_params.is_caustics_enabled() {
+					Some(RayTraceCausticMap::new(&arc_scene, &arc_params))
+				} else {
+					None
+				};
+				Arc::get_mut(&mut arc_scene).unwrap().set_caustics(caustics);
 				/*let scene = Arc::get_mut(&mut arc_scene).unwrap();
 				scene.init(frame);
 
@@ -64,118 +610,807 @@ impl RayTracer {
 
 			info!("Initialized frame {} in {}", frame + 1, (time::now() - start));
 
-			info!("Rendering frame {} ...", frame + 1);
-			let start = time::now();
-			thread_pool.scoped(|scoped| {
-				for y in 0..out_params.get_height() {
-					for x in 0..out_params.get_width() {
-						let scoped_camera: Arc<&Box<RayTraceCamera>> = Arc::new(&arc_camera);
+			for (view, camera) in views.iter().enumerate() {
+				let camera: &Box<RayTraceCamera> = camera;
+				let sink = &mut sinks[view];
+				// The preview only shows the first camera
+				let view_preview = if view == 0 { preview.clone() } else { None };
+
+				if views.len() > 1 {
+					info!("Camera {} of {}", view + 1, views.len());
+				}
+
+				// The cache only keeps the frame itself
+				let frame_key = if self.frame_cache.is_some() && !arc_params.is_light_passes_enabled() {
+					Some(make_frame_key(version, view, frame))
+				} else {
+					None
+				};
+
+				if try!(self.write_cached_frame(frame_key, frame, sink, &view_preview)) {
+					info!("Frame {} is unchanged, took it from the cache", frame + 1);
+					continue;
+				}
+
+				{
+					// The media at the center of the view are assumed for all camera rays
+					let center_ray = camera.make_ray(0.5 * out_params.get_width() as f64,
+						0.5 * out_params.get_height() as f64);
+					let camera_media = arc_scene.find_media_at(center_ray.get_position().clone(),
+						center_ray.get_direction().clone());
+					Arc::get_mut(&mut arc_scene).unwrap().set_camera_media(camera_media);
+				}
+
+				let tiles = make_tiles(out_params.get_width(), out_params.get_height(), self.tile_size,
+					self.render_order);
+
+				// The finished tiles are passed to the sink on this thread while the others render
+				let (tile_sender, tile_receiver) = if sink.receives_tiles() {
+					let (tile_sender, tile_receiver) = channel();
+					(Some(tile_sender), Some(tile_receiver))
+				} else {
+					(None, None)
+				};
+				let mut tile_result = Ok(());
+
+				info!("Rendering frame {} ...", frame + 1);
+				let start = time::now();
+				thread_pool.scoped(|scoped| {
+					for tile in tiles.iter() {
+						let tile = *tile;
+						let scoped_camera: Arc<&Box<RayTraceCamera>> = Arc::new(camera);
 						let scoped_scene: Arc<&RayTraceScene> = Arc::new(&arc_scene);
 						let scoped_params: Arc<&RayTraceParams> = Arc::new(&arc_params);
 						let scoped_acc = arc_acc.clone();
+						let scoped_preview = view_preview.clone();
+						let scoped_tile_sender = tile_sender.clone();
+						let scoped_fallback_color = self.budget_fallback_color.clone();
+						let scoped_budget_expired = &budget_expired;
 						//let scoped_tree = arc_tree.clone();
 
 						scoped.execute(move || {
-							compute_samples(scoped_camera, scoped_scene, scoped_params, x, y, scoped_acc/*, scoped_tree*/);
+							let expired = match budget {
+								Some(budget) => render_start.to(time::PreciseTime::now()) > budget,
+								None => false
+							};
+
+							if expired {
+								scoped_budget_expired.store(true, Ordering::Relaxed);
+								fill_tile(&scoped_scene, &scoped_params, tile, &scoped_acc,
+									&scoped_fallback_color);
+							} else {
+								compute_tile_samples(&scoped_camera, &scoped_scene, &scoped_params, tile, &scoped_acc);
+							}
+
+							if scoped_preview.is_some() || scoped_tile_sender.is_some() {
+								let mut colors = Vec::with_capacity(tile.width * tile.height);
+								for y in tile.y..(tile.y + tile.height) {
+									for x in tile.x..(tile.x + tile.width) {
+										let source_y = scoped_params.get_preview_row(y);
+										colors.push(scoped_params.apply_output_transform(
+											scoped_acc.get_average(x, source_y)));
+									}
+								}
+
+								if let Some(tile_sender) = scoped_tile_sender {
+									let _ = tile_sender.send((tile, colors.clone()));
+								}
+
+								// The receiver might be gone already, the sink still gets the frame
+								if let Some(preview) = scoped_preview {
+									let _ = preview.send((tile, colors));
+								}
+							}
 						});
 					}
-				}
-			});
 
-			info!("Rendered frame {} in {}", frame + 1, (time::now() - start));
+					// Without the original sender the receiving stops early if a tile panicked
+					drop(tile_sender);
+					if let Some(tile_receiver) = tile_receiver {
+						for (tile, colors) in tile_receiver.iter() {
+							if tile_result.is_ok() {
+								tile_result = sink.set_tile(frame, &tile, &colors);
+							}
+						}
+					}
+				});
+				try!(tile_result);
+
+				info!("Rendered frame {} in {}", frame + 1, (time::now() - start));
 
-			// TODO: Do sinking async.
-			let start = time::now();
-			info!("Sinking frame {} ...", frame + 1);
-			try!(arc_acc.flush(sink, frame));
-			Arc::get_mut(&mut arc_acc).unwrap().reset();
-			info!("Sank frame {} in {}", frame + 1, (time::now() - start));
+				// The passes were traced along with the frame and are filtered the same way
+				let rendered_frame = arc_acc.resolve(&arc_params);
+				let pass_frames: Vec<(String, RayTraceFrame)> = if arc_params.is_light_passes_enabled() {
+					(0..(arc_scene.get_lights().len() + 1))
+						.map(|pass| (RayTraceLightPasses::get_name(pass), arc_acc.resolve_pass(&arc_params, pass)))
+						.collect()
+				} else {
+					Vec::new()
+				};
+				Arc::get_mut(&mut arc_acc).unwrap().reset();
+
+				// TODO: Do sinking async.
+				let start = time::now();
+				info!("Sinking frame {} ...", frame + 1);
+				try!(rendered_frame.write_with_passes(sink, frame, &pass_frames));
 
+				// Frames with skipped tiles don't show the actual scene
+				if let (Some(key), Some(cache)) = (frame_key, self.frame_cache.as_mut()) {
+					if !budget_expired.load(Ordering::Relaxed) {
+						cache.insert(key, rendered_frame);
+					}
+				}
+				info!("Sank frame {} in {}", frame + 1, (time::now() - start));
+			}
+
+			// Complete once every camera has written it
+			*completed_frames += 1;
+
+			if budget_expired.load(Ordering::Relaxed) {
+				info!("Time budget exceeded, skipping the remaining {} frames", out_params.get_frames() - frame - 1);
+				break;
+			}
 		}
 
 		let sample_filter = Arc::get_mut(&mut arc_acc).unwrap().destroy();
 		Arc::get_mut(&mut arc_params).unwrap().set_filter(sample_filter);
 
+		for sink in sinks.iter_mut() {
+			try!(sink.finish());
+		}
+
 		Ok(())
 	}
+
+	// Returns whether the frame was in the cache
+	fn write_cached_frame(&mut self, frame_key: Option<u64>, frame: usize, sink: &mut Box<RayTraceSink>,
+			preview: &Option<Sender<(RayTraceTileRect, Vec<RayTraceColor>)>>) -> Result<bool, IOError> {
+		if let (Some(key), Some(cache)) = (frame_key, self.frame_cache.as_mut()) {
+			if let Some(cached_frame) = cache.lookup(key) {
+				try!(cached_frame.write(sink, frame));
+
+				if let Some(ref preview) = *preview {
+					let tile = RayTraceTileRect { x: 0, y: 0, width: cached_frame.get_width(),
+						height: cached_frame.get_height() };
+					let _ = preview.send((tile, cached_frame.get_colors().clone()));
+				}
+
+				return Ok(true);
+			}
+		}
+
+		Ok(false)
+	}
 }
 
-fn compute_samples(camera: Arc<&Box<RayTraceCamera>>, scene: Arc<&RayTraceScene>, params: Arc<&RayTraceParams>,
-		x: usize, y: usize, acc: Arc<RayTraceSampleAccumulator>/*, tree: Arc<RayTraceOctree<usize>>*/) {
-	match params.get_sampling() {
-		&None => {
-			let p_x = x as f64 + 0.5_f64;
-			let p_y = y as f64 + 0.5_f64;
+// Cameras built for another image size would silently render with the wrong aspect ratio
+fn check_screen_size(camera: &Box<RayTraceCamera>, out_params: &RayTraceOutputParams) -> Result<(), IOError> {
+	match camera.get_screen_size() {
+		Some((width, height)) if width != out_params.get_width() || height != out_params.get_height() => {
+			Err(IOError::new(ErrorKind::InvalidInput, format!("Camera was created for {}x{} but the output is {}x{}",
+				width, height, out_params.get_width(), out_params.get_height())))
+		},
+		_ => Ok(())
+	}
+}
 
-			let ray = camera.make_ray(p_x, p_y);
-			let color = compute_color_for_ray(&ray, *camera, *scene, *params/*, &*tree.as_ref()*/, 0);
+fn make_tiles(width: usize, height: usize, tile_size: usize, order: RayTraceRenderOrder) -> Vec<RayTraceTileRect> {
+	let tiles_x = (width + tile_size - 1) / tile_size;
+	let tiles_y = (height + tile_size - 1) / tile_size;
 
-			acc.add_sample(x, y, RayTraceSample { x: p_x, y: p_y, color: color });
+	let mut tiles = Vec::with_capacity(tiles_x * tiles_y);
+	for t_y in 0..tiles_y {
+		for t_x in 0..tiles_x {
+			tiles.push(RayTraceTileRect {
+				x: t_x * tile_size,
+				y: t_y * tile_size,
+				width: tile_size.min(width - t_x * tile_size),
+				height: tile_size.min(height - t_y * tile_size)
+			});
+		}
+	}
+
+	match order {
+		RayTraceRenderOrder::Linear => { },
+		RayTraceRenderOrder::Spiral => {
+			let center_x = width as f64 / 2.0;
+			let center_y = height as f64 / 2.0;
+			let tile_size = tile_size as f64;
+
+			// Sort by the ring around the center first, then by the angle within the ring
+			let key = |tile: &RayTraceTileRect| {
+				let d_x = (tile.x as f64 + tile.width as f64 / 2.0 - center_x) / tile_size;
+				let d_y = (tile.y as f64 + tile.height as f64 / 2.0 - center_y) / tile_size;
+				(d_x.abs().max(d_y.abs()).round(), d_y.atan2(d_x))
+			};
+
+			tiles.sort_by(|a, b| key(a).partial_cmp(&key(b)).unwrap());
 		},
-		&Some(ref sampling) => {
-			let ray_count = sampling.get_ray_count();
-
-			for _ in 0..ray_count {
-				let (p_x, p_y) = sampling.apply(x as f64, y as f64);
-				let ray = camera.make_ray(p_x, p_y);
-				let color = compute_color_for_ray(&ray, *camera, *scene, *params/*, &*tree.as_ref()*/, 0);
-				acc.add_sample(x, y, RayTraceSample { x: p_x, y: p_y, color: color });
+		RayTraceRenderOrder::Morton => {
+			tiles.sort_by_key(|tile| morton_index(tile.x / tile_size, tile.y / tile_size));
+		}
+	}
+
+	tiles
+}
+
+fn morton_index(x: usize, y: usize) -> u64 {
+	let mut index = 0_u64;
+	for bit in 0..32 {
+		index |= (((x >> bit) & 1) as u64) << (2 * bit);
+		index |= (((y >> bit) & 1) as u64) << (2 * bit + 1);
+	}
+
+	index
+}
+
+#[cfg(not(feature = "ray_packets"))]
+fn compute_tile_samples(camera: &Arc<&Box<RayTraceCamera>>, scene: &Arc<&RayTraceScene>, params: &Arc<&RayTraceParams>,
+		tile: RayTraceTileRect, acc: &Arc<RayTraceSampleAccumulator>) {
+	for y in tile.y..(tile.y + tile.height) {
+		if params.get_preview_row(y) != y {
+			continue;
+		}
+
+		for x in tile.x..(tile.x + tile.width) {
+			compute_samples(camera.clone(), scene.clone(), params.clone(), x, y, acc.clone()/*, tree.clone()*/);
+		}
+	}
+}
+
+// Traces the same sample of neighbouring pixels in one packet
+#[cfg(feature = "ray_packets")]
+fn compute_tile_samples(camera: &Arc<&Box<RayTraceCamera>>, scene: &Arc<&RayTraceScene>, params: &Arc<&RayTraceParams>,
+		tile: RayTraceTileRect, acc: &Arc<RayTraceSampleAccumulator>) {
+	let (camera, scene, params) = (**camera, **scene, **params);
+	let ray_count = match params.get_sampling() {
+		&Some(ref sampling) => sampling.get_ray_count(),
+		&None => 1
+	};
+
+	let mut positions = Vec::with_capacity(PACKET_SIZE);
+	let mut rays = Vec::with_capacity(PACKET_SIZE);
+
+	for y in tile.y..(tile.y + tile.height) {
+		if params.get_preview_row(y) != y {
+			continue;
+		}
+
+		let mut x_start = tile.x;
+		while x_start < tile.x + tile.width {
+			let x_end = (x_start + PACKET_SIZE).min(tile.x + tile.width);
+			let mut stats = [SampleStats::new(); PACKET_SIZE];
+
+			for index in 0..ray_count {
+				positions.clear();
+				rays.clear();
+
+				for x in x_start..x_end {
+					if stats[x - x_start].is_converged(params) {
+						continue;
+					}
+
+					let (position, ray) = make_sample_ray(camera, params, x, y, index, ray_count);
+					positions.push((x, position));
+					rays.push(ray);
+				}
+
+				if rays.is_empty() {
+					break;
+				}
+
+				let hits = scene.ray_cast_visible_packet(&rays, params);
+				for ((&(x, position), ray), hit) in positions.iter().zip(rays.iter()).zip(hits.into_iter()) {
+					let color = trace_sample(ray, hit, camera, scene, params, acc, x, y, index, position);
+					stats[x - x_start].add(&color);
+				}
 			}
+
+			x_start = x_end;
+		}
+	}
+}
+
+// One sample in the pixel center per pixel, so the filter reproduces the color
+fn fill_tile(scene: &Arc<&RayTraceScene>, params: &Arc<&RayTraceParams>, tile: RayTraceTileRect,
+		acc: &Arc<RayTraceSampleAccumulator>, color: &RayTraceColor) {
+	for y in tile.y..(tile.y + tile.height) {
+		for x in tile.x..(tile.x + tile.width) {
+			add_sample(acc, **params, x, y, (x as f64 + 0.5, y as f64 + 0.5),
+				environment_color(color.clone(), **scene, **params), None, None);
 		}
 	}
 }
 
+// The light passes get the alpha of the color, so they share its coverage
+fn add_sample(acc: &RayTraceSampleAccumulator, params: &RayTraceParams, x: usize, y: usize, position: (f64, f64),
+		color: RayColor, object_id: Option<usize>, depth: Option<f64>) {
+	let alpha = color.color.get_a();
+	let passes = match color.passes {
+		Some(passes) => passes.get_colors().iter().map(|pass| {
+			let mut pass = pass.clone();
+			pass.set_a(alpha);
+			params.to_averaging_space(pass)
+		}).collect(),
+		None => Vec::new()
+	};
+
+	acc.add_sample(x, y, RayTraceSample { x: position.0, y: position.1, color: params.to_averaging_space(color.color),
+		object_id: object_id, depth: depth, passes: passes });
+}
+
+fn compute_samples(camera: Arc<&Box<RayTraceCamera>>, scene: Arc<&RayTraceScene>, params: Arc<&RayTraceParams>,
+		x: usize, y: usize, acc: Arc<RayTraceSampleAccumulator>/*, tree: Arc<RayTraceOctree<usize>>*/) {
+	let ray_count = match params.get_sampling() {
+		&Some(ref sampling) => sampling.get_ray_count(),
+		&None => 1
+	};
+	let mut stats = SampleStats::new();
+
+	for index in 0..ray_count {
+		if stats.is_converged(*params) {
+			break;
+		}
+
+		let (position, ray) = make_sample_ray(*camera, *params, x, y, index, ray_count);
+		let nearest_hit = scene.ray_cast_visible(&ray, *params);
+		let color = trace_sample(&ray, nearest_hit, *camera, *scene, *params, &acc, x, y, index, position);
+		stats.add(&color);
+	}
+}
+
+// Position of the sample in the pixel and the camera ray through it, the pixel center without sampling
+fn make_sample_ray(camera: &Box<RayTraceCamera>, params: &RayTraceParams, x: usize, y: usize, index: usize,
+		ray_count: usize) -> ((f64, f64), RayTraceRay) {
+	let (p_x, p_y) = match params.get_sampling() {
+		&Some(ref sampling) => sampling.apply_indexed(x as f64, y as f64, index, params.get_sample_seed(x, y, index)),
+		&None => (x as f64 + 0.5_f64, y as f64 + 0.5_f64)
+	};
+
+	let mut ray = camera.make_ray(p_x, p_y);
+	ray.set_time(compute_sample_time(params, x, y, index, ray_count));
+	((p_x, p_y), ray)
+}
+
+// Shades the sample with the nearest hit of its camera ray and adds it to the pixel, the color is returned for
+// the convergence check. Shared by the tracing of single rays and of ray packets.
+fn trace_sample(ray: &RayTraceRay, nearest_hit: Option<RayTraceRayHit>, camera: &Box<RayTraceCamera>,
+		scene: &RayTraceScene, params: &RayTraceParams, acc: &RayTraceSampleAccumulator, x: usize, y: usize,
+		index: usize, position: (f64, f64)) -> RayTraceColor {
+	let state = RayState::new_primary(scene, position, params.is_debug_pixel(x, y));
+	if state.debug {
+		info!("Debug pixel ({}, {}) sample {} at ({}, {})", x, y, index, position.0, position.1);
+	}
+
+	let (color, object_id, depth) = shade_primary_sample(ray, nearest_hit, camera, scene, params, state);
+	if state.debug {
+		info!("Debug pixel ({}, {}) sample {} color {:?}", x, y, index, color.color);
+	}
+
+	let sample_color = color.color.clone();
+	add_sample(acc, params, x, y, position, color, object_id, depth);
+	sample_color
+}
+
+// Time of the camera ray inside the shutter interval (relative to the frame) for motion blur. The strata are
+// rotated per pixel, so the time doesn't follow the position of the sample in the pixel.
+// A single sample shows the frame itself.
+fn compute_sample_time(params: &RayTraceParams, x: usize, y: usize, index: usize, count: usize) -> f64 {
+	if count <= 1 {
+		return 0.0;
+	}
+
+	let stratum = (index + (params.get_sample_seed(x, y, 0) % count as u64) as usize) % count;
+	params.get_sample_time(0, stratum, count)
+}
+
+fn compute_primary_sample(ray: &RayTraceRay, camera: &Box<RayTraceCamera>, scene: &RayTraceScene,
+		params: &RayTraceParams/*, tree: &RayTraceOctree<usize>*/, state: RayState)
+		-> (RayColor, Option<usize>, Option<f64>) {
+	let nearest_hit = scene.ray_cast_visible(ray, params);
+	shade_primary_sample(ray, nearest_hit, camera, scene, params, state)
+}
+
+// Color, object id and depth of a camera ray with its nearest hit
+fn shade_primary_sample(ray: &RayTraceRay, nearest_hit: Option<RayTraceRayHit>, camera: &Box<RayTraceCamera>,
+		scene: &RayTraceScene, params: &RayTraceParams, state: RayState) -> (RayColor, Option<usize>, Option<f64>) {
+	count_ray();
+	if state.debug {
+		log_debug_ray(ray, &nearest_hit, scene, params, state);
+	}
+
+	let object_id = nearest_hit.as_ref().and_then(|hit| hit.get_object_id());
+	let depth = nearest_hit.as_ref().map(|hit| hit.get_distance());
+	(compute_color_for_hit(ray, nearest_hit, camera, scene, params, state), object_id, depth)
+}
+
 fn compute_color_for_ray(ray: &RayTraceRay, camera: &Box<RayTraceCamera>, scene: &RayTraceScene,
-		params: &RayTraceParams/*, tree: &RayTraceOctree<usize>*/, depth: usize) -> RayTraceColor {
+		params: &RayTraceParams/*, tree: &RayTraceOctree<usize>*/, state: RayState) -> RayColor {
 	// If this is an indirect ray we cancel after a maximum depth
-	if depth > params.get_max_depth() {
-		return params.get_indirect_color().clone();
+	if is_max_depth_reached(params, state) {
+		if state.debug {
+			info!("{}Maximum depth reached, using the indirect color", debug_indent(state));
+		}
+		return environment_color(params.get_indirect_color().clone(), scene, params);
+	}
+
+	count_ray();
+	let nearest_hit = scene.ray_cast_visible(ray, params);
+	if state.debug {
+		log_debug_ray(ray, &nearest_hit, scene, params, state);
 	}
 
-	// Collect all ray hits
-	let mut ray_hits = BinaryHeap::<RayTraceHitHeapEntry<RayTraceRayHit>>::new();
+	let is_indirect = state.reflection_depth > 0 || state.refraction_depth > 0;
+	let color = compute_color_for_hit(ray, nearest_hit, camera, scene, params, state);
+	let color = if is_indirect && params.is_indirect_clamped() {
+		clamp_luminance(color, params.get_clamp_indirect())
+	} else {
+		color
+	};
+
+	if state.debug {
+		info!("{}Ray color {:?}", debug_indent(state), color.color);
+	}
+	color
+}
+
+fn is_max_depth_reached(params: &RayTraceParams, state: RayState) -> bool {
+	let (max_reflection_depth, max_refraction_depth) = match state.max_depth {
+		Some(max_depth) => (max_depth, max_depth),
+		None => (params.get_max_reflection_depth(), params.get_max_refraction_depth())
+	};
+	state.reflection_depth > max_reflection_depth || state.refraction_depth > max_refraction_depth
+}
+
+fn debug_indent(state: RayState) -> String {
+	format!("{:1$}", "", state.get_depth() * 2)
+}
+
+// Retests all objects for the ray of the debug pixel to log why each of them was hit or missed
+fn log_debug_ray(ray: &RayTraceRay, nearest_hit: &Option<RayTraceRayHit>, scene: &RayTraceScene,
+		params: &RayTraceParams, state: RayState) {
+	let indent = debug_indent(state);
+	info!("{}Ray from {:?} towards {:?} (reflection depth {}, refraction depth {})", indent, ray.get_position(),
+		ray.get_direction(), state.reflection_depth, state.refraction_depth);
 
-	for object in scene.get_objects().iter() {
+	for (object_id, object) in scene.get_objects().iter().enumerate() {
+		let name = object.get_name().unwrap_or("");
 		if let Some(aabb) = object.get_aabb() {
 			if !aabb.is_hit(ray) {
+				info!("{}  Object {} '{}': AABB missed", indent, object_id, name);
 				continue;
 			}
 		}
 
-		if let Some(hit) = object.next_hit(ray) {
-			ray_hits.push(RayTraceHitHeapEntry::new(hit.get_distance(), hit));
+		match object.next_visible_hit(ray, params) {
+			Some(hit) => info!("{}  Object {} '{}': hit at distance {}", indent, object_id, name, hit.get_distance()),
+			None => info!("{}  Object {} '{}': missed", indent, object_id, name)
 		}
 	}
 
+	match *nearest_hit {
+		Some(ref hit) => {
+			let material = hit.get_surface_material();
+			info!("{}Nearest hit: object {:?} at distance {}, position {:?}, normal {:?}", indent, hit.get_object_id(),
+				hit.get_distance(), hit.get_position(), hit.get_surface_normal());
+			info!("{}  Color {:?}, reflectance {}, transparency {}, refraction index {}", indent, material.get_color(),
+				material.get_reflectance(), material.get_transparency(), material.get_refraction_index());
+		},
+		None => info!("{}No hit, using the background", indent)
+	}
+}
+
+// Scales the rgb channels down so the luminance doesn't exceed the maximum, keeps the hue and alpha
+fn clamp_luminance(color: RayColor, max_luminance: f32) -> RayColor {
+	let luminance = color.color.get_luminance();
+	if luminance <= max_luminance {
+		return color;
+	}
+
+	color.scale(max_luminance / luminance)
+}
+
+// Backplate behind the pixel for camera rays, None for all other rays or without a backplate
+fn compute_backplate_color(camera: &Box<RayTraceCamera>, params: &RayTraceParams, state: RayState)
+		-> Option<RayTraceColor> {
+	match (params.get_backplate(), state.pixel, camera.get_screen_size()) {
+		(&Some(ref backplate), Some((x, y)), Some((width, height))) if state.is_primary() =>
+			Some(backplate.get_color(x / width as f64, y / height as f64)),
+		_ => None
+	}
+}
+
+// Colors that are not caused by a light only show in the ambient pass
+fn environment_color(color: RayTraceColor, scene: &RayTraceScene, params: &RayTraceParams) -> RayColor {
+	let passes = if params.is_light_passes_enabled() {
+		Some(RayTraceLightPasses::new_ambient(&color, scene.get_lights().len()))
+	} else {
+		None
+	};
+	RayColor::new(color, passes)
+}
+
+// The volumes (fog, ...) in front of the hit are composited over the color of the surface or background
+fn compute_color_for_hit(ray: &RayTraceRay, nearest_hit: Option<RayTraceRayHit>, camera: &Box<RayTraceCamera>,
+		scene: &RayTraceScene, params: &RayTraceParams, state: RayState) -> RayColor {
+	let distance = nearest_hit.as_ref().map_or(f64::INFINITY, |hit| hit.get_distance());
+	let RayColor { color, mut passes } = compute_surface_color(ray, nearest_hit, camera, scene, params, state);
+	let color = scene.apply_volumes(ray, distance, color, passes.as_mut(), params);
+	RayColor::new(color, passes)
+}
+
+fn compute_surface_color(ray: &RayTraceRay, nearest_hit: Option<RayTraceRayHit>, camera: &Box<RayTraceCamera>,
+		scene: &RayTraceScene, params: &RayTraceParams, state: RayState) -> RayColor {
 	// Return background color on no hit
-	match ray_hits.pop() {
+	match nearest_hit {
 		None => {
-			if depth == 0 {
-				return params.get_background_color().clone();
+			if state.is_primary() && params.is_transparent_background() {
+				return environment_color(RayTraceColor::transparent(), scene, params);
+			}
+
+			if let Some(color) = compute_backplate_color(camera, params, state) {
+				return environment_color(color, scene, params);
+			}
+
+			if let &Some(ref sky) = params.get_sky() {
+				let up = row_mat3_transform(scene.get_coordinate_system().get_matrix(), [0.0, 1.0, 0.0]);
+				return environment_color(sky.get_color(ray.get_direction().clone(), up,
+					params.get_working_color_space()), scene, params);
+			}
+
+			// Refracted rays leaving the scene either show the background or the fixed indirect color
+			if state.is_primary() || (state.refraction_depth > 0 && params.is_background_behind_transparent()) {
+				return environment_color(params.get_background_color().clone(), scene, params);
 			} else {
-				return params.get_indirect_color().clone();
+				return environment_color(params.get_indirect_color().clone(), scene, params);
 			}
 		},
-		Some(RayTraceHitHeapEntry { value: hit, distance: _ }) => {
-			let (mut material_color, overlay_color);
+		Some(hit) => compute_layered_color(ray, hit, camera, scene, params, state)
+	}
+}
+
+// Shading of one surface without the light coming through it, the color behind is composited over by it
+struct RaySurfaceLayer {
+	material_color: RayColor,
+	overlay_color: RayColor,
+	transparency: f32,
+	// Colored glass filters the light passing through it
+	tint: Option<RayTraceColor>,
+	reflectance: f32,
+	reflected_color: Option<RayColor>
+}
+
+impl RaySurfaceLayer {
+	// The behind color is None for opaque surfaces
+	fn composite(&self, behind_color: Option<RayColor>) -> RayColor {
+		let mut material_color = self.material_color.clone();
+		if let Some(behind_color) = behind_color {
+			let behind_color = match self.tint {
+				Some(ref tint) => behind_color.tint(tint),
+				None => behind_color
+			};
+			material_color = material_color.mix(&behind_color, self.transparency);
+		}
 
-			if let &Some(ref shading_fn) = params.get_shading() {
-				let (m, o) = shading_fn.apply(ray, &hit, camera, scene, params);
-				material_color = m;
-				overlay_color = o;
+		if let Some(ref reflected_color) = self.reflected_color {
+			material_color = if reflected_color.color.is_black(BLACK_THRESHOLD) {
+				// Nothing is reflected, the surface only loses the reflected share of its own color
+				material_color.scale(1.0 - self.reflectance.min(1.0))
 			} else {
-				material_color = hit.get_surface_material().get_color().clone();
-				overlay_color = RayTraceColor::transparent();
+				material_color.mix_keep_alpha(reflected_color, self.reflectance)
+			};
+		}
+
+		material_color.mix(&self.overlay_color, self.overlay_color.color.get_a())
+	}
+}
+
+// Transparent surfaces are composited front to back instead of tracing a new ray through each of them. The remaining
+// hits of an object come from its next_hits list as long as the ray goes on straight, only the objects in the gaps
+// between them are tested. The walk stops once too little light makes it through the surfaces in front.
+fn compute_layered_color(ray: &RayTraceRay, hit: RayTraceRayHit, camera: &Box<RayTraceCamera>,
+		scene: &RayTraceScene, params: &RayTraceParams, state: RayState) -> RayColor {
+	// Each layer with the ray segment arriving at it, None for the first one
+	let mut layers: Vec<(RaySurfaceLayer, Option<(RayTraceRay, f64, RayState)>)> = Vec::new();
+	let mut transmittance = RayTraceColor::white();
+	// Hits of the object of the current layer along the current ray, nearest last
+	let mut object_hits: Vec<RayTraceRayHit> = Vec::new();
+
+	let mut layer_ray = ray.derive(ray.get_position().clone(), ray.get_direction().clone());
+	let (mut layer_hit, mut layer_state, mut segment) = (hit, state, None);
+	let behind_color = loop {
+		let state = layer_state.spawned_off(layer_hit.get_surface_material());
+		let layer = shade_surface_layer(&layer_ray, &layer_hit, camera, scene, params, state);
+		let transparency = layer.transparency;
+		if transparency == 0.0 {
+			layers.push((layer, segment));
+			break None;
+		}
+
+		// Black glass lets nothing through and the ray behind it isn't traced at all
+		let tint = layer.tint.clone().unwrap_or(RayTraceColor::white());
+		transmittance = RayTraceColor::new_with(transmittance.get_r() * tint.get_r() * transparency,
+			transmittance.get_g() * tint.get_g() * transparency, transmittance.get_b() * tint.get_b() * transparency,
+			1.0);
+		if tint.is_black(BLACK_THRESHOLD) || transmittance.is_black(BLACK_THRESHOLD) {
+			if state.debug {
+				info!("{}Too little light passes the surfaces, stopping", debug_indent(state));
 			}
+			layers.push((layer, segment));
+			break Some(environment_color(RayTraceColor::black(), scene, params));
+		}
+
+		// Track the media the ray is in to handle nested objects (ice in water, ...)
+		let refraction_index = layer_hit.get_surface_material().get_refraction_index() as f64;
+		let surface_normal = layer_hit.get_surface_normal().clone();
+		let (from_index, to_index, next_state) = if vec3_dot(layer_ray.get_direction().clone(), surface_normal) < 0.0 {
+			(state.get_medium(), refraction_index, state.refracted().entered(refraction_index))
+		} else {
+			let next_state = state.refracted().exited();
+			(refraction_index, next_state.get_medium(), next_state)
+		};
+
+		let (next_ray, next_state) = match compute_refracted_ray_between(&layer_ray, &layer_hit, from_index, to_index,
+				params.get_ray_offset()) {
+			Some(refracted_ray) => (refracted_ray, next_state),
+			None => (compute_reflected_ray(&layer_ray, &layer_hit, params.get_ray_offset()), state.refracted())
+		};
+
+		if is_max_depth_reached(params, next_state) {
+			if state.debug {
+				info!("{}Maximum depth reached, using the indirect color", debug_indent(next_state));
+			}
+			layers.push((layer, segment));
+			break Some(environment_color(params.get_indirect_color().clone(), scene, params));
+		}
+
+		// Bent rays and other objects start a new list of hits
+		let straight = vec3_dot(next_ray.get_direction().clone(), layer_ray.get_direction().clone()) > 1.0 - 1.0e-9;
+		let object_id = layer_hit.get_object_id();
+		if !straight || object_hits.last().map_or(true, |hit| hit.get_object_id() != object_id) {
+			object_hits = find_object_hits(&next_ray, object_id, scene, params);
+		}
 
-			let reflectance = hit.get_surface_material().get_reflectance();
-			if reflectance != 0.0 {
-				let reflected_ray = compute_reflected_ray(hit.get_surface_normal().clone(), ray, hit.get_distance());
-				let reflected_color = compute_color_for_ray(&reflected_ray, camera, scene, params/*, tree*/, depth + 1);
-				material_color = mix_color(&material_color, &reflected_color, reflectance);
+		// Hits behind the origin of the next ray were already passed
+		let origin = next_ray.get_position().clone();
+		while object_hits.last().map_or(false, |hit| {
+			vec3_dot(vec3_sub(hit.get_position().clone(), origin), next_ray.get_direction().clone()) <= 0.0
+		}) {
+			object_hits.pop();
+		}
+
+		count_ray();
+		let next_hit = match (object_hits.pop(), object_id) {
+			(Some(mut object_hit), Some(object_id)) => {
+				let distance = vec3_len(vec3_sub(object_hit.get_position().clone(), origin));
+				object_hit.set_distance(distance);
+				match scene.ray_cast_visible_before(&next_ray, distance, object_id, params) {
+					Some(other_hit) => {
+						object_hits.clear();
+						Some(other_hit)
+					},
+					None => Some(object_hit)
+				}
+			},
+			_ => scene.ray_cast_visible(&next_ray, params)
+		};
+		if next_state.debug {
+			log_debug_ray(&next_ray, &next_hit, scene, params, next_state);
+		}
+
+		layers.push((layer, segment));
+		match next_hit {
+			Some(next_hit) => {
+				segment = Some((next_ray.derive(next_ray.get_position().clone(), next_ray.get_direction().clone()),
+					next_hit.get_distance(), next_state));
+				layer_ray = next_ray;
+				layer_hit = next_hit;
+				layer_state = next_state;
+			},
+			None => {
+				let color = compute_surface_color(&next_ray, None, camera, scene, params, next_state);
+				break Some(finish_transmitted_color(&next_ray, f64::INFINITY, color, scene, params, next_state));
 			}
+		}
+	};
+
+	let mut color = behind_color;
+	while let Some((layer, segment)) = layers.pop() {
+		let layer_color = layer.composite(color);
+		color = Some(match segment {
+			Some((segment_ray, distance, segment_state)) =>
+				finish_transmitted_color(&segment_ray, distance, layer_color, scene, params, segment_state),
+			None => layer_color
+		});
+	}
+	color.unwrap()
+}
+
+// Visible hits of the object along the ray, nearest last
+fn find_object_hits(ray: &RayTraceRay, object_id: Option<usize>, scene: &RayTraceScene, params: &RayTraceParams)
+		-> Vec<RayTraceRayHit> {
+	let object_id = match object_id {
+		Some(object_id) => object_id,
+		None => { return Vec::new(); }
+	};
+
+	let mut hits: Vec<RayTraceRayHit> = scene.get_objects()[object_id].next_hits(ray).into_iter()
+		.filter(|hit| !hit.get_surface_material().is_cut_out() && !params.is_clipped(hit.get_position().clone()))
+		.map(|mut hit| {
+			hit.set_object_id(Some(object_id));
+			hit
+		})
+		.collect();
+	hits.reverse();
+	hits
+}
 
-			return mix_color(&material_color, &overlay_color, overlay_color.get_a());
+// The volumes in front of the surface and the clamping of indirect rays for the rays between transparent surfaces,
+// like compute_color_for_ray does for traced rays
+fn finish_transmitted_color(ray: &RayTraceRay, distance: f64, color: RayColor, scene: &RayTraceScene,
+		params: &RayTraceParams, state: RayState) -> RayColor {
+	let RayColor { color, mut passes } = color;
+	let color = scene.apply_volumes(ray, distance, color, passes.as_mut(), params);
+	let color = RayColor::new(color, passes);
+	let color = if params.is_indirect_clamped() {
+		clamp_luminance(color, params.get_clamp_indirect())
+	} else {
+		color
+	};
+
+	if state.debug {
+		info!("{}Ray color {:?}", debug_indent(state), color.color);
+	}
+	color
+}
+
+fn shade_surface_layer(ray: &RayTraceRay, hit: &RayTraceRayHit, camera: &Box<RayTraceCamera>, scene: &RayTraceScene,
+		params: &RayTraceParams, state: RayState) -> RaySurfaceLayer {
+	let (material_color, overlay_color);
+	if let &Some(ref shading_fn) = params.get_shading() {
+		let ((m, o), (m_passes, o_passes)) = if params.is_light_passes_enabled() {
+			let (colors, (m_passes, o_passes)) = shading_fn.apply_with_passes(ray, hit, camera, scene, params);
+			(colors, (Some(m_passes), Some(o_passes)))
+		} else {
+			(shading_fn.apply(ray, hit, camera, scene, params), (None, None))
+		};
+		if state.debug {
+			info!("{}Shaded color {:?}, overlay {:?}", debug_indent(state), m, o);
 		}
+
+		material_color = RayColor::new(m, m_passes);
+		overlay_color = RayColor::new(o, o_passes);
+	} else {
+		material_color = environment_color(hit.get_surface_material().get_color().clone(), scene, params);
+		overlay_color = environment_color(RayTraceColor::transparent(), scene, params);
+	}
+
+	let tint = if hit.get_surface_material().is_fresnel() {
+		Some(hit.get_surface_material().get_color().clone())
+	} else {
+		None
+	};
+
+	let mut reflectance = hit.get_surface_material().get_reflectance();
+	if hit.get_surface_material().is_fresnel() {
+		let cos_i = vec3_dot(ray.get_direction().clone(), hit.get_surface_normal().clone());
+		reflectance = compute_fresnel_schlick(reflectance as f64, cos_i) as f32;
+	}
+
+	// Reflections too weak to show aren't traced
+	let reflected_color = if reflectance > BLACK_THRESHOLD {
+		let reflected_ray = compute_reflected_ray(ray, hit, params.get_ray_offset());
+		Some(compute_color_for_ray(&reflected_ray, camera, scene, params/*, tree*/, state.reflected()))
+	} else {
+		None
+	};
+
+	RaySurfaceLayer {
+		material_color: material_color,
+		overlay_color: overlay_color,
+		transparency: hit.get_surface_material().get_transparency(),
+		tint: tint,
+		reflectance: reflectance,
+		reflected_color: reflected_color
 	}
 }
@@ -14,19 +14,7 @@ impl RayTraceAverageFilter {
 impl RayTraceSampleFilter for RayTraceAverageFilter {
 	fn filter(&self, x: usize, y: usize, width: usize, height: usize, samples: &Box<[&Vec<RayTraceSample>]>)
 			-> RayTraceColor {
-		let mut average = RayTraceColor::new_with(0.0, 0.0, 0.0, 0.0);
-		let mut sample_count: f32 = 0.0;
-
-		for sample in samples[index_of(x, y, width, height)].iter() {
-			average += &sample.color;
-			sample_count += 1.0;
-		}
-
-		if sample_count == 0.0 {
-			average
-		} else {
-			average / sample_count
-		}
+		RayTraceColor::average_of(samples[index_of(x, y, width, height)].iter().map(|sample| &sample.color))
 	}
 }
 
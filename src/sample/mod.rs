@@ -10,13 +10,21 @@ use std::cell::UnsafeCell;
 use std::io::Error as IOError;
 use std::mem::swap;
 
+use cache::RayTraceFrame;
 use color::RayTraceColor;
+use params::RayTraceParams;
 use sink::RayTraceSink;
 
 pub struct RayTraceSample {
 	pub x: f64,
 	pub y: f64,
-	pub color: RayTraceColor
+	pub color: RayTraceColor,
+	pub object_id: Option<usize>,
+	// Distance from the camera to the primary hit
+	pub depth: Option<f64>,
+	// Split of the color into the light passes (see RayTraceLightPasses) in the averaging space, empty if the
+	// passes are disabled
+	pub passes: Vec<RayTraceColor>
 }
 
 pub trait RayTraceSampleFilter {
@@ -88,33 +96,92 @@ impl RayTraceSampleAccumulator {
 		}
 	}
 
-	pub fn flush(&self, sink: &mut Box<RayTraceSink>, frame: usize) -> Result<(), IOError> {
+	// Unfiltered average of the samples of one pixel
+	pub fn get_average(&self, x: usize, y: usize) -> RayTraceColor {
 		if let Some(ref samples) = self.samples {
-			try!(sink.start_frame(frame));
+			unsafe {
+				RayTraceColor::average_of((*samples[index_of(x, y, self.width, self.height)].get()).iter()
+					.map(|sample| &sample.color))
+			}
+		} else {
+			panic!("Using uninitialized SampleAcumulator!")
+		}
+	}
+
+	pub fn flush(&self, sink: &mut Box<RayTraceSink>, frame: usize, params: &RayTraceParams) -> Result<(), IOError> {
+		self.resolve(params).write(sink, frame)
+	}
 
+	// Filters the samples into the final frame without writing it
+	pub fn resolve(&self, params: &RayTraceParams) -> RayTraceFrame {
+		if let Some(ref samples) = self.samples {
 			let mut data = box Vec::with_capacity(samples.len());
 			for sample in samples.iter() {
 				unsafe {
 					data.push(& *sample.get());
 				}
 			}
-			let data_slice = data.into_boxed_slice();
+			self.resolve_samples(params, data.into_boxed_slice())
+		} else {
+			panic!("Using uninitialized SampleAcumulator!")
+		}
+	}
 
-			for y in 0..self.height {
-				for x in 0..self.width {
-					let color = self.filter.filter(x, y, self.width, self.height, &data_slice);
-					try!(sink.set_sample(x, y, &color))
-				}
+	// Resolves one light pass of the samples the same way as the frame
+	pub fn resolve_pass(&self, params: &RayTraceParams, pass: usize) -> RayTraceFrame {
+		if let Some(ref samples) = self.samples {
+			let mut pass_samples = Vec::with_capacity(samples.len());
+			for sample in samples.iter() {
+				let pixel_samples = unsafe { & *sample.get() };
+				pass_samples.push(pixel_samples.iter().map(|sample| RayTraceSample {
+					x: sample.x,
+					y: sample.y,
+					color: sample.passes[pass].clone(),
+					object_id: sample.object_id,
+					depth: sample.depth,
+					passes: Vec::new()
+				}).collect::<Vec<RayTraceSample>>());
 			}
 
-			try!(sink.finish_frame(frame));
-
-			Ok(())
+			let data: Vec<&Vec<RayTraceSample>> = pass_samples.iter().collect();
+			self.resolve_samples(params, data.into_boxed_slice())
 		} else {
 			panic!("Using uninitialized SampleAcumulator!")
 		}
 	}
 
+	fn resolve_samples(&self, params: &RayTraceParams, data_slice: Box<[&Vec<RayTraceSample>]>) -> RayTraceFrame {
+		// Post effects need the whole linear frame
+		let mut colors = Vec::with_capacity(self.width * self.height);
+		for y in 0..self.height {
+			let source_y = params.get_preview_row(y);
+			for x in 0..self.width {
+				colors.push(params.from_averaging_space(self.filter.filter(x, source_y, self.width, self.height,
+					&data_slice)));
+			}
+		}
+
+		if let Some(ref bloom) = *params.get_bloom() {
+			bloom.apply(&mut colors, self.width, self.height);
+		}
+
+		let mut luminances = Vec::with_capacity(self.width * self.height);
+		let mut object_ids = Vec::with_capacity(self.width * self.height);
+		let mut depths = Vec::with_capacity(self.width * self.height);
+		for y in 0..self.height {
+			for x in 0..self.width {
+				let index = index_of(x, y, self.width, self.height);
+				luminances.push(colors[index].get_luminance());
+				colors[index] = params.apply_display_transform(colors[index].clone());
+				let source_index = index_of(x, params.get_preview_row(y), self.width, self.height);
+				object_ids.push(get_object_id(&data_slice[source_index]));
+				depths.push(params.map_depth(get_depth(&data_slice[source_index])));
+			}
+		}
+
+		RayTraceFrame::new(self.width, self.height, colors, luminances, object_ids, depths)
+	}
+
 	pub fn destroy(&mut self) -> Option<Box<RayTraceSampleFilter + Sync>> {
 		let mut filter: Box<RayTraceSampleFilter + Sync> = box RayTraceAverageFilter::new();
 		swap(&mut filter, &mut self.filter);
@@ -122,6 +189,29 @@ impl RayTraceSampleAccumulator {
 	}
 }
 
+// The object covering most of the samples
+fn get_object_id(samples: &Vec<RayTraceSample>) -> Option<usize> {
+	let mut counts: Vec<(usize, usize)> = Vec::new();
+	for sample in samples.iter() {
+		if let Some(object_id) = sample.object_id {
+			match counts.iter().position(|&(id, _)| id == object_id) {
+				Some(index) => { counts[index].1 += 1; },
+				None => { counts.push((object_id, 1)); }
+			}
+		}
+	}
+
+	counts.iter().max_by_key(|&&(_, count)| count).map(|&(id, _)| id)
+}
+
+// The nearest surface seen by any sample, so edges don't get depths in between the objects
+fn get_depth(samples: &Vec<RayTraceSample>) -> Option<f64> {
+	samples.iter().filter_map(|sample| sample.depth).fold(None, |nearest, depth| match nearest {
+		Some(nearest) if nearest <= depth => Some(nearest),
+		_ => Some(depth)
+	})
+}
+
 unsafe impl Sync for RayTraceSampleAccumulator { }
 unsafe impl Send for RayTraceSampleAccumulator { }
 
@@ -1,15 +1,49 @@
+use std::cmp::Ordering;
+use std::collections::BTreeMap;
+use std::f64;
+use std::fmt;
 use std::mem;
 
+use vecmath::Vector3;
+use vecmath::vec3_dot;
+use vecmath::vec3_sub;
+
 use nonsync::Unsafe;
 use nonsync::UnsafeRef;
 
+use aabb::AABB;
+use bvh::RayTraceBvh;
+use color::BLACK_THRESHOLD;
+use color::RayTraceColor;
+use hit::RayTraceHitHeapEntry;
+use hit::RayTraceRayHit;
 use object::RayTraceObject;
+use object::RayTraceSetupError;
+use light::RayTraceCausticMap;
 use light::RayTraceLight;
+use light::RayTraceLightPasses;
+use math_util::RayTraceCoordinateSystem;
+#[cfg(feature = "ray_packets")]
+use packet::{RayTracePacket, PACKET_SIZE};
+use params::RayTraceParams;
+use ray::RayTraceRay;
+use scratch::with_hit_heap;
 
 #[allow(dead_code)]
 pub struct RayTraceScene {
 	objects: Vec<Unsafe<Box<RayTraceObject>>>,
-	lights: Vec<Unsafe<Box<RayTraceLight>>>
+	lights: Vec<Unsafe<Box<RayTraceLight>>>,
+	coordinate_system: RayTraceCoordinateSystem,
+	caustics: Option<RayTraceCausticMap>,
+	camera_media: Vec<f64>,
+	// Ids of the objects that are volumes (fog, ...), collected by init
+	volumes: Vec<usize>,
+	// Built by init over the bounded objects, the unbounded ones are always tested
+	bvh: Option<RayTraceBvh>,
+	unbounded: Vec<usize>,
+	// Object bounds the hierarchy was built for, it is only rebuilt once they change
+	bvh_bounds: Vec<Option<AABB>>,
+	bvh_builds: usize
 }
 
 #[allow(dead_code, unused_variables)]
@@ -17,30 +51,544 @@ impl RayTraceScene {
 	pub fn new() -> Self {
 		Self {
 			objects: Vec::new(),
-			lights: Vec::new()
+			lights: Vec::new(),
+			coordinate_system: RayTraceCoordinateSystem::RightHandedYUp,
+			caustics: None,
+			camera_media: Vec::new(),
+			volumes: Vec::new(),
+			bvh: None,
+			unbounded: Vec::new(),
+			bvh_bounds: Vec::new(),
+			bvh_builds: 0
 		}
 	}
 
-	pub fn init(&mut self, frame: usize) {
+	pub fn set_coordinate_system(&mut self, coordinate_system: RayTraceCoordinateSystem) {
+		self.coordinate_system = coordinate_system;
+	}
+
+	pub fn get_coordinate_system(&self) -> RayTraceCoordinateSystem {
+		self.coordinate_system
+	}
+
+	pub fn prepare(&mut self) {
 		for obj in self.objects.iter_mut() {
-			obj.init(frame);
+			obj.set_coordinate_system(self.coordinate_system);
+			obj.prepare();
+		}
+	}
+
+	// Stops at the first object that can't be set up, the error names it by index and name
+	pub fn init(&mut self, frame: usize) -> Result<(), RayTraceSetupError> {
+		for (index, obj) in self.objects.iter_mut().enumerate() {
+			obj.set_coordinate_system(self.coordinate_system);
+			if let Err(mut error) = obj.init(frame) {
+				if error.get_object().is_none() {
+					error.set_object(&format!("{} '{}'", index, obj.get_name().unwrap_or("")));
+				}
+				return Err(error);
+			}
 		}
 
+		self.update_bvh();
+
+		self.volumes = self.objects.iter().enumerate()
+			.filter(|&(_, obj)| obj.is_volume())
+			.map(|(index, _)| index)
+			.collect();
+
+		let object_bounds: Vec<Option<&AABB>> = self.objects.iter().map(|obj| obj.get_aabb()).collect();
 		for light in self.lights.iter_mut() {
+			light.set_coordinate_system(self.coordinate_system);
 			light.init(frame);
+			light.init_shadow_casters(&object_bounds);
+		}
+
+		Ok(())
+	}
+
+	// Number of times init had to build the hierarchy, camera only animations of static scenes build it once
+	pub fn get_bvh_build_count(&self) -> usize {
+		self.bvh_builds
+	}
+
+	// Added objects and objects whose bounds changed in init (moved, resized, ...) make the hierarchy outdated
+	fn update_bvh(&mut self) {
+		let unchanged = self.bvh.is_some() && self.bvh_bounds.len() == self.objects.len()
+			&& self.objects.iter().zip(self.bvh_bounds.iter())
+				.all(|(obj, bounds)| match (obj.get_aabb(), bounds.as_ref()) {
+					(Some(aabb), Some(bounds)) => aabb.get_start() == bounds.get_start()
+						&& aabb.get_end() == bounds.get_end(),
+					(None, None) => true,
+					_ => false
+				});
+		if unchanged {
+			return;
+		}
+
+		self.bvh_bounds = self.objects.iter().map(|obj| obj.get_aabb().cloned()).collect();
+		let bounded: Vec<(usize, AABB)> = self.bvh_bounds.iter().enumerate()
+			.filter_map(|(id, bounds)| bounds.as_ref().map(|aabb| (id, aabb.clone())))
+			.collect();
+		self.unbounded = self.bvh_bounds.iter().enumerate()
+			.filter(|&(_, bounds)| bounds.is_none())
+			.map(|(id, _)| id)
+			.collect();
+		self.bvh = Some(RayTraceBvh::new(&bounded));
+		self.bvh_builds += 1;
+	}
+
+	// Only valid after init, objects without an AABB (planes, ...) are ignored
+	pub fn get_bounds(&self) -> Option<AABB> {
+		let mut bounds: Option<AABB> = None;
+
+		for obj in self.objects.iter() {
+			if let Some(aabb) = obj.get_aabb() {
+				if let Some(ref mut bounds) = bounds {
+					bounds.expand_aabb(aabb);
+					continue;
+				}
+
+				bounds = Some(aabb.clone());
+			}
+		}
+
+		bounds
+	}
+
+	// Nearest surface along the ray (picking, line of sight, ...) without any shading.
+	// Clip planes are ignored, the returned hit knows the index of the object.
+	pub fn ray_cast(&self, ray: &RayTraceRay) -> Option<RayTraceRayHit> {
+		self.find_nearest_hit(ray, |object| object.next_solid_hit(ray))
+	}
+
+	// Same as ray_cast, but surfaces clipped away by the params are skipped like the renderer does
+	pub fn ray_cast_visible(&self, ray: &RayTraceRay, params: &RayTraceParams) -> Option<RayTraceRayHit> {
+		self.find_nearest_hit(ray, |object| object.next_visible_hit(ray, params))
+	}
+
+	// Same as ray_cast_visible for the rays of a packet, the hierarchy is walked once for all of them.
+	// Clip planes and scenes that are not initialized yet fall back to casting the rays one by one.
+	#[cfg(feature = "ray_packets")]
+	pub fn ray_cast_visible_packet(&self, rays: &[RayTraceRay], params: &RayTraceParams)
+			-> Vec<Option<RayTraceRayHit>> {
+		let bvh = match self.bvh {
+			Some(ref bvh) if params.get_clip_planes().is_empty() => bvh,
+			_ => { return rays.iter().map(|ray| self.ray_cast_visible(ray, params)).collect(); }
+		};
+
+		let packet = RayTracePacket::new(rays);
+		let mut nearest_hits: Vec<Option<RayTraceRayHit>> = rays.iter().map(|_| None).collect();
+		let mut max_distances = [f64::INFINITY; PACKET_SIZE];
+
+		{
+			let mut visit = |object_id: usize, lanes: [bool; PACKET_SIZE], max_distances: &mut [f64; PACKET_SIZE]| {
+				let object = &self.objects[object_id];
+				let aabb_hits = match object.get_aabb() {
+					Some(aabb) => packet.is_hit(aabb),
+					None => lanes
+				};
+
+				for (lane, ray) in rays.iter().enumerate() {
+					if !lanes[lane] || !aabb_hits[lane] {
+						continue;
+					}
+
+					if let Some(mut hit) = object.next_visible_hit(ray, params) {
+						if hit.get_distance() < max_distances[lane] {
+							max_distances[lane] = hit.get_distance();
+							hit.set_object_id(Some(object_id));
+							nearest_hits[lane] = Some(hit);
+						}
+					}
+				}
+			};
+
+			let mut all_lanes = [false; PACKET_SIZE];
+			for lane in 0..rays.len() {
+				all_lanes[lane] = true;
+			}
+			for &object_id in self.unbounded.iter() {
+				visit(object_id, all_lanes, &mut max_distances);
+			}
+
+			bvh.walk_packet(&packet, &mut max_distances, &mut visit);
+		}
+
+		nearest_hits
+	}
+
+	fn find_nearest_hit<F>(&self, ray: &RayTraceRay, next_hit: F) -> Option<RayTraceRayHit>
+			where F: Fn(&Box<RayTraceObject>) -> Option<RayTraceRayHit> {
+		// Collect all ray hits
+		let nearest_hit = with_hit_heap(|ray_hits| {
+			if let Some(ref bvh) = self.bvh {
+				for &object_id in self.unbounded.iter() {
+					if let Some(mut hit) = next_hit(&self.objects[object_id]) {
+						hit.set_object_id(Some(object_id));
+						ray_hits.push(RayTraceHitHeapEntry::new(hit.get_distance(), hit));
+					}
+				}
+
+				// Nodes behind the nearest hit so far are skipped
+				let max_distance = ray_hits.peek().map_or(f64::INFINITY, |entry| entry.value.get_distance());
+				bvh.walk(ray, max_distance, |object_id, max_distance| {
+					let object = &self.objects[object_id];
+					if !object.get_aabb().map_or(true, |aabb| aabb.is_hit(ray)) {
+						return max_distance;
+					}
+
+					match next_hit(object) {
+						Some(mut hit) => {
+							let distance = hit.get_distance();
+							hit.set_object_id(Some(object_id));
+							ray_hits.push(RayTraceHitHeapEntry::new(distance, hit));
+							max_distance.min(distance)
+						},
+						None => max_distance
+					}
+				});
+
+				return ray_hits.pop();
+			}
+
+			// Not initialized yet
+			for (object_id, object) in self.objects.iter().enumerate() {
+				if let Some(aabb) = object.get_aabb() {
+					if !aabb.is_hit(ray) {
+						continue;
+					}
+				}
+
+				if let Some(mut hit) = next_hit(object) {
+					hit.set_object_id(Some(object_id));
+					ray_hits.push(RayTraceHitHeapEntry::new(hit.get_distance(), hit));
+				}
+			}
+
+			ray_hits.pop()
+		});
+
+		nearest_hit.map(|entry| entry.value)
+	}
+
+	// Whether a surface blocks the shadow ray before it reaches the light, only the shadow casters of the light
+	// are tested
+	pub fn is_light_blocked(&self, light: &Box<RayTraceLight>, light_ray: &RayTraceRay, light_distance: f64,
+			params: &RayTraceParams) -> bool {
+		match light.get_shadow_casters() {
+			Some(casters) => casters.iter()
+				.any(|&object_id| occludes_light(&self.objects[object_id], light_ray, light_distance, params)),
+			None => match self.bvh {
+				Some(ref bvh) => {
+					if self.unbounded.iter()
+							.any(|&object_id| occludes_light(&self.objects[object_id], light_ray, light_distance, params)) {
+						return true;
+					}
+
+					let mut blocked = false;
+					bvh.walk(light_ray, light_distance, |object_id, max_distance| {
+						if occludes_light(&self.objects[object_id], light_ray, light_distance, params) {
+							blocked = true;
+							f64::NEG_INFINITY
+						} else {
+							max_distance
+						}
+					});
+					blocked
+				},
+				None => self.objects.iter().any(|object| occludes_light(object, light_ray, light_distance, params))
+			}
+		}
+	}
+
+	// Composites the volumes between the ray origin and the distance over the color arriving from there,
+	// the farthest volume first. The light passes (if given) follow the color. Only valid after init.
+	pub fn apply_volumes(&self, ray: &RayTraceRay, distance: f64, color: RayTraceColor,
+			mut passes: Option<&mut RayTraceLightPasses>, params: &RayTraceParams) -> RayTraceColor {
+		if self.volumes.is_empty() {
+			return color;
+		}
+
+		let mut volumes: Vec<(f64, usize)> = self.volumes.iter()
+			.filter_map(|&id| match self.objects[id].get_aabb() {
+				Some(aabb) => aabb.get_interval(ray).map(|(start, _)| (start, id)),
+				None => Some((0.0, id))
+			})
+			.filter(|&(start, _)| start < distance)
+			.collect();
+		volumes.sort_by(|a, b| b.0.partial_cmp(&a.0).unwrap_or(Ordering::Equal));
+
+		volumes.into_iter().fold(color, |color, (_, id)| {
+			let passes = passes.as_mut().map(|passes| &mut **passes);
+			self.apply_volume(id, ray, distance, color, passes, params)
+		})
+	}
+
+	// Marches the ray through the volume in steps, the medium absorbs part of the color arriving from behind and
+	// scatters the light of the scene lights towards the ray (light shafts behind occluders, ...)
+	fn apply_volume(&self, id: usize, ray: &RayTraceRay, distance: f64, color: RayTraceColor,
+			passes: Option<&mut RayTraceLightPasses>, params: &RayTraceParams) -> RayTraceColor {
+		let object = &self.objects[id];
+		let medium = match object.get_volume_medium() {
+			Some(medium) => medium,
+			None => { return color; }
+		};
+
+		let (start, end) = match object.get_aabb() {
+			Some(aabb) => match aabb.get_interval(ray) {
+				Some((start, end)) => (start, end.min(distance)),
+				None => { return color; }
+			},
+			None => (0.0, distance)
+		};
+		if end <= start || !end.is_finite() {
+			return color;
+		}
+
+		let steps = (((end - start) / medium.get_step_size()).ceil() as usize).max(1).min(medium.get_max_steps());
+		let step = (end - start) / steps as f64;
+		let direction = ray.get_direction().clone();
+		let ambient_light = params.get_ambient_light();
+		let ambient = [ambient_light.get_r() * ambient_light.get_a(), ambient_light.get_g() * ambient_light.get_a(),
+			ambient_light.get_b() * ambient_light.get_a()];
+
+		// The ambient light goes to the first pass, each light to its own one
+		// (empty without passes, so only renders with light passes allocate it)
+		let pass_count = passes.as_ref().map(|passes| passes.get_count()).unwrap_or(0);
+		let mut scattered_passes = vec![[0.0; 3]; pass_count];
+
+		let mut transmittance = 1.0;
+		let mut scattered = [0.0; 3];
+
+		for index in 0..steps {
+			// Middle of the step
+			let step_distance = start + (index as f64 + 0.5) * step;
+			let density = object.get_volume_density(ray, step_distance);
+			if density <= 0.0 {
+				continue;
+			}
+
+			// Light scattered within the step that makes it out of the volume in front of it
+			let step_transmittance = (-density * step).exp();
+			let weight = transmittance * (1.0 - step_transmittance);
+			transmittance *= step_transmittance;
+
+			add_scattered(&mut scattered, weight, ambient);
+			if pass_count > 0 {
+				add_scattered(&mut scattered_passes[0], weight, ambient);
+			}
+
+			let position = ray.get_position_on_ray(step_distance);
+			for (light_index, scene_light) in self.get_lights().iter().enumerate() {
+				if !scene_light.affects(Some(id)) {
+					continue;
+				}
+
+				let (light_direction, light_distance, light_color) = scene_light.sample_toward(position);
+				if light_color.is_black(BLACK_THRESHOLD) || light_color.get_a() <= BLACK_THRESHOLD {
+					continue;
+				}
+
+				let light_ray = ray.derive(position, light_direction);
+				if self.is_light_blocked(scene_light, &light_ray, light_distance, params) {
+					continue;
+				}
+
+				let phase = (henyey_greenstein(vec3_dot(light_direction, direction), medium.get_anisotropy()) as f32)
+					* light_color.get_a();
+				let light = [light_color.get_r() * phase, light_color.get_g() * phase, light_color.get_b() * phase];
+
+				add_scattered(&mut scattered, weight, light);
+				if pass_count > 0 {
+					add_scattered(&mut scattered_passes[light_index + 1], weight, light);
+				}
+			}
+		}
+
+		// Straight alpha, the volume covers the part of the color behind that it absorbs
+		let behind = color.get_a() as f64 * transmittance;
+		let alpha = behind + (1.0 - transmittance);
+		if alpha <= 0.0 {
+			return color;
+		}
+
+		let medium_color = medium.get_color();
+		let medium_color = [medium_color.get_r(), medium_color.get_g(), medium_color.get_b()];
+		let composite = |color: &RayTraceColor, scattered: &[f64; 3]| {
+			let mut channels = [0.0; 3];
+			for channel in 0..3 {
+				let scattered = scattered[channel] * (medium_color[channel] * medium.get_scattering()) as f64;
+				channels[channel] = ((color.channel(channel) as f64 * behind + scattered) / alpha) as f32;
+			}
+			RayTraceColor::new_with(channels[0], channels[1], channels[2], alpha as f32)
+		};
+
+		if let Some(passes) = passes {
+			for (pass_color, pass_scattered) in passes.get_colors_mut().iter_mut().zip(scattered_passes.iter()) {
+				*pass_color = composite(&*pass_color, pass_scattered);
+			}
+		}
+
+		composite(&color, &scattered)
+	}
+
+	// Ids of the objects whose bounds overlap the box (box select, region based light linking, ...).
+	// Only valid after init, objects without an AABB (planes, ...) are unbounded and always included.
+	pub fn objects_in_aabb(&self, aabb: &AABB) -> Vec<usize> {
+		match self.bvh {
+			Some(ref bvh) => {
+				let mut object_ids = self.unbounded.clone();
+				bvh.walk_aabb(aabb, |object_id| {
+					if self.objects[object_id].get_aabb().map_or(true, |bounds| bounds.intersects(aabb)) {
+						object_ids.push(object_id);
+					}
+				});
+
+				object_ids.sort();
+				object_ids
+			},
+			None => self.objects.iter().enumerate()
+				.filter(|&(_, object)| object.get_aabb().map_or(true, |bounds| bounds.intersects(aabb)))
+				.map(|(object_id, _)| object_id)
+				.collect()
+		}
+	}
+
+	// Nearest visible hit of the other objects in front of the distance (surfaces between the hits of one object).
+	// Only the objects in the bounds of that stretch of the ray are tested.
+	pub fn ray_cast_visible_before(&self, ray: &RayTraceRay, max_distance: f64, skipped_object_id: usize,
+			params: &RayTraceParams) -> Option<RayTraceRayHit> {
+		let bounds = AABB::new(ray.get_position().clone(), ray.get_position_on_ray(max_distance));
+		self.objects_in_aabb(&bounds).into_iter()
+			.filter(|&object_id| object_id != skipped_object_id)
+			.filter_map(|object_id| self.objects[object_id].next_visible_hit(ray, params).map(|mut hit| {
+				hit.set_object_id(Some(object_id));
+				hit
+			}))
+			.filter(|hit| hit.get_distance() < max_distance)
+			.min_by(|a, b| a.get_distance().partial_cmp(&b.get_distance()).unwrap_or(Ordering::Equal))
+	}
+
+	// Overview of what the scene consists of, only valid after init
+	pub fn statistics(&self) -> RayTraceSceneStats {
+		let mut object_counts = BTreeMap::new();
+		let mut triangle_count = 0;
+		for obj in self.objects.iter() {
+			*object_counts.entry(obj.get_type_name().to_string()).or_insert(0) += 1;
+			triangle_count += obj.get_triangle_count();
+		}
+
+		let mut light_counts = BTreeMap::new();
+		for light in self.lights.iter() {
+			*light_counts.entry(light.get_type_name().to_string()).or_insert(0) += 1;
+		}
+
+		RayTraceSceneStats {
+			object_counts: object_counts,
+			light_counts: light_counts,
+			triangle_count: triangle_count,
+			bounds: self.get_bounds(),
+			has_unbounded: self.objects.iter().any(|obj| obj.get_aabb().is_none())
+		}
+	}
+
+	// Built by the renderer after init if enabled in the params
+	pub fn set_caustics(&mut self, caustics: Option<RayTraceCausticMap>) {
+		self.caustics = caustics;
+	}
+
+	pub fn get_caustics(&self) -> Option<&RayTraceCausticMap> {
+		self.caustics.as_ref()
+	}
+
+	// Refraction indices of the transparent objects containing the point, outermost first.
+	// The material is taken from where a ray in the direction leaves the object, only valid after init.
+	pub fn find_media_at(&self, point: Vector3<f64>, direction: Vector3<f64>) -> Vec<f64> {
+		let ray = RayTraceRay::new(point, direction);
+		let mut media: Vec<(f64, f64)> = Vec::new();
+
+		for obj in self.objects.iter().filter(|obj| obj.contains(point)) {
+			if let Some(hit) = obj.next_solid_hit(&ray) {
+				let material = hit.get_surface_material();
+				if material.get_transparency() > 0.0 {
+					// Nested objects are smaller than the ones around them
+					let volume = obj.get_aabb().map_or(f64::INFINITY, |aabb| {
+						let size = vec3_sub(*aabb.get_end(), *aabb.get_start());
+						size[0] * size[1] * size[2]
+					});
+					media.push((volume, material.get_refraction_index() as f64));
+				}
+			}
 		}
+
+		media.sort_by(|a, b| b.0.partial_cmp(&a.0).unwrap_or(Ordering::Equal));
+		media.into_iter().map(|(_, refraction_index)| refraction_index).collect()
+	}
+
+	// Built by the renderer after init, primary rays start inside these media
+	pub fn set_camera_media(&mut self, camera_media: Vec<f64>) {
+		self.camera_media = camera_media;
+	}
+
+	pub fn get_camera_media(&self) -> &[f64] {
+		&self.camera_media
 	}
 
 	pub fn get_objects(&self) -> &Vec<Unsafe<Box<RayTraceObject>>> {
 		&self.objects
 	}
 
+	pub fn find_by_name(&self, name: &str) -> Option<&Box<RayTraceObject>> {
+		self.objects.iter().map(|obj| &**obj).find(|obj| obj.get_name() == Some(name))
+	}
+
+	// Object id (index in the scene) of the object with the name
+	pub fn find_id_by_name(&self, name: &str) -> Option<usize> {
+		self.objects.iter().position(|obj| obj.get_name() == Some(name))
+	}
+
+	// Moves all objects and lights of the other scene into this one. The other objects get the next free ids
+	// (the light linking of the other lights follows them) and duplicate names are made unique with a numbered
+	// suffix ("name.001", ...).
+	pub fn merge(&mut self, other: RayTraceScene) {
+		if other.coordinate_system != self.coordinate_system {
+			warn!("Merging a scene with coordinate system {:?} into one with {:?}", other.coordinate_system,
+				self.coordinate_system);
+		}
+
+		let offset = self.objects.len();
+		for mut obj in other.objects.into_iter() {
+			let name = obj.get_name().map(|name| name.to_string());
+			if let Some(name) = name {
+				if self.find_by_name(&name).is_some() {
+					let mut index = 1;
+					while self.find_by_name(&format!("{}.{:03}", name, index)).is_some() {
+						index += 1;
+					}
+					obj.set_name(&format!("{}.{:03}", name, index));
+				}
+			}
+
+			self.objects.push(obj);
+		}
+
+		for mut light in other.lights.into_iter() {
+			light.offset_object_ids(offset);
+			self.lights.push(light);
+		}
+		self.bvh = None;
+		self.caustics = None;
+		self.camera_media.clear();
+	}
+
 	pub fn add_object<T: RayTraceObject + 'static>(&mut self, object: Box<T>) -> UnsafeRef<Box<T>> {
 		// Totally safe from here ...
 		let cell = Unsafe::<Box<RayTraceObject>>::new(object);
 		let cell_ref = cell.get_ref();
 
 		self.objects.push(cell);
+		self.bvh = None;
 
 		unsafe {
 			mem::transmute(cell_ref)
@@ -62,4 +610,252 @@ impl RayTraceScene {
 			mem::transmute(cell_ref)
 		}
 	}
-}
\ No newline at end of file
+}
+
+fn occludes_light(object: &Box<RayTraceObject>, light_ray: &RayTraceRay, light_distance: f64,
+		params: &RayTraceParams) -> bool {
+	if let Some(aabb) = object.get_aabb() {
+		if !aabb.is_hit(light_ray) {
+			return false;
+		}
+	}
+
+	if params.get_clip_planes().is_empty() {
+		object.occludes(light_ray, light_distance)
+	} else {
+		object.next_visible_hit(light_ray, params)
+			.map_or(false, |hit| hit.get_distance() > 0.0 && hit.get_distance() < light_distance)
+	}
+}
+
+// Phase function scaled so that an isotropic medium (anisotropy 0) scatters with a factor of 1 in all directions.
+// The cosine is between the direction towards the light and the direction of the ray.
+fn henyey_greenstein(cos_theta: f64, anisotropy: f64) -> f64 {
+	let g = anisotropy;
+	let denominator = 1.0 + g * g - 2.0 * g * cos_theta;
+	(1.0 - g * g) / (denominator * denominator.sqrt())
+}
+
+fn add_scattered(scattered: &mut [f64; 3], weight: f64, light: [f32; 3]) {
+	for channel in 0..3 {
+		scattered[channel] += weight * light[channel] as f64;
+	}
+}
+
+pub struct RayTraceSceneStats {
+	object_counts: BTreeMap<String, usize>,
+	light_counts: BTreeMap<String, usize>,
+	triangle_count: usize,
+	bounds: Option<AABB>,
+	has_unbounded: bool
+}
+
+#[allow(dead_code)]
+impl RayTraceSceneStats {
+	// Number of objects by type name
+	pub fn get_object_counts(&self) -> &BTreeMap<String, usize> {
+		&self.object_counts
+	}
+
+	pub fn get_object_count(&self) -> usize {
+		self.object_counts.values().sum()
+	}
+
+	// Number of lights by type name
+	pub fn get_light_counts(&self) -> &BTreeMap<String, usize> {
+		&self.light_counts
+	}
+
+	pub fn get_light_count(&self) -> usize {
+		self.light_counts.values().sum()
+	}
+
+	pub fn get_triangle_count(&self) -> usize {
+		self.triangle_count
+	}
+
+	// Bounds of all bounded objects
+	pub fn get_bounds(&self) -> Option<&AABB> {
+		self.bounds.as_ref()
+	}
+
+	// Whether any object has no AABB (planes, ...)
+	pub fn has_unbounded(&self) -> bool {
+		self.has_unbounded
+	}
+}
+
+impl fmt::Display for RayTraceSceneStats {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		try!(write!(f, "{} objects", self.get_object_count()));
+		for (type_name, count) in self.object_counts.iter() {
+			try!(write!(f, ", {} {}", count, type_name));
+		}
+		try!(write!(f, "; {} triangles; {} lights", self.triangle_count, self.get_light_count()));
+		for (type_name, count) in self.light_counts.iter() {
+			try!(write!(f, ", {} {}", count, type_name));
+		}
+
+		match self.bounds {
+			Some(ref bounds) => try!(write!(f, "; bounds {:?} to {:?}", bounds.get_start(), bounds.get_end())),
+			None => try!(write!(f, "; no bounds"))
+		}
+		if self.has_unbounded {
+			try!(write!(f, " (some objects are unbounded)"));
+		}
+
+		Ok(())
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use std::collections::HashSet;
+	use std::f64;
+
+	use aabb::AABB;
+	use color::RayTraceColor;
+	#[cfg(feature = "ray_packets")]
+	use hit::RayTraceRayHit;
+	use light::RayTraceLightLinking;
+	use light::lights::RayTraceSpotLight;
+	use material::RayTraceSimpleMaterial;
+	use object::RayTraceObjectFog;
+	use object::RayTraceObjectPlane;
+	use object::RayTraceObjectSphere;
+	use params::RayTraceParams;
+	use ray::RayTraceRay;
+
+	use super::RayTraceScene;
+
+	fn glass_sphere(size: f64, refraction_index: f32) -> Box<RayTraceObjectSphere> {
+		Box::new(RayTraceObjectSphere::new([0.0, 0.0, 0.0], size,
+			Box::new(RayTraceSimpleMaterial::dielectric(refraction_index, RayTraceColor::white()))))
+	}
+
+	#[test]
+	fn nested_media_are_ordered_outermost_first() {
+		// Glass in water in a large bubble of oil, added in an unrelated order, and an opaque sphere around them
+		let mut scene = RayTraceScene::new();
+		scene.add_object(glass_sphere(2.0, 1.5));
+		scene.add_object(glass_sphere(8.0, 1.47));
+		scene.add_object(Box::new(RayTraceObjectSphere::new([0.0, 0.0, 0.0], 20.0,
+			Box::new(RayTraceSimpleMaterial::new(RayTraceColor::white())))));
+		scene.add_object(glass_sphere(4.0, 1.33));
+		scene.init(0).unwrap();
+
+		let media_at = |point| -> Vec<f32> {
+			scene.find_media_at(point, [0.0, 0.0, 1.0]).into_iter().map(|index| index as f32).collect()
+		};
+		assert_eq!(media_at([0.0, 0.0, 0.0]), vec![1.47, 1.33, 1.5]);
+		assert_eq!(media_at([1.5, 0.0, 0.0]), vec![1.47, 1.33]);
+		assert_eq!(media_at([3.0, 0.0, 0.0]), vec![1.47]);
+		assert!(media_at([5.0, 0.0, 0.0]).is_empty());
+	}
+
+	#[test]
+	fn objects_in_aabb_walks_the_hierarchy() {
+		// A row of small spheres at x = 0, 2, 4, ... and a plane, which is unbounded
+		let mut scene = RayTraceScene::new();
+		for i in 0..16 {
+			scene.add_object(Box::new(RayTraceObjectSphere::new([2.0 * i as f64, 0.0, 0.0], 1.0,
+				Box::new(RayTraceSimpleMaterial::new(RayTraceColor::white())))));
+		}
+		scene.add_object(Box::new(RayTraceObjectPlane::new([0.0, -5.0, 0.0], [0.0, 0.0, 0.0],
+			Box::new(RayTraceSimpleMaterial::new(RayTraceColor::white())))));
+		scene.init(0).unwrap();
+
+		assert_eq!(scene.objects_in_aabb(&AABB::new([3.5, -0.5, -0.5], [8.2, 0.5, 0.5])), vec![2, 3, 4, 16]);
+		assert_eq!(scene.objects_in_aabb(&AABB::new([0.0, 10.0, 0.0], [40.0, 11.0, 1.0])), vec![16]);
+	}
+
+	#[test]
+	fn ray_casts_before_a_distance_skip_the_object() {
+		// A small sphere inside of a large glass sphere
+		let mut scene = RayTraceScene::new();
+		scene.add_object(glass_sphere(4.0, 1.5));
+		scene.add_object(Box::new(RayTraceObjectSphere::new([0.0, 0.0, 0.0], 1.0,
+			Box::new(RayTraceSimpleMaterial::new(RayTraceColor::white())))));
+		scene.init(0).unwrap();
+
+		// From the front of the large sphere towards its back
+		let params = RayTraceParams::new();
+		let ray = RayTraceRay::new([-4.0, 0.0, 0.0], [1.0, 0.0, 0.0]);
+		let hit = scene.ray_cast_visible_before(&ray, 8.0, 0, &params).unwrap();
+		assert_eq!(hit.get_object_id(), Some(1));
+		assert!((hit.get_distance() - 3.0).abs() < 1.0e-6);
+
+		assert!(scene.ray_cast_visible_before(&ray, 2.0, 0, &params).is_none());
+		assert!(scene.ray_cast_visible_before(&RayTraceRay::new([-4.0, 2.0, 0.0], [1.0, 0.0, 0.0]), 8.0, 0, &params)
+			.is_none());
+	}
+
+	#[test]
+	fn volumes_absorb_the_color_behind_them() {
+		// Fog cube of size 2 around the origin without any light to scatter
+		let mut scene = RayTraceScene::new();
+		scene.add_object(Box::new(RayTraceObjectFog::new([0.0, 0.0, 0.0], [2.0, 2.0, 2.0], RayTraceColor::white(),
+			1.0)));
+		scene.init(0).unwrap();
+
+		let mut params = RayTraceParams::new();
+		params.set_ambient_light(RayTraceColor::new_with(0.0, 0.0, 0.0, 0.0));
+
+		// Through the whole fog and up to a surface in its middle
+		let ray = RayTraceRay::new([0.0, 0.0, -5.0], [0.0, 0.0, 1.0]);
+		let color = scene.apply_volumes(&ray, f64::INFINITY, RayTraceColor::white(), None, &params);
+		assert!((color.get_r() as f64 - (-2.0_f64).exp()).abs() < 1.0e-4);
+		assert!((color.get_a() - 1.0).abs() < 1.0e-6);
+
+		let color = scene.apply_volumes(&ray, 4.5, RayTraceColor::white(), None, &params);
+		assert!((color.get_r() as f64 - (-0.5_f64).exp()).abs() < 1.0e-4);
+	}
+
+	#[cfg(feature = "ray_packets")]
+	#[test]
+	fn packet_ray_casts_match_single_rays() {
+		// Rays straight down onto the spheres at x = 0 and 6 and between them onto the plane
+		let mut scene = RayTraceScene::new();
+		for i in 0..16 {
+			scene.add_object(Box::new(RayTraceObjectSphere::new([2.0 * i as f64, 0.0, 0.0], 1.0,
+				Box::new(RayTraceSimpleMaterial::new(RayTraceColor::white())))));
+		}
+		scene.add_object(Box::new(RayTraceObjectPlane::new([0.0, -5.0, 0.0], [0.0, 0.0, 0.0],
+			Box::new(RayTraceSimpleMaterial::new(RayTraceColor::white())))));
+		scene.init(0).unwrap();
+
+		let params = RayTraceParams::new();
+		let rays: Vec<RayTraceRay> = (0..4).map(|i| RayTraceRay::new([3.0 * i as f64, 10.0, 0.0], [0.0, -1.0, 0.0]))
+			.collect();
+		let object_ids = |hits: Vec<Option<RayTraceRayHit>>| -> Vec<Option<usize>> {
+			hits.iter().map(|hit| hit.as_ref().and_then(|hit| hit.get_object_id())).collect()
+		};
+
+		let single_hits = rays.iter().map(|ray| scene.ray_cast_visible(ray, &params)).collect();
+		assert_eq!(object_ids(scene.ray_cast_visible_packet(&rays, &params)), object_ids(single_hits));
+		assert_eq!(object_ids(scene.ray_cast_visible_packet(&rays, &params)),
+			vec![Some(0), Some(16), Some(3), Some(16)]);
+	}
+
+	#[test]
+	fn merged_light_linking_follows_the_objects() {
+		let white_sphere = || Box::new(RayTraceObjectSphere::new([0.0, 0.0, 0.0], 1.0,
+			Box::new(RayTraceSimpleMaterial::new(RayTraceColor::white()))));
+
+		let mut scene = RayTraceScene::new();
+		scene.add_object(white_sphere());
+		scene.add_object(white_sphere());
+
+		// Only lights the first object of its own scene, which becomes object 2
+		let mut other = RayTraceScene::new();
+		other.add_object(white_sphere());
+		let mut light = RayTraceSpotLight::new([0.0, 5.0, 0.0], RayTraceColor::white());
+		light.set_linking(RayTraceLightLinking::Include(vec![0].into_iter().collect::<HashSet<usize>>()));
+		other.add_light::<RayTraceSpotLight>(Box::new(light));
+
+		scene.merge(other);
+		let light = &scene.get_lights()[0];
+		assert!(light.affects(Some(2)));
+		assert!(!light.affects(Some(0)));
+	}
+}
@@ -0,0 +1,102 @@
+use camera::RayTracerCameraPerspective;
+use color::RayTraceColor;
+use light::RayTracePhongShading;
+use light::lights::RayTraceSpotLight;
+use material::RayTraceCheckerboardMaterial;
+use material::RayTraceSimpleMaterial;
+use object::RayTraceObjectCube;
+use object::RayTraceObjectPlane;
+use object::RayTraceObjectSphere;
+use params::RayTraceOutputParams;
+use params::RayTraceParams;
+use params::RayTraceRotatedGridSampling;
+use scene::RayTraceScene;
+use source::RayTraceSource;
+
+// Ready to render single frame scenes built from the primitives of the crate, a starting point for own scenes
+// and a known input when comparing renders.
+
+// A red sphere on a checkerboard floor lit by one light
+pub fn single_sphere(width: usize, height: usize) -> RayTraceSource {
+	let out_params = RayTraceOutputParams::new(width, height, 1);
+
+	let mut scene = RayTraceScene::new();
+	scene.add_object(box RayTraceObjectSphere::new([0.0, 0.0, 0.0], 2.0,
+		box RayTraceSimpleMaterial::new(RayTraceColor::red())));
+	scene.add_object(box RayTraceObjectPlane::new([0.0, -1.0, 0.0], [0.0, 0.0, 0.0],
+		box RayTraceCheckerboardMaterial::new_with_colors([RayTraceColor::gray(), RayTraceColor::white()])));
+	scene.add_light::<RayTraceSpotLight>(box RayTraceSpotLight::new([3.0, 5.0, 5.0], RayTraceColor::white()));
+
+	let mut camera = RayTracerCameraPerspective::new_with_fov(&out_params, 50.0);
+	camera.set_position([0.0, 0.5, 6.0]);
+
+	RayTraceSource::new(scene, box camera, out_params, make_params(1))
+}
+
+// Mirror, glass and matte spheres in a row over a reflective checkerboard floor
+pub fn reflection_test(width: usize, height: usize) -> RayTraceSource {
+	let out_params = RayTraceOutputParams::new(width, height, 1);
+
+	let mut scene = RayTraceScene::new();
+	scene.add_object(box RayTraceObjectSphere::new([-2.2, 0.0, 0.0], 2.0,
+		box RayTraceSimpleMaterial::new_with(RayTraceColor::white(), 0.9)));
+	scene.add_object(box RayTraceObjectSphere::new([0.0, 0.0, 0.0], 2.0,
+		box RayTraceSimpleMaterial::dielectric(1.5, RayTraceColor::white())));
+	scene.add_object(box RayTraceObjectSphere::new([2.2, 0.0, 0.0], 2.0,
+		box RayTraceSimpleMaterial::new(RayTraceColor::blue())));
+	scene.add_object(box RayTraceObjectPlane::new([0.0, -1.0, 0.0], [0.0, 0.0, 0.0],
+		box RayTraceCheckerboardMaterial::new_with_reflectance(0.3)));
+	scene.add_light::<RayTraceSpotLight>(box RayTraceSpotLight::new([-3.0, 6.0, 6.0], RayTraceColor::white()));
+
+	let mut camera = RayTracerCameraPerspective::new_with_fov(&out_params, 55.0);
+	camera.set_position([0.0, 1.0, 7.0]);
+
+	let mut params = make_params(2);
+	params.set_max_depth(6);
+	params.set_background_color(RayTraceColor::new_with(0.6, 0.7, 0.9, 1.0));
+
+	RayTraceSource::new(scene, box camera, out_params, params)
+}
+
+// Box with a red left and a green right wall, open towards the camera, with a mirror and a glass sphere inside
+pub fn cornell_box(width: usize, height: usize) -> RayTraceSource {
+	let out_params = RayTraceOutputParams::new(width, height, 1);
+	let white = || box RayTraceSimpleMaterial::new(RayTraceColor::new_with(0.8, 0.8, 0.8, 1.0));
+
+	// Walls of a 4 x 4 x 4 box around the origin
+	let mut scene = RayTraceScene::new();
+	scene.add_object(box RayTraceObjectCube::new([0.0, -2.05, 0.0], [4.2, 0.1, 4.2], white()));
+	scene.add_object(box RayTraceObjectCube::new([0.0, 2.05, 0.0], [4.2, 0.1, 4.2], white()));
+	scene.add_object(box RayTraceObjectCube::new([0.0, 0.0, -2.05], [4.2, 4.2, 0.1], white()));
+	scene.add_object(box RayTraceObjectCube::new([-2.05, 0.0, 0.0], [0.1, 4.2, 4.2],
+		box RayTraceSimpleMaterial::new(RayTraceColor::new_with(0.75, 0.1, 0.1, 1.0))));
+	scene.add_object(box RayTraceObjectCube::new([2.05, 0.0, 0.0], [0.1, 4.2, 4.2],
+		box RayTraceSimpleMaterial::new(RayTraceColor::new_with(0.1, 0.75, 0.1, 1.0))));
+
+	scene.add_object(box RayTraceObjectSphere::new([-0.8, -1.3, -0.6], 1.4,
+		box RayTraceSimpleMaterial::new_with(RayTraceColor::white(), 0.9)));
+	scene.add_object(box RayTraceObjectSphere::new([0.9, -1.3, 0.5], 1.4,
+		box RayTraceSimpleMaterial::dielectric(1.5, RayTraceColor::white())));
+
+	// Just below the ceiling, the light would be inside the ceiling otherwise
+	scene.add_light::<RayTraceSpotLight>(box RayTraceSpotLight::new([0.0, 1.8, 0.0], RayTraceColor::white()));
+
+	let mut camera = RayTracerCameraPerspective::new_with_fov(&out_params, 40.0);
+	camera.set_position([0.0, 0.0, 7.5]);
+
+	let mut params = make_params(2);
+	params.set_max_depth(5);
+
+	RayTraceSource::new(scene, box camera, out_params, params)
+}
+
+// Phong shading with a dim ambient light, anti-aliased if the grid size is above 1
+fn make_params(grid_size: usize) -> RayTraceParams {
+	let mut params = RayTraceParams::new();
+	params.set_shading(Some(box RayTracePhongShading::new()));
+	params.set_ambient_light(RayTraceColor::new_with(1.0, 1.0, 1.0, 0.1));
+	if grid_size > 1 {
+		params.set_sampling(Some(box RayTraceRotatedGridSampling::new_with(grid_size)));
+	}
+	params
+}
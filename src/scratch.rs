@@ -0,0 +1,170 @@
+use std::cell::Cell;
+use std::cell::RefCell;
+use std::collections::BinaryHeap;
+use std::ops::{Deref, DerefMut};
+use std::thread::LocalKey;
+
+use hit::RayTraceHitHeapEntry;
+use hit::RayTraceRayHit;
+
+pub type RayTraceHitHeap = BinaryHeap<RayTraceHitHeapEntry<RayTraceRayHit>>;
+
+thread_local! {
+	// Each worker thread keeps a small pool of heaps, one per nesting level (recursion, meshes, ...)
+	static HIT_HEAPS: RefCell<Vec<RayTraceHitHeap>> = RefCell::new(Vec::new());
+
+	// Number of scratch buffers this thread had to allocate because its pool was empty
+	static SCRATCH_ALLOCATIONS: Cell<usize> = Cell::new(0);
+
+	// Buffers are dropped instead of pooled when disabled (to compare against the unpooled allocations)
+	static SCRATCH_POOLING: Cell<bool> = Cell::new(true);
+}
+
+// Buffers the pools can hand out, they are reset before going back into the pool
+pub trait RayTraceScratchBuffer: Default {
+	fn reset(&mut self);
+}
+
+impl RayTraceScratchBuffer for RayTraceHitHeap {
+	fn reset(&mut self) {
+		BinaryHeap::clear(self);
+	}
+}
+
+// Buffer taken from the pool of the thread, it goes back into the pool when dropped
+pub struct RayTraceScratch<T: RayTraceScratchBuffer + 'static> {
+	buffer: Option<T>,
+	pool: &'static LocalKey<RefCell<Vec<T>>>
+}
+
+impl<T: RayTraceScratchBuffer + 'static> Deref for RayTraceScratch<T> {
+	type Target = T;
+
+	fn deref(&self) -> &T {
+		self.buffer.as_ref().unwrap()
+	}
+}
+
+impl<T: RayTraceScratchBuffer + 'static> DerefMut for RayTraceScratch<T> {
+	fn deref_mut(&mut self) -> &mut T {
+		self.buffer.as_mut().unwrap()
+	}
+}
+
+impl<T: RayTraceScratchBuffer + 'static> Drop for RayTraceScratch<T> {
+	fn drop(&mut self) {
+		if let Some(mut buffer) = self.buffer.take() {
+			if !SCRATCH_POOLING.with(|pooling| pooling.get()) {
+				return;
+			}
+
+			// Keep the allocation for the next ray
+			buffer.reset();
+			self.pool.with(|pool| pool.borrow_mut().push(buffer));
+		}
+	}
+}
+
+fn take_scratch<T: RayTraceScratchBuffer + 'static>(pool: &'static LocalKey<RefCell<Vec<T>>>) -> RayTraceScratch<T> {
+	let buffer = match pool.with(|pool| pool.borrow_mut().pop()) {
+		Some(buffer) => buffer,
+		None => {
+			SCRATCH_ALLOCATIONS.with(|count| count.set(count.get() + 1));
+			T::default()
+		}
+	};
+
+	RayTraceScratch {
+		buffer: Some(buffer),
+		pool: pool
+	}
+}
+
+// Empty heap, one per nesting level is kept by each thread
+pub fn get_hit_heap() -> RayTraceScratch<RayTraceHitHeap> {
+	take_scratch(&HIT_HEAPS)
+}
+
+pub fn with_hit_heap<F, R>(func: F) -> R where F: FnOnce(&mut RayTraceHitHeap) -> R {
+	func(&mut get_hit_heap())
+}
+
+// Allocations of this thread's scratch pools, stays constant once every nesting level has its buffers
+pub fn get_scratch_allocations() -> usize {
+	SCRATCH_ALLOCATIONS.with(|count| count.get())
+}
+
+// Enables or disables the pooling of this thread, the pools are emptied when disabled
+pub fn set_scratch_pooling(enabled: bool) {
+	SCRATCH_POOLING.with(|pooling| pooling.set(enabled));
+	if !enabled {
+		HIT_HEAPS.with(|pool| pool.borrow_mut().clear());
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use std::alloc::GlobalAlloc;
+	use std::alloc::Layout;
+	use std::alloc::System;
+	use std::cell::Cell;
+
+	use render::RayTracer;
+	use scenes;
+
+	use super::get_scratch_allocations;
+	use super::set_scratch_pooling;
+
+	// Counts the heap allocations of each thread, so parallel tests don't show up in the numbers
+	struct RayTraceCountingAllocator;
+
+	thread_local! {
+		static HEAP_ALLOCATIONS: Cell<usize> = Cell::new(0);
+	}
+
+	unsafe impl GlobalAlloc for RayTraceCountingAllocator {
+		unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+			let _ = HEAP_ALLOCATIONS.try_with(|count| count.set(count.get() + 1));
+			System.alloc(layout)
+		}
+
+		unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+			System.dealloc(ptr, layout)
+		}
+
+		unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+			let _ = HEAP_ALLOCATIONS.try_with(|count| count.set(count.get() + 1));
+			System.realloc(ptr, layout, new_size)
+		}
+	}
+
+	#[global_allocator]
+	static ALLOCATOR: RayTraceCountingAllocator = RayTraceCountingAllocator;
+
+	fn count_heap_allocations<F>(func: F) -> usize where F: FnOnce() {
+		let start = HEAP_ALLOCATIONS.with(|count| count.get());
+		func();
+		HEAP_ALLOCATIONS.with(|count| count.get()) - start
+	}
+
+	#[test]
+	fn pooling_reduces_the_allocations_of_the_benchmark_scene() {
+		// The estimate traces its calibration samples of the benchmark scene on this thread
+		let mut source = scenes::cornell_box(64, 48);
+		let mut tracer = RayTracer::new();
+
+		// Before: every scratch buffer is allocated and freed again
+		set_scratch_pooling(false);
+		tracer.estimate(&mut source).unwrap();
+		let unpooled = count_heap_allocations(|| { tracer.estimate(&mut source).unwrap(); });
+
+		// After: the first run fills the pools and the second one has to get along with them
+		set_scratch_pooling(true);
+		tracer.estimate(&mut source).unwrap();
+		let scratch_allocations = get_scratch_allocations();
+		let pooled = count_heap_allocations(|| { tracer.estimate(&mut source).unwrap(); });
+
+		assert_eq!(get_scratch_allocations(), scratch_allocations);
+		assert!(pooled < unpooled, "pooled {} vs. unpooled {} allocations", pooled, unpooled);
+	}
+}
@@ -0,0 +1,349 @@
+use std::collections::BTreeMap;
+use std::io::Error;
+use std::io::ErrorKind;
+use std::path::Path;
+use std::sync::Arc;
+use std::sync::RwLock;
+
+use color::RayTraceColor;
+
+use sink::RayTraceSink;
+use sink::image;
+use sink::png_sink::write_rgba;
+
+// The auto exposure histogram covers the luminances from 2^MIN_LOG_LUMINANCE to 2^MAX_LOG_LUMINANCE
+const LUMINANCE_BINS: usize = 256;
+const MIN_LOG_LUMINANCE: f32 = -12.0;
+const MAX_LOG_LUMINANCE: f32 = 4.0;
+const MIDDLE_GRAY: f32 = 0.18;
+
+#[allow(dead_code)]
+struct BufferData {
+	width: usize,
+	height: usize,
+	frames: Vec<Box<[u8]>>,
+	luminances: Vec<Box<[f32]>>,
+	object_ids: Vec<Box<[Option<usize>]>>,
+	depths: Vec<Box<[f32]>>,
+	passes: Vec<BTreeMap<String, Box<[u8]>>>
+}
+
+// Keeps all rendered frames as rgba values in memory.
+// Clones share the frames, so a clone can be kept for inspection after handing the sink to the renderer.
+#[allow(dead_code)]
+#[derive(Clone)]
+pub struct BufferSink {
+	width: usize,
+	buffer: Box<[u8]>,
+	luminances: Box<[f32]>,
+	object_ids: Box<[Option<usize>]>,
+	depths: Box<[f32]>,
+	passes: BTreeMap<String, Box<[u8]>>,
+	data: Arc<RwLock<BufferData>>
+}
+
+#[allow(dead_code)]
+impl BufferSink {
+	pub fn new() -> Self {
+		Self {
+			width: 0,
+			buffer: Box::new([0]),
+			luminances: Box::new([0.0]),
+			object_ids: Box::new([None]),
+			depths: Box::new([0.0]),
+			passes: BTreeMap::new(),
+			data: Arc::new(RwLock::new(BufferData {
+				width: 0,
+				height: 0,
+				frames: Vec::new(),
+				luminances: Vec::new(),
+				object_ids: Vec::new(),
+				depths: Vec::new(),
+				passes: Vec::new()
+			}))
+		}
+	}
+
+	pub fn get_width(&self) -> usize {
+		self.data.read().unwrap().width
+	}
+
+	pub fn get_height(&self) -> usize {
+		self.data.read().unwrap().height
+	}
+
+	pub fn get_frame_count(&self) -> usize {
+		self.data.read().unwrap().frames.len()
+	}
+
+	pub fn get_frame(&self, frame: usize) -> Option<Box<[u8]>> {
+		self.data.read().unwrap().frames.get(frame).cloned()
+	}
+
+	// See RayTraceSink::set_luminance
+	pub fn get_luminances(&self, frame: usize) -> Option<Box<[f32]>> {
+		self.data.read().unwrap().luminances.get(frame).cloned()
+	}
+
+	pub fn get_object_ids(&self, frame: usize) -> Option<Box<[Option<usize>]>> {
+		self.data.read().unwrap().object_ids.get(frame).cloned()
+	}
+
+	pub fn get_depths(&self, frame: usize) -> Option<Box<[f32]>> {
+		self.data.read().unwrap().depths.get(frame).cloned()
+	}
+
+	// Rgba values of an additional pass (see RayTraceSink::set_pass_sample)
+	pub fn get_pass(&self, frame: usize, pass: &str) -> Option<Box<[u8]>> {
+		self.data.read().unwrap().passes.get(frame).and_then(|passes| passes.get(pass).cloned())
+	}
+
+	pub fn get_pass_names(&self, frame: usize) -> Vec<String> {
+		self.data.read().unwrap().passes.get(frame).map_or(Vec::new(), |passes| passes.keys().cloned().collect())
+	}
+
+	// Bilinear interpolation between the pixel centers at the normalized coordinates u, v in [0, 1].
+	// Frames that were not rendered (yet) read as transparent black.
+	pub fn sample_bilinear(&self, frame: usize, u: f64, v: f64) -> RayTraceColor {
+		let data = self.data.read().unwrap();
+		let buffer = match data.frames.get(frame) {
+			Some(buffer) => buffer,
+			None => { return RayTraceColor::new_with(0.0, 0.0, 0.0, 0.0); }
+		};
+
+		if data.width == 0 || data.height == 0 {
+			return RayTraceColor::new_with(0.0, 0.0, 0.0, 0.0);
+		}
+
+		let x = (u * data.width as f64 - 0.5).max(0.0).min((data.width - 1) as f64);
+		let y = (v * data.height as f64 - 0.5).max(0.0).min((data.height - 1) as f64);
+
+		let x0 = x.floor() as usize;
+		let y0 = y.floor() as usize;
+		let x1 = (x0 + 1).min(data.width - 1);
+		let y1 = (y0 + 1).min(data.height - 1);
+		let fx = (x - x0 as f64) as f32;
+		let fy = (y - y0 as f64) as f32;
+
+		let get_pixel = |x: usize, y: usize| {
+			let offset = (x + y * data.width) << 2;
+			RayTraceColor::new_with(
+				buffer[offset] as f32 / 255.0,
+				buffer[offset + 1] as f32 / 255.0,
+				buffer[offset + 2] as f32 / 255.0,
+				buffer[offset + 3] as f32 / 255.0)
+		};
+
+		let top = get_pixel(x0, y0) * (1.0 - fx) + get_pixel(x1, y0) * fx;
+		let bottom = get_pixel(x0, y1) * (1.0 - fx) + get_pixel(x1, y1) * fx;
+		top * (1.0 - fy) + bottom * fy
+	}
+
+	// Exposure (see RayTraceParams::set_exposure) that maps the linear luminance at the percentile (0.5 = median)
+	// of the last frame to middle gray. The luminance is measured before the exposure, so feeding the result to
+	// the next frame doesn't oscillate. Transparent pixels (background) are ignored, 1.0 is returned if there is
+	// nothing to measure.
+	pub fn compute_auto_exposure(&self, target: f32) -> f32 {
+		let data = self.data.read().unwrap();
+		let (buffer, luminances) = match (data.frames.last(), data.luminances.last()) {
+			(Some(buffer), Some(luminances)) => (buffer, luminances),
+			_ => { return 1.0; }
+		};
+
+		// Logarithmic bins, the linear luminance has no upper bound
+		let log_range = MAX_LOG_LUMINANCE - MIN_LOG_LUMINANCE;
+		let mut histogram = [0_usize; LUMINANCE_BINS];
+		let mut count = 0;
+		for (pixel, &luminance) in buffer.chunks(4).zip(luminances.iter()) {
+			if pixel[3] == 0 {
+				continue;
+			}
+
+			let position = (luminance.max(1e-10).log2() - MIN_LOG_LUMINANCE) / log_range;
+			let bin = (position.max(0.0) * (LUMINANCE_BINS - 1) as f32).round() as usize;
+			histogram[bin.min(LUMINANCE_BINS - 1)] += 1;
+			count += 1;
+		}
+
+		if count == 0 {
+			return 1.0;
+		}
+
+		let target_count = (target.max(0.0).min(1.0) * count as f32).ceil().max(1.0) as usize;
+		let mut sum = 0;
+		for (bin, bin_count) in histogram.iter().enumerate() {
+			sum += *bin_count;
+			if sum >= target_count {
+				// Black images would need infinite exposure, they get the one of the darkest bin
+				let position = bin as f32 / (LUMINANCE_BINS - 1) as f32;
+				return MIDDLE_GRAY / (MIN_LOG_LUMINANCE + position * log_range).exp2();
+			}
+		}
+
+		1.0
+	}
+
+	pub fn compare_with_png(&self, frame: usize, file_name: &Path) -> Result<BufferDiff, Error> {
+		let reference = match image::open(file_name) {
+			Ok(reference) => reference.to_rgba(),
+			Err(err) => { return Err(Error::new(ErrorKind::Other, format!("{}", err))); }
+		};
+
+		let data = self.data.read().unwrap();
+		let buffer = match data.frames.get(frame) {
+			Some(buffer) => buffer,
+			None => { return Err(Error::new(ErrorKind::NotFound, format!("Frame {} was not rendered", frame))); }
+		};
+
+		if reference.width() as usize != data.width || reference.height() as usize != data.height {
+			return Err(Error::new(ErrorKind::InvalidData, format!("Reference has size {}x{} instead of {}x{}",
+				reference.width(), reference.height(), data.width, data.height)));
+		}
+
+		Ok(BufferDiff::new(buffer, &reference.into_raw()))
+	}
+}
+
+#[allow(unused_variables)]
+impl RayTraceSink for BufferSink {
+	fn init(&mut self, width: usize, height: usize, frames: usize) -> Result<(), Error> {
+		let mut data = self.data.write().unwrap();
+		data.width = width;
+		data.height = height;
+		data.frames = Vec::with_capacity(frames);
+		data.luminances = Vec::with_capacity(frames);
+		data.object_ids = Vec::with_capacity(frames);
+		data.depths = Vec::with_capacity(frames);
+		data.passes = Vec::with_capacity(frames);
+		self.width = width;
+
+		// Generate a buffer large enough to hold rgba values for each pixel
+		self.buffer = vec![0; (width * height) << 2].into_boxed_slice();
+		self.luminances = vec![0.0; width * height].into_boxed_slice();
+		self.object_ids = vec![None; width * height].into_boxed_slice();
+		self.depths = vec![0.0; width * height].into_boxed_slice();
+
+		Ok(())
+	}
+
+	fn start_frame(&mut self, frame: usize) -> Result<(), Error> {
+		self.passes.clear();
+		Ok(())
+	}
+
+	fn set_sample(&mut self, x: usize, y: usize, color: &RayTraceColor) -> Result<(), Error> {
+		write_rgba(&mut self.buffer, (x + y * self.width) << 2, color);
+		Ok(())
+	}
+
+	fn set_pass_sample(&mut self, pass: &str, x: usize, y: usize, color: &RayTraceColor) -> Result<(), Error> {
+		if !self.passes.contains_key(pass) {
+			self.passes.insert(pass.to_string(), vec![0; self.buffer.len()].into_boxed_slice());
+		}
+
+		write_rgba(self.passes.get_mut(pass).unwrap(), (x + y * self.width) << 2, color);
+		Ok(())
+	}
+
+	fn set_luminance(&mut self, x: usize, y: usize, luminance: f32) -> Result<(), Error> {
+		self.luminances[x + y * self.width] = luminance;
+		Ok(())
+	}
+
+	fn set_object_id(&mut self, x: usize, y: usize, id: Option<usize>) -> Result<(), Error> {
+		self.object_ids[x + y * self.width] = id;
+		Ok(())
+	}
+
+	fn set_depth(&mut self, x: usize, y: usize, depth: f32) -> Result<(), Error> {
+		self.depths[x + y * self.width] = depth;
+		Ok(())
+	}
+
+	fn finish_frame(&mut self, frame: usize) -> Result<(), Error> {
+		let mut data = self.data.write().unwrap();
+		data.frames.push(self.buffer.clone());
+		data.luminances.push(self.luminances.clone());
+		data.object_ids.push(self.object_ids.clone());
+		data.depths.push(self.depths.clone());
+		data.passes.push(self.passes.clone());
+
+		Ok(())
+	}
+}
+
+#[allow(dead_code)]
+pub struct BufferDiff {
+	max: u8,
+	mean: f64
+}
+
+#[allow(dead_code)]
+impl BufferDiff {
+	pub fn new(buffer_a: &[u8], buffer_b: &[u8]) -> Self {
+		let mut max = 0_u8;
+		let mut sum = 0_u64;
+
+		for (a, b) in buffer_a.iter().zip(buffer_b.iter()) {
+			let diff = if a > b { a - b } else { b - a };
+			max = max.max(diff);
+			sum += diff as u64;
+		}
+
+		Self {
+			max: max,
+			mean: if buffer_a.is_empty() { 0.0 } else { sum as f64 / buffer_a.len() as f64 }
+		}
+	}
+
+	pub fn get_max(&self) -> u8 {
+		self.max
+	}
+
+	pub fn get_mean(&self) -> f64 {
+		self.mean
+	}
+
+	pub fn is_within(&self, max_threshold: u8, mean_threshold: f64) -> bool {
+		self.max <= max_threshold && self.mean <= mean_threshold
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use color::RayTraceColor;
+	use render::RayTracer;
+	use scenes;
+	use sink::RayTraceSink;
+	use source::RayTraceSource;
+
+	use super::BufferSink;
+
+	fn render(mut source: RayTraceSource) -> BufferSink {
+		let buffer = BufferSink::new();
+		let mut sink: Box<RayTraceSink> = Box::new(buffer.clone());
+		RayTracer::new().render(&mut source, &mut sink).unwrap();
+		buffer
+	}
+
+	#[test]
+	fn auto_exposure_maps_the_median_to_middle_gray() {
+		let exposure = render(scenes::single_sphere(64, 48)).compute_auto_exposure(0.5);
+
+		let mut source = scenes::single_sphere(64, 48);
+		source.get().params.set_exposure(exposure);
+		let buffer = render(source);
+
+		// Measured before the exposure, so it stays the same when fed back
+		let measured = buffer.compute_auto_exposure(0.5);
+		assert!((measured - exposure).abs() <= 1e-4 * exposure, "exposure {} measured as {}", exposure, measured);
+
+		let mut luminances: Vec<f32> = buffer.get_frame(0).unwrap().chunks(4)
+			.map(|pixel| RayTraceColor::new_with(pixel[0] as f32 / 255.0, pixel[1] as f32 / 255.0,
+				pixel[2] as f32 / 255.0, 1.0).get_luminance())
+			.collect();
+		luminances.sort_by(|a, b| a.partial_cmp(b).unwrap());
+		let median = luminances[(luminances.len() - 1) / 2];
+		assert!((median - 0.18).abs() <= 0.02, "median luminance {}", median);
+	}
+}
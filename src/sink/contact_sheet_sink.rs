@@ -0,0 +1,149 @@
+use std::io::Error;
+
+use color::RayTraceColor;
+
+use sink::RayTraceSink;
+use sink::png_sink::write_png;
+use sink::png_sink::write_rgba;
+
+// Scales every frame down to a thumbnail and writes all of them as one grid image (contact sheet) after the
+// last frame, which makes it easy to scan a long animation for problems. Only the current frame is kept at
+// full resolution.
+#[allow(dead_code)]
+pub struct ContactSheetSink {
+	file_name: String,
+	thumbnail_width: usize,
+	columns: Option<usize>,
+	width: usize,
+	height: usize,
+	buffer: Box<[u8]>,
+	data: Option<SheetData>
+}
+
+struct SheetData {
+	thumbnail_width: usize,
+	thumbnail_height: usize,
+	columns: usize,
+	width: usize,
+	height: usize,
+	sheet: Box<[u8]>
+}
+
+#[allow(dead_code)]
+impl ContactSheetSink {
+	pub fn new(file_name: String, thumbnail_width: usize) -> Self {
+		Self {
+			file_name: file_name,
+			thumbnail_width: thumbnail_width.max(1),
+			columns: None,
+			width: 0,
+			height: 0,
+			buffer: Box::new([0]),
+			data: None
+		}
+	}
+
+	// Number of thumbnails per row, None chooses a roughly square grid
+	pub fn set_columns(&mut self, columns: Option<usize>) {
+		self.columns = columns.map(|columns| columns.max(1));
+	}
+
+	pub fn get_columns(&self) -> Option<usize> {
+		self.columns
+	}
+
+	// Averages all pixels of the frame covered by the thumbnail pixel (box filter)
+	fn downsample_into(&self, data: &mut SheetData, frame: usize) {
+		let offset_x = (frame % data.columns) * data.thumbnail_width;
+		let offset_y = (frame / data.columns) * data.thumbnail_height;
+
+		for ty in 0..data.thumbnail_height {
+			let y0 = ty * self.height / data.thumbnail_height;
+			let y1 = ((ty + 1) * self.height / data.thumbnail_height).max(y0 + 1);
+
+			for tx in 0..data.thumbnail_width {
+				let x0 = tx * self.width / data.thumbnail_width;
+				let x1 = ((tx + 1) * self.width / data.thumbnail_width).max(x0 + 1);
+
+				let mut sum = [0_usize; 4];
+				for y in y0..y1 {
+					for x in x0..x1 {
+						let offset = (x + y * self.width) << 2;
+						for channel in 0..4 {
+							sum[channel] += self.buffer[offset + channel] as usize;
+						}
+					}
+				}
+
+				let count = (x1 - x0) * (y1 - y0);
+				let sheet_offset = ((offset_x + tx) + (offset_y + ty) * data.width) << 2;
+				for channel in 0..4 {
+					data.sheet[sheet_offset + channel] = ((sum[channel] + count / 2) / count) as u8;
+				}
+			}
+		}
+	}
+}
+
+#[allow(unused_variables)]
+impl RayTraceSink for ContactSheetSink {
+	fn init(&mut self, width: usize, height: usize, frames: usize) -> Result<(), Error> {
+		self.width = width;
+		self.height = height;
+
+		// Generate a buffer large enough to hold rgba values for each pixel
+		self.buffer = vec![0; (width * height) << 2].into_boxed_slice();
+
+		let thumbnail_width = self.thumbnail_width.min(width).max(1);
+		let thumbnail_height = (thumbnail_width * height / width.max(1)).max(1);
+		let frames = frames.max(1);
+		let columns = match self.columns {
+			Some(columns) => columns.min(frames),
+			None => (frames as f64).sqrt().ceil() as usize
+		};
+		let rows = (frames + columns - 1) / columns;
+
+		self.data = Some(SheetData {
+			thumbnail_width: thumbnail_width,
+			thumbnail_height: thumbnail_height,
+			columns: columns,
+			width: columns * thumbnail_width,
+			height: rows * thumbnail_height,
+			sheet: vec![0; (columns * thumbnail_width * rows * thumbnail_height) << 2].into_boxed_slice()
+		});
+
+		Ok(())
+	}
+
+	fn start_frame(&mut self, frame: usize) -> Result<(), Error> {
+		Ok(())
+	}
+
+	fn set_sample(&mut self, x: usize, y: usize, color: &RayTraceColor) -> Result<(), Error> {
+		write_rgba(&mut self.buffer, (x + y * self.width) << 2, color);
+		Ok(())
+	}
+
+	fn finish_frame(&mut self, frame: usize) -> Result<(), Error> {
+		if let Some(mut data) = self.data.take() {
+			// Frames beyond the count given to init have no place on the sheet
+			let rows = data.height / data.thumbnail_height;
+			if frame < data.columns * rows {
+				self.downsample_into(&mut data, frame);
+			}
+			self.data = Some(data);
+		} else {
+			panic!("Contact sheet sink was not initialized!");
+		}
+
+		Ok(())
+	}
+
+	fn finish(&mut self) -> Result<(), Error> {
+		if let Some(ref data) = self.data {
+			write_png(&self.file_name, &data.sheet, data.width, data.height)
+		} else {
+			panic!("Contact sheet sink was not initialized!");
+		}
+	}
+}
@@ -8,6 +8,7 @@ use color::RayTraceColor;
 use sink::RayTraceSink;
 use sink::image::ColorType;
 use sink::image::jpeg::JPEGEncoder;
+use sink::png_sink::write_rgba;
 
 #[allow(dead_code)]
 pub struct JpegSink {
@@ -46,15 +47,7 @@ impl RayTraceSink for JpegSink {
 	}
 
 	fn set_sample(&mut self, x: usize, y: usize, color: &RayTraceColor) -> Result<(), Error> {
-		let offset = (x + y * self.width) << 2;
-		let (r, g, b, a) = color.get();
-
-		// Write pixel values into buffer
-		self.buffer[offset] = clamp_color(r * 255.0);
-		self.buffer[offset + 1] = clamp_color(g * 255.0);
-		self.buffer[offset + 2] = clamp_color(b * 255.0);
-		self.buffer[offset + 3] = clamp_color(a * 255.0);
-
+		write_rgba(&mut self.buffer, (x + y * self.width) << 2, color);
 		Ok(())
 	}
 
@@ -79,10 +72,4 @@ impl RayTraceSink for JpegSink {
 
 		Ok(())
 	}
-}
-
-fn clamp_color(value: f32) -> u8 {
-	if value <= 0.0 { return 0_u8; }
-	if value >= 255.0 { return 255_u8; }
-	return value as u8;
 }
\ No newline at end of file
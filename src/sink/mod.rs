@@ -4,18 +4,68 @@ pub extern crate y4m;
 use std::io::Error as IOError;
 
 use color::RayTraceColor;
+use render::RayTraceTileRect;
 
+mod buffer_sink;
+mod contact_sheet_sink;
 mod png_sink;
 mod jpeg_sink;
 mod y4m_sink;
+mod tcp_sink;
 
+pub use self::buffer_sink::BufferDiff;
+pub use self::buffer_sink::BufferSink;
+pub use self::contact_sheet_sink::ContactSheetSink;
 pub use self::png_sink::PngSink;
 pub use self::jpeg_sink::JpegSink;
 pub use self::y4m_sink::Y4mSink;
+pub use self::tcp_sink::TcpSink;
 
 pub trait RayTraceSink: Send + Sync {
 	fn init(&mut self, width: usize, height: usize, frames: usize) -> Result<(), IOError>;
 	fn start_frame(&mut self, frame: usize) -> Result<(), IOError>;
 	fn set_sample(&mut self, x: usize, y: usize, color: &RayTraceColor) -> Result<(), IOError>;
 	fn finish_frame(&mut self, frame: usize) -> Result<(), IOError>;
+
+	// Called once after the last frame, waits for pending output
+	fn finish(&mut self) -> Result<(), IOError> {
+		Ok(())
+	}
+
+	// Linear luminance of the pixel before the exposure and the display transform (auto exposure)
+	#[allow(unused_variables)]
+	fn set_luminance(&mut self, x: usize, y: usize, luminance: f32) -> Result<(), IOError> {
+		Ok(())
+	}
+
+	// Index of the object seen by most samples of the pixel, None for the background
+	#[allow(unused_variables)]
+	fn set_object_id(&mut self, x: usize, y: usize, id: Option<usize>) -> Result<(), IOError> {
+		Ok(())
+	}
+
+	// Distance to the nearest surface of the pixel, remapped by the depth range of the params
+	#[allow(unused_variables)]
+	fn set_depth(&mut self, x: usize, y: usize, depth: f32) -> Result<(), IOError> {
+		Ok(())
+	}
+
+	// Sample of an additional pass of the frame (ambient, light_0, ... for the light passes), written between
+	// start_frame and finish_frame after the samples of the frame itself
+	#[allow(unused_variables)]
+	fn set_pass_sample(&mut self, pass: &str, x: usize, y: usize, color: &RayTraceColor) -> Result<(), IOError> {
+		Ok(())
+	}
+
+	// Whether the sink wants the tiles of the frame as they finish, the renderer skips collecting them otherwise
+	fn receives_tiles(&self) -> bool {
+		false
+	}
+
+	// Colors of a finished tile row by row, the plain sample average like the preview of the renderer.
+	// Called while the frame renders, before start_frame and the samples of the filtered frame.
+	#[allow(unused_variables)]
+	fn set_tile(&mut self, frame: usize, tile: &RayTraceTileRect, colors: &[RayTraceColor]) -> Result<(), IOError> {
+		Ok(())
+	}
 }
@@ -1,7 +1,12 @@
+use std::collections::BTreeMap;
 use std::fs;
 use std::io::BufWriter;
 use std::io::Error;
+use std::io::ErrorKind;
+use std::mem;
 use std::path::Path;
+use std::thread;
+use std::thread::JoinHandle;
 
 use color::RayTraceColor;
 
@@ -14,7 +19,9 @@ pub struct PngSink {
 	width: usize,
 	height: usize,
 	file_name: String,
-	buffer: Box<[u8]>
+	buffer: Box<[u8]>,
+	passes: BTreeMap<String, Box<[u8]>>,
+	encoders: Vec<JoinHandle<Result<(), Error>>>
 }
 
 #[allow(dead_code)]
@@ -24,11 +31,34 @@ impl PngSink {
 			width: 0,
 			height: 0,
 			file_name: file_name,
-			buffer: Box::new([0])
+			buffer: Box::new([0]),
+			passes: BTreeMap::new(),
+			encoders: Vec::new()
+		}
+	}
+
+	fn queue_encoder(&mut self, file_name: String, buffer: Box<[u8]>) -> Result<(), Error> {
+		let (width, height) = (self.width, self.height);
+		while self.encoders.len() >= MAX_ENCODERS {
+			try!(self.join_encoder());
+		}
+		self.encoders.push(thread::spawn(move || write_png(&file_name, &buffer, width, height)));
+
+		Ok(())
+	}
+
+	fn join_encoder(&mut self) -> Result<(), Error> {
+		let encoder = self.encoders.remove(0);
+		match encoder.join() {
+			Ok(result) => result,
+			Err(_) => Err(Error::new(ErrorKind::Other, "PNG encoder thread panicked"))
 		}
 	}
 }
 
+// Limits the number of frame buffers waiting to be encoded
+const MAX_ENCODERS: usize = 4;
+
 #[allow(unused_variables)]
 impl RayTraceSink for PngSink {
 	fn init(&mut self, width: usize, height: usize, frames: usize) -> Result<(), Error> {
@@ -42,19 +72,22 @@ impl RayTraceSink for PngSink {
 	}
 	
 	fn start_frame(&mut self, frame: usize) -> Result<(), Error> {
+		self.passes.clear();
 		Ok(())
 	}
 	
 	fn set_sample(&mut self, x: usize, y: usize, color: &RayTraceColor) -> Result<(), Error> {
-		let offset = (x + y * self.width) << 2;
-		let (r, g, b, a) = color.get();
-		 
-		 // Write pixel values into buffer
-		self.buffer[offset] = clamp_color(r * 255.0);
-		self.buffer[offset + 1] = clamp_color(g * 255.0);
-		self.buffer[offset + 2] = clamp_color(b * 255.0);
-		self.buffer[offset + 3] = clamp_color(a * 255.0);
-		
+		write_rgba(&mut self.buffer, (x + y * self.width) << 2, color);
+		Ok(())
+	}
+
+	// Each pass goes to its own file with the pass name after the file name
+	fn set_pass_sample(&mut self, pass: &str, x: usize, y: usize, color: &RayTraceColor) -> Result<(), Error> {
+		if !self.passes.contains_key(pass) {
+			self.passes.insert(pass.to_string(), vec![0; (self.width * self.height) << 2].into_boxed_slice());
+		}
+
+		write_rgba(self.passes.get_mut(pass).unwrap(), (x + y * self.width) << 2, color);
 		Ok(())
 	}
 	
@@ -64,25 +97,55 @@ impl RayTraceSink for PngSink {
 			name = name.split_at(name.len() - 4).0;
 		}
 
-		let file_name = format!("{}{:04}.png", name, frame);
-		let path = Path::new(&file_name);
-		if let Some(parent) = path.parent() {
-			try!(fs::create_dir_all(parent));
-		}
+		let name = name.to_string();
 
-		let file = try!(fs::File::create(path));
-		let mut buf_writer = BufWriter::new(file);
-		let encoder = PNGEncoder::new(&mut buf_writer);
-		
-		let box ref buf = self.buffer;
-		try!(encoder.encode(buf, self.width as u32, self.height as u32, ColorType::RGBA(8)));
+		// Encode in the background while the next frame renders
+		let buffer = mem::replace(&mut self.buffer, vec![0; (self.width * self.height) << 2].into_boxed_slice());
+		try!(self.queue_encoder(format!("{}{:04}.png", name, frame), buffer));
+
+		let passes = mem::replace(&mut self.passes, BTreeMap::new());
+		for (pass, buffer) in passes.into_iter() {
+			try!(self.queue_encoder(format!("{}_{}{:04}.png", name, pass, frame), buffer));
+		}
 
 		Ok(())
 	}
+
+	fn finish(&mut self) -> Result<(), Error> {
+		let mut result = Ok(());
+		while !self.encoders.is_empty() {
+			let encoder_result = self.join_encoder();
+			if result.is_ok() {
+				result = encoder_result;
+			}
+		}
+
+		result
+	}
 }
 
-fn clamp_color(value: f32) -> u8 {
-	if value <= 0.0 { return 0_u8; }
-	if value >= 255.0 { return 255_u8; }
-	return value as u8;
+// Clamped rgba bytes of the pixel starting at the offset
+pub fn write_rgba(buffer: &mut [u8], offset: usize, color: &RayTraceColor) {
+	let (r, g, b, a) = color.clamp01().get();
+
+	// Write pixel values into buffer
+	buffer[offset] = (r * 255.0) as u8;
+	buffer[offset + 1] = (g * 255.0) as u8;
+	buffer[offset + 2] = (b * 255.0) as u8;
+	buffer[offset + 3] = (a * 255.0) as u8;
+}
+
+pub fn write_png(file_name: &str, buffer: &[u8], width: usize, height: usize) -> Result<(), Error> {
+	let path = Path::new(file_name);
+	if let Some(parent) = path.parent() {
+		try!(fs::create_dir_all(parent));
+	}
+
+	let file = try!(fs::File::create(path));
+	let mut buf_writer = BufWriter::new(file);
+	let encoder = PNGEncoder::new(&mut buf_writer);
+
+	try!(encoder.encode(buffer, width as u32, height as u32, ColorType::RGBA(8)));
+
+	Ok(())
 }
\ No newline at end of file
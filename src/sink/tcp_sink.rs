@@ -0,0 +1,212 @@
+use std::io::BufWriter;
+use std::io::Error;
+use std::io::ErrorKind;
+use std::io::Write;
+use std::net::Shutdown;
+use std::net::TcpStream;
+
+use color::RayTraceColor;
+use render::RayTraceTileRect;
+
+use sink::RayTraceSink;
+use sink::png_sink::write_rgba;
+
+// Streams the render to a viewer over TCP (watching a headless render from another machine, ...).
+// Every message is its length in bytes followed by the content, all numbers are big endian u32:
+// - after connecting: width, height and frame count
+// - for each finished tile and each finished frame: frame index, x, y, width, height and the rgba bytes of the
+//   rect row by row. The tiles are the plain sample average, the whole frame after them is the filtered one.
+#[allow(dead_code)]
+pub struct TcpSink {
+	width: usize,
+	height: usize,
+	address: String,
+	stream: Option<BufWriter<TcpStream>>,
+	buffer: Box<[u8]>
+}
+
+// Length of the frame index and the rect
+const RECT_HEADER_SIZE: usize = 5 * 4;
+
+#[allow(dead_code)]
+impl TcpSink {
+	// Address of the viewer (host:port), the connection is established by init
+	pub fn new(address: String) -> Self {
+		Self {
+			width: 0,
+			height: 0,
+			address: address,
+			stream: None,
+			buffer: Box::new([0])
+		}
+	}
+
+	pub fn get_address(&self) -> &str {
+		&self.address
+	}
+
+	pub fn is_connected(&self) -> bool {
+		self.stream.is_some()
+	}
+}
+
+#[allow(unused_variables)]
+impl RayTraceSink for TcpSink {
+	fn init(&mut self, width: usize, height: usize, frames: usize) -> Result<(), Error> {
+		self.width = width;
+		self.height = height;
+		self.buffer = vec![0; (width * height) << 2].into_boxed_slice();
+
+		let stream = try!(TcpStream::connect(self.address.as_str()));
+		// The tiles are small, waiting to fill the packets would only delay them
+		try!(stream.set_nodelay(true));
+
+		let mut stream = BufWriter::new(stream);
+		try!(write_u32(&mut stream, 3 * 4));
+		for &value in [width, height, frames].iter() {
+			try!(write_u32(&mut stream, value));
+		}
+		try!(stream.flush());
+
+		self.stream = Some(stream);
+		Ok(())
+	}
+
+	fn start_frame(&mut self, frame: usize) -> Result<(), Error> {
+		Ok(())
+	}
+
+	fn set_sample(&mut self, x: usize, y: usize, color: &RayTraceColor) -> Result<(), Error> {
+		write_rgba(&mut self.buffer, (x + y * self.width) << 2, color);
+		Ok(())
+	}
+
+	fn receives_tiles(&self) -> bool {
+		true
+	}
+
+	fn set_tile(&mut self, frame: usize, tile: &RayTraceTileRect, colors: &[RayTraceColor]) -> Result<(), Error> {
+		let mut rgba = vec![0; colors.len() << 2];
+		for (index, color) in colors.iter().enumerate() {
+			write_rgba(&mut rgba, index << 2, color);
+		}
+
+		match self.stream {
+			Some(ref mut stream) => write_rect(stream, frame, [tile.get_x(), tile.get_y(), tile.get_width(),
+				tile.get_height()], &rgba),
+			None => Err(not_connected())
+		}
+	}
+
+	fn finish_frame(&mut self, frame: usize) -> Result<(), Error> {
+		match self.stream {
+			Some(ref mut stream) => write_rect(stream, frame, [0, 0, self.width, self.height], &self.buffer),
+			None => Err(not_connected())
+		}
+	}
+
+	fn finish(&mut self) -> Result<(), Error> {
+		if let Some(stream) = self.stream.take() {
+			match stream.into_inner() {
+				Ok(stream) => try!(stream.shutdown(Shutdown::Both)),
+				Err(error) => { return Err(Error::new(ErrorKind::Other, format!("{}", error))); }
+			}
+		}
+
+		Ok(())
+	}
+}
+
+// Sent right away, the viewer should show the tile as soon as it is done
+fn write_rect<W: Write>(writer: &mut W, frame: usize, rect: [usize; 4], rgba: &[u8]) -> Result<(), Error> {
+	try!(write_u32(writer, RECT_HEADER_SIZE + rgba.len()));
+	try!(write_u32(writer, frame));
+	for &value in rect.iter() {
+		try!(write_u32(writer, value));
+	}
+	try!(writer.write_all(rgba));
+	writer.flush()
+}
+
+fn not_connected() -> Error {
+	Error::new(ErrorKind::NotConnected, "TCP sink was not initialized!")
+}
+
+fn write_u32<W: Write>(writer: &mut W, value: usize) -> Result<(), Error> {
+	if value > u32::max_value() as usize {
+		return Err(Error::new(ErrorKind::InvalidInput, format!("{} does not fit into a message", value)));
+	}
+
+	let value = value as u32;
+	writer.write_all(&[(value >> 24) as u8, (value >> 16) as u8, (value >> 8) as u8, value as u8])
+}
+
+#[cfg(test)]
+mod tests {
+	use std::io::Read;
+	use std::net::TcpListener;
+	use std::thread;
+
+	use color::RayTraceColor;
+	use render::RayTraceTileRect;
+	use sink::RayTraceSink;
+
+	use super::RECT_HEADER_SIZE;
+	use super::TcpSink;
+
+	fn read_u32(bytes: &[u8]) -> usize {
+		bytes[..4].iter().fold(0, |value, &byte| (value << 8) | byte as usize)
+	}
+
+	// Splits the stream into the contents of the length prefixed messages
+	fn split_messages(mut bytes: &[u8]) -> Vec<Vec<u8>> {
+		let mut messages = Vec::new();
+		while !bytes.is_empty() {
+			let length = read_u32(bytes);
+			messages.push(bytes[4..4 + length].to_vec());
+			bytes = &bytes[4 + length..];
+		}
+		messages
+	}
+
+	#[test]
+	fn messages_arrive_in_order() {
+		let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+		let address = format!("{}", listener.local_addr().unwrap());
+		let viewer = thread::spawn(move || {
+			let mut bytes = Vec::new();
+			listener.accept().unwrap().0.read_to_end(&mut bytes).unwrap();
+			bytes
+		});
+
+		// A 3 x 2 frame with a white 2 x 1 tile in its lower right corner
+		let mut sink = TcpSink::new(address);
+		sink.init(3, 2, 1).unwrap();
+		sink.start_frame(0).unwrap();
+		sink.set_tile(0, &RayTraceTileRect::new(1, 1, 2, 1), &[RayTraceColor::white(), RayTraceColor::white()])
+			.unwrap();
+		for y in 0..2 {
+			for x in 0..3 {
+				sink.set_sample(x, y, &RayTraceColor::new_with(0.0, 0.0, 0.0, 1.0)).unwrap();
+			}
+		}
+		sink.finish_frame(0).unwrap();
+		sink.finish().unwrap();
+
+		// The viewer only gets to the end of the stream once finish closed it
+		let messages = split_messages(&viewer.join().unwrap());
+		assert_eq!(messages.len(), 3);
+
+		let header: Vec<usize> = messages[0].chunks(4).map(read_u32).collect();
+		assert_eq!(header, vec![3, 2, 1]);
+
+		let tile: Vec<usize> = messages[1][..RECT_HEADER_SIZE].chunks(4).map(read_u32).collect();
+		assert_eq!(tile, vec![0, 1, 1, 2, 1]);
+		assert_eq!(&messages[1][RECT_HEADER_SIZE..], &[255; 2 * 4][..]);
+
+		let frame: Vec<usize> = messages[2][..RECT_HEADER_SIZE].chunks(4).map(read_u32).collect();
+		assert_eq!(frame, vec![0, 0, 0, 3, 2]);
+		assert_eq!(messages[2].len(), RECT_HEADER_SIZE + 3 * 2 * 4);
+		assert!(messages[2][RECT_HEADER_SIZE..].chunks(4).all(|pixel| pixel == &[0, 0, 0, 255][..]));
+	}
+}
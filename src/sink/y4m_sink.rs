@@ -86,7 +86,7 @@ impl<'a> RayTraceSink for Y4mSink<'a, BufWriter<fs::File>> {
 
 	fn set_sample(&mut self, x: usize, y: usize, color: &RayTraceColor) -> Result<(), IOError> {
 		let offset = x + y * self.width;
-		let clamped_color = color.get_clamped();
+		let clamped_color = color.clamp01();
 		let (y, u, v) = color_to_yuv(&clamped_color);
 
 		if let Some(ref mut frame_data) = self.frame_data {
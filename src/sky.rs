@@ -0,0 +1,104 @@
+use std::f64::consts::PI;
+
+use vecmath::Vector3;
+use vecmath::{vec3_dot, vec3_normalized};
+
+use color::RayTraceColor;
+use color::RayTraceColorSpace;
+
+// Angular radius of the sun disk in radians
+const SUN_RADIUS: f64 = 0.0047;
+const SUN_INTENSITY: f32 = 20.0;
+
+// Luminance of the zenith after normalization
+const ZENITH_LUMINANCE: f64 = 0.5;
+
+// Analytic daylight sky by Preetham, Shirley and Smits (1999)
+#[derive(Debug, Clone)]
+pub struct RayTraceSky {
+	sun_direction: Vector3<f64>,
+	turbidity: f64,
+	coefficients: [[f64; 5]; 3]
+}
+
+#[allow(dead_code)]
+impl RayTraceSky {
+	// The sun direction points from the scene towards the sun, turbidity is usually in [2, 10]
+	pub fn new(sun_direction: Vector3<f64>, turbidity: f64) -> Self {
+		let t = turbidity.max(1.0);
+		let sun_direction = vec3_normalized(sun_direction);
+
+		Self {
+			sun_direction: sun_direction,
+			turbidity: t,
+			coefficients: [
+				[0.1787 * t - 1.4630, -0.3554 * t + 0.4275, -0.0227 * t + 5.3251, 0.1206 * t - 2.5771,
+					-0.0670 * t + 0.3703],
+				[-0.0193 * t - 0.2592, -0.0665 * t + 0.0008, -0.0004 * t + 0.2125, -0.0641 * t - 0.8989,
+					-0.0033 * t + 0.0452],
+				[-0.0167 * t - 0.2608, -0.0950 * t + 0.0092, -0.0079 * t + 0.2102, -0.0441 * t - 1.6537,
+					-0.0109 * t + 0.0529]
+			]
+		}
+	}
+
+	pub fn get_sun_direction(&self) -> Vector3<f64> {
+		self.sun_direction
+	}
+
+	pub fn get_turbidity(&self) -> f64 {
+		self.turbidity
+	}
+
+	// Directions below the horizon get the color at the horizon
+	pub fn get_color(&self, direction: Vector3<f64>, up: Vector3<f64>, color_space: RayTraceColorSpace)
+			-> RayTraceColor {
+		let direction = vec3_normalized(direction);
+		let cos_theta_sun = vec3_dot(self.sun_direction, up).max(0.0).min(1.0);
+		let theta_sun = cos_theta_sun.acos();
+		let cos_theta = vec3_dot(direction, up).max(0.01);
+		let cos_gamma = vec3_dot(direction, self.sun_direction).max(-1.0).min(1.0);
+		let gamma = cos_gamma.acos();
+
+		let zenith = compute_zenith(self.turbidity, theta_sun);
+		let mut values = [0.0; 3];
+		for i in 0..3 {
+			values[i] = zenith[i] * self.perez(i, cos_theta, gamma, cos_gamma)
+				/ self.perez(i, 1.0, theta_sun, cos_theta_sun);
+		}
+
+		// Relative luminance to keep the colors in a displayable range
+		let luminance = values[0] / zenith[0] * ZENITH_LUMINANCE;
+		let (x, y) = (values[1], values[2]);
+
+		let mut color = RayTraceColor::from_xyz((x / y * luminance) as f32, luminance as f32,
+			((1.0 - x - y) / y * luminance) as f32, color_space);
+
+		if gamma < SUN_RADIUS && vec3_dot(direction, up) > 0.0 {
+			color += RayTraceColor::new_with(1.0, 0.95, 0.9, 0.0) * SUN_INTENSITY;
+		}
+
+		color
+	}
+
+	fn perez(&self, index: usize, cos_theta: f64, gamma: f64, cos_gamma: f64) -> f64 {
+		let [a, b, c, d, e] = self.coefficients[index];
+		(1.0 + a * (b / cos_theta).exp()) * (1.0 + c * (d * gamma).exp() + e * cos_gamma * cos_gamma)
+	}
+}
+
+// Luminance (kcd/m^2) and chromaticity x, y of the zenith
+fn compute_zenith(t: f64, theta_sun: f64) -> [f64; 3] {
+	let (t1, t2, t3) = (theta_sun, theta_sun * theta_sun, theta_sun * theta_sun * theta_sun);
+	let chi = (4.0 / 9.0 - t / 120.0) * (PI - 2.0 * theta_sun);
+
+	let luminance = (4.0453 * t - 4.9710) * chi.tan() - 0.2155 * t + 2.4192;
+	let x = t * t * (0.00166 * t3 - 0.00375 * t2 + 0.00209 * t1)
+		+ t * (-0.02903 * t3 + 0.06377 * t2 - 0.03202 * t1 + 0.00394)
+		+ (0.11693 * t3 - 0.21196 * t2 + 0.06052 * t1 + 0.25886);
+	let y = t * t * (0.00275 * t3 - 0.00610 * t2 + 0.00317 * t1)
+		+ t * (-0.04214 * t3 + 0.08970 * t2 - 0.04153 * t1 + 0.00516)
+		+ (0.15346 * t3 - 0.26756 * t2 + 0.06670 * t1 + 0.26688);
+
+	[luminance.max(1e-3), x, y]
+}
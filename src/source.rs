@@ -1,5 +1,6 @@
 use std::sync::RwLock;
 use std::sync::RwLockWriteGuard;
+use std::sync::atomic::{AtomicUsize, Ordering, ATOMIC_USIZE_INIT};
 
 use camera::RayTraceCamera;
 use params::RayTraceOutputParams;
@@ -9,13 +10,20 @@ use scene::RayTraceScene;
 pub struct RayTraceSourceSet {
 	pub scene: RayTraceScene,
 	pub camera: Box<RayTraceCamera>,
+	// Further views of the same scene, only rendered by render_all_cameras
+	pub cameras: Vec<Box<RayTraceCamera>>,
 	pub out_params: RayTraceOutputParams,
 	pub params: RayTraceParams
 
 }
 
+// Versions are unique over all sources, so the frames of two sources never share a key in the frame cache
+static NEXT_VERSION: AtomicUsize = ATOMIC_USIZE_INIT;
+
 pub struct RayTraceSource {
-	objects: RwLock<RayTraceSourceSet>
+	objects: RwLock<RayTraceSourceSet>,
+	// Changes with every mutable access, the frame cache keeps the rendered frames by it
+	version: usize
 }
 
 impl RayTraceSource {
@@ -25,29 +33,67 @@ impl RayTraceSource {
 			objects: RwLock::new(RayTraceSourceSet {
 				scene: scene,
 				camera: camera,
+				cameras: Vec::new(),
 				out_params: out_params,
 				params: params
-			})
+			}),
+			version: NEXT_VERSION.fetch_add(1, Ordering::SeqCst)
 		}
 	}
 
 	pub fn set_scene(&mut self, scene: RayTraceScene) {
+		self.mark_changed();
 		self.objects.write().unwrap().scene = scene;
 	}
 
 	pub fn set_camera(&mut self, camera: Box<RayTraceCamera>) {
+		self.mark_changed();
 		self.objects.write().unwrap().camera = camera;
 	}
 
+	pub fn add_camera(&mut self, camera: Box<RayTraceCamera>) {
+		self.mark_changed();
+		self.objects.write().unwrap().cameras.push(camera);
+	}
+
+	pub fn set_cameras(&mut self, cameras: Vec<Box<RayTraceCamera>>) {
+		self.mark_changed();
+		self.objects.write().unwrap().cameras = cameras;
+	}
+
+	// The camera set by the constructor included, reading doesn't change the version
+	pub fn get_camera_count(&self) -> usize {
+		self.objects.read().unwrap().cameras.len() + 1
+	}
+
 	pub fn set_out_params(&mut self, out_params: RayTraceOutputParams) {
+		self.mark_changed();
 		self.objects.write().unwrap().out_params = out_params;
 	}
 
 	pub fn set_params(&mut self, params: RayTraceParams) {
+		self.mark_changed();
 		self.objects.write().unwrap().params = params;
 	}
 
+	// Everything changed through the returned guard counts as a change
 	pub fn get(&mut self) -> RwLockWriteGuard<RayTraceSourceSet> {
+		self.mark_changed();
 		self.objects.write().unwrap()
 	}
+
+	// Same as get for the renderer, which only changes the animated state of each frame. Other changes made through
+	// it have to be announced with mark_changed.
+	pub fn get_unchanged(&mut self) -> RwLockWriteGuard<RayTraceSourceSet> {
+		self.objects.write().unwrap()
+	}
+
+	// Changes made to the scene through the handles returned by add_object and add_light are not noticed otherwise
+	pub fn mark_changed(&mut self) {
+		self.version = NEXT_VERSION.fetch_add(1, Ordering::SeqCst);
+	}
+
+	pub fn get_version(&self) -> usize {
+		self.version
+	}
 }
@@ -0,0 +1,50 @@
+use texture::RayTraceTexture;
+
+// Height field on top of a surface, the height is the texture value times the scale.
+// Currently only used for bump mapping (perturbing the shading normal).
+#[allow(dead_code)]
+pub struct RayTraceDisplacementMap {
+	texture: Box<RayTraceTexture>,
+	scale: f64,
+	step: f64
+}
+
+#[allow(dead_code)]
+impl RayTraceDisplacementMap {
+	pub fn new(texture: Box<RayTraceTexture>, scale: f64) -> Self {
+		Self {
+			texture: texture,
+			scale: scale,
+			step: 1e-3
+		}
+	}
+
+	pub fn set_scale(&mut self, scale: f64) {
+		self.scale = scale;
+	}
+
+	pub fn get_scale(&self) -> f64 {
+		self.scale
+	}
+
+	// Distance in material coordinates used for the finite differences
+	pub fn set_step(&mut self, step: f64) {
+		self.step = step;
+	}
+
+	pub fn get_step(&self) -> f64 {
+		self.step
+	}
+
+	pub fn get_height(&self, x: f64, y: f64) -> f64 {
+		self.texture.get_value(x, y) as f64 * self.scale
+	}
+
+	pub fn get_gradient(&self, x: f64, y: f64) -> [f64; 2] {
+		let step = self.step;
+		[
+			(self.get_height(x + step, y) - self.get_height(x - step, y)) / (2.0 * step),
+			(self.get_height(x, y + step) - self.get_height(x, y - step)) / (2.0 * step)
+		]
+	}
+}
@@ -0,0 +1,81 @@
+use std::io::Error;
+use std::io::ErrorKind;
+use std::path::Path;
+
+use sink::image;
+
+// Monospaced bitmap font from an atlas image with the glyphs in a grid, row by row starting at the first char.
+// The coverage is the alpha channel, or the luminance for atlases without transparency (white on black).
+#[allow(dead_code)]
+pub struct RayTraceFont {
+	glyph_width: usize,
+	glyph_height: usize,
+	columns: usize,
+	glyph_count: usize,
+	first_char: u32,
+	atlas_width: usize,
+	coverage: Vec<f32>
+}
+
+#[allow(dead_code)]
+impl RayTraceFont {
+	pub fn new(file_name: &Path, columns: usize, rows: usize, first_char: char) -> Result<Self, Error> {
+		let image = match image::open(file_name) {
+			Ok(image) => image.to_rgba(),
+			Err(err) => { return Err(Error::new(ErrorKind::Other, format!("{}", err))); }
+		};
+
+		let (width, height) = (image.width() as usize, image.height() as usize);
+		let pixels = image.into_raw();
+		let has_alpha = pixels.chunks(4).any(|p| p[3] < 255);
+		let coverage = pixels.chunks(4)
+			.map(|p| if has_alpha {
+				p[3] as f32 / 255.0
+			} else {
+				(0.2126 * p[0] as f32 + 0.7152 * p[1] as f32 + 0.0722 * p[2] as f32) / 255.0
+			})
+			.collect();
+
+		Ok(Self::new_with_coverage(width, height, coverage, columns, rows, first_char))
+	}
+
+	pub fn new_with_coverage(width: usize, height: usize, coverage: Vec<f32>, columns: usize, rows: usize,
+			first_char: char) -> Self {
+		let columns = columns.max(1);
+		let rows = rows.max(1);
+
+		Self {
+			glyph_width: width / columns,
+			glyph_height: height / rows,
+			columns: columns,
+			glyph_count: columns * rows,
+			first_char: first_char as u32,
+			atlas_width: width,
+			coverage: coverage
+		}
+	}
+
+	pub fn get_glyph_width(&self) -> usize {
+		self.glyph_width
+	}
+
+	pub fn get_glyph_height(&self) -> usize {
+		self.glyph_height
+	}
+
+	pub fn has_glyph(&self, c: char) -> bool {
+		(c as u32) >= self.first_char && ((c as u32) - self.first_char) < self.glyph_count as u32
+	}
+
+	// Pixel of the glyph from its top left corner, chars missing in the atlas are empty
+	pub fn get_coverage(&self, c: char, x: usize, y: usize) -> f32 {
+		if !self.has_glyph(c) || x >= self.glyph_width || y >= self.glyph_height {
+			return 0.0;
+		}
+
+		let index = ((c as u32) - self.first_char) as usize;
+		let atlas_x = (index % self.columns) * self.glyph_width + x;
+		let atlas_y = (index / self.columns) * self.glyph_height + y;
+		self.coverage[atlas_x + atlas_y * self.atlas_width]
+	}
+}
@@ -0,0 +1,83 @@
+use std::io::Error;
+use std::io::ErrorKind;
+use std::path::Path;
+
+use color::RayTraceColor;
+
+use sink::image;
+
+use texture::RayTraceTexture;
+
+// Repeating texture with bilinear filtering, one repetition covers scale[0] x scale[1] units
+#[allow(dead_code)]
+pub struct RayTraceImageTexture {
+	width: usize,
+	height: usize,
+	pixels: Vec<RayTraceColor>,
+	scale: [f64; 2]
+}
+
+#[allow(dead_code)]
+impl RayTraceImageTexture {
+	pub fn new(file_name: &Path) -> Result<Self, Error> {
+		let image = match image::open(file_name) {
+			Ok(image) => image.to_rgba(),
+			Err(err) => { return Err(Error::new(ErrorKind::Other, format!("{}", err))); }
+		};
+
+		let (width, height) = (image.width() as usize, image.height() as usize);
+		let pixels = image.into_raw().chunks(4)
+			.map(|p| RayTraceColor::new_with(p[0] as f32 / 255.0, p[1] as f32 / 255.0, p[2] as f32 / 255.0,
+				p[3] as f32 / 255.0))
+			.collect();
+
+		Self::new_with_pixels(width, height, pixels)
+	}
+
+	// The pixels are stored row by row
+	pub fn new_with_pixels(width: usize, height: usize, pixels: Vec<RayTraceColor>) -> Result<Self, Error> {
+		if pixels.len() != width * height {
+			return Err(Error::new(ErrorKind::InvalidInput, format!("Got {} pixels for a {}x{} texture",
+				pixels.len(), width, height)));
+		}
+
+		Ok(Self {
+			width: width,
+			height: height,
+			pixels: pixels,
+			scale: [1.0, 1.0]
+		})
+	}
+
+	pub fn set_scale(&mut self, scale: [f64; 2]) {
+		self.scale = scale;
+	}
+
+	pub fn get_scale(&self) -> [f64; 2] {
+		self.scale
+	}
+
+	fn get_pixel(&self, x: i64, y: i64) -> &RayTraceColor {
+		let p_x = x.wrapping_rem(self.width as i64).wrapping_add(self.width as i64) as usize % self.width;
+		let p_y = y.wrapping_rem(self.height as i64).wrapping_add(self.height as i64) as usize % self.height;
+		&self.pixels[p_x + p_y * self.width]
+	}
+}
+
+impl RayTraceTexture for RayTraceImageTexture {
+	fn get_color(&self, x: f64, y: f64) -> RayTraceColor {
+		if self.width == 0 || self.height == 0 {
+			return RayTraceColor::transparent();
+		}
+
+		let t_x = x / self.scale[0] * self.width as f64 - 0.5;
+		let t_y = y / self.scale[1] * self.height as f64 - 0.5;
+		let (f_x, f_y) = (t_x.floor(), t_y.floor());
+		let (p_x, p_y) = (f_x as i64, f_y as i64);
+		let (w_x, w_y) = ((t_x - f_x) as f32, (t_y - f_y) as f32);
+
+		let top = self.get_pixel(p_x, p_y).mix(self.get_pixel(p_x + 1, p_y), w_x);
+		let bottom = self.get_pixel(p_x, p_y + 1).mix(self.get_pixel(p_x + 1, p_y + 1), w_x);
+		top.mix(&bottom, w_y)
+	}
+}
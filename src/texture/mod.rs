@@ -0,0 +1,17 @@
+mod displacement;
+mod font;
+mod image_texture;
+
+pub use self::displacement::RayTraceDisplacementMap;
+pub use self::font::RayTraceFont;
+pub use self::image_texture::RayTraceImageTexture;
+
+use color::RayTraceColor;
+
+pub trait RayTraceTexture: Send + Sync {
+	fn get_color(&self, x: f64, y: f64) -> RayTraceColor;
+
+	fn get_value(&self, x: f64, y: f64) -> f32 {
+		self.get_color(x, y).get_luminance()
+	}
+}
@@ -0,0 +1,104 @@
+extern crate ray_tracer;
+
+use std::env;
+use std::path::PathBuf;
+
+use ray_tracer::camera::RayTracerCameraPerspective;
+use ray_tracer::color::RayTraceColor;
+use ray_tracer::light::RayTracePhongShading;
+use ray_tracer::light::lights::RayTraceSpotLight;
+use ray_tracer::material::RayTraceCheckerboardMaterial;
+use ray_tracer::material::RayTraceSimpleMaterial;
+use ray_tracer::object::RayTraceObjectCube;
+use ray_tracer::object::RayTraceObjectPlane;
+use ray_tracer::params::RayTraceOutputParams;
+use ray_tracer::params::RayTraceParams;
+use ray_tracer::params::RayTraceRandomSampling;
+use ray_tracer::render::RayTracer;
+use ray_tracer::scene::RayTraceScene;
+use ray_tracer::scenes;
+use ray_tracer::sink::BufferSink;
+use ray_tracer::sink::RayTraceSink;
+use ray_tracer::sink::image;
+use ray_tracer::source::RayTraceSource;
+
+// Renders are compared against the PNGs in tests/golden. After an intended change of the output, run the tests
+// with RAY_TRACER_UPDATE_GOLDEN=1 to write new references and check the changed images before committing them
+// (see the README for the toolchain and the whole procedure).
+
+const WIDTH: usize = 64;
+const HEIGHT: usize = 48;
+// Largest difference of a single channel and mean difference over all channels (0 - 255)
+const MAX_THRESHOLD: u8 = 8;
+const MEAN_THRESHOLD: f64 = 0.5;
+
+fn render(mut source: RayTraceSource) -> BufferSink {
+	let buffer = BufferSink::new();
+	let mut sink: Box<RayTraceSink> = Box::new(buffer.clone());
+	RayTracer::new().render(&mut source, &mut sink).unwrap();
+	buffer
+}
+
+fn check_golden(name: &str, source: RayTraceSource) {
+	let buffer = render(source);
+
+	let path = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("tests").join("golden")
+		.join(format!("{}.png", name));
+	if env::var("RAY_TRACER_UPDATE_GOLDEN").is_ok() {
+		image::save_buffer(&path, &buffer.get_frame(0).unwrap(), WIDTH as u32, HEIGHT as u32,
+			image::ColorType::RGBA(8)).unwrap();
+		return;
+	}
+
+	let diff = buffer.compare_with_png(0, &path).unwrap();
+	assert!(diff.is_within(MAX_THRESHOLD, MEAN_THRESHOLD),
+		"{} differs from {}: max {} (allowed {}), mean {:.4} (allowed {})",
+		name, path.display(), diff.get_max(), MAX_THRESHOLD, diff.get_mean(), MEAN_THRESHOLD);
+}
+
+fn make_params() -> RayTraceParams {
+	let mut params = RayTraceParams::new();
+	params.set_shading(Some(Box::new(RayTracePhongShading::new())));
+	params.set_ambient_light(RayTraceColor::new_with(1.0, 1.0, 1.0, 0.1));
+	// Seeded jitter, so the reference stays valid
+	params.set_sampling(Some(Box::new(RayTraceRandomSampling::new_with(0.5, 4))));
+	params.set_seed(402);
+	params
+}
+
+#[test]
+fn golden_sphere() {
+	check_golden("sphere", scenes::single_sphere(WIDTH, HEIGHT));
+}
+
+#[test]
+fn golden_cube() {
+	let out_params = RayTraceOutputParams::new(WIDTH, HEIGHT, 1);
+
+	let mut scene = RayTraceScene::new();
+	let mut cube = RayTraceObjectCube::new([0.0, 0.0, 0.0], [1.5, 1.5, 1.5],
+		Box::new(RayTraceSimpleMaterial::new(RayTraceColor::new_with(0.2, 0.4, 0.9, 1.0))));
+	cube.set_rotation([0.4, 0.7, 0.0]);
+	scene.add_object(Box::new(cube));
+	scene.add_light::<RayTraceSpotLight>(Box::new(RayTraceSpotLight::new([3.0, 5.0, 5.0], RayTraceColor::white())));
+
+	let mut camera = RayTracerCameraPerspective::new_with_fov(&out_params, 50.0);
+	camera.set_position([0.0, 0.5, 5.0]);
+
+	check_golden("cube", RayTraceSource::new(scene, Box::new(camera), out_params, make_params()));
+}
+
+#[test]
+fn golden_plane() {
+	let out_params = RayTraceOutputParams::new(WIDTH, HEIGHT, 1);
+
+	let mut scene = RayTraceScene::new();
+	scene.add_object(Box::new(RayTraceObjectPlane::new([0.0, -1.0, 0.0], [0.0, 0.3, 0.0],
+		Box::new(RayTraceCheckerboardMaterial::new_with_colors([RayTraceColor::gray(), RayTraceColor::white()])))));
+	scene.add_light::<RayTraceSpotLight>(Box::new(RayTraceSpotLight::new([0.0, 4.0, 0.0], RayTraceColor::white())));
+
+	let mut camera = RayTracerCameraPerspective::new_with_fov(&out_params, 60.0);
+	camera.set_position([0.0, 1.0, 6.0]);
+
+	check_golden("plane", RayTraceSource::new(scene, Box::new(camera), out_params, make_params()));
+}